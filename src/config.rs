@@ -0,0 +1,740 @@
+//! Server Configuration
+//!
+//! Settings start from the defaults below, can be replaced by a JSON document
+//! passed as the actor's init state, and are then overridden by `GIT_SERVER_*`
+//! environment variables (read through the host, i.e. the Theater
+//! `environment` interface). The result is validated before the server starts.
+//!
+//! ```json
+//! {
+//!   "port": 8080,
+//!   "default_branch": "refs/heads/trunk",
+//!   "tls": { "cert_path": "/etc/git/cert.pem", "key_path": "/etc/git/key.pem" },
+//!   "limits": { "max_body_bytes": 104857600 },
+//!   "features": { "receive_pack": false }
+//! }
+//! ```
+
+use crate::host;
+use crate::utils::hash::HashAlgorithm;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+/// Agent string sent in capability advertisements by default
+pub const DEFAULT_AGENT: &str = concat!("git-server/", env!("CARGO_PKG_VERSION"));
+
+/// Events a webhook can subscribe to (`*` subscribes to all of them)
+pub const WEBHOOK_EVENTS: &[&str] = &["push", "create", "delete", "repository"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address the HTTP server binds to
+    pub host: String,
+    /// HTTP port (0 lets the system pick one)
+    pub port: u16,
+    /// Serve HTTPS with this certificate and key
+    pub tls: Option<TlsSettings>,
+    /// Repository name shown on the debug page (new repositories only)
+    pub repo_name: String,
+    /// Serve the bare repository at this host filesystem path in place,
+    /// creating it if needed, instead of keeping refs and objects in actor state
+    pub repo_path: Option<String>,
+    /// Let the actor write the bare repository's binary files (objects,
+    /// packs, indexes) by running `sh` and `base64` through the Theater
+    /// `filesystem` handler, whose `write-file` only takes text. The handler
+    /// must allow running them; this is checked at startup.
+    pub shell_writes: bool,
+    /// Branch HEAD points to in a new repository
+    pub default_branch: String,
+    /// Object id algorithm for a new repository
+    pub object_format: HashAlgorithm,
+    /// `agent=` capability value
+    pub agent: String,
+    pub limits: Limits,
+    pub gc: GcSettings,
+    pub repack: RepackSettings,
+    pub reflog: ReflogSettings,
+    pub transfer: TransferSettings,
+    pub hooks: HookSettings,
+    pub webhooks: WebhookSettings,
+    pub features: Features,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSettings {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Largest request body accepted, before and after Content-Encoding inflation
+    pub max_body_bytes: u64,
+    /// Most objects accepted in one pushed pack
+    pub max_pack_objects: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GcSettings {
+    /// Unreachable objects written more recently than this are kept
+    pub grace_period_secs: u64,
+    /// Collect garbage after a request once this long has passed since the
+    /// last run (0: only when asked through `POST /admin/gc`)
+    pub interval_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepackSettings {
+    /// Earlier objects of the same type each object is tried as a delta against
+    pub window: usize,
+    /// Longest chain of deltas on deltas
+    pub depth: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReflogSettings {
+    /// Reflog entries older than this are dropped, and no longer keep their
+    /// objects from garbage collection (0: keep entries forever)
+    pub expire_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransferSettings {
+    /// Refs left out of advertisements and refused to pushes, like git's
+    /// `transfer.hideRefs`: a ref prefix, `!` to unhide, `^` to match the
+    /// name with its namespace
+    pub hide_refs: Vec<String>,
+    /// Fetches may ask for hidden ref tips (`uploadpack.allowTipSHA1InWant`)
+    pub allow_tip_sha1_in_want: bool,
+    /// Fetches may ask for anything reachable from a ref, hidden or not
+    /// (`uploadpack.allowReachableSHA1InWant`)
+    pub allow_reachable_sha1_in_want: bool,
+    /// Fetches may ask for any object (`uploadpack.allowAnySHA1InWant`)
+    pub allow_any_sha1_in_want: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HookSettings {
+    /// Actors asked to approve each push before any ref changes (pre-receive)
+    pub pre_receive: Vec<String>,
+    /// Actors asked to approve each ref update on its own (update)
+    pub update: Vec<String>,
+    /// Actors told about each push once its refs have changed (post-receive)
+    pub post_receive: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookSettings {
+    /// Where event payloads are POSTed
+    pub endpoints: Vec<WebhookEndpoint>,
+    /// Tries per delivery before it's logged as failed
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for each one after it
+    pub retry_backoff_ms: u64,
+    /// Most recent deliveries kept in the delivery log
+    pub delivery_log_size: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookEndpoint {
+    /// `http://` or `https://` URL
+    pub url: String,
+    /// Key for the `X-Hub-Signature-256` HMAC of each payload
+    pub secret: Option<String>,
+    /// Events sent to this endpoint (see `WEBHOOK_EVENTS`)
+    pub events: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    /// Accept pushes (receive-pack)
+    pub receive_pack: bool,
+    /// Serve the read-only dumb HTTP protocol
+    pub dumb_http: bool,
+    /// Accept git:// connections over message-server channels
+    pub git_daemon: bool,
+    /// Serve the `/`, `/refs` and `/objects` debug pages (hidden refs and
+    /// objects that can't be fetched are left out)
+    pub debug_routes: bool,
+    /// Serve maintenance endpoints such as `POST /admin/gc`
+    pub admin_routes: bool,
+    /// Serve ref namespaces under `/ns/<name>/`
+    pub namespaces: bool,
+    /// Stream repository events to `/events` WebSocket subscribers
+    pub event_stream: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 8080,
+            tls: None,
+            repo_name: "git-server".to_string(),
+            repo_path: None,
+            shell_writes: false,
+            default_branch: "refs/heads/main".to_string(),
+            object_format: HashAlgorithm::default(),
+            agent: DEFAULT_AGENT.to_string(),
+            limits: Limits::default(),
+            gc: GcSettings::default(),
+            repack: RepackSettings::default(),
+            reflog: ReflogSettings::default(),
+            transfer: TransferSettings::default(),
+            hooks: HookSettings::default(),
+            webhooks: WebhookSettings::default(),
+            features: Features::default(),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 256 * 1024 * 1024,
+            max_pack_objects: 1_000_000,
+        }
+    }
+}
+
+impl Default for GcSettings {
+    fn default() -> Self {
+        Self {
+            // Two weeks, like git's gc.pruneExpire
+            grace_period_secs: 14 * 24 * 60 * 60,
+            interval_secs: 0,
+        }
+    }
+}
+
+impl Default for RepackSettings {
+    fn default() -> Self {
+        // git's pack.window and pack.depth
+        Self {
+            window: 10,
+            depth: 50,
+        }
+    }
+}
+
+impl Default for ReflogSettings {
+    fn default() -> Self {
+        Self {
+            // 90 days, like git's gc.reflogExpire
+            expire_secs: 90 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            max_attempts: 3,
+            retry_backoff_ms: 1000,
+            delivery_log_size: 100,
+        }
+    }
+}
+
+impl Default for WebhookEndpoint {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            secret: None,
+            events: vec!["push".to_string()],
+        }
+    }
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            receive_pack: true,
+            dumb_http: true,
+            git_daemon: true,
+            debug_routes: false,
+            admin_routes: false,
+            namespaces: true,
+            event_stream: true,
+        }
+    }
+}
+
+/// A setting that couldn't be parsed or is out of range
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    /// Setting name as written in the JSON document or environment
+    pub setting: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(setting: &str, message: impl Into<String>) -> Self {
+        Self {
+            setting: setting.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid config `{}`: {}", self.setting, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Parse a JSON config document (missing settings keep their defaults)
+    pub fn from_json(data: &[u8]) -> Result<Self, ConfigError> {
+        serde_json::from_slice(data).map_err(|e| ConfigError::new("init state", e.to_string()))
+    }
+
+    /// Apply `GIT_SERVER_*` environment overrides from the host, then validate
+    pub fn load(self) -> Result<Self, ConfigError> {
+        let config = self.with_overrides(host::get_var)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Apply overrides from `get_var` (environment variable lookup)
+    pub fn with_overrides(
+        mut self,
+        get_var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        if let Some(host) = get_var("GIT_SERVER_HOST") {
+            self.host = host;
+        }
+        if let Some(port) = get_var("GIT_SERVER_PORT") {
+            self.port = parse_var("GIT_SERVER_PORT", &port)?;
+        }
+        match (
+            get_var("GIT_SERVER_TLS_CERT_PATH"),
+            get_var("GIT_SERVER_TLS_KEY_PATH"),
+        ) {
+            (Some(cert_path), Some(key_path)) => {
+                self.tls = Some(TlsSettings {
+                    cert_path,
+                    key_path,
+                })
+            }
+            (None, None) => {}
+            _ => {
+                return Err(ConfigError::new(
+                    "GIT_SERVER_TLS_CERT_PATH",
+                    "GIT_SERVER_TLS_CERT_PATH and GIT_SERVER_TLS_KEY_PATH must be set together",
+                ))
+            }
+        }
+        if let Some(repo_name) = get_var("GIT_SERVER_REPO_NAME") {
+            self.repo_name = repo_name;
+        }
+        if let Some(path) = get_var("GIT_SERVER_REPO_PATH") {
+            self.repo_path = Some(path);
+        }
+        if let Some(branch) = get_var("GIT_SERVER_DEFAULT_BRANCH") {
+            self.default_branch = branch;
+        }
+        if let Some(format) = get_var("GIT_SERVER_OBJECT_FORMAT") {
+            self.object_format = HashAlgorithm::from_name(&format).ok_or_else(|| {
+                ConfigError::new(
+                    "GIT_SERVER_OBJECT_FORMAT",
+                    format!(
+                        "unknown object format `{}` (expected sha1 or sha256)",
+                        format
+                    ),
+                )
+            })?;
+        }
+        if let Some(agent) = get_var("GIT_SERVER_AGENT") {
+            self.agent = agent;
+        }
+        if let Some(bytes) = get_var("GIT_SERVER_MAX_BODY_BYTES") {
+            self.limits.max_body_bytes = parse_var("GIT_SERVER_MAX_BODY_BYTES", &bytes)?;
+        }
+        if let Some(objects) = get_var("GIT_SERVER_MAX_PACK_OBJECTS") {
+            self.limits.max_pack_objects = parse_var("GIT_SERVER_MAX_PACK_OBJECTS", &objects)?;
+        }
+
+        if let Some(secs) = get_var("GIT_SERVER_GC_GRACE_PERIOD_SECS") {
+            self.gc.grace_period_secs = parse_var("GIT_SERVER_GC_GRACE_PERIOD_SECS", &secs)?;
+        }
+        if let Some(secs) = get_var("GIT_SERVER_GC_INTERVAL_SECS") {
+            self.gc.interval_secs = parse_var("GIT_SERVER_GC_INTERVAL_SECS", &secs)?;
+        }
+        if let Some(window) = get_var("GIT_SERVER_REPACK_WINDOW") {
+            self.repack.window = parse_var("GIT_SERVER_REPACK_WINDOW", &window)?;
+        }
+        if let Some(depth) = get_var("GIT_SERVER_REPACK_DEPTH") {
+            self.repack.depth = parse_var("GIT_SERVER_REPACK_DEPTH", &depth)?;
+        }
+        if let Some(secs) = get_var("GIT_SERVER_REFLOG_EXPIRE_SECS") {
+            self.reflog.expire_secs = parse_var("GIT_SERVER_REFLOG_EXPIRE_SECS", &secs)?;
+        }
+        if let Some(patterns) = get_var("GIT_SERVER_HIDE_REFS") {
+            self.transfer.hide_refs = parse_list(&patterns);
+        }
+        let hooks = [
+            ("GIT_SERVER_PRE_RECEIVE_HOOKS", &mut self.hooks.pre_receive),
+            ("GIT_SERVER_UPDATE_HOOKS", &mut self.hooks.update),
+            (
+                "GIT_SERVER_POST_RECEIVE_HOOKS",
+                &mut self.hooks.post_receive,
+            ),
+        ];
+        for (name, actors) in hooks {
+            if let Some(value) = get_var(name) {
+                *actors = parse_list(&value);
+            }
+        }
+        // A single endpoint, replacing any from the document
+        if let Some(url) = get_var("GIT_SERVER_WEBHOOK_URL") {
+            let mut endpoint = WebhookEndpoint {
+                url,
+                secret: get_var("GIT_SERVER_WEBHOOK_SECRET"),
+                ..WebhookEndpoint::default()
+            };
+            if let Some(events) = get_var("GIT_SERVER_WEBHOOK_EVENTS") {
+                endpoint.events = parse_list(&events);
+            }
+            self.webhooks.endpoints = vec![endpoint];
+        }
+        if let Some(attempts) = get_var("GIT_SERVER_WEBHOOK_MAX_ATTEMPTS") {
+            self.webhooks.max_attempts = parse_var("GIT_SERVER_WEBHOOK_MAX_ATTEMPTS", &attempts)?;
+        }
+        if let Some(ms) = get_var("GIT_SERVER_WEBHOOK_RETRY_BACKOFF_MS") {
+            self.webhooks.retry_backoff_ms = parse_var("GIT_SERVER_WEBHOOK_RETRY_BACKOFF_MS", &ms)?;
+        }
+
+        let toggles = [
+            ("GIT_SERVER_RECEIVE_PACK", &mut self.features.receive_pack),
+            ("GIT_SERVER_DUMB_HTTP", &mut self.features.dumb_http),
+            ("GIT_SERVER_GIT_DAEMON", &mut self.features.git_daemon),
+            ("GIT_SERVER_DEBUG_ROUTES", &mut self.features.debug_routes),
+            ("GIT_SERVER_ADMIN_ROUTES", &mut self.features.admin_routes),
+            ("GIT_SERVER_NAMESPACES", &mut self.features.namespaces),
+            ("GIT_SERVER_EVENT_STREAM", &mut self.features.event_stream),
+            ("GIT_SERVER_SHELL_WRITES", &mut self.shell_writes),
+            (
+                "GIT_SERVER_ALLOW_TIP_SHA1_IN_WANT",
+                &mut self.transfer.allow_tip_sha1_in_want,
+            ),
+            (
+                "GIT_SERVER_ALLOW_REACHABLE_SHA1_IN_WANT",
+                &mut self.transfer.allow_reachable_sha1_in_want,
+            ),
+            (
+                "GIT_SERVER_ALLOW_ANY_SHA1_IN_WANT",
+                &mut self.transfer.allow_any_sha1_in_want,
+            ),
+        ];
+        for (name, enabled) in toggles {
+            if let Some(value) = get_var(name) {
+                *enabled = parse_bool(name, &value)?;
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Check every setting, reporting the first invalid one
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.host.trim().is_empty() {
+            return Err(ConfigError::new("host", "must not be empty"));
+        }
+        if let Some(tls) = &self.tls {
+            if tls.cert_path.is_empty() {
+                return Err(ConfigError::new("tls.cert_path", "must not be empty"));
+            }
+            if tls.key_path.is_empty() {
+                return Err(ConfigError::new("tls.key_path", "must not be empty"));
+            }
+        }
+        if self.repo_name.trim().is_empty() {
+            return Err(ConfigError::new("repo_name", "must not be empty"));
+        }
+        if self
+            .repo_path
+            .as_deref()
+            .is_some_and(|path| path.trim().is_empty())
+        {
+            return Err(ConfigError::new("repo_path", "must not be empty"));
+        }
+        let branch = self.default_branch.strip_prefix("refs/heads/");
+        if branch.is_none_or(|name| {
+            name.is_empty() || name.contains(|c: char| c.is_whitespace() || c.is_control())
+        }) {
+            return Err(ConfigError::new(
+                "default_branch",
+                format!(
+                    "`{}` is not a branch ref (expected e.g. refs/heads/main)",
+                    self.default_branch
+                ),
+            ));
+        }
+        if self.agent.is_empty() || self.agent.contains(char::is_whitespace) {
+            return Err(ConfigError::new(
+                "agent",
+                "must be a single non-empty token (it is sent as a capability)",
+            ));
+        }
+        if self.limits.max_body_bytes == 0 {
+            return Err(ConfigError::new(
+                "limits.max_body_bytes",
+                "must be at least 1",
+            ));
+        }
+        if self.limits.max_pack_objects == 0 {
+            return Err(ConfigError::new(
+                "limits.max_pack_objects",
+                "must be at least 1",
+            ));
+        }
+        let hooks = &self.hooks;
+        for (setting, actors) in [
+            ("hooks.pre_receive", &hooks.pre_receive),
+            ("hooks.update", &hooks.update),
+            ("hooks.post_receive", &hooks.post_receive),
+        ] {
+            if actors.iter().any(|actor| actor.trim().is_empty()) {
+                return Err(ConfigError::new(setting, "actor ids must not be empty"));
+            }
+        }
+        if self
+            .transfer
+            .hide_refs
+            .iter()
+            .any(|pattern| pattern.trim_start_matches(['!', '^']).is_empty())
+        {
+            return Err(ConfigError::new(
+                "transfer.hide_refs",
+                "patterns must name a ref prefix",
+            ));
+        }
+        for endpoint in &self.webhooks.endpoints {
+            if !(endpoint.url.starts_with("http://") || endpoint.url.starts_with("https://")) {
+                return Err(ConfigError::new(
+                    "webhooks.endpoints.url",
+                    format!("`{}` is not an http:// or https:// URL", endpoint.url),
+                ));
+            }
+            if let Some(event) = endpoint
+                .events
+                .iter()
+                .find(|event| *event != "*" && !WEBHOOK_EVENTS.contains(&event.as_str()))
+            {
+                return Err(ConfigError::new(
+                    "webhooks.endpoints.events",
+                    format!(
+                        "unknown event `{}` (expected one of {} or *)",
+                        event,
+                        WEBHOOK_EVENTS.join(", ")
+                    ),
+                ));
+            }
+        }
+        if self.webhooks.max_attempts == 0 {
+            return Err(ConfigError::new(
+                "webhooks.max_attempts",
+                "must be at least 1",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn parse_var<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, ConfigError>
+where
+    T::Err: Display,
+{
+    value
+        .trim()
+        .parse()
+        .map_err(|e| ConfigError::new(name, format!("`{}`: {}", value, e)))
+}
+
+/// A comma-separated list, ignoring blank entries
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, ConfigError> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(ConfigError::new(
+            name,
+            format!("`{}` is not a boolean (use true or false)", value),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_json_document_keeps_defaults_for_missing_settings() {
+        let config = Config::from_json(
+            br#"{"port": 9000, "features": {"receive_pack": false}, "limits": {"max_pack_objects": 10}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.host, "0.0.0.0");
+        assert!(!config.features.receive_pack);
+        assert!(config.features.dumb_http);
+        assert_eq!(config.limits.max_pack_objects, 10);
+        assert_eq!(
+            config.limits.max_body_bytes,
+            Limits::default().max_body_bytes
+        );
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_unknown_setting_is_rejected() {
+        let err = Config::from_json(br#"{"prot": 9000}"#).unwrap_err();
+        assert!(err.to_string().contains("unknown field `prot`"));
+    }
+
+    #[test]
+    fn test_environment_overrides() {
+        let config = Config::default()
+            .with_overrides(env(&[
+                ("GIT_SERVER_PORT", "8443"),
+                ("GIT_SERVER_TLS_CERT_PATH", "/tls/cert.pem"),
+                ("GIT_SERVER_TLS_KEY_PATH", "/tls/key.pem"),
+                ("GIT_SERVER_OBJECT_FORMAT", "sha256"),
+                ("GIT_SERVER_DUMB_HTTP", "off"),
+                ("GIT_SERVER_REPO_PATH", "repos/site.git"),
+                ("GIT_SERVER_SHELL_WRITES", "true"),
+                ("GIT_SERVER_GC_INTERVAL_SECS", "86400"),
+                ("GIT_SERVER_REPACK_DEPTH", "10"),
+                ("GIT_SERVER_REFLOG_EXPIRE_SECS", "0"),
+                ("GIT_SERVER_ADMIN_ROUTES", "true"),
+                ("GIT_SERVER_NAMESPACES", "false"),
+                ("GIT_SERVER_EVENT_STREAM", "0"),
+                ("GIT_SERVER_HIDE_REFS", "refs/pull, !refs/pull/open,"),
+                ("GIT_SERVER_ALLOW_REACHABLE_SHA1_IN_WANT", "yes"),
+                ("GIT_SERVER_POST_RECEIVE_HOOKS", "ci-trigger,audit"),
+                ("GIT_SERVER_WEBHOOK_URL", "https://ci.example.com/hook"),
+                ("GIT_SERVER_WEBHOOK_EVENTS", "push, delete"),
+                ("GIT_SERVER_WEBHOOK_MAX_ATTEMPTS", "5"),
+            ]))
+            .unwrap();
+
+        assert_eq!(config.port, 8443);
+        assert_eq!(config.tls.unwrap().key_path, "/tls/key.pem");
+        assert_eq!(config.object_format, HashAlgorithm::Sha256);
+        assert!(!config.features.dumb_http);
+        assert_eq!(config.repo_path.as_deref(), Some("repos/site.git"));
+        assert!(config.shell_writes);
+        assert_eq!(config.gc.interval_secs, 86400);
+        assert_eq!(config.repack.depth, 10);
+        assert_eq!(config.reflog.expire_secs, 0);
+        assert!(config.features.admin_routes);
+        assert!(!config.features.namespaces);
+        assert!(!config.features.event_stream);
+        assert_eq!(config.transfer.hide_refs, ["refs/pull", "!refs/pull/open"]);
+        assert!(config.transfer.allow_reachable_sha1_in_want);
+        assert!(!config.transfer.allow_any_sha1_in_want);
+        assert_eq!(config.hooks.post_receive, ["ci-trigger", "audit"]);
+        assert!(config.hooks.pre_receive.is_empty());
+        assert_eq!(
+            config.webhooks.endpoints,
+            [WebhookEndpoint {
+                url: "https://ci.example.com/hook".to_string(),
+                secret: None,
+                events: vec!["push".to_string(), "delete".to_string()],
+            }]
+        );
+        assert_eq!(config.webhooks.max_attempts, 5);
+    }
+
+    #[test]
+    fn test_invalid_values_name_the_setting() {
+        let err = Config::default()
+            .with_overrides(env(&[("GIT_SERVER_PORT", "80000")]))
+            .unwrap_err();
+        assert_eq!(err.setting, "GIT_SERVER_PORT");
+
+        let err = Config::default()
+            .with_overrides(env(&[("GIT_SERVER_TLS_CERT_PATH", "/tls/cert.pem")]))
+            .unwrap_err();
+        assert!(err.message.contains("must be set together"));
+
+        let config = Config {
+            default_branch: "main".to_string(),
+            ..Config::default()
+        };
+        assert_eq!(config.validate().unwrap_err().setting, "default_branch");
+
+        let config = Config {
+            agent: "git server".to_string(),
+            ..Config::default()
+        };
+        assert_eq!(config.validate().unwrap_err().setting, "agent");
+
+        let config = Config {
+            transfer: TransferSettings {
+                hide_refs: vec!["!^".to_string()],
+                ..TransferSettings::default()
+            },
+            ..Config::default()
+        };
+        assert_eq!(config.validate().unwrap_err().setting, "transfer.hide_refs");
+
+        let config = Config {
+            hooks: HookSettings {
+                update: vec![" ".to_string()],
+                ..HookSettings::default()
+            },
+            ..Config::default()
+        };
+        assert_eq!(config.validate().unwrap_err().setting, "hooks.update");
+
+        let config = Config {
+            webhooks: WebhookSettings {
+                endpoints: vec![WebhookEndpoint {
+                    url: "https://ci.example.com/hook".to_string(),
+                    events: vec!["pull_request".to_string()],
+                    ..WebhookEndpoint::default()
+                }],
+                ..WebhookSettings::default()
+            },
+            ..Config::default()
+        };
+        assert_eq!(
+            config.validate().unwrap_err().setting,
+            "webhooks.endpoints.events"
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! Git Server Errors
+//!
+//! One error type for every layer, from pkt-line parsing up to ref updates.
+//! Each variant knows how it is reported to a client:
+//!
+//! - `http_status` for the HTTP response status
+//! - `pkt_line_message` for a pkt-line `ERR <message>` (upload-pack)
+//! - `ng_reason` for a per-ref `ng <ref> <reason>` line (receive-pack)
+//!
+//! and `kind` gives a stable label for logs and monitoring.
+
+use std::fmt::Display;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GitError {
+    /// Malformed request or protocol violation by the client
+    Protocol(String),
+    /// Pack data that can't be parsed or fails verification
+    PackCorrupt(String),
+    /// An object id that isn't in the repository
+    MissingObject(String),
+    /// A ref update that conflicts with the ref's current state
+    RefConflict { ref_name: String, reason: String },
+    /// A ref name that breaks git's `check-ref-format` rules
+    InvalidRefName { ref_name: String, reason: String },
+    /// The operation is not allowed for this client or ref
+    PermissionDenied(String),
+    /// A server-side hook refused a push or one of its refs
+    HookDeclined(String),
+    /// A configured size or count limit was exceeded
+    LimitExceeded(String),
+    /// Reading or writing repository files on the host failed
+    Storage(String),
+}
+
+impl GitError {
+    /// Stable, machine-friendly name of the error kind
+    pub fn kind(&self) -> &'static str {
+        match self {
+            GitError::Protocol(_) => "protocol",
+            GitError::PackCorrupt(_) => "pack-corrupt",
+            GitError::MissingObject(_) => "missing-object",
+            GitError::RefConflict { .. } => "ref-conflict",
+            GitError::InvalidRefName { .. } => "invalid-ref-name",
+            GitError::PermissionDenied(_) => "permission-denied",
+            GitError::HookDeclined(_) => "hook-declined",
+            GitError::LimitExceeded(_) => "limit-exceeded",
+            GitError::Storage(_) => "storage",
+        }
+    }
+
+    /// HTTP status used when the error aborts a whole request
+    pub fn http_status(&self) -> u16 {
+        match self {
+            GitError::Protocol(_) => 400,
+            GitError::PackCorrupt(_) => 400,
+            GitError::MissingObject(_) => 404,
+            GitError::RefConflict { .. } => 409,
+            GitError::InvalidRefName { .. } => 400,
+            GitError::PermissionDenied(_) => 403,
+            GitError::HookDeclined(_) => 403,
+            GitError::LimitExceeded(_) => 413,
+            GitError::Storage(_) => 500,
+        }
+    }
+
+    /// Message for a pkt-line `ERR` packet
+    pub fn pkt_line_message(&self) -> String {
+        match self {
+            GitError::MissingObject(oid) => format!("upload-pack: not our ref {}", oid),
+            other => other.to_string(),
+        }
+    }
+
+    /// Reason for a receive-pack `ng <ref> <reason>` status line
+    pub fn ng_reason(&self) -> String {
+        match self {
+            GitError::Protocol(msg) => format!("protocol error: {}", msg),
+            GitError::PackCorrupt(_) => "unpacker error".to_string(),
+            GitError::MissingObject(_) => "missing necessary objects".to_string(),
+            GitError::RefConflict { reason, .. } => reason.clone(),
+            GitError::InvalidRefName { .. } => "funny refname".to_string(),
+            GitError::PermissionDenied(msg) => format!("permission denied: {}", msg),
+            GitError::HookDeclined(reason) => reason.clone(),
+            GitError::LimitExceeded(msg) => format!("limit exceeded: {}", msg),
+            GitError::Storage(_) => "failed to write".to_string(),
+        }
+    }
+}
+
+impl Display for GitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GitError::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            GitError::PackCorrupt(msg) => write!(f, "corrupt pack: {}", msg),
+            GitError::MissingObject(oid) => write!(f, "missing object {}", oid),
+            GitError::RefConflict { ref_name, reason } => {
+                write!(f, "ref conflict on {}: {}", ref_name, reason)
+            }
+            GitError::InvalidRefName { ref_name, reason } => {
+                write!(f, "invalid ref name {:?}: {}", ref_name, reason)
+            }
+            GitError::PermissionDenied(msg) => write!(f, "permission denied: {}", msg),
+            GitError::HookDeclined(reason) => write!(f, "declined by hook: {}", reason),
+            GitError::LimitExceeded(msg) => write!(f, "limit exceeded: {}", msg),
+            GitError::Storage(msg) => write!(f, "storage error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for GitError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_facing_mappings() {
+        let missing = GitError::MissingObject("abc".to_string());
+        assert_eq!(missing.http_status(), 404);
+        assert_eq!(missing.pkt_line_message(), "upload-pack: not our ref abc");
+        assert_eq!(missing.ng_reason(), "missing necessary objects");
+
+        let conflict = GitError::RefConflict {
+            ref_name: "refs/heads/main".to_string(),
+            reason: "stale info".to_string(),
+        };
+        assert_eq!(conflict.http_status(), 409);
+        assert_eq!(conflict.ng_reason(), "stale info");
+        assert_eq!(conflict.kind(), "ref-conflict");
+
+        let funny = GitError::InvalidRefName {
+            ref_name: "refs/heads/a..b".to_string(),
+            reason: "contains ..".to_string(),
+        };
+        assert_eq!(funny.http_status(), 400);
+        assert_eq!(funny.ng_reason(), "funny refname");
+
+        let declined = GitError::HookDeclined("no force-pushes to main".to_string());
+        assert_eq!(declined.http_status(), 403);
+        assert_eq!(declined.ng_reason(), "no force-pushes to main");
+        assert_eq!(declined.kind(), "hook-declined");
+    }
+}
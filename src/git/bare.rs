@@ -0,0 +1,532 @@
+//! Bare Repository Layout
+//!
+//! A repository on the host filesystem in the layout `git init --bare`
+//! creates, so stock `git` can inspect it, ordinary tools can back it up,
+//! and existing repositories can be served in place:
+//!
+//! ```text
+//! HEAD                    ref: refs/heads/main
+//! config                  [core] bare = true, [extensions] objectformat
+//! packed-refs             <oid> <ref> lines, `^<oid>` peel lines
+//! refs/heads/<branch>     <oid> (overrides packed-refs)
+//! refs/<name>             ref: <target> (symbolic ref)
+//! objects/<xx>/<rest>     zlib-compressed loose object
+//! objects/pack/pack-<checksum>.{pack,idx}
+//! ```
+//!
+//! Ref names are checked against `check-ref-format` before they become
+//! paths, which also keeps them inside `refs/`.
+//!
+//! Loose objects are written from the exact bytes that were received, so
+//! their content always hashes to their path.
+//!
+//! The Theater host writes binary files (objects, packs, indexes) through
+//! `sh`, decoding into a temporary file that is renamed into place, and only
+//! once `shell_writes` allows it (see `host::prepare_binary_writes`). `HEAD`,
+//! ref files and `packed-refs` are replaced the way git replaces them: written
+//! to `<file>.lock`, then renamed over the file, and left alone while someone
+//! else holds the lock.
+//!
+//! Refs and objects are read once and then kept up to date in memory (see
+//! `GitRepoState::open_bare`), so the server should be the only writer.
+
+use crate::error::GitError;
+use crate::git::objects::{GitObject, RawObject};
+use crate::git::refname::check_ref_name;
+use crate::host::{self, log};
+use crate::utils::compression::{compress_zlib, decompress_zlib};
+use crate::utils::hash::HashAlgorithm;
+use std::collections::{BTreeMap, HashMap};
+
+/// A bare repository rooted at a host filesystem path
+#[derive(Debug, Clone, PartialEq)]
+pub struct BareRepository {
+    root: String,
+}
+
+impl BareRepository {
+    pub fn new(root: &str) -> Self {
+        let trimmed = root.trim_end_matches('/');
+        Self {
+            root: if trimmed.is_empty() { root } else { trimmed }.to_string(),
+        }
+    }
+
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    /// Host path of a file inside the repository
+    pub fn path(&self, relative: &str) -> String {
+        format!("{}/{}", self.root, relative)
+    }
+
+    /// Whether the repository has been initialized (has a `HEAD`)
+    pub fn exists(&self) -> Result<bool, GitError> {
+        Ok(self.read_file("HEAD")?.is_some())
+    }
+
+    /// Create the directories, `config` and `HEAD` of an empty repository
+    pub fn init(&self, head: &str, algo: HashAlgorithm) -> Result<(), GitError> {
+        for dir in ["objects/info", "objects/pack", "refs/heads", "refs/tags"] {
+            host::create_dir(&self.path(dir)).map_err(GitError::Storage)?;
+        }
+
+        let mut config = String::from("[core]\n");
+        // Version 1 is required for any `extensions.*` setting
+        let version = if algo == HashAlgorithm::Sha1 { 0 } else { 1 };
+        config.push_str(&format!("\trepositoryformatversion = {}\n", version));
+        config.push_str("\tfilemode = true\n\tbare = true\n");
+        if algo != HashAlgorithm::Sha1 {
+            config.push_str(&format!("[extensions]\n\tobjectformat = {}\n", algo.name()));
+        }
+        self.write_file("config", config.as_bytes())?;
+
+        // HEAD last: a repository without one was never initialized
+        self.write_head(head)?;
+        log(&format!("Initialized bare repository at {}", self.root));
+        Ok(())
+    }
+
+    /// Object format from `extensions.objectformat` in `config` (default sha1)
+    pub fn object_format(&self) -> Result<HashAlgorithm, GitError> {
+        let config = self.read_text("config")?.unwrap_or_default();
+        match config_value(&config, "extensions", "objectformat") {
+            Some(name) => HashAlgorithm::from_name(&name).ok_or_else(|| {
+                GitError::Storage(format!("{}: unknown object format {}", self.root, name))
+            }),
+            None => Ok(HashAlgorithm::Sha1),
+        }
+    }
+
+    /// The branch `HEAD` points to
+    pub fn read_head(&self) -> Result<String, GitError> {
+        let head = self
+            .read_text("HEAD")?
+            .ok_or_else(|| GitError::Storage(format!("{}: missing HEAD", self.root)))?;
+        match head.trim_end().strip_prefix("ref: ") {
+            Some(target) => Ok(target.to_string()),
+            None => Err(GitError::Storage(format!(
+                "{}: detached HEAD is not supported",
+                self.root
+            ))),
+        }
+    }
+
+    pub fn write_head(&self, ref_name: &str) -> Result<(), GitError> {
+        check_ref_name(ref_name)?;
+        self.write_locked("HEAD", format!("ref: {}\n", ref_name).as_bytes())
+    }
+
+    /// Every ref from `packed-refs` and the loose ref files, which take
+    /// precedence. Symbolic refs are read by `read_symrefs`.
+    pub fn read_refs(&self, algo: HashAlgorithm) -> Result<HashMap<String, String>, GitError> {
+        let mut refs = HashMap::new();
+        if let Some(packed) = self.read_text("packed-refs")? {
+            for line in packed.lines() {
+                if line.starts_with('#') || line.starts_with('^') || line.is_empty() {
+                    continue;
+                }
+                match line.split_once(' ') {
+                    Some((oid, name)) if is_oid(oid, algo) => {
+                        refs.insert(name.to_string(), oid.to_string());
+                    }
+                    _ => log(&format!("Skipping malformed packed-refs line: {}", line)),
+                }
+            }
+        }
+        for (name, content) in self.loose_refs()? {
+            if is_oid(&content, algo) {
+                refs.insert(name, content);
+            } else if !content.starts_with("ref: ") {
+                log(&format!("Skipping ref {} ({})", name, content));
+            }
+        }
+        Ok(refs)
+    }
+
+    /// Symbolic refs under `refs/` (such as `refs/remotes/origin/HEAD`) and
+    /// their targets
+    pub fn read_symrefs(&self) -> Result<BTreeMap<String, String>, GitError> {
+        Ok(self
+            .loose_refs()?
+            .into_iter()
+            .filter_map(|(name, content)| {
+                let target = content.strip_prefix("ref: ")?.to_string();
+                Some((name, target))
+            })
+            .collect())
+    }
+
+    /// Name and (trimmed) content of every loose ref file
+    fn loose_refs(&self) -> Result<Vec<(String, String)>, GitError> {
+        let mut files = Vec::new();
+        let mut dirs = vec!["refs".to_string()];
+        while let Some(dir) = dirs.pop() {
+            let entries = host::list_dir(&self.path(&dir)).map_err(GitError::Storage)?;
+            for entry in entries.unwrap_or_default() {
+                let name = format!("{}/{}", dir, entry);
+                if host::list_dir(&self.path(&name))
+                    .map_err(GitError::Storage)?
+                    .is_some()
+                {
+                    dirs.push(name);
+                    continue;
+                }
+                if name.ends_with(".lock") {
+                    continue;
+                }
+                let content = self.read_text(&name)?.unwrap_or_default();
+                files.push((name, content.trim_end().to_string()));
+            }
+        }
+        Ok(files)
+    }
+
+    /// Create or update a loose ref file
+    pub fn write_ref(&self, ref_name: &str, oid: &str) -> Result<(), GitError> {
+        check_ref_name(ref_name)?;
+        self.write_locked(ref_name, format!("{}\n", oid).as_bytes())
+    }
+
+    /// Create or update a symbolic ref file
+    pub fn write_symref(&self, ref_name: &str, target: &str) -> Result<(), GitError> {
+        check_ref_name(ref_name)?;
+        check_ref_name(target)?;
+        self.write_locked(ref_name, format!("ref: {}\n", target).as_bytes())
+    }
+
+    /// Remove a ref from both the loose files and `packed-refs`
+    pub fn delete_ref(&self, ref_name: &str) -> Result<(), GitError> {
+        check_ref_name(ref_name)?;
+        self.check_unlocked(ref_name)?;
+        self.remove_file(ref_name)?;
+
+        let Some(packed) = self.read_text("packed-refs")? else {
+            return Ok(());
+        };
+        let mut kept = String::with_capacity(packed.len());
+        let mut removed = false;
+        let mut skipping_peel = false;
+        for line in packed.lines() {
+            if line.starts_with('^') && skipping_peel {
+                continue;
+            }
+            skipping_peel = line.split_once(' ').map(|(_, name)| name) == Some(ref_name);
+            if skipping_peel {
+                removed = true;
+                continue;
+            }
+            kept.push_str(line);
+            kept.push('\n');
+        }
+        if removed {
+            self.write_locked("packed-refs", kept.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Ids of the loose objects under `objects/<xx>/`
+    pub fn loose_object_ids(&self, algo: HashAlgorithm) -> Result<Vec<String>, GitError> {
+        let mut ids = Vec::new();
+        let dirs = host::list_dir(&self.path("objects")).map_err(GitError::Storage)?;
+        for dir in dirs.unwrap_or_default() {
+            if dir.len() != 2 || !dir.chars().all(|c| c.is_ascii_hexdigit()) {
+                continue;
+            }
+            let files = host::list_dir(&self.path(&format!("objects/{}", dir)))
+                .map_err(GitError::Storage)?;
+            for file in files.unwrap_or_default() {
+                let id = format!("{}{}", dir, file);
+                if is_oid(&id, algo) {
+                    ids.push(id);
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Read and inflate a loose object (`None` if there is no such file)
+    pub fn read_loose_object(
+        &self,
+        id: &str,
+        algo: HashAlgorithm,
+    ) -> Result<Option<GitObject>, GitError> {
+        let Some(raw) = self.read_loose_raw(id)? else {
+            return Ok(None);
+        };
+        raw.to_object(algo)
+            .map(Some)
+            .map_err(|e| GitError::Storage(format!("corrupt loose object {}: {}", id, e)))
+    }
+
+    /// Type and exact content of a loose object (`None` if there is no such
+    /// file)
+    pub fn read_loose_raw(&self, id: &str) -> Result<Option<RawObject>, GitError> {
+        let Some(compressed) = self.read_file(&loose_object_path(id))? else {
+            return Ok(None);
+        };
+        let corrupt = |e: String| GitError::Storage(format!("corrupt loose object {}: {}", id, e));
+        let data = decompress_zlib(&compressed).map_err(|e| corrupt(e.to_string()))?;
+        RawObject::from_loose_format(&data)
+            .map(Some)
+            .map_err(corrupt)
+    }
+
+    pub fn write_loose_object(&self, id: &str, raw: &RawObject) -> Result<(), GitError> {
+        self.write_file(
+            &loose_object_path(id),
+            &compress_zlib(&raw.to_loose_format()),
+        )
+    }
+
+    pub fn remove_loose_object(&self, id: &str) -> Result<(), GitError> {
+        self.remove_file(&loose_object_path(id))
+    }
+
+    /// Size of a loose object file (0 if there is none)
+    pub fn loose_object_size(&self, id: &str) -> Result<u64, GitError> {
+        Ok(self
+            .read_file(&loose_object_path(id))?
+            .map_or(0, |data| data.len() as u64))
+    }
+
+    /// Names (`pack-<name>.pack`) of the packs that have an index
+    pub fn pack_names(&self) -> Result<Vec<String>, GitError> {
+        let files = host::list_dir(&self.path("objects/pack"))
+            .map_err(GitError::Storage)?
+            .unwrap_or_default();
+        let mut names: Vec<String> = files
+            .iter()
+            .filter_map(|file| file.strip_prefix("pack-")?.strip_suffix(".idx"))
+            .filter(|name| files.contains(&format!("pack-{}.pack", name)))
+            .map(str::to_string)
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Read a file inside the repository
+    pub fn read_file(&self, relative: &str) -> Result<Option<Vec<u8>>, GitError> {
+        host::read_path(&self.path(relative)).map_err(GitError::Storage)
+    }
+
+    /// Write a file inside the repository, creating its directory if needed
+    pub fn write_file(&self, relative: &str, data: &[u8]) -> Result<(), GitError> {
+        if let Some((dir, _)) = relative.rsplit_once('/') {
+            host::create_dir(&self.path(dir)).map_err(GitError::Storage)?;
+        }
+        host::write_path(&self.path(relative), data).map_err(GitError::Storage)
+    }
+
+    /// Replace a file the way git does: write `<file>.lock`, then rename it
+    /// over the file, so readers see the old content or the new, never part
+    /// of it
+    fn write_locked(&self, relative: &str, data: &[u8]) -> Result<(), GitError> {
+        self.check_unlocked(relative)?;
+        let lock = format!("{}.lock", relative);
+        self.write_file(&lock, data)?;
+        if let Err(e) = host::rename_path(&self.path(&lock), &self.path(relative)) {
+            let _ = self.remove_file(&lock);
+            return Err(GitError::Storage(e));
+        }
+        Ok(())
+    }
+
+    /// Fail if someone else (such as git itself) holds the file's lock
+    fn check_unlocked(&self, relative: &str) -> Result<(), GitError> {
+        let lock = format!("{}.lock", relative);
+        if self.read_file(&lock)?.is_some() {
+            return Err(GitError::Storage(format!(
+                "unable to create '{}': File exists",
+                self.path(&lock)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Delete a file inside the repository (a missing file is not an error)
+    pub fn remove_file(&self, relative: &str) -> Result<(), GitError> {
+        host::remove_path(&self.path(relative)).map_err(GitError::Storage)
+    }
+
+    fn read_text(&self, relative: &str) -> Result<Option<String>, GitError> {
+        Ok(self
+            .read_file(relative)?
+            .map(|data| String::from_utf8_lossy(&data).into_owned()))
+    }
+}
+
+fn loose_object_path(id: &str) -> String {
+    format!("objects/{}/{}", &id[..2], &id[2..])
+}
+
+fn is_oid(value: &str, algo: HashAlgorithm) -> bool {
+    value.len() == algo.hex_len() && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Value of `key` in `[section]` of a git config file. Subsections, includes
+/// and quoting aren't supported; only the settings this server reads.
+fn config_value(config: &str, section: &str, key: &str) -> Option<String> {
+    let mut current = String::new();
+    let mut value = None;
+    for line in config.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            current = header.trim().to_ascii_lowercase();
+            continue;
+        }
+        if current != section {
+            continue;
+        }
+        let (name, setting) = line.split_once('=').unwrap_or((line, "true"));
+        if name.trim().eq_ignore_ascii_case(key) {
+            // The last occurrence wins, as in git
+            value = Some(setting.trim().to_string());
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_repo(name: &str) -> BareRepository {
+        let dir = std::env::temp_dir().join(format!("git-server-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        BareRepository::new(dir.to_str().unwrap())
+    }
+
+    #[test]
+    fn test_init_writes_head_and_object_format() {
+        let repo = temp_repo("bare-init");
+        assert!(!repo.exists().unwrap());
+
+        repo.init("refs/heads/trunk", HashAlgorithm::Sha256)
+            .unwrap();
+        assert!(repo.exists().unwrap());
+        assert_eq!(repo.read_head().unwrap(), "refs/heads/trunk");
+        assert_eq!(repo.object_format().unwrap(), HashAlgorithm::Sha256);
+        assert!(repo.read_refs(HashAlgorithm::Sha256).unwrap().is_empty());
+        assert!(repo.pack_names().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_loose_refs_override_packed_refs() {
+        let repo = temp_repo("bare-refs");
+        let algo = HashAlgorithm::Sha1;
+        repo.init("refs/heads/main", algo).unwrap();
+        let (a, b, c) = ("a".repeat(40), "b".repeat(40), "c".repeat(40));
+        let packed = format!(
+            "# pack-refs with: peeled fully-peeled sorted \n{a} refs/heads/main\n{b} refs/tags/v1\n^{c}\n"
+        );
+        repo.write_file("packed-refs", packed.as_bytes()).unwrap();
+        repo.write_ref("refs/heads/main", &c).unwrap();
+        repo.write_ref("refs/heads/feature/x", &b).unwrap();
+
+        let refs = repo.read_refs(algo).unwrap();
+        assert_eq!(refs.len(), 3);
+        assert_eq!(refs["refs/heads/main"], c);
+        assert_eq!(refs["refs/tags/v1"], b);
+        assert_eq!(refs["refs/heads/feature/x"], b);
+
+        // Deleting drops the packed entry and its peel line too
+        repo.delete_ref("refs/tags/v1").unwrap();
+        repo.delete_ref("refs/heads/main").unwrap();
+        let refs = repo.read_refs(algo).unwrap();
+        assert_eq!(refs.keys().collect::<Vec<_>>(), ["refs/heads/feature/x"]);
+        let packed = String::from_utf8(repo.read_file("packed-refs").unwrap().unwrap()).unwrap();
+        assert!(!packed.contains(&c));
+
+        // Symbolic refs are kept apart from the refs they point to
+        repo.write_symref("refs/remotes/origin/HEAD", "refs/heads/feature/x")
+            .unwrap();
+        assert_eq!(repo.read_refs(algo).unwrap().len(), 1);
+        let symrefs = repo.read_symrefs().unwrap();
+        assert_eq!(
+            symrefs.into_iter().collect::<Vec<_>>(),
+            [(
+                "refs/remotes/origin/HEAD".to_string(),
+                "refs/heads/feature/x".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_ref_names_stay_inside_refs() {
+        let repo = temp_repo("bare-escape");
+        let oid = "a".repeat(40);
+        for name in [
+            "HEAD",
+            "refs/../config",
+            "refs/heads//x",
+            "refs/heads/x.lock",
+            "objects/x",
+        ] {
+            assert!(repo.write_ref(name, &oid).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_loose_objects_roundtrip() {
+        let repo = temp_repo("bare-objects");
+        let algo = HashAlgorithm::Sha1;
+        repo.init("refs/heads/main", algo).unwrap();
+        let blob = GitObject::Blob {
+            content: b"hello world".to_vec(),
+        };
+        let id = blob.compute_hash(algo);
+
+        assert_eq!(repo.read_loose_object(&id, algo).unwrap(), None);
+        let raw = RawObject::from_object(&blob);
+        repo.write_loose_object(&id, &raw).unwrap();
+        assert_eq!(repo.loose_object_ids(algo).unwrap(), vec![id.clone()]);
+        assert_eq!(repo.read_loose_raw(&id).unwrap(), Some(raw));
+        assert_eq!(repo.read_loose_object(&id, algo).unwrap(), Some(blob));
+    }
+
+    #[test]
+    fn test_refs_are_replaced_through_lock_files() {
+        let repo = temp_repo("bare-lock");
+        let algo = HashAlgorithm::Sha1;
+        repo.init("refs/heads/main", algo).unwrap();
+        let (old, new) = ("a".repeat(40), "b".repeat(40));
+        repo.write_ref("refs/heads/main", &old).unwrap();
+        repo.write_ref("refs/heads/main", &new).unwrap();
+        assert_eq!(
+            repo.read_file("refs/heads/main").unwrap(),
+            Some(format!("{}\n", new).into_bytes())
+        );
+        assert_eq!(repo.read_file("refs/heads/main.lock").unwrap(), None);
+
+        // A lock someone else holds is left alone, and so is the ref
+        repo.write_file("refs/heads/main.lock", b"held").unwrap();
+        assert!(repo.write_ref("refs/heads/main", &old).is_err());
+        assert!(repo.delete_ref("refs/heads/main").is_err());
+        assert_eq!(
+            repo.read_file("refs/heads/main").unwrap(),
+            Some(format!("{}\n", new).into_bytes())
+        );
+        assert_eq!(
+            repo.read_file("refs/heads/main.lock").unwrap(),
+            Some(b"held".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_config_value() {
+        let config = "[core]\n\tbare = true\n[Extensions]\n\tobjectFormat = sha256 \n";
+        assert_eq!(
+            config_value(config, "extensions", "objectformat").as_deref(),
+            Some("sha256")
+        );
+        assert_eq!(
+            config_value(config, "core", "bare").as_deref(),
+            Some("true")
+        );
+        assert_eq!(config_value(config, "core", "objectformat"), None);
+    }
+}
@@ -0,0 +1,291 @@
+//! Git Delta Encoding
+//!
+//! A delta rebuilds a target object from a base object, as in `OBJ_OFS_DELTA`
+//! and `OBJ_REF_DELTA` pack entries:
+//!
+//! ```text
+//! base size (varint) | target size (varint) | instructions
+//! copy:   1oooossss  offset bytes (oooo: which are present, LSB first)
+//!                    size bytes (sss: which are present; size 0 means 0x10000)
+//! insert: 0nnnnnnn   n literal bytes (1..=127)
+//! ```
+//!
+//! `DeltaIndex` finds copies by hashing the base in `BLOCK_SIZE` blocks and
+//! extending every match as far as it goes, which is enough for revisions of
+//! the same file or tree.
+
+use std::collections::HashMap;
+
+/// Length of the base blocks matches start from
+const BLOCK_SIZE: usize = 16;
+
+/// Largest copy a single instruction encodes (the size 0 special case)
+const MAX_COPY: usize = 0x10000;
+
+/// Longest literal run a single insert instruction carries
+const MAX_INSERT: usize = 0x7f;
+
+/// Base blocks remembered per hash; more just slows matching down
+const MAX_CANDIDATES: usize = 8;
+
+/// Rebuild the target of `delta` from `base`
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, String> {
+    let mut cursor = 0;
+    let base_size = read_size(delta, &mut cursor)?;
+    if base_size != base.len() {
+        return Err(format!(
+            "delta base is {} bytes, expected {}",
+            base.len(),
+            base_size
+        ));
+    }
+    let target_size = read_size(delta, &mut cursor)?;
+
+    let mut target = Vec::with_capacity(target_size);
+    while cursor < delta.len() {
+        let op = delta[cursor];
+        cursor += 1;
+        if op & 0x80 != 0 {
+            let mut read_bytes = |flags: u8, count: usize| -> Result<usize, String> {
+                let mut value = 0usize;
+                for i in 0..count {
+                    if flags & (1 << i) != 0 {
+                        let byte = *delta.get(cursor).ok_or("truncated copy instruction")?;
+                        value |= (byte as usize) << (8 * i);
+                        cursor += 1;
+                    }
+                }
+                Ok(value)
+            };
+            let offset = read_bytes(op, 4)?;
+            let size = match read_bytes(op >> 4, 3)? {
+                0 => MAX_COPY,
+                size => size,
+            };
+            let copy = offset
+                .checked_add(size)
+                .and_then(|end| base.get(offset..end))
+                .ok_or_else(|| {
+                    format!("copy of {} bytes at {} is outside the base", size, offset)
+                })?;
+            target.extend_from_slice(copy);
+        } else if op != 0 {
+            let literal = delta
+                .get(cursor..cursor + op as usize)
+                .ok_or("truncated insert instruction")?;
+            target.extend_from_slice(literal);
+            cursor += op as usize;
+        } else {
+            return Err("invalid delta instruction 0".to_string());
+        }
+        if target.len() > target_size {
+            return Err("delta produces more than its target size".to_string());
+        }
+    }
+
+    if target.len() != target_size {
+        return Err(format!(
+            "delta produced {} bytes, expected {}",
+            target.len(),
+            target_size
+        ));
+    }
+    Ok(target)
+}
+
+/// A base object prepared for computing deltas against it
+pub struct DeltaIndex<'a> {
+    base: &'a [u8],
+    /// Block hash -> offsets of base blocks with that hash
+    blocks: HashMap<u32, Vec<usize>>,
+}
+
+impl<'a> DeltaIndex<'a> {
+    pub fn new(base: &'a [u8]) -> Self {
+        let mut blocks: HashMap<u32, Vec<usize>> = HashMap::new();
+        for offset in (0..base.len().saturating_sub(BLOCK_SIZE - 1)).step_by(BLOCK_SIZE) {
+            let offsets = blocks.entry(block_hash(&base[offset..])).or_default();
+            if offsets.len() < MAX_CANDIDATES {
+                offsets.push(offset);
+            }
+        }
+        Self { base, blocks }
+    }
+
+    /// A delta turning the base into `target`, or `None` if it would be
+    /// larger than `max_size` bytes
+    pub fn delta(&self, target: &[u8], max_size: usize) -> Option<Vec<u8>> {
+        let mut delta = Vec::new();
+        write_size(&mut delta, self.base.len());
+        write_size(&mut delta, target.len());
+
+        let mut literal_start = 0;
+        let mut position = 0;
+        while position + BLOCK_SIZE <= target.len() {
+            let Some((base_offset, length)) = self.longest_match(target, position) else {
+                position += 1;
+                continue;
+            };
+
+            // Grow the match backwards over bytes that would otherwise be inserted
+            let mut back = 0;
+            while position - back > literal_start
+                && base_offset > back
+                && self.base[base_offset - back - 1] == target[position - back - 1]
+            {
+                back += 1;
+            }
+
+            write_inserts(&mut delta, &target[literal_start..position - back]);
+            write_copies(&mut delta, base_offset - back, length + back);
+            position += length;
+            literal_start = position;
+            if delta.len() > max_size {
+                return None;
+            }
+        }
+        write_inserts(&mut delta, &target[literal_start..]);
+
+        (delta.len() <= max_size).then_some(delta)
+    }
+
+    /// The longest run of base bytes matching `target` from `position`
+    fn longest_match(&self, target: &[u8], position: usize) -> Option<(usize, usize)> {
+        let candidates = self.blocks.get(&block_hash(&target[position..]))?;
+        candidates
+            .iter()
+            .map(|&offset| {
+                let length = self.base[offset..]
+                    .iter()
+                    .zip(&target[position..])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                (offset, length)
+            })
+            .filter(|&(_, length)| length >= BLOCK_SIZE)
+            .max_by_key(|&(offset, length)| (length, std::cmp::Reverse(offset)))
+    }
+}
+
+/// Compute a delta from `base` to `target` (see `DeltaIndex::delta`)
+pub fn create_delta(base: &[u8], target: &[u8], max_size: usize) -> Option<Vec<u8>> {
+    DeltaIndex::new(base).delta(target, max_size)
+}
+
+/// FNV-1a over the first `BLOCK_SIZE` bytes
+fn block_hash(data: &[u8]) -> u32 {
+    data[..BLOCK_SIZE].iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+fn write_size(delta: &mut Vec<u8>, mut size: usize) {
+    while size >= 0x80 {
+        delta.push((size as u8 & 0x7f) | 0x80);
+        size >>= 7;
+    }
+    delta.push(size as u8);
+}
+
+fn read_size(delta: &[u8], cursor: &mut usize) -> Result<usize, String> {
+    let mut size = 0usize;
+    let mut shift = 0;
+    loop {
+        let byte = *delta.get(*cursor).ok_or("truncated delta header")?;
+        *cursor += 1;
+        if shift > 56 {
+            return Err("delta size is too large".to_string());
+        }
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+fn write_inserts(delta: &mut Vec<u8>, literal: &[u8]) {
+    for run in literal.chunks(MAX_INSERT) {
+        delta.push(run.len() as u8);
+        delta.extend_from_slice(run);
+    }
+}
+
+fn write_copies(delta: &mut Vec<u8>, mut offset: usize, mut length: usize) {
+    while length > 0 {
+        let size = length.min(MAX_COPY);
+        let mut op = 0x80u8;
+        let mut args = Vec::with_capacity(7);
+        for i in 0..4 {
+            let byte = (offset >> (8 * i)) as u8;
+            if byte != 0 {
+                op |= 1 << i;
+                args.push(byte);
+            }
+        }
+        // A size of MAX_COPY is encoded as no size bytes at all
+        for i in 0..3 {
+            let byte = ((size % MAX_COPY) >> (8 * i)) as u8;
+            if byte != 0 {
+                op |= 0x10 << i;
+                args.push(byte);
+            }
+        }
+        delta.push(op);
+        delta.extend(args);
+        offset += size;
+        length -= size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn revisions() -> (Vec<u8>, Vec<u8>) {
+        let base: Vec<u8> = (0..2000)
+            .flat_map(|i| format!("line {} of the original file\n", i).into_bytes())
+            .collect();
+        let mut target = base.clone();
+        target.splice(1000..1000, b"an inserted paragraph\n".iter().copied());
+        target.drain(30_000..30_500);
+        target.extend_from_slice(b"and a new ending\n");
+        (base, target)
+    }
+
+    #[test]
+    fn test_delta_roundtrip_is_small() {
+        let (base, target) = revisions();
+        let delta = create_delta(&base, &target, target.len()).unwrap();
+        assert!(delta.len() < 200, "delta is {} bytes", delta.len());
+        assert_eq!(apply_delta(&base, &delta).unwrap(), target);
+
+        // Unrelated content doesn't fit a small delta
+        assert_eq!(create_delta(&base, &[7u8; 4000], 2000), None);
+        let delta = create_delta(b"", b"new", 100).unwrap();
+        assert_eq!(apply_delta(b"", &delta).unwrap(), b"new");
+    }
+
+    #[test]
+    fn test_long_copies_are_split() {
+        let base: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let delta = create_delta(&base, &base, 100).unwrap();
+        assert_eq!(apply_delta(&base, &delta).unwrap(), base);
+    }
+
+    #[test]
+    fn test_applies_git_delta() {
+        // base 11 bytes, target 11 bytes: copy 6 bytes at 0, insert "there"
+        let delta = [11, 11, 0x90, 6, 5, b't', b'h', b'e', b'r', b'e'];
+        assert_eq!(apply_delta(b"hello world", &delta).unwrap(), b"hello there");
+    }
+
+    #[test]
+    fn test_rejects_invalid_deltas() {
+        assert!(apply_delta(b"short", &[10, 1, 1, b'x']).is_err());
+        assert!(apply_delta(b"base", &[4, 8, 0x91, 2, 8]).is_err());
+        assert!(apply_delta(b"base", &[4, 1, 3, b'a']).is_err());
+        assert!(apply_delta(b"base", &[4, 2, 1, b'a']).is_err());
+        assert!(apply_delta(b"base", &[4, 1, 0]).is_err());
+    }
+}
@@ -0,0 +1,226 @@
+//! Repository events for the live event stream.
+//!
+//! Ref updates, the commits pushes add, pushes themselves and garbage
+//! collection runs are recorded as they happen, in an outbox the actor
+//! drains after each request and streams to `/events` WebSocket subscribers
+//! (see `protocol::events`). Events carry the namespace they happened in and
+//! ref names within it; hidden refs are never reported. Nothing is recorded
+//! while `features.event_stream` is off.
+
+use super::gc::GcReport;
+use super::namespace::Namespace;
+use super::reflog::RefUpdateContext;
+use super::repository::{GitRepoState, RefUpdateStatus};
+use serde::Serialize;
+use serde_json::Value;
+
+/// Something that happened to the repository, as streamed (JSON, tagged by
+/// `type`)
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RepoEvent {
+    /// A ref was created, moved or deleted (null ids for created and
+    /// deleted refs)
+    RefUpdate {
+        namespace: Option<String>,
+        #[serde(rename = "ref")]
+        ref_name: String,
+        old_id: String,
+        new_id: String,
+        identity: String,
+        message: String,
+    },
+    /// A push added a commit to a ref
+    Commit {
+        namespace: Option<String>,
+        #[serde(rename = "ref")]
+        ref_name: String,
+        commit: Value,
+    },
+    /// A push finished, with the outcome of each of its ref updates
+    Push {
+        namespace: Option<String>,
+        pusher: String,
+        push_options: Vec<String>,
+        updates: Vec<PushedRef>,
+    },
+    /// Garbage collection ran (dry runs aren't reported)
+    Gc { report: GcReport },
+}
+
+/// One ref update of a push and how it went
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PushedRef {
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+    pub old_id: String,
+    pub new_id: String,
+    /// `ok`, or the reason it was refused
+    pub status: String,
+}
+
+impl RepoEvent {
+    /// The namespace the event happened in (`None` for the default, and for
+    /// events about the whole repository)
+    pub fn namespace(&self) -> Option<&str> {
+        match self {
+            RepoEvent::RefUpdate { namespace, .. }
+            | RepoEvent::Commit { namespace, .. }
+            | RepoEvent::Push { namespace, .. } => namespace.as_deref(),
+            RepoEvent::Gc { .. } => None,
+        }
+    }
+
+    /// The `type` it's streamed with
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RepoEvent::RefUpdate { .. } => "ref_update",
+            RepoEvent::Commit { .. } => "commit",
+            RepoEvent::Push { .. } => "push",
+            RepoEvent::Gc { .. } => "gc",
+        }
+    }
+}
+
+impl GitRepoState {
+    /// Record an event for the stream, if it's enabled
+    pub fn record_event(&mut self, event: RepoEvent) {
+        if self.config.features.event_stream {
+            self.events.push(event);
+        }
+    }
+
+    /// Take the events recorded since the last call, oldest first
+    pub fn take_events(&mut self) -> Vec<RepoEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Record a change to the ref stored as `full_name`, unless it's hidden
+    pub fn record_ref_event(
+        &mut self,
+        full_name: &str,
+        old_id: &str,
+        new_id: &str,
+        context: &RefUpdateContext,
+    ) {
+        let (namespace, name) = Namespace::of(full_name);
+        if !self.config.features.event_stream || self.is_hidden_ref(&namespace, name) {
+            return;
+        }
+        let event = RepoEvent::RefUpdate {
+            namespace: namespace.name().map(str::to_string),
+            ref_name: name.to_string(),
+            old_id: old_id.to_string(),
+            new_id: new_id.to_string(),
+            identity: context.identity.clone(),
+            message: context.message.clone(),
+        };
+        self.record_event(event);
+    }
+
+    /// Record a finished push to `namespace`: the commits it added to each
+    /// ref, then the push itself. `ref_updates` and `statuses` use stored
+    /// names, in the same order.
+    pub fn record_push_events(
+        &mut self,
+        ref_updates: &[(String, String, String)],
+        statuses: &[RefUpdateStatus],
+        context: &RefUpdateContext,
+        namespace: &Namespace,
+    ) {
+        if !self.config.features.event_stream {
+            return;
+        }
+
+        let mut updates = Vec::new();
+        for ((full_name, old_id, new_id), status) in ref_updates.iter().zip(statuses) {
+            let name = namespace.strip(full_name).unwrap_or(full_name);
+            if self.is_hidden_ref(namespace, name) {
+                continue;
+            }
+            if status.result.is_ok() && !self.object_format.is_null_oid(new_id) {
+                for commit in self.pushed_commits(full_name, old_id, new_id) {
+                    self.record_event(RepoEvent::Commit {
+                        namespace: namespace.name().map(str::to_string),
+                        ref_name: name.to_string(),
+                        commit,
+                    });
+                }
+            }
+            updates.push(PushedRef {
+                ref_name: name.to_string(),
+                old_id: old_id.clone(),
+                new_id: new_id.clone(),
+                status: match &status.result {
+                    Ok(_) => "ok".to_string(),
+                    Err(e) => e.ng_reason(),
+                },
+            });
+        }
+
+        self.record_event(RepoEvent::Push {
+            namespace: namespace.name().map(str::to_string),
+            pusher: context.identity.clone(),
+            push_options: context.push_options.clone(),
+            updates,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::objects::GitObject;
+
+    #[test]
+    fn test_push_records_ref_commit_and_push_events() {
+        let mut repo = GitRepoState::default();
+        repo.config.transfer.hide_refs = vec!["refs/hidden".to_string()];
+        let signature = "A <a@example.com> 1700000000 +0000".to_string();
+        let tree = GitObject::Tree { entries: vec![] };
+        let tree_id = tree.compute_hash(repo.object_format);
+        repo.add_object(tree_id.clone(), tree).unwrap();
+        let commit = GitObject::Commit {
+            tree: tree_id,
+            parents: vec![],
+            author: signature.clone(),
+            committer: signature,
+            message: "first".to_string(),
+        };
+        let id = commit.compute_hash(repo.object_format);
+        repo.add_object(id.clone(), commit).unwrap();
+        let null = repo.object_format.null_oid();
+        let fork = Namespace::new("fork").unwrap();
+
+        let updates = vec![
+            (fork.full_name("refs/heads/main"), null.clone(), id.clone()),
+            (fork.full_name("refs/hidden/x"), null.clone(), id.clone()),
+        ];
+        let context = RefUpdateContext::push("alice", &[]);
+        let outcome = repo
+            .process_push_operation(&[], updates, &context, &fork)
+            .unwrap();
+        assert!(outcome.statuses[1].result.is_err());
+
+        let kinds: Vec<&str> = repo.events.iter().map(RepoEvent::kind).collect();
+        assert_eq!(kinds, ["ref_update", "commit", "push"]);
+        assert!(repo.events.iter().all(|e| e.namespace() == Some("fork")));
+        let RepoEvent::Push { updates, .. } = &repo.events[2] else {
+            panic!("expected a push event");
+        };
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].ref_name, "refs/heads/main");
+        assert_eq!(updates[0].status, "ok");
+
+        let json = serde_json::to_value(&repo.events[0]).unwrap();
+        assert_eq!(json["type"], "ref_update");
+        assert_eq!(json["ref"], "refs/heads/main");
+        assert_eq!(repo.take_events().len(), 3);
+        assert!(repo.events.is_empty());
+
+        // Nothing is recorded with the stream off
+        repo.config.features.event_stream = false;
+        repo.collect_garbage(1_700_000_000_000, false).unwrap();
+        assert!(repo.events.is_empty());
+    }
+}
@@ -0,0 +1,318 @@
+//! Garbage collection of unreachable objects.
+//!
+//! Objects reachable from any ref or unexpired reflog entry are kept, and so
+//! is everything written within the configured grace period: a push stores its pack before the ref
+//! updates land, and a concurrent collection must not take it away. The
+//! remaining objects are pruned from loose storage and from packs, which are
+//! rewritten when only part of them is garbage.
+
+use super::events::RepoEvent;
+use super::objects::GitObject;
+use super::repository::GitRepoState;
+use crate::bindings::theater::simple::http_types::{HttpRequest, HttpResponse};
+use crate::error::GitError;
+use crate::host::{self, log};
+use crate::protocol::http::create_response;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Bookkeeping that survives between collections
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct GcState {
+    /// When the last (non dry run) collection finished, in ms since the epoch
+    pub last_run_ms: u64,
+    /// Objects written within the grace period, oldest first
+    pub recent_writes: Vec<WriteBatch>,
+}
+
+/// Objects stored together by one push or object write
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WriteBatch {
+    pub time_ms: u64,
+    pub ids: Vec<String>,
+}
+
+impl GcState {
+    /// Remember `ids` as written at `now_ms`, forgetting batches older than
+    /// the grace period
+    pub fn record_write(&mut self, now_ms: u64, ids: Vec<String>, grace_period_secs: u64) {
+        self.expire(now_ms, grace_period_secs);
+        if !ids.is_empty() {
+            self.recent_writes.push(WriteBatch {
+                time_ms: now_ms,
+                ids,
+            });
+        }
+    }
+
+    fn expire(&mut self, now_ms: u64, grace_period_secs: u64) {
+        let grace_ms = grace_period_secs.saturating_mul(1000);
+        self.recent_writes
+            .retain(|batch| now_ms.saturating_sub(batch.time_ms) < grace_ms);
+    }
+}
+
+/// What a collection found and removed
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct GcReport {
+    pub dry_run: bool,
+    /// Objects reachable from refs and reflog entries
+    pub reachable: usize,
+    /// Unreachable objects kept because they were written within the grace period
+    pub kept_recent: usize,
+    /// Objects removed (or that would be removed)
+    pub pruned: usize,
+    pub freed_bytes: u64,
+    pub packs_removed: usize,
+    pub packs_rewritten: usize,
+}
+
+impl GitRepoState {
+    /// Whether automatic collection is enabled and the interval has passed
+    pub fn gc_due(&self, now_ms: u64) -> bool {
+        let interval = self.config.gc.interval_secs;
+        interval > 0 && now_ms.saturating_sub(self.gc.last_run_ms) >= interval.saturating_mul(1000)
+    }
+
+    /// Remember objects just stored so the next collection keeps them for a while
+    pub fn record_write(&mut self, now_ms: u64, ids: Vec<String>) {
+        let grace = self.config.gc.grace_period_secs;
+        self.gc.record_write(now_ms, ids, grace);
+    }
+
+    /// Prune objects that are neither reachable from a ref or reflog entry
+    /// nor written within the grace period. A dry run reports the same
+    /// numbers without removing anything (or expiring reflog entries).
+    pub fn collect_garbage(&mut self, now_ms: u64, dry_run: bool) -> Result<GcReport, GitError> {
+        let mut reflog = self.reflog.clone();
+        reflog.expire(now_ms, self.config.reflog.expire_secs);
+        let mut keep = self.reachable_objects(self.refs.values().chain(reflog.ids()));
+        let reachable = keep.len();
+
+        self.gc.expire(now_ms, self.config.gc.grace_period_secs);
+        let mut kept_recent = 0;
+        for batch in &self.gc.recent_writes {
+            for id in &batch.ids {
+                if self.objects.contains_key(id) && keep.insert(id.clone()) {
+                    kept_recent += 1;
+                }
+            }
+        }
+
+        let stats = self.objects.prune(&keep, dry_run)?;
+        if !dry_run {
+            self.gc.last_run_ms = now_ms;
+            self.reflog = reflog;
+        }
+
+        let report = GcReport {
+            dry_run,
+            reachable,
+            kept_recent,
+            pruned: stats.objects,
+            freed_bytes: stats.bytes,
+            packs_removed: stats.packs_removed,
+            packs_rewritten: stats.packs_rewritten,
+        };
+        log(&format!(
+            "Garbage collection{}: {} reachable, {} kept as recent, {} pruned ({} bytes)",
+            if dry_run { " (dry run)" } else { "" },
+            report.reachable,
+            report.kept_recent,
+            report.pruned,
+            report.freed_bytes
+        ));
+        if !dry_run {
+            self.record_event(RepoEvent::Gc {
+                report: report.clone(),
+            });
+        }
+        Ok(report)
+    }
+
+    /// Handle POST /admin/gc[?dry_run=true] - Collect garbage now and report
+    /// what was removed as JSON
+    pub fn admin_gc(&mut self, request: &HttpRequest, query: &Option<String>) -> HttpResponse {
+        if request.method != "POST" {
+            return create_response(405, "text/plain", b"Method Not Allowed");
+        }
+        let dry_run = query.as_deref().is_some_and(|q| {
+            q.split('&')
+                .any(|param| matches!(param, "dry_run=true" | "dry_run=1"))
+        });
+
+        match self.collect_garbage(host::now(), dry_run) {
+            Ok(report) => match serde_json::to_vec(&report) {
+                Ok(body) => create_response(200, "application/json", &body),
+                Err(e) => create_response(500, "text/plain", e.to_string().as_bytes()),
+            },
+            Err(e) => {
+                log(&format!("Garbage collection failed: {}", e));
+                create_response(e.http_status(), "text/plain", e.to_string().as_bytes())
+            }
+        }
+    }
+
+    /// Every stored object reachable from `roots`. Missing objects
+    /// (including the null ids of reflog creations and deletions) are
+    /// skipped: they can't be pruned, and a broken ref shouldn't stop
+    /// collection.
+    pub fn reachable_objects<'a>(
+        &self,
+        roots: impl Iterator<Item = &'a String>,
+    ) -> HashSet<String> {
+        let mut seen = HashSet::new();
+        let mut pending: Vec<String> = roots.cloned().collect();
+
+        while let Some(id) = pending.pop() {
+            if seen.contains(&id) {
+                continue;
+            }
+            let Some(object) = self.objects.get(&id) else {
+                continue;
+            };
+            match object {
+                GitObject::Blob { .. } => {}
+                GitObject::Tree { entries } => pending.extend(
+                    entries
+                        .into_iter()
+                        // Submodule commits live in another repository
+                        .filter(|entry| entry.mode != "160000")
+                        .map(|entry| entry.hash),
+                ),
+                GitObject::Commit { tree, parents, .. } => {
+                    pending.push(tree);
+                    pending.extend(parents);
+                }
+                GitObject::Tag { object, .. } => pending.push(object),
+            }
+            seen.insert(id);
+        }
+
+        seen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::test_support::commit;
+
+    const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+    #[test]
+    fn test_force_push_leaves_old_commit_to_be_pruned() {
+        let mut repo = GitRepoState::default();
+        let first = commit(&mut repo, "one", 0, vec![]);
+        let second = commit(&mut repo, "two", 0, vec![]);
+        let second_blob = GitObject::Blob {
+            content: b"two".to_vec(),
+        }
+        .compute_hash(repo.object_format);
+        repo.update_ref("refs/heads/main".into(), first.clone())
+            .unwrap();
+        repo.update_ref("refs/heads/main".into(), second.clone())
+            .unwrap();
+
+        let report = repo.collect_garbage(DAY_MS, false).unwrap();
+        assert_eq!(report.reachable, 3);
+        assert_eq!(report.pruned, 3);
+        assert!(report.freed_bytes > 0);
+        assert!(!repo.objects.contains_key(&first));
+        assert!(repo.objects.contains_key(&second));
+        assert!(repo.objects.contains_key(&second_blob));
+        assert_eq!(repo.gc.last_run_ms, DAY_MS);
+    }
+
+    #[test]
+    fn test_deleted_ref_and_history_are_kept_while_reachable() {
+        let mut repo = GitRepoState::default();
+        let first = commit(&mut repo, "one", 0, vec![]);
+        let second = commit(&mut repo, "two", 0, vec![first.clone()]);
+        let topic = commit(&mut repo, "topic", 0, vec![]);
+        repo.update_ref("refs/heads/main".into(), second).unwrap();
+        repo.update_ref("refs/heads/topic".into(), topic.clone())
+            .unwrap();
+        repo.delete_ref("refs/heads/topic").unwrap();
+
+        let report = repo.collect_garbage(DAY_MS, false).unwrap();
+        assert_eq!(report.reachable, 6);
+        assert_eq!(report.pruned, 3);
+        assert!(repo.objects.contains_key(&first));
+        assert!(!repo.objects.contains_key(&topic));
+    }
+
+    #[test]
+    fn test_reflog_keeps_force_pushed_commits_until_it_expires() {
+        use crate::git::reflog::RefUpdateContext;
+
+        let mut repo = GitRepoState::default();
+        repo.config.reflog.expire_secs = 30 * 24 * 60 * 60;
+        let first = commit(&mut repo, "one", 0, vec![]);
+        let second = commit(&mut repo, "two", 0, vec![]);
+        let push = RefUpdateContext::push("alice", &[]);
+        let null = repo.object_format.null_oid();
+        repo.update_refs_from_push(vec![("refs/heads/main".into(), null, first.clone())], &push);
+        repo.update_refs_from_push(
+            vec![("refs/heads/main".into(), first.clone(), second)],
+            &push,
+        );
+        let pushed_at = repo.reflog.entries("refs/heads/main")[1].time_ms;
+
+        let report = repo.collect_garbage(pushed_at + DAY_MS, false).unwrap();
+        assert_eq!((report.reachable, report.pruned), (6, 0));
+
+        // A dry run already leaves out expired entries, without dropping them
+        let expired = pushed_at + 31 * DAY_MS;
+        assert_eq!(repo.collect_garbage(expired, true).unwrap().pruned, 3);
+        assert_eq!(repo.reflog.entries("refs/heads/main").len(), 2);
+
+        let report = repo.collect_garbage(expired, false).unwrap();
+        assert_eq!((report.reachable, report.pruned), (3, 3));
+        assert!(!repo.objects.contains_key(&first));
+        assert!(repo.reflog.refs.is_empty());
+    }
+
+    #[test]
+    fn test_recent_writes_survive_the_grace_period() {
+        let mut repo = GitRepoState::default();
+        repo.config.gc.grace_period_secs = 60;
+        let orphan = commit(&mut repo, "orphan", 0, vec![]);
+        let ids = repo.objects.ids().into_iter().collect();
+        repo.record_write(1_000, ids);
+
+        let report = repo.collect_garbage(30_000, false).unwrap();
+        assert_eq!((report.kept_recent, report.pruned), (3, 0));
+        assert!(repo.objects.contains_key(&orphan));
+
+        let report = repo.collect_garbage(61_000, false).unwrap();
+        assert_eq!((report.kept_recent, report.pruned), (0, 3));
+        assert!(repo.gc.recent_writes.is_empty());
+    }
+
+    #[test]
+    fn test_dry_run_changes_nothing() {
+        let mut repo = GitRepoState::default();
+        let orphan = commit(&mut repo, "orphan", 0, vec![]);
+
+        let report = repo.collect_garbage(DAY_MS, true).unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report.pruned, 3);
+        assert!(repo.objects.contains_key(&orphan));
+        assert_eq!(repo.gc.last_run_ms, 0);
+        assert_eq!(
+            repo.collect_garbage(DAY_MS, false).unwrap().freed_bytes,
+            report.freed_bytes
+        );
+    }
+
+    #[test]
+    fn test_gc_due_after_interval() {
+        let mut repo = GitRepoState::default();
+        assert!(!repo.gc_due(DAY_MS));
+        repo.config.gc.interval_secs = 3600;
+        repo.gc.last_run_ms = DAY_MS;
+        assert!(!repo.gc_due(DAY_MS + 1000));
+        assert!(repo.gc_due(DAY_MS + 3600 * 1000));
+    }
+}
@@ -0,0 +1,323 @@
+//! Hook actors run around pushes.
+//!
+//! Like git's server-side hooks, but each one is another actor. Once a
+//! push's pack is stored and before any ref changes, every `pre-receive`
+//! actor is sent a request describing the whole push, then every `update`
+//! actor one request per remaining ref. Either can refuse single refs or
+//! (`pre-receive`) the whole push, and their messages are shown to the
+//! pusher as `remote:` lines. A hook that can't be reached or answers with
+//! something other than a verdict refuses what it was asked about.
+//!
+//! After the refs change, `post-receive` actors are sent (without waiting
+//! for an answer) the updates that succeeded. Hooks are told the names the
+//! client used, so within a namespace they see its refs unprefixed.
+//!
+//! Requests wait on the hook actor, so a `pre-receive` or `update` hook must
+//! not call back into this server while answering.
+
+use super::namespace::Namespace;
+use super::reflog::RefUpdateContext;
+use super::repository::GitRepoState;
+use crate::error::GitError;
+use crate::host::{self, log};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// What a hook actor is told about a push, as JSON
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PushEvent {
+    /// `pre-receive`, `update` or `post-receive`
+    pub hook: String,
+    pub repository: String,
+    /// The namespace pushed to, if not the default
+    pub namespace: Option<String>,
+    pub pusher: String,
+    pub push_options: Vec<String>,
+    pub updates: Vec<RefUpdateEvent>,
+}
+
+/// One ref update of a push; null ids for created and deleted refs
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RefUpdateEvent {
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+    pub old_id: String,
+    pub new_id: String,
+}
+
+/// A `pre-receive` or `update` hook's answer, as JSON. An empty answer
+/// accepts everything.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct HookVerdict {
+    /// `false` refuses every ref the hook was asked about
+    pub accept: bool,
+    /// Shown to the pusher
+    pub message: Option<String>,
+    /// Refs to refuse, by name, with the reason to report for each
+    pub rejected: BTreeMap<String, String>,
+}
+
+impl Default for HookVerdict {
+    fn default() -> Self {
+        Self {
+            accept: true,
+            message: None,
+            rejected: BTreeMap::new(),
+        }
+    }
+}
+
+/// What the `pre-receive` and `update` hooks made of a push
+#[derive(Debug, Default, PartialEq)]
+pub struct HookReview {
+    /// Refused updates, by their index in the push
+    pub refused: BTreeMap<usize, GitError>,
+    /// Lines to show the pusher
+    pub messages: Vec<String>,
+}
+
+impl HookReview {
+    fn refuse(&mut self, index: usize, reason: &str) {
+        self.refused
+            .entry(index)
+            .or_insert_with(|| GitError::HookDeclined(reason.to_string()));
+    }
+}
+
+/// Ask `actor` for its verdict on `event`
+fn ask_hook(actor: &str, event: &PushEvent) -> Result<HookVerdict, String> {
+    let request = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+    let response = host::request_message(actor, &request)?;
+    if response.is_empty() {
+        return Ok(HookVerdict::default());
+    }
+    serde_json::from_slice(&response).map_err(|e| format!("unreadable verdict: {}", e))
+}
+
+impl GitRepoState {
+    fn push_event(
+        &self,
+        hook: &str,
+        updates: Vec<RefUpdateEvent>,
+        context: &RefUpdateContext,
+        namespace: &Namespace,
+    ) -> PushEvent {
+        PushEvent {
+            hook: hook.to_string(),
+            repository: self.repo_name.clone(),
+            namespace: namespace.name().map(str::to_string),
+            pusher: context.identity.clone(),
+            push_options: context.push_options.clone(),
+            updates,
+        }
+    }
+
+    /// Ask the `pre-receive` and then the `update` hooks about a push's ref
+    /// updates (stored names, in `namespace`)
+    pub fn run_receive_hooks(
+        &self,
+        ref_updates: &[(String, String, String)],
+        context: &RefUpdateContext,
+        namespace: &Namespace,
+    ) -> HookReview {
+        let hooks = &self.config.hooks;
+        let mut review = HookReview::default();
+        let events = ref_update_events(ref_updates, namespace);
+
+        for actor in &hooks.pre_receive {
+            let event = self.push_event("pre-receive", events.clone(), context, namespace);
+            let verdict = match ask_hook(actor, &event) {
+                Ok(verdict) => verdict,
+                Err(e) => {
+                    log(&format!("❌ pre-receive hook {} failed: {}", actor, e));
+                    HookVerdict {
+                        accept: false,
+                        message: Some("pre-receive hook failed".to_string()),
+                        rejected: BTreeMap::new(),
+                    }
+                }
+            };
+            review.messages.extend(verdict.message);
+            if !verdict.accept {
+                log(&format!("pre-receive hook {} declined the push", actor));
+                for index in 0..events.len() {
+                    review.refuse(index, "pre-receive hook declined");
+                }
+                return review;
+            }
+            for (index, event) in events.iter().enumerate() {
+                if let Some(reason) = verdict.rejected.get(&event.ref_name) {
+                    log(&format!(
+                        "pre-receive hook {} declined {}: {}",
+                        actor, event.ref_name, reason
+                    ));
+                    review.refuse(index, reason);
+                }
+            }
+        }
+
+        for (index, update) in events.iter().enumerate() {
+            for actor in &hooks.update {
+                if review.refused.contains_key(&index) {
+                    break;
+                }
+                let event = self.push_event("update", vec![update.clone()], context, namespace);
+                match ask_hook(actor, &event) {
+                    Ok(verdict) => {
+                        review.messages.extend(verdict.message);
+                        if let Some(reason) = verdict.rejected.get(&update.ref_name) {
+                            review.refuse(index, reason);
+                        } else if !verdict.accept {
+                            review.refuse(index, "update hook declined");
+                        }
+                    }
+                    Err(e) => {
+                        log(&format!("❌ update hook {} failed: {}", actor, e));
+                        review.refuse(index, "update hook failed");
+                    }
+                }
+                if review.refused.contains_key(&index) {
+                    log(&format!(
+                        "update hook {} declined {}",
+                        actor, update.ref_name
+                    ));
+                }
+            }
+        }
+        review
+    }
+
+    /// Tell the `post-receive` hooks about the ref updates a push made
+    /// (stored names, in `namespace`)
+    pub fn notify_post_receive(
+        &self,
+        applied: &[(String, String, String)],
+        context: &RefUpdateContext,
+        namespace: &Namespace,
+    ) {
+        let hooks = &self.config.hooks.post_receive;
+        if hooks.is_empty() || applied.is_empty() {
+            return;
+        }
+        let event = self.push_event(
+            "post-receive",
+            ref_update_events(applied, namespace),
+            context,
+            namespace,
+        );
+        let message = match serde_json::to_vec(&event) {
+            Ok(message) => message,
+            Err(e) => {
+                log(&format!("❌ Failed to encode post-receive event: {}", e));
+                return;
+            }
+        };
+        for actor in hooks {
+            if let Err(e) = host::send_message(actor, &message) {
+                log(&format!("❌ post-receive hook {} failed: {}", actor, e));
+            }
+        }
+    }
+}
+
+/// Ref updates by the names the client used
+fn ref_update_events(
+    ref_updates: &[(String, String, String)],
+    namespace: &Namespace,
+) -> Vec<RefUpdateEvent> {
+    ref_updates
+        .iter()
+        .map(|(name, old_id, new_id)| RefUpdateEvent {
+            ref_name: namespace.strip(name).unwrap_or(name).to_string(),
+            old_id: old_id.clone(),
+            new_id: new_id.clone(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::register_native_actor;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn updates(names: &[&str]) -> Vec<(String, String, String)> {
+        names
+            .iter()
+            .map(|name| (name.to_string(), "0".repeat(40), "1".repeat(40)))
+            .collect()
+    }
+
+    fn reply(verdict: &'static str) -> host::NativeActor {
+        Rc::new(move |_| Ok(verdict.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn test_pre_receive_and_update_verdicts() {
+        let mut repo = GitRepoState::default();
+        let asked = Rc::new(RefCell::new(Vec::new()));
+        let seen = asked.clone();
+        register_native_actor(
+            "policy",
+            Rc::new(move |msg| {
+                let event: PushEvent = serde_json::from_slice(msg).unwrap();
+                seen.borrow_mut().push(event);
+                Ok(br#"{"message":"checked","rejected":{"refs/heads/wip":"no wip"}}"#.to_vec())
+            }),
+        );
+        register_native_actor("no-tags", reply(r#"{"accept":false}"#));
+        repo.config.hooks.pre_receive = vec!["policy".to_string()];
+        repo.config.hooks.update = vec!["no-tags".to_string()];
+
+        let fork = Namespace::new("fork").unwrap();
+        let push = updates(&[
+            "refs/namespaces/fork/refs/heads/main",
+            "refs/namespaces/fork/refs/heads/wip",
+        ]);
+        let context = RefUpdateContext::push("alice", &["ci.skip".to_string()]);
+        let review = repo.run_receive_hooks(&push, &context, &fork);
+
+        // The update hook only saw main, and refused it
+        assert_eq!(
+            review.refused,
+            BTreeMap::from([
+                (
+                    0,
+                    GitError::HookDeclined("update hook declined".to_string())
+                ),
+                (1, GitError::HookDeclined("no wip".to_string())),
+            ])
+        );
+        assert_eq!(review.messages, ["checked"]);
+        let event = &asked.borrow()[0];
+        assert_eq!(event.hook, "pre-receive");
+        assert_eq!(event.namespace.as_deref(), Some("fork"));
+        assert_eq!(event.pusher, "alice");
+        assert_eq!(event.push_options, ["ci.skip"]);
+        assert_eq!(event.updates[1].ref_name, "refs/heads/wip");
+    }
+
+    #[test]
+    fn test_unanswered_hooks_refuse_the_push() {
+        let mut repo = GitRepoState::default();
+        register_native_actor("garbled", reply("not json"));
+        repo.config.hooks.pre_receive = vec!["garbled".to_string()];
+        let push = updates(&["refs/heads/main", "refs/heads/dev"]);
+        let context = RefUpdateContext::push("alice", &[]);
+
+        let review = repo.run_receive_hooks(&push, &context, &Namespace::default());
+        assert_eq!(review.refused.len(), 2);
+        assert_eq!(review.messages, ["pre-receive hook failed"]);
+
+        repo.config.hooks.pre_receive = vec!["missing".to_string()];
+        let review = repo.run_receive_hooks(&push, &context, &Namespace::default());
+        assert_eq!(review.refused.len(), 2);
+
+        register_native_actor("silent", reply(""));
+        repo.config.hooks.pre_receive = vec!["silent".to_string()];
+        let review = repo.run_receive_hooks(&push, &context, &Namespace::default());
+        assert_eq!(review, HookReview::default());
+    }
+}
@@ -1,2 +1,20 @@
+pub mod bare;
+pub mod delta;
+pub mod events;
+pub mod gc;
+pub mod hooks;
+pub mod namespace;
+pub mod object_store;
 pub mod objects;
+pub mod pack_index;
+pub mod reflog;
+pub mod refname;
+pub mod repack;
 pub mod repository;
+pub mod signature;
+pub mod state;
+pub mod symref;
+#[cfg(test)]
+pub mod test_support;
+pub mod visibility;
+pub mod webhooks;
@@ -0,0 +1,227 @@
+//! Ref namespaces.
+//!
+//! Like git's `GIT_NAMESPACE`, several logical repositories can share one
+//! object store by keeping their refs under `refs/namespaces/<name>/`, so
+//! forks of a project store its objects once. The namespace is picked by
+//! URL: `/ns/<name>/info/refs` and so on over HTTP, or a `/ns/<name>` path
+//! over git://. Clients only see and update that namespace's refs, with the
+//! prefix stripped from advertised names and added to pushed ones.
+//!
+//! Each namespace has its own `HEAD`, a symbolic ref stored as
+//! `refs/namespaces/<name>/HEAD`, set by the first branch pushed to it.
+//! Without a namespace every ref is visible, namespaced ones included.
+
+use super::refname::check_ref_name;
+use super::repository::GitRepoState;
+use crate::error::GitError;
+
+/// The refs a request works with: everything (the default), or those under
+/// one `refs/namespaces/<name>/`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Namespace {
+    prefix: String,
+}
+
+impl Namespace {
+    /// The namespace `name`, a single ref name component (namespaces aren't
+    /// nested)
+    pub fn new(name: &str) -> Result<Self, GitError> {
+        let prefix = format!("refs/namespaces/{}/", name);
+        if name.contains('/') {
+            return Err(GitError::InvalidRefName {
+                ref_name: prefix,
+                reason: "namespaces can't be nested".to_string(),
+            });
+        }
+        check_ref_name(&format!("{}HEAD", prefix))?;
+        Ok(Self { prefix })
+    }
+
+    /// Split a request path into the namespace of its `/ns/<name>` prefix
+    /// (the default without one) and the rest of the path
+    pub fn from_path(path: &str) -> Result<(Self, &str), GitError> {
+        let Some(rest) = path.strip_prefix("/ns/") else {
+            return Ok((Self::default(), path));
+        };
+        let (name, rest) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        Ok((Self::new(name)?, rest))
+    }
+
+    /// The namespace a stored ref is in, and its name there
+    pub fn of(full_name: &str) -> (Self, &str) {
+        let split = full_name
+            .strip_prefix("refs/namespaces/")
+            .and_then(|rest| rest.split_once('/'))
+            .and_then(|(name, inner)| Some((Self::new(name).ok()?, inner)));
+        split.unwrap_or_else(|| (Self::default(), full_name))
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.prefix.is_empty()
+    }
+
+    /// The namespace's name (`None` for the default)
+    pub fn name(&self) -> Option<&str> {
+        self.prefix
+            .strip_prefix("refs/namespaces/")
+            .and_then(|rest| rest.strip_suffix('/'))
+    }
+
+    /// The stored name of the namespace's ref `name`
+    pub fn full_name(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+
+    /// The namespace's name for a stored ref, if the ref is in it
+    pub fn strip<'a>(&self, full_name: &'a str) -> Option<&'a str> {
+        full_name.strip_prefix(self.prefix.as_str())
+    }
+}
+
+impl GitRepoState {
+    /// The branch `HEAD` points to in `namespace`. A namespace nothing has
+    /// been pushed to yet has an unborn default branch.
+    pub fn namespace_head(&self, namespace: &Namespace) -> &str {
+        self.namespace_symref_target(namespace, "HEAD")
+            .unwrap_or(&self.config.default_branch)
+    }
+
+    /// The target of one of `namespace`'s symbolic refs, when it's in the
+    /// namespace too
+    pub fn namespace_symref_target(&self, namespace: &Namespace, name: &str) -> Option<&str> {
+        let target = self.symref_target(&namespace.full_name(name))?;
+        namespace.strip(target)
+    }
+
+    /// The object id one of `namespace`'s refs resolves to
+    pub fn namespace_resolve(&self, namespace: &Namespace, name: &str) -> Option<&String> {
+        self.resolve_ref(&namespace.full_name(name))
+    }
+
+    /// Every ref in `namespace` except `HEAD`, as `resolved_refs` lists them
+    /// but with the namespace's names
+    pub fn namespace_refs(&self, namespace: &Namespace) -> Vec<(&str, &String)> {
+        self.resolved_refs()
+            .into_iter()
+            .filter_map(|(name, id)| Some((namespace.strip(name)?, id)))
+            .filter(|(name, _)| *name != "HEAD")
+            .collect()
+    }
+
+    /// Every branch in `namespace`, by stored name
+    pub fn namespace_branches(&self, namespace: &Namespace) -> Vec<&str> {
+        let mut branches: Vec<&str> = self
+            .refs
+            .keys()
+            .map(String::as_str)
+            .filter(|name| {
+                namespace
+                    .strip(name)
+                    .is_some_and(|name| name.starts_with("refs/heads/"))
+            })
+            .collect();
+        branches.sort_unstable();
+        branches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::objects::GitObject;
+    use crate::git::reflog::RefUpdateContext;
+
+    #[test]
+    fn test_namespace_from_path() {
+        let (namespace, rest) = Namespace::from_path("/ns/fork/info/refs").unwrap();
+        assert_eq!(rest, "/info/refs");
+        assert_eq!(namespace.name(), Some("fork"));
+        assert_eq!(
+            namespace.full_name("refs/heads/main"),
+            "refs/namespaces/fork/refs/heads/main"
+        );
+        assert_eq!(
+            namespace.strip("refs/namespaces/fork/refs/tags/v1"),
+            Some("refs/tags/v1")
+        );
+        assert_eq!(namespace.strip("refs/heads/main"), None);
+
+        let (namespace, rest) = Namespace::from_path("/git-upload-pack").unwrap();
+        assert!(namespace.is_default());
+        assert_eq!(namespace.name(), None);
+        assert_eq!(rest, "/git-upload-pack");
+        assert_eq!(namespace.full_name("HEAD"), "HEAD");
+
+        for path in ["/ns//info/refs", "/ns/a..b/info/refs", "/ns/x.lock"] {
+            assert!(Namespace::from_path(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_namespace_of_stored_ref() {
+        let (namespace, name) = Namespace::of("refs/namespaces/fork/refs/heads/main");
+        assert_eq!(
+            (namespace, name),
+            (Namespace::new("fork").unwrap(), "refs/heads/main")
+        );
+        assert_eq!(
+            Namespace::of("refs/heads/main"),
+            (Namespace::default(), "refs/heads/main")
+        );
+        assert_eq!(
+            Namespace::of("refs/namespaces/fork"),
+            (Namespace::default(), "refs/namespaces/fork")
+        );
+    }
+
+    #[test]
+    fn test_namespaces_share_objects_but_not_refs() {
+        let mut repo = GitRepoState::default();
+        let blob = GitObject::Blob {
+            content: b"shared".to_vec(),
+        };
+        let id = blob.compute_hash(repo.object_format);
+        repo.add_object(id.clone(), blob).unwrap();
+        let null = repo.object_format.null_oid();
+        let fork = Namespace::new("fork").unwrap();
+
+        repo.update_refs_from_push(
+            vec![
+                ("refs/heads/main".into(), null.clone(), id.clone()),
+                (fork.full_name("refs/heads/dev"), null.clone(), id.clone()),
+            ],
+            &RefUpdateContext::push("tester", &[]),
+        );
+
+        // Each namespace got its own HEAD from its first branch
+        assert_eq!(repo.head, "refs/heads/main");
+        assert_eq!(repo.namespace_head(&fork), "refs/heads/dev");
+        assert_eq!(repo.namespace_resolve(&fork, "HEAD"), Some(&id));
+        assert_eq!(repo.namespace_refs(&fork), [("refs/heads/dev", &id)]);
+        assert_eq!(
+            repo.namespace_branches(&fork),
+            ["refs/namespaces/fork/refs/heads/dev"]
+        );
+
+        // The default namespace sees everything
+        let names: Vec<&str> = repo
+            .namespace_refs(&Namespace::default())
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "refs/heads/main",
+                "refs/namespaces/fork/HEAD",
+                "refs/namespaces/fork/refs/heads/dev"
+            ]
+        );
+
+        // An empty namespace has an unborn default branch
+        let other = Namespace::new("other").unwrap();
+        assert_eq!(repo.namespace_head(&other), "refs/heads/main");
+        assert_eq!(repo.namespace_resolve(&other, "HEAD"), None);
+        assert!(repo.namespace_refs(&other).is_empty());
+    }
+}
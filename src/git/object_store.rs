@@ -0,0 +1,1120 @@
+//! Object Storage
+//!
+//! Objects live in one of two places:
+//!
+//! - Packs received on push, kept as real pack files next to a generated
+//!   `.idx` (`objects/pack/pack-<checksum>.{pack,idx}`) in host storage.
+//!   Only the indexes are held in memory; objects are read on demand by
+//!   offset, and their entry bytes can be copied into outgoing packs as is.
+//! - Loose objects added one at a time, held in memory and persisted with
+//!   the repository state.
+//!
+//! Pack entries may be deltas (`OBJ_OFS_DELTA` / `OBJ_REF_DELTA`, e.g. after a
+//! repack): reading one resolves its base first, and recently resolved bases
+//! are cached. A delta entry is copied into another pack only along with its
+//! base, rewritten to name the base by id.
+//!
+//! Host storage (the Theater content store) has no ranged reads, so pack files
+//! are written in `PACK_CHUNK_SIZE` pieces (`<path>.<n>`). Reading an object
+//! fetches only the chunks its entry spans, and recently used chunks are cached.
+//!
+//! A store opened on a bare repository (`open_bare`) keeps both in the
+//! repository's `objects/` directory instead: loose objects as
+//! `objects/<xx>/<rest>` files and packs as whole files, read once and cached.
+//! Small pushes are written loose, larger ones as a pack, like git does.
+
+use crate::error::GitError;
+use crate::git::bare::BareRepository;
+use crate::git::delta::apply_delta;
+use crate::git::objects::{DeltaBase, GitObject, PackSerializer, RawObject, OBJ_REF_DELTA};
+use crate::git::pack_index::PackIndex;
+use crate::host::{self, log};
+use crate::utils::compression::compress_zlib;
+use crate::utils::hash::HashAlgorithm;
+use flate2::Crc;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+
+/// Size of the pieces pack files are stored in
+pub const PACK_CHUNK_SIZE: u64 = 1 << 20;
+
+/// Pack chunks kept in memory between reads
+const CHUNK_CACHE_SIZE: usize = 16;
+
+/// Resolved delta bases kept in memory between reads
+const DELTA_CACHE_SIZE: usize = 64;
+
+/// Longest delta chain followed before giving up on a (probably cyclic) pack
+const MAX_DELTA_CHAIN: usize = 10_000;
+
+/// Pushes to a bare repository with fewer new objects than this are written
+/// as loose objects (git's default `transfer.unpackLimit`)
+pub const UNPACK_LIMIT: usize = 100;
+
+type ChunkKey = (String, String, u64);
+
+/// A resolved object: pack type (1-4) and content
+type Content = (u8, Rc<Vec<u8>>);
+
+/// A pack entry's raw id, offset and CRC32, as its index lists it
+type IndexEntry = (Vec<u8>, u64, u32);
+
+thread_local! {
+    // Parsed indexes by storage and pack name; packs never change once written
+    static INDEXES: RefCell<HashMap<(String, String), Rc<PackIndex>>> = RefCell::new(HashMap::new());
+
+    // Recently read pack chunks, oldest first
+    static CHUNKS: RefCell<VecDeque<(ChunkKey, Rc<Vec<u8>>)>> = const { RefCell::new(VecDeque::new()) };
+
+    // Whole pack files of bare repositories by host path
+    static BARE_PACKS: RefCell<HashMap<String, Rc<Vec<u8>>>> = RefCell::new(HashMap::new());
+
+    // Recently resolved deltas and delta bases by pack name and offset, oldest first
+    static DELTA_BASES: RefCell<VecDeque<((String, u64), Content)>> = const { RefCell::new(VecDeque::new()) };
+}
+
+/// What pruning removed (or would remove, in a dry run)
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct PruneStats {
+    /// Distinct objects removed
+    pub objects: usize,
+    /// Stored bytes freed: compressed loose objects, pack entries and their
+    /// index entries
+    pub bytes: u64,
+    pub packs_removed: usize,
+    pub packs_rewritten: usize,
+}
+
+/// A stored pack file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PackFile {
+    /// Hex checksum from the pack trailer
+    pub name: String,
+    /// Size of the pack in bytes
+    pub size: u64,
+}
+
+impl PackFile {
+    pub fn pack_path(&self) -> String {
+        format!("objects/pack/pack-{}.pack", self.name)
+    }
+
+    pub fn index_path(&self) -> String {
+        format!("objects/pack/pack-{}.idx", self.name)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LoadedPack {
+    file: PackFile,
+    index: Rc<PackIndex>,
+}
+
+/// Every object in the repository, loose or packed
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(transparent)]
+pub struct ObjectStore {
+    loose: HashMap<String, GitObject>,
+    /// Host storage area holding the packs (created with the first pack)
+    #[serde(skip)]
+    storage: Option<String>,
+    #[serde(skip)]
+    packs: Vec<LoadedPack>,
+    /// Bare repository holding the packs and loose objects instead
+    #[serde(skip)]
+    bare: Option<BareObjects>,
+}
+
+/// Objects in a bare repository's `objects/` directory
+#[derive(Debug, Clone)]
+struct BareObjects {
+    repo: BareRepository,
+    algo: HashAlgorithm,
+    /// Ids of the loose object files
+    loose: HashSet<String>,
+}
+
+impl ObjectStore {
+    /// Reopen a store from persisted state, loading the index of every pack
+    pub fn restore(
+        storage: Option<String>,
+        loose: HashMap<String, GitObject>,
+        packs: Vec<PackFile>,
+        algo: HashAlgorithm,
+    ) -> Result<Self, GitError> {
+        let mut loaded = Vec::with_capacity(packs.len());
+        if !packs.is_empty() {
+            let area = storage.as_deref().ok_or_else(|| {
+                GitError::PackCorrupt("packs listed without a storage area".to_string())
+            })?;
+            for file in packs {
+                let index = load_index(area, &file, algo, || {
+                    host::read_file(area, &file.index_path())
+                })?;
+                if hex::encode(index.pack_checksum()) != file.name {
+                    return Err(GitError::PackCorrupt(format!(
+                        "{} belongs to a different pack",
+                        file.index_path()
+                    )));
+                }
+                loaded.push(LoadedPack { file, index });
+            }
+        }
+
+        Ok(Self {
+            loose,
+            storage,
+            packs: loaded,
+            ..Self::default()
+        })
+    }
+
+    /// Open the objects of a bare repository: list its loose objects and load
+    /// the index of every pack
+    pub fn open_bare(bare: BareRepository, algo: HashAlgorithm) -> Result<Self, GitError> {
+        let mut packs = Vec::new();
+        for name in bare.pack_names()? {
+            let mut file = PackFile { name, size: 0 };
+            let index = load_index(bare.root(), &file, algo, || {
+                host::read_path(&bare.path(&file.index_path()))
+            })?;
+            let data = read_bare_pack(&bare, &file)?;
+            if !data.ends_with(index.pack_checksum()) {
+                return Err(GitError::PackCorrupt(format!(
+                    "{} belongs to a different pack",
+                    file.index_path()
+                )));
+            }
+            file.size = data.len() as u64;
+            packs.push(LoadedPack { file, index });
+        }
+
+        Ok(Self {
+            bare: Some(BareObjects {
+                loose: bare.loose_object_ids(algo)?.into_iter().collect(),
+                repo: bare,
+                algo,
+            }),
+            packs,
+            ..Self::default()
+        })
+    }
+
+    /// Host storage area id, if any pack has been stored
+    pub fn storage(&self) -> Option<&str> {
+        self.storage.as_deref()
+    }
+
+    /// Stored packs, oldest first
+    pub fn packs(&self) -> Vec<PackFile> {
+        self.packs.iter().map(|pack| pack.file.clone()).collect()
+    }
+
+    /// Objects held in memory rather than in a pack
+    pub fn loose(&self) -> &HashMap<String, GitObject> {
+        &self.loose
+    }
+
+    /// Add a single object (kept loose)
+    pub fn insert(&mut self, id: String, object: GitObject) -> Result<(), GitError> {
+        match &mut self.bare {
+            Some(bare) => {
+                bare.repo
+                    .write_loose_object(&id, &RawObject::from_object(&object))?;
+                bare.loose.insert(id);
+            }
+            None => {
+                self.loose.insert(id, object);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn contains_key(&self, id: &str) -> bool {
+        self.loose.contains_key(id)
+            || self
+                .bare
+                .as_ref()
+                .is_some_and(|bare| bare.loose.contains(id))
+            || self.find_packed(id).is_some()
+    }
+
+    /// Look up an object, reading it from its pack if needed. Storage errors
+    /// are logged and reported as a missing object.
+    pub fn get(&self, id: &str) -> Option<GitObject> {
+        if let Some(object) = self.loose.get(id) {
+            return Some(object.clone());
+        }
+        if let Some(bare) = self.bare.as_ref().filter(|bare| bare.loose.contains(id)) {
+            match bare.repo.read_loose_object(id, bare.algo) {
+                Ok(Some(object)) => return Some(object),
+                Ok(None) => {}
+                Err(e) => log(&format!("❌ Failed to read loose object {}: {}", id, e)),
+            }
+        }
+        let (pack, n) = self.find_packed(id)?;
+        let result = self
+            .read_packed(pack, n, 0)
+            .and_then(|(obj_type, content)| {
+                PackSerializer::object_from_content(obj_type, &content, pack.index.algo())
+            });
+        match result {
+            Ok(object) => Some(object),
+            Err(e) => {
+                log(&format!("❌ Failed to read object {} from pack: {}", id, e));
+                None
+            }
+        }
+    }
+
+    /// Look up an object's type and exact content, as its id hashes them.
+    /// Storage errors are logged and reported as a missing object.
+    pub fn get_raw(&self, id: &str) -> Option<RawObject> {
+        match self.read_content(id, 0) {
+            Ok(Some((obj_type, content))) => Some(RawObject::new(obj_type, content.to_vec())),
+            Ok(None) => None,
+            Err(e) => {
+                log(&format!("❌ Failed to read object {}: {}", id, e));
+                None
+            }
+        }
+    }
+
+    /// The stored pack entry (header and compressed data) for an object, for
+    /// copying into another pack without re-encoding it. A delta is only
+    /// reusable if `has_base` accepts its base's id, i.e. the base goes into
+    /// the same pack; it is returned as an `OBJ_REF_DELTA`, since offsets
+    /// differ from pack to pack. `None` if there's no reusable entry.
+    pub fn reusable_entry(
+        &self,
+        id: &str,
+        has_base: impl Fn(&str) -> bool,
+    ) -> Result<Option<Vec<u8>>, GitError> {
+        match self.find_packed(id) {
+            Some((pack, n)) => self.reusable_entry_at(pack, n, has_base),
+            None => Ok(None),
+        }
+    }
+
+    fn reusable_entry_at(
+        &self,
+        pack: &LoadedPack,
+        n: usize,
+        has_base: impl Fn(&str) -> bool,
+    ) -> Result<Option<Vec<u8>>, GitError> {
+        let entry = self.read_entry(pack, n)?;
+        let header = PackSerializer::entry_header(&entry, pack.index.algo())?;
+        let base = match &header.base {
+            None => return Ok(Some(entry)),
+            Some(DeltaBase::Offset(distance)) => {
+                pack.index.id(find_ofs_base(pack, n, *distance)?).to_vec()
+            }
+            Some(DeltaBase::Id(id)) => id.clone(),
+        };
+        if !has_base(&hex::encode(&base)) {
+            return Ok(None);
+        }
+        Ok(Some(PackSerializer::encode_entry(
+            OBJ_REF_DELTA,
+            header.size,
+            Some(&DeltaBase::Id(base)),
+            &entry[header.data_start..],
+        )))
+    }
+
+    /// Ids of every object, sorted
+    pub fn ids(&self) -> Vec<String> {
+        let mut ids: HashSet<String> = self.loose.keys().cloned().collect();
+        if let Some(bare) = &self.bare {
+            ids.extend(bare.loose.iter().cloned());
+        }
+        for pack in &self.packs {
+            ids.extend((0..pack.index.len()).map(|n| hex::encode(pack.index.id(n))));
+        }
+        let mut ids: Vec<String> = ids.into_iter().collect();
+        ids.sort();
+        ids
+    }
+
+    /// Number of distinct objects
+    pub fn len(&self) -> usize {
+        if self.packs.is_empty() && self.bare.is_none() {
+            self.loose.len()
+        } else {
+            self.ids().len()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.loose.is_empty()
+            && self.bare.as_ref().is_none_or(|bare| bare.loose.is_empty())
+            && self.packs.iter().all(|pack| pack.index.is_empty())
+    }
+
+    /// Number of loose objects, in memory or in a bare repository
+    pub fn loose_len(&self) -> usize {
+        self.loose.len() + self.bare.as_ref().map_or(0, |bare| bare.loose.len())
+    }
+
+    /// Bytes the objects take in storage: pack files plus compressed loose
+    /// objects
+    pub fn stored_size(&self) -> Result<u64, GitError> {
+        let mut size: u64 = self.packs.iter().map(|pack| pack.file.size).sum();
+        size += self
+            .loose
+            .values()
+            .map(|object| compress_zlib(&object.to_loose_format()).len() as u64)
+            .sum::<u64>();
+        if let Some(bare) = &self.bare {
+            for id in &bare.loose {
+                size += bare.repo.loose_object_size(id)?;
+            }
+        }
+        Ok(size)
+    }
+
+    /// Verify a received pack and store it with a generated index. Returns
+    /// the ids of objects that weren't in the repository yet; a pack without
+    /// any is not stored.
+    ///
+    /// A thin pack (deltas against objects the client knows we have) is
+    /// completed with those bases before it's stored, like `index-pack
+    /// --fix-thin`, so every stored pack stands on its own.
+    pub fn add_pack(&mut self, data: &[u8], algo: HashAlgorithm) -> Result<Vec<String>, GitError> {
+        let (entries, bases) =
+            PackSerializer::parse_thin_entries(data, algo, |id| self.get_raw(id))?;
+        log(&format!("Parsed {} objects from pack file", entries.len()));
+
+        let mut seen = HashSet::with_capacity(entries.len());
+        let mut new_ids = Vec::new();
+        let mut new_objects = Vec::new();
+        let mut index_entries = Vec::with_capacity(entries.len());
+        let mut repeated = false;
+        for entry in entries {
+            let id = entry.id;
+            let raw_id = hex::decode(&id).map_err(|e| GitError::PackCorrupt(e.to_string()))?;
+            index_entries.push((raw_id, entry.offset, entry.crc32));
+            if !seen.insert(id.clone()) {
+                repeated = true;
+            } else if !self.contains_key(&id) {
+                new_ids.push(id);
+                new_objects.push(entry.raw);
+            }
+        }
+        if new_ids.is_empty() {
+            log("Pack holds no new objects, not storing it");
+            return Ok(new_ids);
+        }
+
+        // Written from the bytes received, which the parsed objects may not
+        // reproduce (signatures, encodings, tag names)
+        if let Some(bare) = self.bare.as_mut().filter(|_| new_ids.len() < UNPACK_LIMIT) {
+            for (id, raw) in new_ids.iter().zip(new_objects) {
+                bare.repo.write_loose_object(id, &raw)?;
+                bare.loose.insert(id.clone());
+            }
+            log(&format!("Wrote {} loose objects", new_ids.len()));
+            return Ok(new_ids);
+        }
+
+        // The index lists each id once, and entries end where the next
+        // indexed one starts, so a pack holding an object twice is rewritten
+        let mut data = Cow::Borrowed(data);
+        if repeated {
+            log("Pack holds repeated objects, storing it without them");
+            let (rewritten, entries) = drop_repeated_entries(&data, &index_entries, algo)?;
+            data = Cow::Owned(rewritten);
+            index_entries = entries;
+        }
+        if !bases.is_empty() {
+            log(&format!("Completing thin pack with {} bases", bases.len()));
+            data = Cow::Owned(complete_thin_pack(&data, &bases, &mut index_entries, algo)?);
+        }
+        self.store_pack(&data, index_entries, algo)?;
+        Ok(new_ids)
+    }
+
+    /// Write a pack and its index, given `(raw id, offset, crc32)` per entry
+    fn store_pack(
+        &mut self,
+        data: &[u8],
+        index_entries: Vec<(Vec<u8>, u64, u32)>,
+        algo: HashAlgorithm,
+    ) -> Result<(), GitError> {
+        let checksum = &data[data.len() - algo.raw_len()..];
+        let index = PackIndex::build(index_entries, checksum, algo);
+        let file = PackFile {
+            name: hex::encode(checksum),
+            size: data.len() as u64,
+        };
+
+        // The index is written last: a pack without one was never added
+        let location = match &self.bare {
+            Some(bare) => {
+                bare.repo.write_file(&file.pack_path(), data)?;
+                bare.repo
+                    .write_file(&file.index_path(), &index.to_bytes())?;
+                BARE_PACKS.with(|packs| {
+                    packs
+                        .borrow_mut()
+                        .insert(bare.repo.path(&file.pack_path()), Rc::new(data.to_vec()))
+                });
+                bare.repo.root().to_string()
+            }
+            None => {
+                let storage = match &self.storage {
+                    Some(storage) => storage.clone(),
+                    None => {
+                        let storage = host::create_storage().map_err(storage_error)?;
+                        self.storage = Some(storage.clone());
+                        storage
+                    }
+                };
+                for (n, chunk) in data.chunks(PACK_CHUNK_SIZE as usize).enumerate() {
+                    host::write_file(&storage, &chunk_path(&file, n as u64), chunk)
+                        .map_err(storage_error)?;
+                }
+                host::write_file(&storage, &file.index_path(), &index.to_bytes())
+                    .map_err(storage_error)?;
+                storage
+            }
+        };
+        log(&format!(
+            "Stored {} ({} bytes, {} objects)",
+            file.pack_path(),
+            file.size,
+            index.len()
+        ));
+
+        let index = Rc::new(index);
+        INDEXES.with(|indexes| {
+            indexes
+                .borrow_mut()
+                .insert((location, file.name.clone()), index.clone())
+        });
+        self.packs.push(LoadedPack { file, index });
+        Ok(())
+    }
+
+    /// Remove every object not in `keep`. Packs that still hold kept objects
+    /// are rewritten without the others, and packs left empty are deleted. A
+    /// dry run only counts what would be freed.
+    pub fn prune(&mut self, keep: &HashSet<String>, dry_run: bool) -> Result<PruneStats, GitError> {
+        let mut stats = PruneStats {
+            objects: self.ids().iter().filter(|id| !keep.contains(*id)).count(),
+            ..PruneStats::default()
+        };
+
+        let doomed: Vec<String> = self
+            .loose
+            .keys()
+            .filter(|id| !keep.contains(*id))
+            .cloned()
+            .collect();
+        for id in doomed {
+            stats.bytes += compress_zlib(&self.loose[&id].to_loose_format()).len() as u64;
+            if !dry_run {
+                self.loose.remove(&id);
+            }
+        }
+
+        if let Some(bare) = &mut self.bare {
+            let doomed: Vec<String> = bare
+                .loose
+                .iter()
+                .filter(|id| !keep.contains(*id))
+                .cloned()
+                .collect();
+            for id in doomed {
+                stats.bytes += bare.repo.loose_object_size(&id)?;
+                if !dry_run {
+                    bare.repo.remove_loose_object(&id)?;
+                    bare.loose.remove(&id);
+                }
+            }
+        }
+
+        for pack in self.packs.clone() {
+            let (kept, pruned): (Vec<usize>, Vec<usize>) =
+                (0..pack.index.len()).partition(|&n| keep.contains(&hex::encode(pack.index.id(n))));
+            if pruned.is_empty() {
+                continue;
+            }
+
+            // Index entries are an id, a CRC32 and a 4-byte offset
+            let hash_len = pack.index.algo().raw_len() as u64;
+            for &n in &pruned {
+                let (start, end) = pack.index.entry_range(n, pack.file.size);
+                stats.bytes += end - start + hash_len + 8;
+            }
+            if kept.is_empty() {
+                // Pack header and trailer, index header, fanout and trailer
+                stats.bytes += 12 + hash_len + 8 + 256 * 4 + 2 * hash_len;
+                stats.packs_removed += 1;
+            } else {
+                stats.packs_rewritten += 1;
+            }
+            if dry_run {
+                continue;
+            }
+
+            if !kept.is_empty() {
+                self.rewrite_pack(&pack, kept, keep)?;
+            }
+            self.remove_pack(&pack.file)?;
+        }
+
+        Ok(stats)
+    }
+
+    /// Store a new pack holding only entries `kept` of `pack`. Deltas stay
+    /// deltas while their base is kept; the others are stored whole.
+    fn rewrite_pack(
+        &mut self,
+        pack: &LoadedPack,
+        mut kept: Vec<usize>,
+        keep: &HashSet<String>,
+    ) -> Result<(), GitError> {
+        let algo = pack.index.algo();
+        kept.sort_by_key(|&n| pack.index.offset(n));
+
+        let mut data = b"PACK".to_vec();
+        data.extend_from_slice(&2u32.to_be_bytes());
+        data.extend_from_slice(&(kept.len() as u32).to_be_bytes());
+        let mut index_entries = Vec::with_capacity(kept.len());
+        for n in kept {
+            let entry = match self.reusable_entry_at(pack, n, |base| keep.contains(base))? {
+                Some(entry) => entry,
+                None => {
+                    let (obj_type, content) = self.read_packed(pack, n, 0)?;
+                    PackSerializer::encode_entry(
+                        obj_type,
+                        content.len(),
+                        None,
+                        &compress_zlib(&content),
+                    )
+                }
+            };
+            let mut crc = Crc::new();
+            crc.update(&entry);
+            index_entries.push((pack.index.id(n).to_vec(), data.len() as u64, crc.sum()));
+            data.extend_from_slice(&entry);
+        }
+        let checksum = algo.digest(&data);
+        data.extend_from_slice(&checksum);
+
+        self.store_pack(&data, index_entries, algo)
+    }
+
+    /// Store `data` as the only pack, then delete every other pack and loose
+    /// object: whatever they held must be in the new pack
+    pub fn replace_with_pack(
+        &mut self,
+        data: &[u8],
+        index_entries: Vec<(Vec<u8>, u64, u32)>,
+        algo: HashAlgorithm,
+    ) -> Result<(), GitError> {
+        let name = hex::encode(&data[data.len() - algo.raw_len()..]);
+        let old = self.packs();
+        if !old.iter().any(|file| file.name == name) {
+            self.store_pack(data, index_entries, algo)?;
+        }
+        for file in old.iter().filter(|file| file.name != name) {
+            self.remove_pack(file)?;
+        }
+
+        self.loose.clear();
+        if let Some(bare) = &mut self.bare {
+            for id in std::mem::take(&mut bare.loose) {
+                bare.repo.remove_loose_object(&id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget a pack and delete its files, index first
+    fn remove_pack(&mut self, file: &PackFile) -> Result<(), GitError> {
+        self.packs.retain(|pack| pack.file != *file);
+        match (&self.bare, &self.storage) {
+            (Some(bare), _) => {
+                bare.repo.remove_file(&file.index_path())?;
+                bare.repo.remove_file(&file.pack_path())?;
+                BARE_PACKS.with(|packs| {
+                    packs
+                        .borrow_mut()
+                        .remove(&bare.repo.path(&file.pack_path()))
+                });
+            }
+            (None, Some(storage)) => {
+                host::remove_file(storage, &file.index_path()).map_err(storage_error)?;
+                for n in 0..file.size.div_ceil(PACK_CHUNK_SIZE) {
+                    host::remove_file(storage, &chunk_path(file, n)).map_err(storage_error)?;
+                }
+            }
+            (None, None) => {}
+        }
+        log(&format!("Removed {}", file.pack_path()));
+        Ok(())
+    }
+
+    fn find_packed(&self, id: &str) -> Option<(&LoadedPack, usize)> {
+        let raw_id = hex::decode(id).ok()?;
+        self.packs
+            .iter()
+            .find_map(|pack| pack.index.find(&raw_id).map(|n| (pack, n)))
+    }
+
+    /// Type and content of an object, wherever it is stored. `depth` counts
+    /// the deltas waiting on it.
+    fn read_content(&self, id: &str, depth: usize) -> Result<Option<Content>, GitError> {
+        let whole = |object: &GitObject| {
+            let raw = RawObject::from_object(object);
+            (raw.obj_type, Rc::new(raw.content))
+        };
+        if let Some(object) = self.loose.get(id) {
+            return Ok(Some(whole(object)));
+        }
+        if let Some(bare) = self.bare.as_ref().filter(|bare| bare.loose.contains(id)) {
+            if let Some(raw) = bare.repo.read_loose_raw(id)? {
+                return Ok(Some((raw.obj_type, Rc::new(raw.content))));
+            }
+        }
+        match self.find_packed(id) {
+            Some((pack, n)) => self.read_packed(pack, n, depth).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Type and content of the `n`th object of a pack, applying deltas to
+    /// their bases. `depth` counts the deltas waiting on it.
+    fn read_packed(&self, pack: &LoadedPack, n: usize, depth: usize) -> Result<Content, GitError> {
+        let key = (pack.file.name.clone(), pack.index.offset(n));
+        let cached = DELTA_BASES.with(|cache| {
+            cache
+                .borrow()
+                .iter()
+                .find(|(cached, _)| *cached == key)
+                .map(|(_, content)| content.clone())
+        });
+        if let Some(content) = cached {
+            return Ok(content);
+        }
+        if depth > MAX_DELTA_CHAIN {
+            return Err(GitError::PackCorrupt(format!(
+                "delta chain in {} is too long",
+                pack.file.pack_path()
+            )));
+        }
+
+        let entry = self.read_entry(pack, n)?;
+        let header = PackSerializer::entry_header(&entry, pack.index.algo())?;
+        let (data, _) = PackSerializer::inflate(&entry[header.data_start..], header.size)?;
+        let (obj_type, base) = match &header.base {
+            None if depth == 0 => return Ok((header.obj_type, Rc::new(data))),
+            None => (header.obj_type, None),
+            Some(DeltaBase::Offset(distance)) => {
+                let base = find_ofs_base(pack, n, *distance)?;
+                let (obj_type, base) = self.read_packed(pack, base, depth + 1)?;
+                (obj_type, Some(base))
+            }
+            Some(DeltaBase::Id(id)) => {
+                let id = hex::encode(id);
+                let (obj_type, base) = self
+                    .read_content(&id, depth + 1)?
+                    .ok_or(GitError::MissingObject(id))?;
+                (obj_type, Some(base))
+            }
+        };
+        let content = match base {
+            Some(base) => apply_delta(&base, &data).map_err(|e| {
+                GitError::PackCorrupt(format!(
+                    "delta at offset {} in {}: {}",
+                    key.1,
+                    pack.file.pack_path(),
+                    e
+                ))
+            })?,
+            None => data,
+        };
+
+        let content = (obj_type, Rc::new(content));
+        DELTA_BASES.with(|cache| {
+            let mut cache = cache.borrow_mut();
+            if cache.len() == DELTA_CACHE_SIZE {
+                cache.pop_front();
+            }
+            cache.push_back((key, content.clone()));
+        });
+        Ok(content)
+    }
+
+    /// Read the `n`th entry of a pack and check it against the index's CRC32
+    fn read_entry(&self, pack: &LoadedPack, n: usize) -> Result<Vec<u8>, GitError> {
+        let (start, end) = pack.index.entry_range(n, pack.file.size);
+        let entry = match (&self.bare, &self.storage) {
+            (Some(bare), _) => {
+                let data = read_bare_pack(&bare.repo, &pack.file)?;
+                data.get(start as usize..end as usize)
+                    .ok_or_else(|| {
+                        GitError::PackCorrupt(format!(
+                            "{} is shorter than expected",
+                            pack.file.pack_path()
+                        ))
+                    })?
+                    .to_vec()
+            }
+            (None, Some(storage)) => read_range(storage, &pack.file, start, end)?,
+            (None, None) => return Err(GitError::PackCorrupt("no storage area".to_string())),
+        };
+
+        let mut crc = Crc::new();
+        crc.update(&entry);
+        if crc.sum() != pack.index.crc32(n) {
+            return Err(GitError::PackCorrupt(format!(
+                "CRC mismatch for entry at offset {} in {}",
+                start,
+                pack.file.pack_path()
+            )));
+        }
+        Ok(entry)
+    }
+}
+
+/// Copy a pack without the later copies of objects it holds more than once,
+/// given `(raw id, offset, crc32)` per entry in pack order. `OBJ_OFS_DELTA`
+/// entries are re-encoded with their new distance, to the first copy of
+/// their base. Returns the new pack and its index entries.
+fn drop_repeated_entries(
+    data: &[u8],
+    entries: &[IndexEntry],
+    algo: HashAlgorithm,
+) -> Result<(Vec<u8>, Vec<IndexEntry>), GitError> {
+    let body_end = (data.len() - algo.raw_len()) as u64;
+    let mut pack = data[..12].to_vec();
+    // New offset of each old entry (or of the first copy of its object)
+    let mut moved: HashMap<u64, u64> = HashMap::new();
+    let mut first: HashMap<&[u8], u64> = HashMap::new();
+    let mut index_entries = Vec::new();
+    for (n, (id, offset, _)) in entries.iter().enumerate() {
+        if let Some(&at) = first.get(id.as_slice()) {
+            moved.insert(*offset, at);
+            continue;
+        }
+        let end = entries.get(n + 1).map_or(body_end, |(_, next, _)| *next);
+        let entry = &data[*offset as usize..end as usize];
+        let new_offset = pack.len() as u64;
+        let header = PackSerializer::entry_header(entry, algo)?;
+        let entry = match header.base {
+            Some(DeltaBase::Offset(distance)) => {
+                let base = offset
+                    .checked_sub(distance)
+                    .and_then(|base| moved.get(&base))
+                    .ok_or_else(|| {
+                        GitError::PackCorrupt(format!("delta at offset {offset} has no base entry"))
+                    })?;
+                PackSerializer::encode_entry(
+                    header.obj_type,
+                    header.size,
+                    Some(&DeltaBase::Offset(new_offset - base)),
+                    &entry[header.data_start..],
+                )
+            }
+            _ => entry.to_vec(),
+        };
+        let mut crc = Crc::new();
+        crc.update(&entry);
+        index_entries.push((id.clone(), new_offset, crc.sum()));
+        moved.insert(*offset, new_offset);
+        first.insert(id, new_offset);
+        pack.extend_from_slice(&entry);
+    }
+    pack[8..12].copy_from_slice(&(index_entries.len() as u32).to_be_bytes());
+    let checksum = algo.digest(&pack);
+    pack.extend_from_slice(&checksum);
+    Ok((pack, index_entries))
+}
+
+/// Append the bases a thin pack's deltas refer to as whole entries, with a
+/// new object count and trailer. Entries already in the pack keep their
+/// offsets; the bases are added to `index_entries`.
+fn complete_thin_pack(
+    data: &[u8],
+    bases: &[RawObject],
+    index_entries: &mut Vec<(Vec<u8>, u64, u32)>,
+    algo: HashAlgorithm,
+) -> Result<Vec<u8>, GitError> {
+    let count = u32::from_be_bytes(data[8..12].try_into().unwrap()) as usize + bases.len();
+    let count = u32::try_from(count)
+        .map_err(|_| GitError::LimitExceeded("too many objects in pack".to_string()))?;
+    let mut completed = data[..data.len() - algo.raw_len()].to_vec();
+    completed[8..12].copy_from_slice(&count.to_be_bytes());
+    for base in bases {
+        let entry = base.to_pack_entry();
+        let raw_id = algo.digest(&base.to_loose_format());
+        let mut crc = Crc::new();
+        crc.update(&entry);
+        index_entries.push((raw_id, completed.len() as u64, crc.sum()));
+        completed.extend_from_slice(&entry);
+    }
+    let checksum = algo.digest(&completed);
+    completed.extend_from_slice(&checksum);
+    Ok(completed)
+}
+
+/// Position of the base of the `OBJ_OFS_DELTA` at position `n`
+fn find_ofs_base(pack: &LoadedPack, n: usize, distance: u64) -> Result<usize, GitError> {
+    let offset = pack.index.offset(n);
+    offset
+        .checked_sub(distance)
+        .and_then(|base| pack.index.find_offset(base))
+        .ok_or_else(|| {
+            GitError::PackCorrupt(format!(
+                "delta at offset {} in {} has no base entry",
+                offset,
+                pack.file.pack_path()
+            ))
+        })
+}
+
+fn storage_error(message: String) -> GitError {
+    GitError::Storage(message)
+}
+
+fn chunk_path(file: &PackFile, n: u64) -> String {
+    format!("{}.{}", file.pack_path(), n)
+}
+
+/// Parse a pack's index, or reuse the cached one. `location` is the storage
+/// area or bare repository the pack lives in.
+fn load_index(
+    location: &str,
+    file: &PackFile,
+    algo: HashAlgorithm,
+    read: impl FnOnce() -> Result<Option<Vec<u8>>, String>,
+) -> Result<Rc<PackIndex>, GitError> {
+    let key = (location.to_string(), file.name.clone());
+    if let Some(index) = INDEXES.with(|indexes| indexes.borrow().get(&key).cloned()) {
+        return Ok(index);
+    }
+
+    let data = read()
+        .map_err(storage_error)?
+        .ok_or_else(|| GitError::PackCorrupt(format!("missing {}", file.index_path())))?;
+    let index = Rc::new(PackIndex::parse(&data, algo)?);
+
+    INDEXES.with(|indexes| indexes.borrow_mut().insert(key, index.clone()));
+    Ok(index)
+}
+
+/// Read a whole pack file of a bare repository, or reuse the cached copy
+fn read_bare_pack(bare: &BareRepository, file: &PackFile) -> Result<Rc<Vec<u8>>, GitError> {
+    let path = bare.path(&file.pack_path());
+    if let Some(data) = BARE_PACKS.with(|packs| packs.borrow().get(&path).cloned()) {
+        return Ok(data);
+    }
+
+    let data = bare
+        .read_file(&file.pack_path())?
+        .ok_or_else(|| GitError::PackCorrupt(format!("missing {}", path)))?;
+    let data = Rc::new(data);
+    BARE_PACKS.with(|packs| packs.borrow_mut().insert(path, data.clone()));
+    Ok(data)
+}
+
+/// Read bytes `start..end` of a stored pack
+fn read_range(storage: &str, file: &PackFile, start: u64, end: u64) -> Result<Vec<u8>, GitError> {
+    let mut data = Vec::with_capacity((end - start) as usize);
+    for n in start / PACK_CHUNK_SIZE..=(end.max(start + 1) - 1) / PACK_CHUNK_SIZE {
+        let chunk = read_chunk(storage, file, n)?;
+        let chunk_start = n * PACK_CHUNK_SIZE;
+        let from = start.saturating_sub(chunk_start) as usize;
+        let to = ((end - chunk_start) as usize).min(chunk.len());
+        if from > to {
+            return Err(GitError::PackCorrupt(format!(
+                "{} is shorter than expected",
+                file.pack_path()
+            )));
+        }
+        data.extend_from_slice(&chunk[from..to]);
+    }
+    Ok(data)
+}
+
+fn read_chunk(storage: &str, file: &PackFile, n: u64) -> Result<Rc<Vec<u8>>, GitError> {
+    let key = (storage.to_string(), file.name.clone(), n);
+    let cached = CHUNKS.with(|chunks| {
+        chunks
+            .borrow()
+            .iter()
+            .find(|(cached, _)| *cached == key)
+            .map(|(_, chunk)| chunk.clone())
+    });
+    if let Some(chunk) = cached {
+        return Ok(chunk);
+    }
+
+    let path = chunk_path(file, n);
+    let chunk = host::read_file(storage, &path)
+        .map_err(storage_error)?
+        .ok_or_else(|| GitError::PackCorrupt(format!("missing {}", path)))?;
+    let chunk = Rc::new(chunk);
+
+    CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        if chunks.len() == CHUNK_CACHE_SIZE {
+            chunks.pop_front();
+        }
+        chunks.push_back((key, chunk.clone()));
+    });
+    Ok(chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture() -> (Vec<u8>, HashAlgorithm) {
+        let pack = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/sha1.pack"
+        ))
+        .unwrap();
+        (pack, HashAlgorithm::Sha1)
+    }
+
+    #[test]
+    fn test_packed_objects_are_read_on_demand() {
+        let (pack, algo) = fixture();
+        let expected = PackSerializer::parse(&pack, algo).unwrap();
+
+        let mut store = ObjectStore::default();
+        let new_ids = store.add_pack(&pack, algo).unwrap();
+        assert_eq!(new_ids.len(), expected.len());
+        assert!(store.loose().is_empty());
+        assert_eq!(store.len(), expected.len());
+
+        for object in &expected {
+            let id = object.compute_hash(algo);
+            assert!(store.contains_key(&id));
+            assert_eq!(store.get(&id).as_ref(), Some(object));
+
+            // Stored entries decode to the same object
+            let entry = store.reusable_entry(&id, |_| false).unwrap().unwrap();
+            assert_eq!(&PackSerializer::read_entry(&entry, algo).unwrap(), object);
+        }
+
+        // Nothing new: the pack isn't stored twice
+        assert!(store.add_pack(&pack, algo).unwrap().is_empty());
+        assert_eq!(store.packs().len(), 1);
+    }
+
+    #[test]
+    fn test_repeated_objects_are_stored_once() {
+        use crate::git::delta::create_delta;
+        use crate::git::objects::OBJ_OFS_DELTA;
+
+        let algo = HashAlgorithm::Sha1;
+        let blob = |content: &[u8]| GitObject::Blob {
+            content: content.to_vec(),
+        };
+        let base: Vec<u8> = (0..100)
+            .flat_map(|i| format!("{i}\n").into_bytes())
+            .collect();
+        let mut changed = base.clone();
+        changed.extend_from_slice(b"100\n");
+
+        // base, base again, then a delta against the second copy
+        let mut pack = b"PACK\0\0\0\x02\0\0\0\x03".to_vec();
+        pack.extend(PackSerializer::serialize_object(&blob(&base)).unwrap());
+        let second = pack.len() as u64;
+        pack.extend(PackSerializer::serialize_object(&blob(&base)).unwrap());
+        let delta = create_delta(&base, &changed, changed.len()).unwrap();
+        let distance = pack.len() as u64 - second;
+        pack.extend(PackSerializer::encode_entry(
+            OBJ_OFS_DELTA,
+            delta.len(),
+            Some(&DeltaBase::Offset(distance)),
+            &compress_zlib(&delta),
+        ));
+        pack.extend(algo.digest(&pack));
+
+        let mut store = ObjectStore::default();
+        assert_eq!(store.add_pack(&pack, algo).unwrap().len(), 2);
+        assert_eq!(store.packs[0].index.len(), 2);
+        for content in [&base, &changed] {
+            let object = blob(content);
+            assert_eq!(store.get(&object.compute_hash(algo)), Some(object));
+        }
+    }
+
+    #[test]
+    fn test_prune_rewrites_and_removes_packs() {
+        let (pack, algo) = fixture();
+        let mut store = ObjectStore::default();
+        let mut ids = store.add_pack(&pack, algo).unwrap();
+        ids.sort();
+        let old = store.packs()[0].clone();
+        let keep: HashSet<String> = ids[..3].iter().cloned().collect();
+
+        let dry = store.prune(&keep, true).unwrap();
+        assert_eq!((dry.objects, dry.packs_rewritten), (ids.len() - 3, 1));
+        assert_eq!(store.packs(), vec![old.clone()]);
+
+        let stats = store.prune(&keep, false).unwrap();
+        assert_eq!(stats, dry);
+        assert_eq!(store.len(), 3);
+        for id in &keep {
+            let object = store.get(id).unwrap();
+            assert_eq!(&object.compute_hash(algo), id);
+        }
+        let storage = store.storage().unwrap().to_string();
+        assert_eq!(host::read_file(&storage, &old.index_path()).unwrap(), None);
+
+        let stats = store.prune(&HashSet::new(), false).unwrap();
+        assert_eq!((stats.objects, stats.packs_removed), (3, 1));
+        assert!(store.packs().is_empty() && store.is_empty());
+    }
+
+    #[test]
+    fn test_restore_reloads_indexes_from_storage() {
+        let (pack, algo) = fixture();
+        let mut store = ObjectStore::default();
+        store.add_pack(&pack, algo).unwrap();
+        let storage = store.storage().map(str::to_string);
+
+        // A fresh thread-local cache forces the index to be read back
+        INDEXES.with(|indexes| indexes.borrow_mut().clear());
+        CHUNKS.with(|chunks| chunks.borrow_mut().clear());
+        let restored =
+            ObjectStore::restore(storage.clone(), HashMap::new(), store.packs(), algo).unwrap();
+        assert_eq!(restored.ids(), store.ids());
+
+        let missing = PackFile {
+            name: "00".repeat(20),
+            size: 100,
+        };
+        assert!(ObjectStore::restore(storage, HashMap::new(), vec![missing], algo).is_err());
+    }
+
+    #[test]
+    fn test_reads_span_chunk_boundaries() {
+        let storage = host::create_storage().unwrap();
+        let file = PackFile {
+            name: "ab".repeat(20),
+            size: PACK_CHUNK_SIZE * 2 + 10,
+        };
+        let data: Vec<u8> = (0..file.size).map(|i| (i % 251) as u8).collect();
+        for (n, chunk) in data.chunks(PACK_CHUNK_SIZE as usize).enumerate() {
+            host::write_file(&storage, &chunk_path(&file, n as u64), chunk).unwrap();
+        }
+
+        let (start, end) = (PACK_CHUNK_SIZE - 5, PACK_CHUNK_SIZE * 2 + 3);
+        assert_eq!(
+            read_range(&storage, &file, start, end).unwrap(),
+            &data[start as usize..end as usize]
+        );
+    }
+}
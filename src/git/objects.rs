@@ -6,9 +6,20 @@
 //! - PackSerializer: Handles pack file format
 //!
 //! Key insight: Both formats use identical content serialization to ensure
-//! consistent SHA-1 hashes across different storage formats.
+//! consistent object ids across different storage formats.
+//!
+//! `GitObject` doesn't keep every header git writes (a commit's `gpgsig`,
+//! `encoding` or `mergetag`, a tag's name), so objects received from
+//! clients are also kept as `RawObject`s: their ids and stored bytes come
+//! from the exact content, never from re-encoding the parsed object.
+//!
+//! Object ids are computed with the repository's `HashAlgorithm`, which also
+//! decides the width of binary ids inside tree objects and pack trailers.
 
-use crate::bindings::theater::simple::runtime::log;
+use super::signature::Signature;
+use crate::error::GitError;
+use crate::host::log;
+use crate::utils::hash::HashAlgorithm;
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 
@@ -67,14 +78,10 @@ impl GitObject {
         }
     }
 
-    /// Compute SHA-1 hash of this object (using loose format)
-    pub fn compute_hash(&self) -> String {
-        use sha1::{Digest, Sha1};
-
+    /// Compute the object id of this object (using loose format)
+    pub fn compute_hash(&self, algo: HashAlgorithm) -> String {
         let loose_data = LooseObjectSerializer::serialize(self);
-        let mut hasher = Sha1::new();
-        hasher.update(&loose_data);
-        hex::encode(hasher.finalize())
+        algo.hex_digest(&loose_data)
     }
 
     /// Serialize for loose object storage
@@ -88,13 +95,41 @@ impl GitObject {
     }
 
     /// Create from loose object data
-    pub fn from_loose_format(data: &[u8]) -> Result<Self, String> {
-        LooseObjectSerializer::deserialize(data)
+    pub fn from_loose_format(data: &[u8], algo: HashAlgorithm) -> Result<Self, String> {
+        LooseObjectSerializer::deserialize(data, algo)
     }
 
     /// Create from pack object data
-    pub fn from_pack_format(pack_type: u8, compressed_data: &[u8]) -> Result<Self, String> {
-        PackSerializer::deserialize_object(pack_type, compressed_data)
+    pub fn from_pack_format(
+        pack_type: u8,
+        compressed_data: &[u8],
+        algo: HashAlgorithm,
+    ) -> Result<Self, String> {
+        PackSerializer::deserialize_object(pack_type, compressed_data, algo)
+    }
+
+    /// Parsed author of a commit (`None` for other object types)
+    pub fn author(&self) -> Option<Result<Signature, String>> {
+        match self {
+            GitObject::Commit { author, .. } => Some(author.parse()),
+            _ => None,
+        }
+    }
+
+    /// Parsed committer of a commit (`None` for other object types)
+    pub fn committer(&self) -> Option<Result<Signature, String>> {
+        match self {
+            GitObject::Commit { committer, .. } => Some(committer.parse()),
+            _ => None,
+        }
+    }
+
+    /// Parsed tagger of an annotated tag (`None` for other object types)
+    pub fn tagger(&self) -> Option<Result<Signature, String>> {
+        match self {
+            GitObject::Tag { tagger, .. } => Some(tagger.parse()),
+            _ => None,
+        }
     }
 }
 
@@ -136,6 +171,80 @@ impl Display for GitObject {
     }
 }
 
+/// An object exactly as git stores it: pack type (1-4) and content
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawObject {
+    pub obj_type: u8,
+    pub content: Vec<u8>,
+}
+
+impl RawObject {
+    pub fn new(obj_type: u8, content: Vec<u8>) -> Self {
+        Self { obj_type, content }
+    }
+
+    /// Encode an object built in memory
+    pub fn from_object(object: &GitObject) -> Self {
+        Self::new(
+            object.object_type_byte(),
+            LooseObjectSerializer::serialize_content(object),
+        )
+    }
+
+    /// Read `<type> <size>\0<content>`, as found in a loose object file
+    pub fn from_loose_format(data: &[u8]) -> Result<Self, String> {
+        let (obj_type, content) = LooseObjectSerializer::split(data)?;
+        let obj_type = match obj_type {
+            "commit" => 1,
+            "tree" => 2,
+            "blob" => 3,
+            "tag" => 4,
+            _ => return Err(format!("Unknown object type: {}", obj_type)),
+        };
+        Ok(Self::new(obj_type, content.to_vec()))
+    }
+
+    /// Git's name for the object type
+    pub fn type_name(&self) -> &'static str {
+        match self.obj_type {
+            1 => "commit",
+            2 => "tree",
+            3 => "blob",
+            4 => "tag",
+            _ => "unknown",
+        }
+    }
+
+    /// `<type> <size>\0<content>`: what the id hashes and loose files hold
+    pub fn to_loose_format(&self) -> Vec<u8> {
+        let header = format!("{} {}\0", self.type_name(), self.content.len());
+        let mut data = Vec::with_capacity(header.len() + self.content.len());
+        data.extend_from_slice(header.as_bytes());
+        data.extend_from_slice(&self.content);
+        data
+    }
+
+    /// The object id
+    pub fn id(&self, algo: HashAlgorithm) -> String {
+        algo.hex_digest(&self.to_loose_format())
+    }
+
+    /// Parse the content
+    pub fn to_object(&self, algo: HashAlgorithm) -> Result<GitObject, GitError> {
+        PackSerializer::object_from_content(self.obj_type, &self.content, algo)
+    }
+
+    /// A whole (non-delta) pack entry for the object
+    pub fn to_pack_entry(&self) -> Vec<u8> {
+        PackSerializer::encode_entry(
+            self.obj_type,
+            self.content.len(),
+            None,
+            &crate::utils::compression::compress_zlib(&self.content),
+        )
+    }
+}
+
 impl TreeEntry {
     /// Create a new tree entry
     pub fn new(mode: String, name: String, hash: String) -> Self {
@@ -167,7 +276,14 @@ impl LooseObjectSerializer {
     }
 
     /// Deserialize object from loose format
-    pub fn deserialize(data: &[u8]) -> Result<GitObject, String> {
+    pub fn deserialize(data: &[u8], algo: HashAlgorithm) -> Result<GitObject, String> {
+        let (obj_type, content) = Self::split(data)?;
+        Self::deserialize_content(obj_type, content, algo)
+    }
+
+    /// Split loose format into the object type and the content, checking the
+    /// size the header declares
+    fn split(data: &[u8]) -> Result<(&str, &[u8]), String> {
         // Find the null terminator that separates header from content
         let null_pos = data
             .iter()
@@ -198,7 +314,7 @@ impl LooseObjectSerializer {
             ));
         }
 
-        Self::deserialize_content(obj_type, content)
+        Ok((obj_type, content))
     }
 
     /// Serialize just the content part (used by both loose and pack formats)
@@ -210,7 +326,7 @@ impl LooseObjectSerializer {
             GitObject::Tree { entries } => {
                 let mut data = Vec::new();
                 for entry in entries {
-                    // Git tree format: <mode> <name>\0<20-byte-hash>
+                    // Git tree format: <mode> <name>\0<binary-hash> (20 or 32 bytes)
                     data.extend_from_slice(entry.mode.as_bytes());
                     data.push(b' ');
                     data.extend_from_slice(entry.name.as_bytes());
@@ -294,13 +410,17 @@ impl LooseObjectSerializer {
     }
 
     /// Deserialize content based on object type
-    pub fn deserialize_content(obj_type: &str, content: &[u8]) -> Result<GitObject, String> {
+    pub fn deserialize_content(
+        obj_type: &str,
+        content: &[u8],
+        algo: HashAlgorithm,
+    ) -> Result<GitObject, String> {
         match obj_type {
             "blob" => Ok(GitObject::Blob {
                 content: content.to_vec(),
             }),
 
-            "tree" => Self::parse_tree_content(content),
+            "tree" => Self::parse_tree_content(content, algo),
             "commit" => Self::parse_commit_content(content),
             "tag" => Self::parse_tag_content(content),
 
@@ -308,7 +428,8 @@ impl LooseObjectSerializer {
         }
     }
 
-    fn parse_tree_content(data: &[u8]) -> Result<GitObject, String> {
+    fn parse_tree_content(data: &[u8], algo: HashAlgorithm) -> Result<GitObject, String> {
+        let hash_len = algo.raw_len();
         let mut entries = Vec::new();
         let mut pos = 0;
 
@@ -333,13 +454,13 @@ impl LooseObjectSerializer {
                 .map_err(|_| "Invalid UTF-8 in tree name")?;
             pos += null_pos + 1;
 
-            // Parse 20-byte SHA-1
-            if pos + 20 > data.len() {
+            // Parse binary object id (20 bytes for SHA-1, 32 for SHA-256)
+            if pos + hash_len > data.len() {
                 return Err("Malformed tree entry: truncated hash".to_string());
             }
 
-            let hash = hex::encode(&data[pos..pos + 20]);
-            pos += 20;
+            let hash = hex::encode(&data[pos..pos + hash_len]);
+            pos += hash_len;
 
             entries.push(TreeEntry {
                 mode: mode.to_string(),
@@ -430,24 +551,92 @@ impl LooseObjectSerializer {
 // Pack Format Handler (for git protocol transfer)
 // ============================================================================
 
+use super::delta::apply_delta;
 use flate2::read::ZlibDecoder;
+use flate2::Crc;
+use std::collections::HashMap;
 use std::io::Read;
 
+/// Pack entry type of a delta against a base earlier in the same pack
+pub const OBJ_OFS_DELTA: u8 = 6;
+
+/// Pack entry type of a delta against a base named by its id
+pub const OBJ_REF_DELTA: u8 = 7;
+
 pub struct PackSerializer;
 
+/// An object parsed from a pack, with where its entry sits in the pack
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackEntry {
+    /// Offset of the entry header from the start of the pack
+    pub offset: u64,
+    /// CRC32 of the entry bytes (header and compressed data), as in `.idx` files
+    pub crc32: u32,
+    /// Hex id, hashed from `raw`
+    pub id: String,
+    /// Type and content, with any delta applied
+    pub raw: RawObject,
+    pub object: GitObject,
+}
+
+/// Where a delta entry's base object is
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeltaBase {
+    /// `OBJ_OFS_DELTA`: the entry this many bytes before the delta's own
+    Offset(u64),
+    /// `OBJ_REF_DELTA`: the object with this raw id
+    Id(Vec<u8>),
+}
+
+/// The header of a pack entry
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryHeader {
+    /// Pack object type: 1-4, or `OBJ_OFS_DELTA` / `OBJ_REF_DELTA`
+    pub obj_type: u8,
+    /// Inflated size of the object (or of the delta)
+    pub size: usize,
+    pub base: Option<DeltaBase>,
+    /// Where the compressed data starts within the entry
+    pub data_start: usize,
+}
+
 impl PackSerializer {
-    pub fn parse(data: &[u8]) -> Result<Vec<GitObject>, String> {
+    pub fn parse(data: &[u8], algo: HashAlgorithm) -> Result<Vec<GitObject>, GitError> {
+        Ok(Self::parse_entries(data, algo)?
+            .into_iter()
+            .map(|entry| entry.object)
+            .collect())
+    }
+
+    /// Parse and verify a pack, keeping each entry's offset and CRC32. Deltas
+    /// are resolved against bases in the same pack.
+    pub fn parse_entries(data: &[u8], algo: HashAlgorithm) -> Result<Vec<PackEntry>, GitError> {
+        Ok(Self::parse_thin_entries(data, algo, |_| None)?.0)
+    }
+
+    /// Parse and verify a pack that may be thin: `OBJ_REF_DELTA` bases that
+    /// aren't in the pack are looked up with `external` (by hex id). Also
+    /// returns the external bases used, for completing the pack.
+    pub fn parse_thin_entries(
+        data: &[u8],
+        algo: HashAlgorithm,
+        external: impl Fn(&str) -> Option<RawObject>,
+    ) -> Result<(Vec<PackEntry>, Vec<RawObject>), GitError> {
         let mut cursor = 0;
 
         // ---- Pack header -----------------------------------------------------
         if data.len() < 12 || &data[..4] != b"PACK" {
-            return Err("Invalid pack file: missing PACK signature".into());
+            return Err(GitError::PackCorrupt(
+                "Invalid pack file: missing PACK signature".into(),
+            ));
         }
         cursor += 4;
 
         let version = u32::from_be_bytes(data[cursor..cursor + 4].try_into().unwrap());
         if version != 2 {
-            return Err(format!("Unsupported pack version: {version}"));
+            return Err(GitError::PackCorrupt(format!(
+                "Unsupported pack version: {version}"
+            )));
         }
         cursor += 4;
 
@@ -456,54 +645,280 @@ impl PackSerializer {
 
         log(&format!("Parsing pack v{version} – {num_objects} objects"));
 
-        let mut objects = Vec::with_capacity(num_objects);
+        // (offset, crc32, header, inflated object or delta) per entry
+        let mut raw = Vec::with_capacity(num_objects);
 
         // ---- Objects ---------------------------------------------------------
         for _ in 0..num_objects {
-            // 1. Header
-            let (obj_type, raw_size, header_len) = Self::decode_pack_header(&data[cursor..])?;
-            cursor += header_len;
+            let entry_start = cursor;
+
+            // 1. Header, including a delta's base
+            let header = Self::entry_header(&data[cursor..], algo)?;
+            cursor += header.data_start;
 
             // 2. Decompress once, keep how many bytes zlib consumed
-            let mut decoder = ZlibDecoder::new(&data[cursor..]);
-            let mut content = Vec::with_capacity(raw_size);
-            decoder
-                .read_to_end(&mut content)
-                .map_err(|e| format!("Failed to decompress object: {e}"))?;
-
-            let consumed = decoder.total_in() as usize;
-            if content.len() != raw_size {
-                return Err(format!(
-                    "Size mismatch: header says {raw_size}, zlib produced {}",
-                    content.len()
-                ));
-            }
+            let (content, consumed) = Self::inflate(&data[cursor..], header.size)?;
 
             // 3. Advance cursor exactly past the compressed stream
-            let compressed_slice = &data[cursor..cursor + consumed];
             cursor += consumed;
 
-            // 4. Turn it into a GitObject (no second inflate!)
-            let git_obj = match obj_type {
-                1 => LooseObjectSerializer::deserialize_content("commit", &content),
-                2 => LooseObjectSerializer::deserialize_content("tree", &content),
-                3 => LooseObjectSerializer::deserialize_content("blob", &content),
-                4 => LooseObjectSerializer::deserialize_content("tag", &content),
-                6 | 7 => Err("Delta objects not supported yet".into()),
-                _ => Err(format!("Unknown pack object type: {obj_type}")),
-            }?;
+            let mut crc = Crc::new();
+            crc.update(&data[entry_start..cursor]);
+            raw.push((entry_start as u64, crc.sum(), header, content));
+        }
 
-            objects.push(git_obj);
+        // ---- Trailer: checksum of everything before it -----------------------
+        let trailer_len = algo.raw_len();
+        if data.len() != cursor + trailer_len {
+            return Err(GitError::PackCorrupt(format!(
+                "Invalid pack trailer: expected {trailer_len}-byte {} checksum, found {} bytes",
+                algo.name(),
+                data.len() - cursor
+            )));
+        }
+        if algo.digest(&data[..cursor]) != data[cursor..] {
+            return Err(GitError::PackCorrupt("Pack checksum mismatch".to_string()));
+        }
 
-            // If you still need the compressed bytes later (e.g. to write them
-            // back out) you already have `compressed_slice` above.
+        // ---- Resolve deltas --------------------------------------------------
+        // A base can follow its `OBJ_REF_DELTA`, so keep going over what's left
+        // until a pass resolves nothing.
+        let by_offset: HashMap<u64, usize> = raw
+            .iter()
+            .enumerate()
+            .map(|(n, (offset, ..))| (*offset, n))
+            .collect();
+        let mut by_id: HashMap<Vec<u8>, usize> = HashMap::new();
+        let mut resolved: Vec<Option<(u8, Vec<u8>)>> = vec![None; raw.len()];
+        // Bases from outside the pack, by id
+        let mut thin_bases: HashMap<Vec<u8>, (u8, Vec<u8>)> = HashMap::new();
+        let mut used_bases: Vec<RawObject> = Vec::new();
+        let mut objects: Vec<Option<(String, GitObject)>> = vec![None; raw.len()];
+        let mut remaining = raw.len();
+        while remaining > 0 {
+            let before = remaining;
+            for n in 0..raw.len() {
+                if resolved[n].is_some() {
+                    continue;
+                }
+                let (offset, _, header, content) = &raw[n];
+                let base = match &header.base {
+                    None => None,
+                    Some(DeltaBase::Offset(distance)) => {
+                        let base = offset
+                            .checked_sub(*distance)
+                            .and_then(|at| by_offset.get(&at))
+                            .ok_or_else(|| {
+                                GitError::PackCorrupt(format!(
+                                    "delta at offset {offset} has no base entry"
+                                ))
+                            })?;
+                        Some(*base)
+                    }
+                    Some(DeltaBase::Id(id)) => by_id.get(id).copied(),
+                };
+                let base = match (base, &header.base) {
+                    (Some(base), _) => match &resolved[base] {
+                        Some(resolved) => Some(resolved),
+                        None => continue,
+                    },
+                    (None, Some(DeltaBase::Id(id))) => match thin_bases.get(id) {
+                        Some(resolved) => Some(resolved),
+                        None => continue,
+                    },
+                    (None, _) => None,
+                };
+                let (obj_type, content) = match base {
+                    None => (header.obj_type, content.clone()),
+                    Some((base_type, base_content)) => (
+                        *base_type,
+                        apply_delta(base_content, content).map_err(|e| {
+                            GitError::PackCorrupt(format!("delta at offset {offset}: {e}"))
+                        })?,
+                    ),
+                };
+
+                // Turn it into a GitObject (no second inflate!), but hash
+                // the content as received: parsing doesn't keep every header
+                let object = Self::object_from_content(obj_type, &content, algo)?;
+                let raw_object = RawObject::new(obj_type, content);
+                let id = algo.digest(&raw_object.to_loose_format());
+                objects[n] = Some((hex::encode(&id), object));
+                by_id.insert(id, n);
+                resolved[n] = Some((obj_type, raw_object.content));
+                remaining -= 1;
+            }
+            if remaining == before {
+                // Stuck: fetch the bases nothing in the pack provides
+                let missing: Vec<Vec<u8>> = raw
+                    .iter()
+                    .zip(&resolved)
+                    .filter_map(
+                        |((_, _, header, _), resolved)| match (&header.base, resolved) {
+                            (Some(DeltaBase::Id(id)), None)
+                                if !by_id.contains_key(id) && !thin_bases.contains_key(id) =>
+                            {
+                                Some(id.clone())
+                            }
+                            _ => None,
+                        },
+                    )
+                    .collect();
+                let mut found = false;
+                for id in &missing {
+                    if thin_bases.contains_key(id) {
+                        continue;
+                    }
+                    if let Some(base) = external(&hex::encode(id)) {
+                        thin_bases.insert(id.clone(), (base.obj_type, base.content.clone()));
+                        used_bases.push(base);
+                        found = true;
+                    }
+                }
+                if !found {
+                    let missing = missing.first().map(hex::encode).unwrap_or_default();
+                    return Err(GitError::PackCorrupt(format!(
+                        "delta base {missing} is not in the pack"
+                    )));
+                }
+            }
         }
 
-        // ---- Optional: verify trailing SHA-1 ---------------------------------
-        // let expected = &data[cursor ..];
-        // …
+        let entries = raw
+            .into_iter()
+            .zip(objects.into_iter().zip(resolved))
+            .map(|((offset, crc32, _, _), (object, resolved))| {
+                let (id, object) = object.expect("every entry is resolved");
+                let (obj_type, content) = resolved.expect("every entry is resolved");
+                PackEntry {
+                    offset,
+                    crc32,
+                    id,
+                    raw: RawObject::new(obj_type, content),
+                    object,
+                }
+            })
+            .collect();
+        Ok((entries, used_bases))
+    }
 
-        Ok(objects)
+    /// Decode a single pack entry (header and compressed data), e.g. one read
+    /// from a stored pack by its offset. Deltas need their base, so they are
+    /// an error here.
+    pub fn read_entry(entry: &[u8], algo: HashAlgorithm) -> Result<GitObject, GitError> {
+        let header = Self::entry_header(entry, algo)?;
+        if header.base.is_some() {
+            return Err(GitError::PackCorrupt(
+                "delta entry read without its base".to_string(),
+            ));
+        }
+        let (content, _) = Self::inflate(&entry[header.data_start..], header.size)?;
+        Self::object_from_content(header.obj_type, &content, algo)
+    }
+
+    /// Decode an entry's type and size, and for deltas where the base is
+    pub fn entry_header(entry: &[u8], algo: HashAlgorithm) -> Result<EntryHeader, GitError> {
+        let (obj_type, size, mut data_start) =
+            Self::decode_pack_header(entry).map_err(GitError::PackCorrupt)?;
+        let truncated = || GitError::PackCorrupt("truncated delta base".to_string());
+
+        let base = match obj_type {
+            OBJ_OFS_DELTA => {
+                // Big-endian base-128, with one added at each continuation
+                let mut byte = *entry.get(data_start).ok_or_else(truncated)?;
+                let mut distance = (byte & 0x7f) as u64;
+                data_start += 1;
+                while byte & 0x80 != 0 {
+                    byte = *entry.get(data_start).ok_or_else(truncated)?;
+                    distance = ((distance + 1) << 7) | (byte & 0x7f) as u64;
+                    data_start += 1;
+                }
+                Some(DeltaBase::Offset(distance))
+            }
+            OBJ_REF_DELTA => {
+                let end = data_start + algo.raw_len();
+                let id = entry.get(data_start..end).ok_or_else(truncated)?;
+                data_start = end;
+                Some(DeltaBase::Id(id.to_vec()))
+            }
+            _ => None,
+        };
+
+        Ok(EntryHeader {
+            obj_type,
+            size,
+            base,
+            data_start,
+        })
+    }
+
+    /// Inflate an entry's compressed data, checking it against the size from
+    /// its header. Returns the data and how many compressed bytes it took.
+    pub fn inflate(compressed: &[u8], size: usize) -> Result<(Vec<u8>, usize), GitError> {
+        let mut decoder = ZlibDecoder::new(compressed);
+        let mut content = Vec::with_capacity(size);
+        decoder
+            .read_to_end(&mut content)
+            .map_err(|e| GitError::PackCorrupt(format!("Failed to decompress object: {e}")))?;
+        if content.len() != size {
+            return Err(GitError::PackCorrupt(format!(
+                "Size mismatch: header says {size}, zlib produced {}",
+                content.len()
+            )));
+        }
+        Ok((content, decoder.total_in() as usize))
+    }
+
+    /// Encode an entry from its header fields and already-compressed data
+    pub fn encode_entry(
+        obj_type: u8,
+        size: usize,
+        base: Option<&DeltaBase>,
+        compressed: &[u8],
+    ) -> Vec<u8> {
+        let mut entry = Vec::with_capacity(compressed.len() + 32);
+        Self::encode_pack_header(&mut entry, obj_type, size);
+        match base {
+            Some(DeltaBase::Offset(distance)) => {
+                let mut distance = *distance;
+                let mut bytes = vec![(distance & 0x7f) as u8];
+                distance >>= 7;
+                while distance > 0 {
+                    distance -= 1;
+                    bytes.push(0x80 | (distance & 0x7f) as u8);
+                    distance >>= 7;
+                }
+                entry.extend(bytes.iter().rev());
+            }
+            Some(DeltaBase::Id(id)) => entry.extend_from_slice(id),
+            None => {}
+        }
+        entry.extend_from_slice(compressed);
+        entry
+    }
+
+    /// Build an object from an inflated full (non-delta) entry
+    pub fn object_from_content(
+        obj_type: u8,
+        content: &[u8],
+        algo: HashAlgorithm,
+    ) -> Result<GitObject, GitError> {
+        Self::content_to_object(obj_type, content, algo).map_err(GitError::PackCorrupt)
+    }
+
+    fn content_to_object(
+        obj_type: u8,
+        content: &[u8],
+        algo: HashAlgorithm,
+    ) -> Result<GitObject, String> {
+        match obj_type {
+            1 => LooseObjectSerializer::deserialize_content("commit", content, algo),
+            2 => LooseObjectSerializer::deserialize_content("tree", content, algo),
+            3 => LooseObjectSerializer::deserialize_content("blob", content, algo),
+            4 => LooseObjectSerializer::deserialize_content("tag", content, algo),
+            OBJ_OFS_DELTA | OBJ_REF_DELTA => Err("Delta entry without its base".into()),
+            _ => Err(format!("Unknown pack object type: {obj_type}")),
+        }
     }
 
     /// Serialize object for pack file (with pack header + compressed content)
@@ -524,7 +939,11 @@ impl PackSerializer {
     }
 
     /// Deserialize object from pack format
-    pub fn deserialize_object(pack_type: u8, compressed_data: &[u8]) -> Result<GitObject, String> {
+    pub fn deserialize_object(
+        pack_type: u8,
+        compressed_data: &[u8],
+        algo: HashAlgorithm,
+    ) -> Result<GitObject, String> {
         // Decompress the data
         let mut decoder = ZlibDecoder::new(compressed_data);
         let mut content = Vec::new();
@@ -542,7 +961,7 @@ impl PackSerializer {
         };
 
         // Use the same content parser as loose objects - ENSURES CONSISTENCY!
-        LooseObjectSerializer::deserialize_content(obj_type, &content)
+        LooseObjectSerializer::deserialize_content(obj_type, &content, algo)
     }
 
     fn encode_pack_header(buf: &mut Vec<u8>, obj_type: u8, size: usize) {
@@ -608,7 +1027,8 @@ mod tests {
         };
 
         let serialized = LooseObjectSerializer::serialize(&original);
-        let deserialized = LooseObjectSerializer::deserialize(&serialized).unwrap();
+        let deserialized =
+            LooseObjectSerializer::deserialize(&serialized, HashAlgorithm::Sha1).unwrap();
 
         assert_eq!(original, deserialized);
     }
@@ -620,15 +1040,49 @@ mod tests {
         };
 
         // Hash should be consistent across serialization round-trips
-        let hash1 = obj.compute_hash();
+        let hash1 = obj.compute_hash(HashAlgorithm::Sha1);
 
         let loose_data = obj.to_loose_format();
-        let obj2 = GitObject::from_loose_format(&loose_data).unwrap();
-        let hash2 = obj2.compute_hash();
+        let obj2 = GitObject::from_loose_format(&loose_data, HashAlgorithm::Sha1).unwrap();
+        let hash2 = obj2.compute_hash(HashAlgorithm::Sha1);
 
         assert_eq!(hash1, hash2);
     }
 
+    #[test]
+    fn test_blob_ids_match_git() {
+        // `echo -n 'hello world' | git hash-object --stdin [--object-format=sha256]`
+        let obj = GitObject::Blob {
+            content: b"hello world".to_vec(),
+        };
+
+        assert_eq!(
+            obj.compute_hash(HashAlgorithm::Sha1),
+            "95d09f2b10159347eece71399a7e2e907ea3df4f"
+        );
+        assert_eq!(
+            obj.compute_hash(HashAlgorithm::Sha256),
+            "fee53a18d32820613c0527aa79be5cb30173c823a9b448fa4817767cc84c6f03"
+        );
+    }
+
+    #[test]
+    fn test_sha256_tree_round_trip() {
+        let original = GitObject::Tree {
+            entries: vec![TreeEntry::new(
+                "100644".to_string(),
+                "file.txt".to_string(),
+                "fee53a18d32820613c0527aa79be5cb30173c823a9b448fa4817767cc84c6f03".to_string(),
+            )],
+        };
+
+        let serialized = LooseObjectSerializer::serialize(&original);
+        let deserialized =
+            LooseObjectSerializer::deserialize(&serialized, HashAlgorithm::Sha256).unwrap();
+
+        assert_eq!(original, deserialized);
+    }
+
     #[test]
     fn test_content_serialization_consistency() {
         let obj = GitObject::Commit {
@@ -655,6 +1109,28 @@ mod tests {
         assert!(content_str.contains("\n\nInitial commit\n"));
     }
 
+    #[test]
+    fn test_commit_signatures() {
+        let commit = GitObject::Commit {
+            tree: "2b297e643c551e76cfa1f93810c50811382f9117".to_string(),
+            parents: vec![],
+            author: "colin <colinrozzi@gmail.com> 1754330635 -0400".to_string(),
+            committer: "CI Bot <ci@example.com> 1754330700 +0000".to_string(),
+            message: "Initial commit\n".to_string(),
+        };
+
+        let author = commit.author().unwrap().unwrap();
+        assert_eq!(author.email, "colinrozzi@gmail.com");
+        assert_eq!(author.tz_offset, -240);
+
+        let committer = commit.committer().unwrap().unwrap();
+        assert_eq!(committer.name, "CI Bot");
+        assert_eq!(committer.timestamp, 1754330700);
+
+        assert!(commit.tagger().is_none());
+        assert!(GitObject::Blob { content: vec![] }.author().is_none());
+    }
+
     #[test]
     fn test_tree_round_trip() {
         let original = GitObject::Tree {
@@ -673,8 +1149,128 @@ mod tests {
         };
 
         let serialized = LooseObjectSerializer::serialize(&original);
-        let deserialized = LooseObjectSerializer::deserialize(&serialized).unwrap();
+        let deserialized =
+            LooseObjectSerializer::deserialize(&serialized, HashAlgorithm::Sha1).unwrap();
 
         assert_eq!(original, deserialized);
     }
+
+    #[test]
+    fn test_parse_resolves_ofs_and_ref_deltas() {
+        use crate::git::delta::create_delta;
+        use crate::utils::compression::compress_zlib;
+
+        let algo = HashAlgorithm::Sha1;
+        let base: Vec<u8> = (0..200)
+            .flat_map(|i| format!("line {}\n", i).into_bytes())
+            .collect();
+        let mut second = base.clone();
+        second.extend_from_slice(b"line 200\n");
+        let mut third = second.clone();
+        third.extend_from_slice(b"line 201\n");
+        let blob = |content: &[u8]| GitObject::Blob {
+            content: content.to_vec(),
+        };
+        let full = |content: &[u8]| {
+            PackSerializer::encode_entry(3, content.len(), None, &compress_zlib(content))
+        };
+        let delta_entry = |from: &[u8], to: &[u8], base: DeltaBase| {
+            let delta = create_delta(from, to, to.len()).unwrap();
+            let obj_type = match base {
+                DeltaBase::Offset(_) => OBJ_OFS_DELTA,
+                DeltaBase::Id(_) => OBJ_REF_DELTA,
+            };
+            PackSerializer::encode_entry(obj_type, delta.len(), Some(&base), &compress_zlib(&delta))
+        };
+
+        // third: REF_DELTA on second, which comes later; second: OFS_DELTA on base
+        let second_id = hex::decode(blob(&second).compute_hash(algo)).unwrap();
+        let mut pack = b"PACK\0\0\0\x02\0\0\0\x03".to_vec();
+        pack.extend(delta_entry(&second, &third, DeltaBase::Id(second_id)));
+        let base_offset = pack.len() as u64;
+        pack.extend(full(&base));
+        let delta_offset = pack.len() as u64;
+        pack.extend(delta_entry(
+            &base,
+            &second,
+            DeltaBase::Offset(delta_offset - base_offset),
+        ));
+        pack.extend(algo.digest(&pack));
+
+        let entries = PackSerializer::parse_entries(&pack, algo).unwrap();
+        let objects: Vec<_> = entries.into_iter().map(|entry| entry.object).collect();
+        assert_eq!(objects, vec![blob(&third), blob(&base), blob(&second)]);
+
+        // Entry headers decode what was encoded
+        let header = PackSerializer::entry_header(&pack[delta_offset as usize..], algo).unwrap();
+        assert_eq!(
+            header.base,
+            Some(DeltaBase::Offset(delta_offset - base_offset))
+        );
+        for distance in [1, 127, 128, 16_511, 16_512, 1 << 40] {
+            let entry = PackSerializer::encode_entry(
+                OBJ_OFS_DELTA,
+                1,
+                Some(&DeltaBase::Offset(distance)),
+                &[],
+            );
+            let header = PackSerializer::entry_header(&entry, algo).unwrap();
+            assert_eq!(header.base, Some(DeltaBase::Offset(distance)));
+            assert_eq!(header.data_start, entry.len());
+        }
+
+        // A thin pack's base comes from the caller, and is an error without it
+        let base_id = hex::decode(blob(&base).compute_hash(algo)).unwrap();
+        let thin = {
+            let mut thin = b"PACK\0\0\0\x02\0\0\0\x01".to_vec();
+            thin.extend(delta_entry(&base, &second, DeltaBase::Id(base_id.clone())));
+            thin.extend(algo.digest(&thin));
+            thin
+        };
+        let (entries, bases) = PackSerializer::parse_thin_entries(&thin, algo, |id| {
+            (id == hex::encode(&base_id)).then(|| RawObject::from_object(&blob(&base)))
+        })
+        .unwrap();
+        assert_eq!(entries[0].object, blob(&second));
+        assert_eq!(bases, [RawObject::from_object(&blob(&base))]);
+        let err = PackSerializer::parse_entries(&thin, algo).unwrap_err();
+        assert!(err.to_string().contains(&hex::encode(&base_id)));
+    }
+
+    #[test]
+    fn test_ids_hash_headers_the_model_drops() {
+        // Made by real git: an annotated tag, a gpg-signed commit, a commit
+        // with an `encoding` header and a merge with a `mergetag` header
+        let fixture = |name: &str| {
+            std::fs::read(format!(
+                "{}/tests/fixtures/{}",
+                env!("CARGO_MANIFEST_DIR"),
+                name
+            ))
+            .unwrap()
+        };
+        let pack = fixture("signed.pack");
+        let ids = String::from_utf8(fixture("signed.ids")).unwrap();
+
+        let entries = PackSerializer::parse_entries(&pack, HashAlgorithm::Sha1).unwrap();
+        for line in ids.lines() {
+            let (name, id) = line.split_once(' ').unwrap();
+            let entry = entries.iter().find(|entry| entry.id == id);
+            let entry = entry.unwrap_or_else(|| panic!("{} {} is not in the pack", name, id));
+            assert_eq!(entry.raw.id(HashAlgorithm::Sha1), id);
+            // Re-encoding the parsed object would lose the header
+            assert_ne!(
+                entry.object.compute_hash(HashAlgorithm::Sha1),
+                id,
+                "{}",
+                name
+            );
+        }
+        for entry in &entries {
+            assert_eq!(
+                entry.raw.to_object(HashAlgorithm::Sha1).unwrap(),
+                entry.object
+            );
+        }
+    }
 }
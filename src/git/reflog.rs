@@ -0,0 +1,302 @@
+//! Ref history.
+//!
+//! Every ref change made by a push or a restore is appended to that ref's
+//! log: the old and new ids, who made the change, when, and why (the push
+//! options the client sent, or which entry was restored). A bad force-push
+//! is undone by restoring the value from before it, and garbage collection
+//! keeps everything an entry points at until the entry expires.
+
+use super::repository::{GitRepoState, RefChange};
+use crate::bindings::theater::simple::http_types::{HttpRequest, HttpResponse};
+use crate::error::GitError;
+use crate::host::{self, log};
+use crate::protocol::http::{create_response, query_param, request_identity};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Logged changes by ref name, oldest first. Deleted refs keep their log so
+/// they can be restored.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Reflog {
+    pub refs: BTreeMap<String, Vec<ReflogEntry>>,
+}
+
+/// One change to a ref
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReflogEntry {
+    /// Null id for a created ref
+    pub old_id: String,
+    /// Null id for a deleted ref
+    pub new_id: String,
+    pub identity: String,
+    pub time_ms: u64,
+    pub message: String,
+}
+
+/// Who is changing refs and why, recorded with each change
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefUpdateContext {
+    pub identity: String,
+    pub message: String,
+    /// The push options (`git push -o`) of a push, for hooks
+    pub push_options: Vec<String>,
+}
+
+impl RefUpdateContext {
+    pub fn new(identity: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            identity: identity.into(),
+            message: message.into(),
+            push_options: Vec::new(),
+        }
+    }
+
+    /// A push, described by its push options (`git push -o`) if it has any
+    pub fn push(identity: impl Into<String>, push_options: &[String]) -> Self {
+        let message = if push_options.is_empty() {
+            "push".to_string()
+        } else {
+            format!("push: {}", push_options.join(", "))
+        };
+        Self {
+            push_options: push_options.to_vec(),
+            ..Self::new(identity, message)
+        }
+    }
+}
+
+impl Reflog {
+    /// Entries for `ref_name`, oldest first
+    pub fn entries(&self, ref_name: &str) -> &[ReflogEntry] {
+        self.refs.get(ref_name).map_or(&[], Vec::as_slice)
+    }
+
+    /// Append an entry, dropping expired ones first
+    pub fn record(&mut self, ref_name: &str, entry: ReflogEntry, expire_secs: u64) {
+        self.expire(entry.time_ms, expire_secs);
+        self.refs
+            .entry(ref_name.to_string())
+            .or_default()
+            .push(entry);
+    }
+
+    /// Drop entries older than `expire_secs` (never, for 0), returning how
+    /// many were dropped
+    pub fn expire(&mut self, now_ms: u64, expire_secs: u64) -> usize {
+        if expire_secs == 0 {
+            return 0;
+        }
+        let expire_ms = expire_secs.saturating_mul(1000);
+        let mut expired = 0;
+        self.refs.retain(|_, entries| {
+            let before = entries.len();
+            entries.retain(|entry| now_ms.saturating_sub(entry.time_ms) < expire_ms);
+            expired += before - entries.len();
+            !entries.is_empty()
+        });
+        expired
+    }
+
+    /// Every id an entry points at, before or after its change
+    pub fn ids(&self) -> impl Iterator<Item = &String> {
+        self.refs
+            .values()
+            .flatten()
+            .flat_map(|entry| [&entry.old_id, &entry.new_id])
+    }
+}
+
+impl GitRepoState {
+    /// Log a ref change that was just applied
+    pub fn record_ref_update(
+        &mut self,
+        ref_name: &str,
+        old_id: &str,
+        new_id: &str,
+        context: &RefUpdateContext,
+    ) {
+        let entry = ReflogEntry {
+            old_id: old_id.to_string(),
+            new_id: new_id.to_string(),
+            identity: context.identity.clone(),
+            time_ms: host::now(),
+            message: context.message.clone(),
+        };
+        let expire_secs = self.config.reflog.expire_secs;
+        self.reflog.record(ref_name, entry, expire_secs);
+    }
+
+    /// Set `ref_name` back to the value it had `back` changes ago, like
+    /// `git reset <ref>@{back}`. The ref is deleted if it didn't exist then.
+    /// The restore is itself logged.
+    pub fn restore_ref(
+        &mut self,
+        ref_name: &str,
+        back: usize,
+        context: &RefUpdateContext,
+    ) -> Result<RefChange, GitError> {
+        let entries = self.reflog.entries(ref_name);
+        let target = match back.checked_sub(1) {
+            None => None,
+            Some(skip) => entries.iter().rev().nth(skip).map(|entry| &entry.old_id),
+        }
+        .cloned()
+        .ok_or_else(|| {
+            GitError::Protocol(format!(
+                "{}@{{{}}} is not in the reflog ({} entries)",
+                ref_name,
+                back,
+                entries.len()
+            ))
+        })?;
+
+        let null = self.object_format.null_oid();
+        let current = self.refs.get(ref_name).cloned().unwrap_or(null);
+        log(&format!(
+            "Restoring {} from {} to {} ({}@{{{}}})",
+            ref_name, current, target, ref_name, back
+        ));
+        self.apply_ref_update(ref_name, &current, &target, context)
+    }
+
+    /// Handle GET /admin/reflog[?ref=<name>] - One ref's log, or every ref's,
+    /// as JSON with the newest entry first (so index `n` is `<ref>@{n + 1}`'s
+    /// change)
+    pub fn admin_reflog(&self, request: &HttpRequest, query: &Option<String>) -> HttpResponse {
+        if request.method != "GET" {
+            return create_response(405, "text/plain", b"Method Not Allowed");
+        }
+
+        fn newest_first(entries: &[ReflogEntry]) -> Vec<&ReflogEntry> {
+            entries.iter().rev().collect()
+        }
+        let body = match query_param(query, "ref") {
+            Some(ref_name) => serde_json::to_vec(&newest_first(self.reflog.entries(ref_name))),
+            None => serde_json::to_vec(
+                &self
+                    .reflog
+                    .refs
+                    .iter()
+                    .map(|(name, entries)| (name, newest_first(entries)))
+                    .collect::<BTreeMap<_, _>>(),
+            ),
+        };
+        match body {
+            Ok(body) => create_response(200, "application/json", &body),
+            Err(e) => create_response(500, "text/plain", e.to_string().as_bytes()),
+        }
+    }
+
+    /// Handle POST /admin/reflog/restore?ref=<name>&at=<n> - Restore
+    /// `<ref>@{n}` and return the new reflog entry as JSON
+    pub fn admin_reflog_restore(
+        &mut self,
+        request: &HttpRequest,
+        query: &Option<String>,
+    ) -> HttpResponse {
+        if request.method != "POST" {
+            return create_response(405, "text/plain", b"Method Not Allowed");
+        }
+        let Some(ref_name) = query_param(query, "ref") else {
+            return create_response(400, "text/plain", b"missing ref parameter");
+        };
+        let back = match query_param(query, "at").unwrap_or("1").parse::<usize>() {
+            Ok(back) => back,
+            Err(_) => return create_response(400, "text/plain", b"invalid at parameter"),
+        };
+
+        let context = RefUpdateContext::new(
+            request_identity(request),
+            format!("restore: {}@{{{}}}", ref_name, back),
+        );
+        match self.restore_ref(ref_name, back, &context) {
+            Ok(_) => match serde_json::to_vec(&self.reflog.entries(ref_name).last()) {
+                Ok(body) => create_response(200, "application/json", &body),
+                Err(e) => create_response(500, "text/plain", e.to_string().as_bytes()),
+            },
+            Err(e) => {
+                log(&format!("Restoring {} failed: {}", ref_name, e));
+                create_response(e.http_status(), "text/plain", e.to_string().as_bytes())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::test_support::commit;
+
+    const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+    fn entry(time_ms: u64) -> ReflogEntry {
+        ReflogEntry {
+            old_id: "0".repeat(40),
+            new_id: "a".repeat(40),
+            identity: "alice".to_string(),
+            time_ms,
+            message: "push".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_entries_expire() {
+        let mut reflog = Reflog::default();
+        reflog.record("refs/heads/main", entry(DAY_MS), 0);
+        reflog.record("refs/heads/topic", entry(2 * DAY_MS), 0);
+        assert_eq!(reflog.entries("refs/heads/main").len(), 1);
+        assert_eq!(reflog.expire(100 * DAY_MS, 0), 0);
+
+        // Recording expires old entries, and drops refs left with none
+        reflog.record("refs/heads/main", entry(10 * DAY_MS), 8 * 24 * 60 * 60);
+        assert_eq!(reflog.entries("refs/heads/main"), &[entry(10 * DAY_MS)]);
+        assert!(!reflog.refs.contains_key("refs/heads/topic"));
+        assert_eq!(reflog.ids().count(), 2);
+    }
+
+    #[test]
+    fn test_push_context_describes_push_options() {
+        assert_eq!(RefUpdateContext::push("bob", &[]).message, "push");
+        let options = vec!["ci.skip".to_string(), "reason=hotfix".to_string()];
+        assert_eq!(
+            RefUpdateContext::push("bob", &options).message,
+            "push: ci.skip, reason=hotfix"
+        );
+    }
+
+    #[test]
+    fn test_restore_undoes_force_push_and_delete() {
+        let mut repo = GitRepoState::default();
+        let null = "0".repeat(40);
+        let good = commit(&mut repo, "good", 0, vec![]);
+        let bad = commit(&mut repo, "bad", 0, vec![]);
+        let alice = RefUpdateContext::push("alice", &[]);
+
+        let main = "refs/heads/main";
+        repo.update_refs_from_push(vec![(main.into(), null.clone(), good.clone())], &alice);
+        repo.update_refs_from_push(vec![(main.into(), good.clone(), bad.clone())], &alice);
+        assert_eq!(repo.reflog.entries(main).len(), 2);
+        assert_eq!(repo.reflog.entries(main)[1].old_id, good);
+
+        let admin = RefUpdateContext::new("admin", "restore");
+        assert_eq!(repo.restore_ref(main, 1, &admin), Ok(RefChange::Update));
+        assert_eq!(repo.refs[main], good);
+        let restore = repo.reflog.entries(main).last().unwrap();
+        assert_eq!(
+            (restore.old_id.as_str(), restore.identity.as_str()),
+            (bad.as_str(), "admin")
+        );
+
+        // Before the first push, main didn't exist
+        assert_eq!(repo.restore_ref(main, 3, &admin), Ok(RefChange::Delete));
+        assert!(!repo.refs.contains_key(main));
+        assert_eq!(repo.restore_ref(main, 1, &admin), Ok(RefChange::Create));
+        assert_eq!(repo.refs[main], good);
+
+        assert!(matches!(
+            repo.restore_ref(main, 9, &admin),
+            Err(GitError::Protocol(_))
+        ));
+        assert!(repo.restore_ref("refs/heads/none", 1, &admin).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+//! Ref names.
+//!
+//! The rules of `git check-ref-format`. A name is made of `/`-separated
+//! components, and:
+//!
+//! - no component is empty, begins with `.` or ends with `.lock`
+//! - there are at least two components
+//! - it doesn't end with `.` or contain `..` or `@{`, and isn't `@`
+//! - it has no control characters, spaces or any of `~ ^ : ? * [ \`
+//!
+//! Like `git receive-pack`, refs that clients update must also be under
+//! `refs/`, with the rules applied to the rest of the name (so `refs/foo`
+//! is refused as a one-level name).
+
+use crate::error::GitError;
+
+/// Check `name` against the `check-ref-format` rules, giving the first one
+/// it breaks
+pub fn check_ref_format(name: &str) -> Result<(), &'static str> {
+    if name == "@" {
+        return Err("is @");
+    }
+    if name.ends_with('.') {
+        return Err("ends with .");
+    }
+    if name.contains("..") {
+        return Err("contains ..");
+    }
+    if name.contains("@{") {
+        return Err("contains @{");
+    }
+    if name
+        .chars()
+        .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+    {
+        return Err("contains a forbidden character");
+    }
+
+    let mut components = 0;
+    for component in name.split('/') {
+        if component.is_empty() {
+            return Err("has an empty component");
+        }
+        if component.starts_with('.') {
+            return Err("has a component beginning with .");
+        }
+        if component.ends_with(".lock") {
+            return Err("has a component ending with .lock");
+        }
+        components += 1;
+    }
+    if components < 2 {
+        return Err("has only one level");
+    }
+    Ok(())
+}
+
+/// Check a ref a client wants to create, update or delete
+pub fn check_ref_name(name: &str) -> Result<(), GitError> {
+    let invalid = |reason: &str| GitError::InvalidRefName {
+        ref_name: name.to_string(),
+        reason: reason.to_string(),
+    };
+    match name.strip_prefix("refs/") {
+        Some(rest) => check_ref_format(rest).map_err(invalid),
+        None => Err(invalid("is not under refs/")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_names() {
+        for name in [
+            "refs/heads/main",
+            "refs/heads/feature/x-1",
+            "refs/tags/v1.0",
+            "refs/remotes/origin/HEAD",
+            "refs/heads/caf\u{e9}",
+            "refs/heads/a@b",
+            "refs/heads/@",
+            "refs/heads/x.lockfile",
+        ] {
+            assert_eq!(check_ref_name(name), Ok(()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_funny_names() {
+        for (name, reason) in [
+            ("HEAD", "is not under refs/"),
+            ("heads/main", "is not under refs/"),
+            ("refs/main", "has only one level"),
+            ("refs/heads/../x", "contains .."),
+            ("refs/heads/a..b", "contains .."),
+            ("refs/heads/x@{1}", "contains @{"),
+            ("refs/heads/x.lock", "has a component ending with .lock"),
+            ("refs/heads/x.lock/y", "has a component ending with .lock"),
+            ("refs/heads/.hidden", "has a component beginning with ."),
+            ("refs/heads/x.", "ends with ."),
+            ("refs/heads//x", "has an empty component"),
+            ("refs/heads/x/", "has an empty component"),
+            ("refs/heads/a b", "contains a forbidden character"),
+            ("refs/heads/a\tb", "contains a forbidden character"),
+            ("refs/heads/a\x7fb", "contains a forbidden character"),
+            ("refs/heads/a~1", "contains a forbidden character"),
+            ("refs/heads/a^", "contains a forbidden character"),
+            ("refs/heads/a:b", "contains a forbidden character"),
+            ("refs/heads/a?", "contains a forbidden character"),
+            ("refs/heads/a*", "contains a forbidden character"),
+            ("refs/heads/a[b", "contains a forbidden character"),
+            ("refs/heads/a\\b", "contains a forbidden character"),
+        ] {
+            match check_ref_name(name) {
+                Err(GitError::InvalidRefName { reason: actual, .. }) => {
+                    assert_eq!(actual, reason, "{:?}", name)
+                }
+                other => panic!("{:?} was accepted: {:?}", name, other),
+            }
+        }
+        assert_eq!(check_ref_format("@"), Err("is @"));
+    }
+}
@@ -0,0 +1,391 @@
+//! Repacking into delta-compressed packs.
+//!
+//! A repack rewrites every stored object into a single pack where blobs and
+//! trees are stored as deltas against similar objects, like
+//! `git repack -a -d -f`. Objects are ordered the way git orders delta
+//! candidates: by type, then by the path they were found at while walking
+//! history from the refs, newest first, so revisions of the same file (or
+//! directory) end up next to each other. Each object is tried against the
+//! `window` objects before it and stored as the smallest delta found, unless
+//! its base is already `depth` deltas deep.
+//!
+//! Bases always come first, so deltas are written as `OBJ_OFS_DELTA`.
+//! Unreachable objects are kept (whole); pruning them is garbage collection's
+//! job.
+
+use super::delta::DeltaIndex;
+use super::objects::{DeltaBase, GitObject, PackSerializer, OBJ_OFS_DELTA};
+use super::repository::GitRepoState;
+use crate::bindings::theater::simple::http_types::{HttpRequest, HttpResponse};
+use crate::error::GitError;
+use crate::host::log;
+use crate::protocol::http::create_response;
+use crate::utils::compression::compress_zlib;
+use flate2::Crc;
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
+
+/// Pack object types that are deltified
+const OBJ_TREE: u8 = 2;
+const OBJ_BLOB: u8 = 3;
+
+/// What a repack did
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RepackReport {
+    pub window: usize,
+    pub depth: usize,
+    pub objects: usize,
+    /// Objects stored as deltas
+    pub deltas: usize,
+    /// Packs and loose objects replaced by the new pack
+    pub packs_before: usize,
+    pub loose_before: usize,
+    /// Stored bytes before and after: packs plus compressed loose objects
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+/// An object waiting to be written, with where it was first found
+struct Candidate {
+    id: String,
+    path: String,
+    obj_type: u8,
+    content: Vec<u8>,
+}
+
+impl GitRepoState {
+    /// Rewrite every object into one pack, deltifying blobs and trees (see
+    /// the module docs)
+    pub fn repack(&mut self, window: usize, depth: usize) -> Result<RepackReport, GitError> {
+        let algo = self.object_format;
+        let packs_before = self.objects.packs().len();
+        let loose_before = self.objects.loose_len();
+        let size_before = self.objects.stored_size()?;
+
+        let mut candidates = Vec::new();
+        for (id, path) in self.repack_order() {
+            let raw = self
+                .objects
+                .get_raw(&id)
+                .ok_or_else(|| GitError::MissingObject(id.clone()))?;
+            candidates.push(Candidate {
+                obj_type: raw.obj_type,
+                content: raw.content,
+                id,
+                path,
+            });
+        }
+        // Stable, so each path's revisions stay newest first
+        candidates.sort_by(|a, b| (a.obj_type, &a.path).cmp(&(b.obj_type, &b.path)));
+
+        let mut report = RepackReport {
+            window,
+            depth,
+            objects: candidates.len(),
+            deltas: 0,
+            packs_before,
+            loose_before,
+            size_before,
+            size_after: size_before,
+        };
+        if candidates.is_empty() {
+            return Ok(report);
+        }
+
+        let mut data = b"PACK".to_vec();
+        data.extend_from_slice(&2u32.to_be_bytes());
+        data.extend_from_slice(&(candidates.len() as u32).to_be_bytes());
+        let mut index_entries = Vec::with_capacity(candidates.len());
+        let mut offsets = Vec::with_capacity(candidates.len());
+        // Deltas between each object and a whole one (0: stored whole)
+        let mut depths = Vec::with_capacity(candidates.len());
+        // Indexes of the last `window` objects, most recent last
+        let mut recent: VecDeque<(usize, DeltaIndex)> = VecDeque::with_capacity(window);
+
+        for (n, candidate) in candidates.iter().enumerate() {
+            let mut best: Option<(usize, Vec<u8>)> = None;
+            if matches!(candidate.obj_type, OBJ_TREE | OBJ_BLOB) {
+                // A delta has to save at least half the object to be worth it
+                let mut max_size = (candidate.content.len() / 2).saturating_sub(20);
+                for (base, index) in recent.iter().rev() {
+                    if candidates[*base].obj_type != candidate.obj_type || depths[*base] >= depth {
+                        continue;
+                    }
+                    if let Some(delta) = index.delta(&candidate.content, max_size) {
+                        max_size = delta.len().saturating_sub(1);
+                        best = Some((*base, delta));
+                    }
+                }
+            }
+
+            let offset = data.len() as u64;
+            let entry = match &best {
+                Some((base, delta)) => PackSerializer::encode_entry(
+                    OBJ_OFS_DELTA,
+                    delta.len(),
+                    Some(&DeltaBase::Offset(offset - offsets[*base])),
+                    &compress_zlib(delta),
+                ),
+                None => PackSerializer::encode_entry(
+                    candidate.obj_type,
+                    candidate.content.len(),
+                    None,
+                    &compress_zlib(&candidate.content),
+                ),
+            };
+            let mut crc = Crc::new();
+            crc.update(&entry);
+            let raw_id =
+                hex::decode(&candidate.id).map_err(|e| GitError::PackCorrupt(e.to_string()))?;
+            index_entries.push((raw_id, offset, crc.sum()));
+            offsets.push(offset);
+            data.extend_from_slice(&entry);
+
+            match best {
+                Some((base, _)) => {
+                    depths.push(depths[base] + 1);
+                    report.deltas += 1;
+                }
+                None => depths.push(0),
+            }
+            if window > 0 && depth > 0 {
+                if recent.len() == window {
+                    recent.pop_front();
+                }
+                recent.push_back((n, DeltaIndex::new(&candidate.content)));
+            }
+        }
+        let checksum = algo.digest(&data);
+        data.extend_from_slice(&checksum);
+
+        self.objects.replace_with_pack(&data, index_entries, algo)?;
+        report.size_after = self.objects.stored_size()?;
+        log(&format!(
+            "Repacked {} objects ({} deltas): {} -> {} bytes",
+            report.objects, report.deltas, report.size_before, report.size_after
+        ));
+        Ok(report)
+    }
+
+    /// Handle POST /admin/repack[?window=N&depth=N] - Repack now and report
+    /// the result as JSON
+    pub fn admin_repack(&mut self, request: &HttpRequest, query: &Option<String>) -> HttpResponse {
+        if request.method != "POST" {
+            return create_response(405, "text/plain", b"Method Not Allowed");
+        }
+
+        let mut window = self.config.repack.window;
+        let mut depth = self.config.repack.depth;
+        for param in query.as_deref().unwrap_or_default().split('&') {
+            let (target, value) = match param.split_once('=') {
+                Some(("window", value)) => (&mut window, value),
+                Some(("depth", value)) => (&mut depth, value),
+                _ => continue,
+            };
+            match value.parse() {
+                Ok(value) => *target = value,
+                Err(_) => {
+                    let message = format!("invalid repack parameter `{}`", param);
+                    return create_response(400, "text/plain", message.as_bytes());
+                }
+            }
+        }
+
+        match self.repack(window, depth) {
+            Ok(report) => match serde_json::to_vec(&report) {
+                Ok(body) => create_response(200, "application/json", &body),
+                Err(e) => create_response(500, "text/plain", e.to_string().as_bytes()),
+            },
+            Err(e) => {
+                log(&format!("Repack failed: {}", e));
+                create_response(e.http_status(), "text/plain", e.to_string().as_bytes())
+            }
+        }
+    }
+
+    /// Every object with the path it was first found at, walking history
+    /// from the refs newest first; objects no ref reaches come last
+    fn repack_order(&self) -> Vec<(String, String)> {
+        let mut order = Vec::new();
+        let mut seen = HashSet::new();
+
+        let mut tips: Vec<(&String, &String)> = self.refs.iter().collect();
+        tips.sort();
+        let mut commits: VecDeque<String> = tips.into_iter().map(|(_, id)| id.clone()).collect();
+        while let Some(id) = commits.pop_front() {
+            if !seen.insert(id.clone()) {
+                continue;
+            }
+            match self.objects.get(&id) {
+                Some(GitObject::Commit { tree, parents, .. }) => {
+                    order.push((id, String::new()));
+                    self.walk_tree(tree, String::new(), &mut seen, &mut order);
+                    commits.extend(parents);
+                }
+                Some(GitObject::Tag { object, .. }) => {
+                    order.push((id, String::new()));
+                    commits.push_back(object);
+                }
+                Some(GitObject::Tree { .. }) => {
+                    seen.remove(&id);
+                    self.walk_tree(id, String::new(), &mut seen, &mut order);
+                }
+                Some(GitObject::Blob { .. }) => order.push((id, String::new())),
+                None => {}
+            }
+        }
+
+        for id in self.objects.ids() {
+            if !seen.contains(&id) {
+                order.push((id, String::new()));
+            }
+        }
+        order
+    }
+
+    fn walk_tree(
+        &self,
+        id: String,
+        path: String,
+        seen: &mut HashSet<String>,
+        order: &mut Vec<(String, String)>,
+    ) {
+        if !seen.insert(id.clone()) {
+            return;
+        }
+        let Some(GitObject::Tree { entries }) = self.objects.get(&id) else {
+            return;
+        };
+        order.push((id, path.clone()));
+        for entry in entries {
+            let entry_path = if path.is_empty() {
+                entry.name
+            } else {
+                format!("{}/{}", path, entry.name)
+            };
+            match entry.mode.as_str() {
+                "40000" | "040000" => self.walk_tree(entry.hash, entry_path, seen, order),
+                // Submodule commits live in another repository
+                "160000" => {}
+                _ => {
+                    if seen.insert(entry.hash.clone()) {
+                        order.push((entry.hash, entry_path));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::objects::TreeEntry;
+    use crate::utils::hash::HashAlgorithm;
+
+    fn add(repo: &mut GitRepoState, object: GitObject) -> String {
+        let id = object.compute_hash(HashAlgorithm::Sha1);
+        repo.add_object(id.clone(), object).unwrap();
+        id
+    }
+
+    /// `revisions` commits, each appending a line to a large file
+    fn history(repo: &mut GitRepoState, revisions: usize) -> Vec<String> {
+        let mut content: String = (0..500).map(|i| format!("original line {}\n", i)).collect();
+        let mut parents = vec![];
+        let mut commits = vec![];
+        for n in 0..revisions {
+            content.push_str(&format!("change {}\n", n));
+            let blob = add(
+                repo,
+                GitObject::Blob {
+                    content: content.clone().into_bytes(),
+                },
+            );
+            let readme = add(
+                repo,
+                GitObject::Blob {
+                    content: format!("readme {}\n", n).into_bytes(),
+                },
+            );
+            let tree = add(
+                repo,
+                GitObject::Tree {
+                    entries: vec![
+                        TreeEntry::new("100644".into(), "README".into(), readme),
+                        TreeEntry::new("100644".into(), "big.txt".into(), blob),
+                    ],
+                },
+            );
+            let commit = add(
+                repo,
+                GitObject::Commit {
+                    tree,
+                    parents,
+                    author: "A <a@example.com> 0 +0000".into(),
+                    committer: "A <a@example.com> 0 +0000".into(),
+                    message: format!("revision {}\n", n),
+                },
+            );
+            parents = vec![commit.clone()];
+            commits.push(commit);
+        }
+        repo.update_ref("refs/heads/main".into(), commits[revisions - 1].clone())
+            .unwrap();
+        commits
+    }
+
+    #[test]
+    fn test_repack_deltifies_revisions() {
+        let mut repo = GitRepoState::default();
+        history(&mut repo, 20);
+        let before: Vec<_> = repo
+            .objects
+            .ids()
+            .into_iter()
+            .map(|id| (id.clone(), repo.objects.get(&id).unwrap()))
+            .collect();
+
+        let report = repo.repack(10, 50).unwrap();
+        assert_eq!(report.objects, 80);
+        assert_eq!(report.loose_before, 80);
+        assert!(report.deltas >= 19, "{} deltas", report.deltas);
+        assert!(report.size_after * 3 < report.size_before, "{:?}", report);
+
+        // Every object reads back the same from the new pack
+        assert!(repo.objects.loose().is_empty());
+        assert_eq!(repo.objects.packs().len(), 1);
+        for (id, object) in before {
+            assert_eq!(repo.objects.get(&id), Some(object));
+        }
+        assert!(repo.validate().is_empty());
+
+        // Repacking again (with stored deltas as input) gives the same pack
+        let pack = repo.objects.packs();
+        repo.repack(10, 50).unwrap();
+        assert_eq!(repo.objects.packs(), pack);
+    }
+
+    #[test]
+    fn test_depth_limits_delta_chains() {
+        let mut repo = GitRepoState::default();
+        history(&mut repo, 12);
+        assert_eq!(repo.repack(1, 3).unwrap().deltas, 9);
+        assert_eq!(repo.repack(10, 0).unwrap().deltas, 0);
+    }
+
+    #[test]
+    fn test_unreachable_objects_are_kept() {
+        let mut repo = GitRepoState::default();
+        history(&mut repo, 2);
+        let orphan = add(
+            &mut repo,
+            GitObject::Blob {
+                content: b"orphan".to_vec(),
+            },
+        );
+        let report = repo.repack(10, 50).unwrap();
+        assert_eq!(report.objects, 9);
+        assert!(repo.objects.contains_key(&orphan));
+    }
+}
@@ -1,15 +1,81 @@
-use super::objects::{GitObject, PackSerializer};
+use super::bare::BareRepository;
+use super::events::RepoEvent;
+use super::gc::GcState;
+use super::namespace::Namespace;
+use super::object_store::ObjectStore;
+use super::objects::GitObject;
+use super::reflog::{RefUpdateContext, Reflog};
+use super::refname::check_ref_name;
+use super::webhooks::WebhookState;
 use crate::bindings::theater::simple::http_types::{HttpRequest, HttpResponse};
-use crate::bindings::theater::simple::runtime::log;
+use crate::config::Config;
+use crate::error::GitError;
+use crate::host::{self, log};
 use crate::protocol::command_request::{parse_command_request, CommandRequest};
 use crate::protocol::http::{
-    create_error_response, create_response, create_status_response,
-    create_status_response_with_capabilities, encode_flush_pkt, encode_pkt_line,
-    encode_sideband_data, not_found, CAPABILITIES, MAX_SIDEBAND_DATA,
+    create_error_response, create_response, create_status_response_with_capabilities,
+    create_status_response_with_messages, create_unpack_error_response, decode_request_body,
+    is_v2_request, not_found, request_identity, requested_protocol_version, sideband_frame_size,
+    smart_content_type, CAPABILITIES, UPLOAD_PACK_CAPABILITIES,
 };
-use crate::protocol::push_request::{parse_receive_pack_request, PushRequest};
+use crate::protocol::pktline::{PktLineWriter, BAND_DATA};
+use crate::protocol::push_request::{parse_push_command, parse_receive_pack_request, PushRequest};
+use crate::protocol::upload_request::{parse_upload_pack_request, UploadRequest};
+use crate::utils::compression::compress_zlib;
+use crate::utils::hash::HashAlgorithm;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+thread_local! {
+    // What each bare repository (by path) held when its state was last
+    // opened or saved, so requests don't read every ref and object again
+    static BARE_SNAPSHOTS: RefCell<HashMap<String, BareSnapshot>> = RefCell::new(HashMap::new());
+}
+
+/// Refs, HEAD and objects of a bare repository, as `open_bare` reads them
+#[derive(Clone)]
+struct BareSnapshot {
+    refs: HashMap<String, String>,
+    objects: ObjectStore,
+    head: String,
+    symrefs: BTreeMap<String, String>,
+    object_format: HashAlgorithm,
+}
+
+/// What a successful ref update did
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RefChange {
+    Create,
+    Update,
+    Delete,
+}
+
+/// Outcome of one ref update within a push
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefUpdateStatus {
+    pub ref_name: String,
+    pub result: Result<RefChange, GitError>,
+}
+
+impl RefUpdateStatus {
+    /// The report-status line for this ref (`ok <ref>` / `ng <ref> <reason>`)
+    pub fn report_line(&self) -> String {
+        match &self.result {
+            Ok(_) => format!("ok {}", self.ref_name),
+            Err(e) => format!("ng {} {}", self.ref_name, e.ng_reason()),
+        }
+    }
+}
+
+/// Outcome of a push whose pack was accepted
+#[derive(Debug, Clone, PartialEq)]
+pub struct PushOutcome {
+    /// One status per ref update, in the order the client sent them
+    pub statuses: Vec<RefUpdateStatus>,
+    /// Lines from hooks to show the pusher
+    pub messages: Vec<String>,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GitRepoState {
@@ -18,28 +84,170 @@ pub struct GitRepoState {
     // Git references (branches/tags) -> commit hash
     pub refs: HashMap<String, String>,
 
-    // Git objects: hash -> object data
-    pub objects: HashMap<String, GitObject>,
+    // Git objects: stored packs plus loose objects
+    pub objects: ObjectStore,
 
     // HEAD reference (usually "refs/heads/main")
     pub head: String,
+
+    // Symbolic refs other than HEAD -> the ref they point to
+    #[serde(default)]
+    pub symrefs: BTreeMap<String, String>,
+
+    // Object id algorithm (sha1 or sha256), fixed for the repository's lifetime
+    pub object_format: HashAlgorithm,
+
+    // Server settings (agent, limits, feature toggles) in effect for requests
+    pub config: Config,
+
+    // Bare repository on the host filesystem holding refs, HEAD and objects,
+    // when `config.repo_path` is set
+    #[serde(skip)]
+    pub bare: Option<BareRepository>,
+
+    // Recent writes and the last collection time, for garbage collection
+    #[serde(skip)]
+    pub gc: GcState,
+
+    // History of every ref change made by pushes and restores
+    #[serde(skip)]
+    pub reflog: Reflog,
+
+    // Queued webhook deliveries and the log of sent ones
+    #[serde(skip)]
+    pub webhooks: WebhookState,
+
+    // Events not yet streamed to `/events` subscribers
+    #[serde(skip)]
+    pub events: Vec<RepoEvent>,
 }
 
 impl Default for GitRepoState {
     fn default() -> Self {
+        Self::from_config(Config::default())
+    }
+}
+
+impl GitRepoState {
+    /// Create an empty repository from the configured name, default branch and object format
+    pub fn from_config(config: Config) -> Self {
         // Start with completely empty repository
         Self {
-            repo_name: "git-server".to_string(),
-            refs: HashMap::new(),    // No refs initially
-            objects: HashMap::new(), // No objects initially
-            head: "refs/heads/main".to_string(),
+            repo_name: config.repo_name.clone(),
+            refs: HashMap::new(),            // No refs initially
+            objects: ObjectStore::default(), // No objects initially
+            head: config.default_branch.clone(),
+            symrefs: BTreeMap::new(),
+            object_format: config.object_format,
+            config,
+            bare: None,
+            gc: GcState::default(),
+            reflog: Reflog::default(),
+            webhooks: WebhookState::default(),
+            events: Vec::new(),
         }
     }
-}
 
-impl GitRepoState {
+    /// Open the bare repository at `config.repo_path`, initializing it first
+    /// if it doesn't exist yet. Refs, HEAD and the object format come from the
+    /// repository on disk the first time; after that they come from what was
+    /// last saved (see `remember_bare`), so the server must be the only writer.
+    pub fn open_bare(config: Config) -> Result<Self, GitError> {
+        let path = config
+            .repo_path
+            .as_deref()
+            .ok_or_else(|| GitError::Storage("no repository path configured".to_string()))?;
+        host::prepare_binary_writes(config.shell_writes)
+            .map_err(|e| GitError::Storage(format!("can't write to {}: {}", path, e)))?;
+        let bare = BareRepository::new(path);
+        let cached = BARE_SNAPSHOTS.with(|snapshots| snapshots.borrow().get(bare.root()).cloned());
+        let snapshot = match cached {
+            Some(snapshot) => snapshot,
+            None => Self::read_bare(&bare, &config)?,
+        };
+
+        let repo = Self {
+            repo_name: config.repo_name.clone(),
+            refs: snapshot.refs,
+            objects: snapshot.objects,
+            head: snapshot.head,
+            symrefs: snapshot.symrefs,
+            object_format: snapshot.object_format,
+            config,
+            bare: Some(bare),
+            gc: GcState::default(),
+            reflog: Reflog::default(),
+            webhooks: WebhookState::default(),
+            events: Vec::new(),
+        };
+        repo.remember_bare();
+        Ok(repo)
+    }
+
+    /// Read a bare repository's refs, HEAD and objects from disk
+    fn read_bare(bare: &BareRepository, config: &Config) -> Result<BareSnapshot, GitError> {
+        if !bare.exists()? {
+            bare.init(&config.default_branch, config.object_format)?;
+        }
+
+        let object_format = bare.object_format()?;
+        if object_format != config.object_format {
+            log(&format!(
+                "Repository at {} uses {}, not the configured {}",
+                bare.root(),
+                object_format.name(),
+                config.object_format.name()
+            ));
+        }
+
+        Ok(BareSnapshot {
+            refs: bare.read_refs(object_format)?,
+            objects: ObjectStore::open_bare(bare.clone(), object_format)?,
+            head: bare.read_head()?,
+            symrefs: bare.read_symrefs()?,
+            object_format,
+        })
+    }
+
+    /// Keep this bare repository's refs, HEAD and objects for the next
+    /// `open_bare`; they are already on disk
+    pub fn remember_bare(&self) {
+        let Some(bare) = &self.bare else {
+            return;
+        };
+        let snapshot = BareSnapshot {
+            refs: self.refs.clone(),
+            objects: self.objects.clone(),
+            head: self.head.clone(),
+            symrefs: self.symrefs.clone(),
+            object_format: self.object_format,
+        };
+        BARE_SNAPSHOTS.with(|snapshots| {
+            snapshots
+                .borrow_mut()
+                .insert(bare.root().to_string(), snapshot)
+        });
+    }
+
+    /// Create an empty repository using the given object format
+    pub fn with_object_format(object_format: HashAlgorithm) -> Self {
+        Self {
+            object_format,
+            ..Self::default()
+        }
+    }
+
+    /// Capabilities advertised for receive-pack, including our agent and object format
+    fn receive_pack_capabilities(&self) -> String {
+        format!(
+            "{} agent={} object-format={}",
+            CAPABILITIES,
+            self.config.agent,
+            self.object_format.name()
+        )
+    }
+
     pub fn dispatch(&mut self, request: &HttpRequest) -> HttpResponse {
-        // Route based on path - Protocol v2 only!
         // Parse the URI to get path and query
         let uri_parts: Vec<&str> = request.uri.splitn(2, '?').collect();
         let path = uri_parts[0];
@@ -49,13 +257,77 @@ impl GitRepoState {
             None
         };
 
+        let max_body_bytes = self.config.limits.max_body_bytes;
+        if let Some(body) = &request.body {
+            if body.len() as u64 > max_body_bytes {
+                let error = GitError::LimitExceeded(format!(
+                    "request body of {} bytes exceeds maximum of {}",
+                    body.len(),
+                    max_body_bytes
+                ));
+                return create_error_response(&error, smart_content_type(path, &query));
+            }
+        }
+
+        // Inflate gzip/deflate bodies before any pkt-line parsing
+        let decoded;
+        let request = match decode_request_body(request, max_body_bytes) {
+            Ok(Some(inflated)) => {
+                decoded = inflated;
+                &decoded
+            }
+            Ok(None) => request,
+            Err(e) => return create_error_response(&e, smart_content_type(path, &query)),
+        };
+
+        // Route based on path
+
+        let features = &self.config.features;
+        match path {
+            "/" if features.debug_routes => self.debug_home(),
+            "/refs" if features.debug_routes => self.debug_refs(),
+            "/objects" if features.debug_routes => self.debug_objects(),
+            "/info/refs" => self.advertise_refs(request, &query, &Namespace::default()),
+            "/git-upload-pack" => self.upload_pack(request, &Namespace::default()),
+            "/git-receive-pack" => self.receive_pack(request, &Namespace::default()),
+            // Dumb HTTP protocol (read-only static files)
+            "/HEAD" if features.dumb_http => self.dumb_head(),
+            "/objects/info/packs" if features.dumb_http => self.dumb_info_packs(),
+            "/admin/gc" if features.admin_routes => self.admin_gc(request, &query),
+            "/admin/repack" if features.admin_routes => self.admin_repack(request, &query),
+            "/admin/reflog" if features.admin_routes => self.admin_reflog(request, &query),
+            "/admin/reflog/restore" if features.admin_routes => {
+                self.admin_reflog_restore(request, &query)
+            }
+            "/admin/symref" if features.admin_routes => self.admin_symref(request, &query),
+            "/admin/webhooks/deliveries" if features.admin_routes => {
+                self.admin_webhook_deliveries(request, &query)
+            }
+            _ if features.namespaces && path.starts_with("/ns/") => {
+                self.dispatch_namespace(request, path, &query)
+            }
+            _ => match path.strip_prefix("/objects/") {
+                Some(object_path) if features.dumb_http => self.dumb_loose_object(object_path),
+                _ => not_found(),
+            },
+        }
+    }
+
+    /// Route a smart HTTP request under `/ns/<name>/` to that ref namespace
+    fn dispatch_namespace(
+        &mut self,
+        request: &HttpRequest,
+        path: &str,
+        query: &Option<String>,
+    ) -> HttpResponse {
+        let (namespace, path) = match Namespace::from_path(path) {
+            Ok(split) => split,
+            Err(e) => return create_error_response(&e, smart_content_type(path, query)),
+        };
         match path {
-            "/" => self.debug_home(),
-            "/refs" => self.debug_refs(),
-            "/objects" => self.debug_objects(),
-            "/info/refs" => self.advertise_refs(request, &query),
-            "/git-upload-pack" => self.upload_pack(request),
-            "/git-receive-pack" => self.receive_pack(request),
+            "/info/refs" => self.advertise_refs(request, query, &namespace),
+            "/git-upload-pack" => self.upload_pack(request, &namespace),
+            "/git-receive-pack" => self.receive_pack(request, &namespace),
             _ => not_found(),
         }
     }
@@ -70,205 +342,731 @@ impl GitRepoState {
              <p>Objects: {}</p>\
              </body></html>",
             self.repo_name,
-            self.refs.len(),
+            self.visible_refs().len(),
             self.objects.len()
         );
         create_response(200, "text/html", body.as_bytes())
     }
 
-    /// Handle GET /refs - Debug refs
+    /// Handle GET /refs - Debug refs, leaving out hidden ones
     pub fn debug_refs(&self) -> HttpResponse {
         // Modern refs debug endpoint
         let mut refs_info = String::new();
 
-        for (ref_name, hash) in &self.refs {
+        for (ref_name, hash) in self.visible_refs() {
             refs_info.push_str(&format!("{} : {}\n", ref_name, hash));
         }
 
         create_response(200, "text/plain", refs_info.as_bytes())
     }
 
-    /// Handle GET /objects - Debug objects
+    /// Handle GET /objects - Debug objects: those clients may fetch
     pub fn debug_objects(&self) -> HttpResponse {
         // Modern objects debug endpoint
         let mut objects_info = String::new();
 
-        for (hash, obj) in &self.objects {
-            objects_info.push_str(&format!("{} : {}\n", hash, obj.object_type()));
+        let visible = self.visible_objects();
+        for hash in self.objects.ids() {
+            if visible
+                .as_ref()
+                .is_some_and(|visible| !visible.contains(&hash))
+            {
+                continue;
+            }
+            if let Some(obj) = self.objects.get(&hash) {
+                objects_info.push_str(&format!("{} : {}\n", hash, obj.object_type()));
+            }
         }
 
         create_response(200, "text/plain", objects_info.as_bytes())
     }
 
+    /// Handle GET /info/refs without `service=` - Dumb protocol ref list
+    pub fn dumb_info_refs(&self) -> HttpResponse {
+        let mut body = String::new();
+        for (ref_name, hash) in self.advertised_refs(&Namespace::default()) {
+            body.push_str(&format!("{}\t{}\n", hash, ref_name));
+
+            // Annotated tags are followed by the object they peel to
+            if let Some(peeled) = self.peel_tag(hash) {
+                body.push_str(&format!("{}\t{}^{{}}\n", peeled, ref_name));
+            }
+        }
+
+        create_response(200, "text/plain", body.as_bytes())
+    }
+
+    /// Handle GET /HEAD - Dumb protocol symbolic HEAD
+    pub fn dumb_head(&self) -> HttpResponse {
+        create_response(
+            200,
+            "text/plain",
+            format!("ref: {}\n", self.head).as_bytes(),
+        )
+    }
+
+    /// Handle GET /objects/info/packs - objects are only served loose, so no packs are listed
+    pub fn dumb_info_packs(&self) -> HttpResponse {
+        create_response(200, "text/plain", b"")
+    }
+
+    /// Handle GET /objects/xx/yyyy... - Dumb protocol loose object, if a
+    /// fetch could reach it from the advertised refs
+    pub fn dumb_loose_object(&self, object_path: &str) -> HttpResponse {
+        let oid = match object_path.split_once('/') {
+            Some((dir, file)) if dir.len() == 2 => format!("{}{}", dir, file),
+            _ => return not_found(),
+        };
+        if oid.len() != self.object_format.hex_len()
+            || !oid
+                .bytes()
+                .all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase())
+        {
+            return not_found();
+        }
+        if self
+            .fetchable_objects(&Namespace::default())
+            .is_some_and(|fetchable| !fetchable.contains(&oid))
+        {
+            log(&format!(
+                "Refusing dumb object {}: not reachable from a ref",
+                oid
+            ));
+            return not_found();
+        }
+
+        // The stored bytes, which are what `oid` hashes
+        match self.objects.get_raw(&oid) {
+            Some(raw) => create_response(
+                200,
+                "application/x-git-loose-object",
+                &compress_zlib(&raw.to_loose_format()),
+            ),
+            None => not_found(),
+        }
+    }
+
+    /// The non-tag object an annotated tag ultimately points to (`None` if `oid` isn't a tag)
+    pub fn peel_tag(&self, oid: &str) -> Option<String> {
+        let mut current = match self.objects.get(oid) {
+            Some(GitObject::Tag { object, .. }) => object,
+            _ => return None,
+        };
+        while let Some(GitObject::Tag { object, .. }) = self.objects.get(&current) {
+            current = object;
+        }
+        Some(current)
+    }
+
     pub fn advertise_refs(
         &mut self,
         request: &HttpRequest,
         query: &Option<String>,
+        namespace: &Namespace,
     ) -> HttpResponse {
         log("Handling advertise refs request");
 
-        // Extract service from query parameters
-        let service = match query {
-            Some(q) => {
-                if let Some(service) = q.strip_prefix("service=") {
-                    service.to_string()
-                } else {
-                    log("No service specified in query, returning error");
-                    return create_error_response("Missing service parameter");
-                }
+        // Extract service from query parameters; without one the client speaks the dumb protocol
+        let service = match query.as_deref().and_then(|q| q.strip_prefix("service=")) {
+            Some(service) => service.to_string(),
+            None if self.config.features.dumb_http && namespace.is_default() => {
+                log("No service specified in query, serving dumb info/refs");
+                return self.dumb_info_refs();
             }
             None => {
-                log("No query parameters found, returning error");
-                return create_error_response("Missing service parameter");
+                log("No service specified in query, returning error");
+                return create_error_response(
+                    &GitError::Protocol("Missing service parameter".to_string()),
+                    None,
+                );
             }
         };
 
-        self.handle_smart_info_refs(&service)
+        self.handle_smart_info_refs(&service, requested_protocol_version(request), namespace)
     }
 
     /// Handle GET /info/refs - Support both Protocol v1 and v2
-    pub fn handle_smart_info_refs(&mut self, service: &str) -> HttpResponse {
+    pub fn handle_smart_info_refs(
+        &mut self,
+        service: &str,
+        version: u32,
+        namespace: &Namespace,
+    ) -> HttpResponse {
         log(&format!(
-            "Processing info/refs request for service: {}",
-            service
+            "Processing info/refs request for service: {} (protocol version {})",
+            service, version
         ));
 
         match service {
-            "git-upload-pack" => {
+            "git-upload-pack" if version >= 2 => {
                 // Upload-pack supports Protocol v2
                 self.handle_upload_pack_info_refs()
             }
+            "git-upload-pack" => {
+                // Clients that didn't ask for v2 get a classic ref advertisement
+                self.handle_upload_pack_info_refs_v1(version, namespace)
+            }
+            "git-receive-pack" if !self.config.features.receive_pack => create_error_response(
+                &push_disabled(),
+                Some("application/x-git-receive-pack-advertisement"),
+            ),
+            "git-receive-pack" if version >= 2 => self.handle_receive_pack_info_refs_v2(),
             "git-receive-pack" => {
                 // Receive-pack falls back to Protocol v1 for compatibility
-                self.handle_receive_pack_info_refs_v1()
+                self.handle_receive_pack_info_refs_v1(namespace)
             }
-            _ => create_error_response("Unknown service"),
+            _ => create_error_response(
+                &GitError::Protocol(format!("Unknown service: {}", service)),
+                None,
+            ),
         }
     }
 
     /// Protocol v2 capability advertisement for upload-pack (fetch operations)
     fn handle_upload_pack_info_refs(&self) -> HttpResponse {
-        log("Generating Protocol v2 capability advertisement for upload-pack");
+        match self.upload_pack_v2_capabilities() {
+            Ok(data) => create_response(200, "application/x-git-upload-pack-advertisement", &data),
+            Err(e) => {
+                create_error_response(&e, Some("application/x-git-upload-pack-advertisement"))
+            }
+        }
+    }
+
+    /// Protocol v0/v1 ref advertisement for upload-pack (fetch from non-v2 clients)
+    fn handle_upload_pack_info_refs_v1(&self, version: u32, namespace: &Namespace) -> HttpResponse {
+        let build = || -> Result<Vec<u8>, GitError> {
+            let mut writer = PktLineWriter::new();
+            writer.write_line("# service=git-upload-pack")?;
+            writer.flush(); // flush-pkt after banner
+
+            let mut data = writer.into_bytes();
+            data.extend(self.upload_pack_ref_advertisement(version, namespace)?);
+            Ok(data)
+        };
 
-        let mut response_data = Vec::new();
+        match build() {
+            Ok(data) => create_response(200, "application/x-git-upload-pack-advertisement", &data),
+            Err(e) => {
+                create_error_response(&e, Some("application/x-git-upload-pack-advertisement"))
+            }
+        }
+    }
+
+    // ------------------------------------------------------------------------
+    // Upload-pack responses shared by every transport (smart HTTP, git://)
+    // ------------------------------------------------------------------------
+
+    /// Protocol v2 capability advertisement for upload-pack
+    pub fn upload_pack_v2_capabilities(&self) -> Result<Vec<u8>, GitError> {
+        log("Generating Protocol v2 capability advertisement for upload-pack");
+        let mut writer = PktLineWriter::new();
 
         // Protocol v2 format for upload-pack
-        response_data.extend(encode_pkt_line(b"version 2\n"));
-        response_data.extend(encode_pkt_line(b"agent=git-server/0.1.0\n"));
-        response_data.extend(encode_pkt_line(b"object-format=sha1\n"));
-        response_data.extend(encode_pkt_line(b"server-option\n"));
-        response_data.extend(encode_pkt_line(b"ls-refs=symrefs peel ref-prefix unborn\n"));
-        response_data.extend(encode_pkt_line(
-            b"fetch=shallow thin-pack no-progress include-tag ofs-delta wait-for-done\n",
+        writer.write_line("version 2")?;
+        writer.write_line(&format!("agent={}", self.config.agent))?;
+        writer.write_line(&format!("object-format={}", self.object_format.name()))?;
+        writer.write_line("server-option")?;
+        writer.write_line("ls-refs=symrefs peel ref-prefix unborn")?;
+        writer.write_line(
+            "fetch=shallow thin-pack no-progress include-tag ofs-delta wait-for-done",
+        )?;
+        writer.write_line("object-info=size")?;
+        writer.flush();
+        Ok(writer.into_bytes())
+    }
+
+    /// Protocol v0/v1 ref advertisement for upload-pack (without the smart HTTP
+    /// banner) of the refs in `namespace`
+    pub fn upload_pack_ref_advertisement(
+        &self,
+        version: u32,
+        namespace: &Namespace,
+    ) -> Result<Vec<u8>, GitError> {
+        log("Generating Protocol v0/v1 ref advertisement for upload-pack");
+        let mut writer = PktLineWriter::new();
+
+        // v1 announces itself; v0 has no version line
+        if version == 1 {
+            writer.write_line("version 1")?;
+        }
+
+        let mut capabilities = format!(
+            "{} agent={} object-format={}",
+            UPLOAD_PACK_CAPABILITIES,
+            self.config.agent,
+            self.object_format.name()
+        );
+
+        // HEAD first (when it resolves), then every ref in sorted order
+        let mut advertised = Vec::new();
+        if let Some(hash) = self.advertised_head(namespace) {
+            capabilities.push_str(&format!(" symref=HEAD:{}", self.namespace_head(namespace)));
+            advertised.push((hash, "HEAD"));
+        }
+        advertised.extend(
+            self.advertised_refs(namespace)
+                .into_iter()
+                .map(|(name, hash)| (hash, name)),
+        );
+
+        if advertised.is_empty() {
+            // Empty repository - advertise capabilities on the null ref
+            writer.write_line(&format!(
+                "{} capabilities^{{}}\0{}",
+                self.object_format.null_oid(),
+                capabilities
+            ))?;
+        }
+        for (i, (hash, ref_name)) in advertised.into_iter().enumerate() {
+            if i == 0 {
+                // First ref includes capabilities
+                writer.write_line(&format!("{} {}\0{}", hash, ref_name, capabilities))?;
+            } else {
+                writer.write_line(&format!("{} {}", hash, ref_name))?;
+            }
+        }
+
+        writer.flush();
+        Ok(writer.into_bytes())
+    }
+
+    /// Response to one Protocol v2 upload-pack command on the refs in `namespace`
+    pub fn upload_pack_command(
+        &self,
+        request: &CommandRequest,
+        namespace: &Namespace,
+    ) -> Result<Vec<u8>, GitError> {
+        match request.command.as_str() {
+            "ls-refs" => self.ls_refs_response(request, namespace),
+            "fetch" => self.fetch_response(request, namespace),
+            "object-info" => Err(GitError::Protocol(
+                "object-info not implemented yet".to_string(),
+            )),
+            _ => Err(GitError::Protocol(format!(
+                "Unknown command: {}",
+                request.command
+            ))),
+        }
+    }
+
+    /// `ls-refs`: HEAD first, then every ref in sorted order, narrowed by
+    /// `ref-prefix` arguments. `symrefs` adds the targets of symbolic refs,
+    /// `peel` what annotated tags point to, and `unborn` lists HEAD even when
+    /// its branch doesn't exist yet.
+    fn ls_refs_response(
+        &self,
+        request: &CommandRequest,
+        namespace: &Namespace,
+    ) -> Result<Vec<u8>, GitError> {
+        log("Handling ls-refs command");
+        let has_arg = |name: &str| request.args.iter().any(|arg| arg == name);
+        let (symrefs, peel, unborn) = (has_arg("symrefs"), has_arg("peel"), has_arg("unborn"));
+        let prefixes: Vec<&str> = request
+            .args
+            .iter()
+            .filter_map(|arg| arg.strip_prefix("ref-prefix "))
+            .collect();
+        let wanted = |name: &str| {
+            prefixes.is_empty() || prefixes.iter().any(|prefix| name.starts_with(prefix))
+        };
+
+        let mut writer = PktLineWriter::new();
+        let mut write_ref = |id: &str, name: &str| -> Result<(), GitError> {
+            let mut line = format!("{} {}", id, name);
+            if symrefs {
+                let target = match name {
+                    "HEAD" => Some(self.namespace_head(namespace)),
+                    _ => self.namespace_symref_target(namespace, name),
+                };
+                if let Some(target) = target {
+                    line.push_str(&format!(" symref-target:{}", target));
+                }
+            }
+            if peel {
+                if let Some(peeled) = self.peel_tag(id) {
+                    line.push_str(&format!(" peeled:{}", peeled));
+                }
+            }
+            writer.write_line(&line)
+        };
+
+        if wanted("HEAD") && !self.is_hidden_ref(namespace, "HEAD") {
+            match self.namespace_resolve(namespace, "HEAD") {
+                Some(id) => write_ref(id, "HEAD")?,
+                None if unborn => {
+                    log("HEAD is unborn");
+                    write_ref("unborn", "HEAD")?
+                }
+                None => {}
+            }
+        }
+        for (name, id) in self.advertised_refs(namespace) {
+            if wanted(name) {
+                write_ref(id, name)?;
+            }
+        }
+
+        writer.flush();
+        Ok(writer.into_bytes())
+    }
+
+    fn fetch_response(
+        &self,
+        request: &CommandRequest,
+        namespace: &Namespace,
+    ) -> Result<Vec<u8>, GitError> {
+        log("Handling Protocol v2 fetch command");
+
+        // Parse want lines from request args
+        let mut wants = Vec::new();
+        let mut has_done = false;
+
+        for arg in &request.args {
+            if let Some(stripped) = arg.strip_prefix("want ") {
+                wants.push(stripped.to_string()); // Remove "want " prefix
+                log(&format!("Client wants: {}", stripped));
+            } else if arg == "done" {
+                has_done = true;
+                log("Client sent 'done' - negotiation finished, skipping acknowledgments");
+            }
+        }
+
+        if wants.is_empty() {
+            log("Error: No wants specified in fetch request");
+            return Err(GitError::Protocol("No wants specified".to_string()));
+        }
+
+        log(&format!(
+            "Fetch request: wants={}, done={}",
+            wants.len(),
+            has_done
         ));
-        response_data.extend(encode_pkt_line(b"object-info=size\n"));
-        response_data.extend(encode_flush_pkt());
+        self.check_wants(&wants, namespace)?;
 
-        create_response(
-            200,
-            "application/x-git-upload-pack-advertisement",
-            &response_data,
-        )
+        // Generate packfile for wanted objects
+        let packfile = self.generate_packfile_for_wants(&wants)?;
+        log(&format!("Generated packfile: {} bytes", packfile.len()));
+
+        let mut writer = PktLineWriter::new();
+
+        // Acknowledgments section (only when negotiation isn't finished)
+        if !has_done {
+            writer.write_line("acknowledgments")?;
+            writer.write_line("NAK")?; // or real ACK/ready lines
+            writer.delim(); // next section
+        }
+
+        // Packfile section: header, then the pack side-band-encoded on band 1
+        writer.write_line("packfile")?;
+        writer.write_sideband(BAND_DATA, &packfile);
+        writer.flush(); // end of response
+
+        let response = writer.into_bytes();
+        log(&format!("Total response size: {} bytes", response.len()));
+        Ok(response)
+    }
+
+    /// One v0/v1 negotiation round: ACK/NAK the haves, and send the pack once
+    /// the client says `done`
+    pub fn upload_pack_v1_response(
+        &self,
+        request: &UploadRequest,
+        namespace: &Namespace,
+    ) -> Result<Vec<u8>, GitError> {
+        log("Handling Protocol v1 fetch");
+
+        if request.wants.is_empty() {
+            // Client is already up to date
+            return Ok(Vec::new());
+        }
+        self.check_wants(&request.wants, namespace)?;
+
+        let has_cap = |name: &str| request.capabilities.iter().any(|cap| cap == name);
+        let multi_ack_detailed = has_cap("multi_ack_detailed");
+        let multi_ack = multi_ack_detailed || has_cap("multi_ack");
+
+        let mut writer = PktLineWriter::new();
+        let mut common: Vec<String> = Vec::new();
+        let mut got_other = false;
+
+        // Negotiation can stop once every want reaches a commit the client
+        // has. Each want's ancestry is walked once, and dropped from
+        // `unreached` when a common commit turns up in it.
+        let mut unreached: Vec<HashSet<String>> = if multi_ack {
+            request
+                .wants
+                .iter()
+                .map(|want| self.commit_ancestry([want.as_str()]))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        for have in &request.haves {
+            if self.objects.contains_key(have) {
+                common.push(have.clone());
+                unreached.retain(|ancestry| !ancestry.contains(have));
+                if multi_ack_detailed {
+                    writer.write_line(&format!("ACK {} common", have))?;
+                } else if multi_ack {
+                    writer.write_line(&format!("ACK {} continue", have))?;
+                } else if common.len() == 1 {
+                    writer.write_line(&format!("ACK {}", have))?;
+                }
+            } else {
+                got_other = true;
+                if multi_ack && unreached.is_empty() {
+                    let status = if multi_ack_detailed {
+                        "ready"
+                    } else {
+                        "continue"
+                    };
+                    writer.write_line(&format!("ACK {} {}", have, status))?;
+                }
+            }
+        }
+
+        if !request.done {
+            // End of this negotiation round; the client will send more haves or done
+            if let Some(last) = common.last() {
+                if multi_ack_detailed && !got_other && unreached.is_empty() {
+                    writer.write_line(&format!("ACK {} ready", last))?;
+                }
+            }
+            if common.is_empty() || multi_ack {
+                writer.write_line("NAK")?;
+            }
+            return Ok(writer.into_bytes());
+        }
+
+        match common.last() {
+            Some(last) if multi_ack => writer.write_line(&format!("ACK {}", last))?,
+            Some(_) => {} // single-ack mode already acknowledged the first common commit
+            None => writer.write_line("NAK")?,
+        }
+
+        // Everything the wants need, minus what the client already has
+        let already_have: HashSet<String> = self
+            .collect_objects_for_wants(&common)?
+            .into_iter()
+            .collect();
+        let to_send: Vec<String> = self
+            .collect_objects_for_wants(&request.wants)?
+            .into_iter()
+            .filter(|oid| !already_have.contains(oid))
+            .collect();
+        let packfile = self.generate_simple_packfile(&to_send)?;
+        log(&format!("Generated packfile: {} bytes", packfile.len()));
+
+        match sideband_frame_size(&request.capabilities) {
+            Some(frame_size) => {
+                writer.write_sideband_frames(BAND_DATA, &packfile, frame_size);
+                writer.flush();
+                Ok(writer.into_bytes())
+            }
+            None => {
+                let mut response = writer.into_bytes();
+                response.extend_from_slice(&packfile);
+                Ok(response)
+            }
+        }
+    }
+
+    /// Protocol v2 capability advertisement for receive-pack: refs are discovered
+    /// with `ls-refs`, then updated with the `push` command. Protocol v2 has no
+    /// `push`; it is our own extension (see `parse_push_command`).
+    fn handle_receive_pack_info_refs_v2(&self) -> HttpResponse {
+        log("Generating Protocol v2 capability advertisement for receive-pack");
+
+        let build = || -> Result<Vec<u8>, GitError> {
+            let mut writer = PktLineWriter::new();
+            writer.write_line("version 2")?;
+            writer.write_line(&format!("agent={}", self.config.agent))?;
+            writer.write_line(&format!("object-format={}", self.object_format.name()))?;
+            writer.write_line("ls-refs=symrefs peel ref-prefix unborn")?;
+            writer.write_line(
+                "push=report-status delete-refs ofs-delta side-band-64k push-options",
+            )?;
+            writer.flush();
+            Ok(writer.into_bytes())
+        };
+
+        match build() {
+            Ok(data) => create_response(200, "application/x-git-receive-pack-advertisement", &data),
+            Err(e) => {
+                create_error_response(&e, Some("application/x-git-receive-pack-advertisement"))
+            }
+        }
     }
 
     /// Protocol v1 capability advertisement for receive-pack (push operations)
-    fn handle_receive_pack_info_refs_v1(&mut self) -> HttpResponse {
+    fn handle_receive_pack_info_refs_v1(&mut self, namespace: &Namespace) -> HttpResponse {
         log(
             "Generating Protocol v1 capability advertisement for receive-pack (push compatibility)",
         );
 
-        let mut response_data = Vec::new();
-
-        //
-        // 1. Smart-HTTP banner
-        //
-        let banner = b"# service=git-receive-pack\n";
-        response_data.extend(encode_pkt_line(banner));
-        response_data.extend(encode_flush_pkt()); // flush-pkt after banner
-
-        // Protocol v1 format - advertise refs first, then capabilities
-        if self.refs.is_empty() {
-            // Empty repository - advertise capabilities on the null ref
-            let line = format!(
-                "0000000000000000000000000000000000000000 capabilities^{{}}\0{}\n",
-                CAPABILITIES
-            );
-            response_data.extend(encode_pkt_line(line.as_bytes()));
-        } else {
-            // Advertise existing refs with capabilities on the first ref
-            let mut refs: Vec<_> = self.refs.iter().collect();
-            refs.sort_by_key(|(name, _)| *name);
-
-            let mut first_ref = true;
-            for (ref_name, hash) in refs {
-                if first_ref {
-                    // First ref includes capabilities
-                    let line = format!("{} {}\0{}\n", hash, ref_name, CAPABILITIES);
-                    response_data.extend(encode_pkt_line(line.as_bytes()));
-                    first_ref = false;
-                } else {
-                    let line = format!("{} {}\n", hash, ref_name);
-                    response_data.extend(encode_pkt_line(line.as_bytes()));
+        let build = || -> Result<Vec<u8>, GitError> {
+            let mut writer = PktLineWriter::new();
+
+            //
+            // 1. Smart-HTTP banner
+            //
+            writer.write_line("# service=git-receive-pack")?;
+            writer.flush(); // flush-pkt after banner
+
+            // Protocol v1 format - advertise refs first, then capabilities
+            let capabilities = self.receive_pack_capabilities();
+            let mut refs: Vec<(&str, &String)> = self
+                .refs
+                .iter()
+                .filter_map(|(name, hash)| Some((namespace.strip(name)?, hash)))
+                .filter(|(name, _)| !self.is_hidden_ref(namespace, name))
+                .collect();
+            if refs.is_empty() {
+                // Empty repository - advertise capabilities on the null ref
+                writer.write_line(&format!(
+                    "{} capabilities^{{}}\0{}",
+                    self.object_format.null_oid(),
+                    capabilities
+                ))?;
+            } else {
+                // Advertise existing refs with capabilities on the first ref
+                refs.sort_by_key(|(name, _)| *name);
+
+                let mut first_ref = true;
+                for (ref_name, hash) in refs {
+                    if first_ref {
+                        // First ref includes capabilities
+                        writer.write_line(&format!("{} {}\0{}", hash, ref_name, capabilities))?;
+                        first_ref = false;
+                    } else {
+                        writer.write_line(&format!("{} {}", hash, ref_name))?;
+                    }
                 }
             }
-        }
 
-        response_data.extend(encode_flush_pkt());
+            writer.flush();
+            Ok(writer.into_bytes())
+        };
 
-        log("returning response");
-        log(&String::from_utf8(response_data.clone()).unwrap());
-        create_response(
-            200,
-            "application/x-git-receive-pack-advertisement",
-            &response_data,
-        )
+        match build() {
+            Ok(data) => {
+                log("returning response");
+                log(&String::from_utf8_lossy(&data));
+                create_response(200, "application/x-git-receive-pack-advertisement", &data)
+            }
+            Err(e) => {
+                create_error_response(&e, Some("application/x-git-receive-pack-advertisement"))
+            }
+        }
     }
 
-    pub fn receive_pack(&mut self, request: &HttpRequest) -> HttpResponse {
+    pub fn receive_pack(&mut self, request: &HttpRequest, namespace: &Namespace) -> HttpResponse {
         log("handle_receive_pack");
 
+        if !self.config.features.receive_pack {
+            return create_error_response(
+                &push_disabled(),
+                Some("application/x-git-receive-pack-result"),
+            );
+        }
+
         let body = match &request.body {
             Some(b) => b,
             None => {
                 log("missing request body, returning with a status response");
-                return create_status_response(false, vec!["unpack missing-request".to_string()]);
+                return create_unpack_error_response(
+                    &GitError::Protocol("missing request body".to_string()),
+                    &[],
+                    &[],
+                );
             }
         };
 
+        let identity = request_identity(request);
+        if is_v2_request(request, body) {
+            return self.receive_pack_v2(body, &identity, namespace);
+        }
+
         log("body found, parsing request");
         match parse_receive_pack_request(body) {
-            Ok(push) => self.handle_v1_push(push),
+            Ok(push) => self.handle_push(push, &identity, namespace),
             Err(e) => {
                 // For parse errors, we don't have capabilities yet, so use basic response
-                create_status_response(false, vec![format!("unpack {}", e)])
+                create_unpack_error_response(&e, &[], &[])
             }
         }
     }
 
-    pub fn upload_pack(&mut self, request: &HttpRequest) -> HttpResponse {
+    /// Protocol v2 receive-pack: `ls-refs` for ref discovery, then `push`
+    fn receive_pack_v2(
+        &mut self,
+        body: &[u8],
+        identity: &str,
+        namespace: &Namespace,
+    ) -> HttpResponse {
+        log("Processing Protocol v2 receive-pack request");
+
+        let parsed = match parse_command_request(body) {
+            Ok(req) => req,
+            Err(e) => {
+                return create_error_response(&e, Some("application/x-git-receive-pack-result"))
+            }
+        };
+
+        match parsed.command.as_str() {
+            "ls-refs" => self.handle_ls_refs(&parsed, namespace),
+            "push" => match parse_push_command(&parsed) {
+                Ok(push) => self.handle_push(push, identity, namespace),
+                Err(e) => create_unpack_error_response(&e, &[], &parsed.capabilities),
+            },
+            _ => create_error_response(
+                &GitError::Protocol(format!("Unknown command: {}", parsed.command)),
+                Some("application/x-git-receive-pack-result"),
+            ),
+        }
+    }
+
+    pub fn upload_pack(&mut self, request: &HttpRequest, namespace: &Namespace) -> HttpResponse {
         log("handle_upload_pack");
 
-        log("Processing Protocol v2 upload-pack request");
         let body = match &request.body {
             Some(b) => b,
-            None => return create_error_response("Missing request body"),
+            None => {
+                return create_error_response(
+                    &GitError::Protocol("Missing request body".to_string()),
+                    Some("application/x-git-upload-pack-result"),
+                )
+            }
         };
 
-        let parsed = match parse_command_request(body) {
-            Ok(req) => req,
-            Err(e) => return create_error_response(&e),
+        // v2 requests start with a command; anything else is v0/v1 want/have negotiation
+        let response = if is_v2_request(request, body) {
+            log("Processing Protocol v2 upload-pack request");
+            parse_command_request(body)
+                .and_then(|parsed| self.upload_pack_command(&parsed, namespace))
+        } else {
+            log("Processing Protocol v1 upload-pack request");
+            parse_upload_pack_request(body)
+                .and_then(|upload| self.upload_pack_v1_response(&upload, namespace))
         };
 
-        match parsed.command.as_str() {
-            "ls-refs" => self.handle_ls_refs(&parsed),
-            "fetch" => self.handle_fetch(&parsed),
-            "object-info" => self.handle_object_info(&parsed),
-            _ => create_error_response(&format!("Unknown command: {}", parsed.command)),
+        match response {
+            Ok(data) => create_response(200, "application/x-git-upload-pack-result", &data),
+            Err(e) => {
+                log(&format!("Upload-pack failed: {}", e));
+                create_error_response(&e, Some("application/x-git-upload-pack-result"))
+            }
         }
     }
 
-    fn handle_v1_push(&mut self, push: PushRequest) -> HttpResponse {
-        log("=== DEBUGGING V1 PUSH OPERATION ===");
+    fn handle_push(
+        &mut self,
+        push: PushRequest,
+        identity: &str,
+        namespace: &Namespace,
+    ) -> HttpResponse {
+        log("=== DEBUGGING PUSH OPERATION ===");
         log(&format!("Push has {} ref updates", push.ref_updates.len()));
         log(&format!(
             "Push has {} bytes of pack data",
@@ -276,6 +1074,27 @@ impl GitRepoState {
         ));
         log(&format!("Push capabilities: {:?}", push.capabilities));
 
+        // Clients announce their object format; refuse a mismatched repository
+        // up front rather than failing on truncated tree ids later.
+        if let Some(format) = push
+            .capabilities
+            .iter()
+            .find_map(|cap| cap.strip_prefix("object-format="))
+        {
+            if HashAlgorithm::from_name(format) != Some(self.object_format) {
+                let error = GitError::Protocol(format!(
+                    "object-format mismatch: client uses {}, repository uses {}",
+                    format,
+                    self.object_format.name()
+                ));
+                return create_unpack_error_response(
+                    &error,
+                    &Self::ref_names(&push.ref_updates),
+                    &push.capabilities,
+                );
+            }
+        }
+
         for (i, (ref_name, old_oid, new_oid)) in push.ref_updates.iter().enumerate() {
             log(&format!(
                 "Ref update {}: {} {} -> {}",
@@ -295,137 +1114,58 @@ impl GitRepoState {
         }
 
         if push.ref_updates.is_empty() && push.pack_data.is_empty() {
-            return create_status_response_with_capabilities(true, vec![], &push.capabilities);
+            return create_status_response_with_capabilities(Ok(()), vec![], &push.capabilities);
         }
 
         log("Starting process_push_operation...");
-        match self.process_push_operation(&push.pack_data, push.ref_updates) {
-            Ok(statuses) => {
-                log("Push operation successful, processing statuses");
-                let ref_statuses: Vec<String> = statuses
+        let ref_names = Self::ref_names(&push.ref_updates);
+        let context = RefUpdateContext::push(identity, &push.push_options);
+        let ref_updates = push
+            .ref_updates
+            .into_iter()
+            .map(|(name, old_oid, new_oid)| (namespace.full_name(&name), old_oid, new_oid))
+            .collect();
+        match self.process_push_operation(&push.pack_data, ref_updates, &context, namespace) {
+            Ok(mut outcome) => {
+                log("Push operation processed, reporting per-ref statuses");
+                // Reported by the names the client sent
+                for (status, name) in outcome.statuses.iter_mut().zip(&ref_names) {
+                    status.ref_name = name.clone();
+                }
+                let ref_statuses: Vec<String> = outcome
+                    .statuses
                     .iter()
-                    .map(|status| {
-                        if let Some(stripped) = status.strip_prefix("create ") {
-                            format!("ok {}", stripped)
-                        } else if let Some(stripped) = status.strip_prefix("update ") {
-                            format!("ok {}", stripped)
-                        } else {
-                            status.clone()
-                        }
-                    })
+                    .map(RefUpdateStatus::report_line)
                     .collect();
-                create_status_response_with_capabilities(true, ref_statuses, &push.capabilities)
+                create_status_response_with_messages(
+                    Ok(()),
+                    ref_statuses,
+                    &push.capabilities,
+                    &outcome.messages,
+                )
             }
             Err(e) => {
                 log(&format!("❌ Push operation failed with error: {}", e));
-                create_status_response_with_capabilities(
-                    false,
-                    vec![format!("unpack {}", e)],
-                    &push.capabilities,
-                )
+                create_unpack_error_response(&e, &ref_names, &push.capabilities)
             }
         }
     }
 
-    fn handle_ls_refs(&self, _request: &CommandRequest) -> HttpResponse {
-        log("Handling ls-refs command");
-        let mut response = Vec::new();
-
-        if self.refs.is_empty() {
-            log("Empty repository - showing unborn HEAD");
-            response.extend(encode_pkt_line(
-                "unborn HEAD symref-target:refs/heads/main\n".as_bytes(),
-            ));
-        } else {
-            let mut refs: Vec<_> = self.refs.iter().collect();
-            refs.sort_by_key(|(name, _)| *name);
-
-            for (ref_name, hash) in refs {
-                let line = format!("{} {}\n", hash, ref_name);
-                response.extend(encode_pkt_line(line.as_bytes()));
-            }
-        }
-
-        response.extend(encode_flush_pkt());
-        create_response(200, "application/x-git-upload-pack-result", &response)
+    fn ref_names(ref_updates: &[(String, String, String)]) -> Vec<String> {
+        ref_updates
+            .iter()
+            .map(|(name, _, _)| name.clone())
+            .collect()
     }
 
-    fn handle_fetch(&self, request: &CommandRequest) -> HttpResponse {
-        log("Handling Protocol v2 fetch command");
-
-        // Parse want lines from request args
-        let mut wants = Vec::new();
-        let mut has_done = false;
-
-        for arg in &request.args {
-            if let Some(stripped) = arg.strip_prefix("want ") {
-                wants.push(stripped.to_string()); // Remove "want " prefix
-                log(&format!("Client wants: {}", stripped));
-            } else if arg == "done" {
-                has_done = true;
-                log("Client sent 'done' - negotiation finished, skipping acknowledgments");
-            }
-        }
-
-        if wants.is_empty() {
-            log("Error: No wants specified in fetch request");
-            return create_error_response("No wants specified");
-        }
-
-        log(&format!(
-            "Fetch request: wants={}, done={}",
-            wants.len(),
-            has_done
-        ));
-
-        // Generate packfile for wanted objects
-        match self.generate_packfile_for_wants(&wants) {
-            Ok(packfile) => {
-                log(&format!("Generated packfile: {} bytes", packfile.len()));
-
-                let mut response = Vec::new();
-
-                /* ----- 1.  acknowledgments  (only when !has_done) ----- */
-                if !has_done {
-                    response.extend(encode_pkt_line(b"acknowledgments\n"));
-                    response.extend(encode_pkt_line(b"NAK\n")); // or real ACK/ready lines
-                    response.extend(b"0001"); // delim-pkt -> next section
-                }
-
-                // Packfile section header
-                response.extend(encode_pkt_line(b"packfile\n"));
-
-                /* ----- 3.  side-band-encode the pack ----- */
-                let mut pos = 0;
-                // ----- 3. side-band-encode the pack -----
-                while pos < packfile.len() {
-                    // how much of the pack we can send in this side-band frame
-                    let chunk_end = std::cmp::min(pos + MAX_SIDEBAND_DATA, packfile.len());
-
-                    // helper builds: <4-byte length><0x01><payload>
-                    response.extend(encode_sideband_data(1, &packfile[pos..chunk_end]));
-
-                    pos = chunk_end;
-                }
-
-                // End packfile section with flush packet
-                response.extend(encode_flush_pkt()); // 0000 - end of response
-
-                log(&format!("Total response size: {} bytes", response.len()));
-                create_response(200, "application/x-git-upload-pack-result", &response)
-            }
-            Err(e) => {
-                log(&format!("Failed to generate packfile: {}", e));
-                create_error_response(&format!("packfile generation failed: {}", e))
-            }
+    fn handle_ls_refs(&self, request: &CommandRequest, namespace: &Namespace) -> HttpResponse {
+        match self.ls_refs_response(request, namespace) {
+            Ok(data) => create_response(200, "application/x-git-receive-pack-result", &data),
+            Err(e) => create_error_response(&e, Some("application/x-git-receive-pack-result")),
         }
     }
 
-    fn handle_object_info(&self, _request: &CommandRequest) -> HttpResponse {
-        create_error_response("object-info not implemented yet")
-    }
-
-    fn generate_packfile_for_wants(&self, wants: &[String]) -> Result<Vec<u8>, String> {
+    fn generate_packfile_for_wants(&self, wants: &[String]) -> Result<Vec<u8>, GitError> {
         log(&format!("Generating packfile for {} wants", wants.len()));
 
         // Collect all objects needed for the wants
@@ -436,9 +1176,7 @@ impl GitRepoState {
         self.generate_simple_packfile(&objects_to_send)
     }
 
-    fn generate_simple_packfile(&self, object_ids: &[String]) -> Result<Vec<u8>, String> {
-        use crate::utils::hash::sha1_hash;
-
+    fn generate_simple_packfile(&self, object_ids: &[String]) -> Result<Vec<u8>, GitError> {
         let mut pack = Vec::new();
 
         // Pack header: "PACK" + version(2) + object_count
@@ -451,30 +1189,36 @@ impl GitRepoState {
             object_ids.len()
         ));
 
-        // Add each object
+        // Add each object, copying entries from stored packs as they are,
+        // including deltas whose base is sent too
+        let sending: HashSet<&str> = object_ids.iter().map(String::as_str).collect();
         for obj_id in object_ids {
-            if let Some(obj) = self.objects.get(obj_id) {
-                log(&format!("Processing object: {}", obj));
-                let obj_data = obj.to_pack_format()?;
-                pack.extend(&obj_data);
+            if let Some(entry) = self
+                .objects
+                .reusable_entry(obj_id, |base| sending.contains(base))?
+            {
+                pack.extend(&entry);
+            } else if let Some(raw) = self.objects.get_raw(obj_id) {
+                pack.extend(&raw.to_pack_entry());
             } else {
-                return Err(format!("Object not found: {}", obj_id));
+                return Err(GitError::MissingObject(obj_id.clone()));
             }
         }
 
-        // Pack checksum (SHA1 of entire pack so far)
-        let checksum = sha1_hash(&pack);
+        // Pack checksum (hash of entire pack so far, using the repository's algorithm)
+        let checksum = self.object_format.digest(&pack);
         pack.extend(&checksum);
 
         log(&format!(
-            "Generated packfile: {} bytes (header + {} objects + 20-byte checksum)",
+            "Generated packfile: {} bytes (header + {} objects + {}-byte checksum)",
             pack.len(),
-            object_ids.len()
+            object_ids.len(),
+            checksum.len()
         ));
         Ok(pack)
     }
 
-    fn collect_objects_for_wants(&self, wants: &[String]) -> Result<Vec<String>, String> {
+    fn collect_objects_for_wants(&self, wants: &[String]) -> Result<Vec<String>, GitError> {
         use std::collections::HashSet;
         let mut objects = HashSet::new();
 
@@ -484,7 +1228,7 @@ impl GitRepoState {
 
             // If it's a commit, traverse to get tree + blobs
             if let Some(obj) = self.objects.get(want_hash) {
-                match obj {
+                match &obj {
                     crate::git::objects::GitObject::Commit { tree, parents, .. } => {
                         // Add the tree
                         objects.insert(tree.clone());
@@ -506,7 +1250,7 @@ impl GitRepoState {
                     }
                 }
             } else {
-                return Err(format!("Wanted object not found: {}", want_hash));
+                return Err(GitError::MissingObject(want_hash.clone()));
             }
         }
 
@@ -517,15 +1261,15 @@ impl GitRepoState {
         &self,
         tree_hash: &str,
         objects: &mut std::collections::HashSet<String>,
-    ) -> Result<(), String> {
+    ) -> Result<(), GitError> {
         if let Some(crate::git::objects::GitObject::Tree { entries }) = self.objects.get(tree_hash)
         {
             for entry in entries {
                 objects.insert(entry.hash.clone());
 
                 // If this entry is also a tree, recurse
-                if entry.mode == "040000" {
-                    // Directory mode
+                if entry.mode == "40000" || entry.mode == "040000" {
+                    // Directory mode (git writes "40000" in tree objects)
                     self.collect_tree_objects(&entry.hash, objects)?;
                 }
             }
@@ -537,7 +1281,7 @@ impl GitRepoState {
         &self,
         commit_hash: &str,
         objects: &mut std::collections::HashSet<String>,
-    ) -> Result<(), String> {
+    ) -> Result<(), GitError> {
         if objects.contains(commit_hash) {
             return Ok(()); // Already processed
         }
@@ -549,10 +1293,10 @@ impl GitRepoState {
         {
             // Add the tree and its contents
             objects.insert(tree.clone());
-            self.collect_tree_objects(tree, objects)?;
+            self.collect_tree_objects(&tree, objects)?;
 
             // Recurse to parents
-            for parent_hash in parents {
+            for parent_hash in &parents {
                 self.collect_commit_ancestors(parent_hash, objects)?;
             }
         }
@@ -576,8 +1320,15 @@ impl GitRepoState {
         }
 
         // Check that all object references are valid
-        for (hash, obj) in &self.objects {
-            match obj {
+        for hash in self.objects.ids() {
+            let obj = match self.objects.get(&hash) {
+                Some(obj) => obj,
+                None => {
+                    errors.push(format!("Object {} can't be read", hash));
+                    continue;
+                }
+            };
+            match &obj {
                 GitObject::Commit { tree, parents, .. } => {
                     if !self.objects.contains_key(tree) {
                         errors.push(format!("Commit {} references missing tree {}", hash, tree));
@@ -639,7 +1390,7 @@ impl GitRepoState {
     }
 
     /// Add an object to the repository (Smart HTTP)
-    pub fn add_object(&mut self, hash: String, object: GitObject) {
+    pub fn add_object(&mut self, hash: String, object: GitObject) -> Result<(), GitError> {
         log(&format!(
             "Adding object to repository: {} ({})",
             hash,
@@ -650,76 +1401,181 @@ impl GitRepoState {
                 GitObject::Tag { .. } => "tag",
             }
         ));
-        self.objects.insert(hash, object);
+        self.objects.insert(hash, object)
     }
 
     /// Update or create a reference
-    pub fn update_ref(&mut self, ref_name: String, new_hash: String) {
+    pub fn update_ref(&mut self, ref_name: String, new_hash: String) -> Result<(), GitError> {
         log(&format!("Updating ref {} to {}", ref_name, new_hash));
+        if let Some(bare) = &self.bare {
+            bare.write_ref(&ref_name, &new_hash)?;
+        }
         self.refs.insert(ref_name, new_hash);
+        Ok(())
     }
 
     /// Delete a reference
-    pub fn delete_ref(&mut self, ref_name: &str) -> Option<String> {
+    pub fn delete_ref(&mut self, ref_name: &str) -> Result<Option<String>, GitError> {
         log(&format!("Deleting ref {}", ref_name));
-        self.refs.remove(ref_name)
+        if let Some(bare) = &self.bare {
+            bare.delete_ref(ref_name)?;
+        }
+        Ok(self.refs.remove(ref_name))
     }
 
     /// Repository update methods for push operations
-    pub fn process_pack_file(&mut self, pack_data: &[u8]) -> Result<Vec<String>, String> {
+    pub fn process_pack_file(&mut self, pack_data: &[u8]) -> Result<Vec<String>, GitError> {
         log("Processing incoming pack file for repository updates");
 
-        log(&format!("pack file {:?}", pack_data));
-        let objects =
-            PackSerializer::parse(pack_data).map_err(|e| format!("Pack parsing error: {}", e))?;
-        let mut new_hashes = Vec::new();
+        // Object count from the pack header, checked before anything is inflated
+        if let Some(count) = pack_data.get(8..12) {
+            let count = u32::from_be_bytes([count[0], count[1], count[2], count[3]]);
+            if count > self.config.limits.max_pack_objects {
+                return Err(GitError::LimitExceeded(format!(
+                    "pack has {} objects, maximum is {}",
+                    count, self.config.limits.max_pack_objects
+                )));
+            }
+        }
 
-        log(&format!("Parsed {} objects from pack file", objects.len()));
+        // The pack is kept as received, with a generated index
+        let new_hashes = self.objects.add_pack(pack_data, self.object_format)?;
+        self.record_write(host::now(), new_hashes.clone());
 
-        for obj in objects {
-            log(&format!(
-                "Processing object: {} ({})",
-                obj.object_type(),
-                match &obj {
-                    GitObject::Blob { .. } => "blob",
-                    GitObject::Tree { .. } => "tree",
-                    GitObject::Commit { .. } => "commit",
-                    GitObject::Tag { .. } => "tag",
+        log(&format!(
+            "Added {} new objects to repository",
+            new_hashes.len()
+        ));
+        Ok(new_hashes)
+    }
+
+    /// Check that `oid` and everything reachable from it is present
+    pub fn check_connectivity(&self, oid: &str) -> Result<(), GitError> {
+        let mut seen = std::collections::HashSet::new();
+        let mut pending = vec![oid.to_string()];
+
+        while let Some(id) = pending.pop() {
+            if !seen.insert(id.clone()) {
+                continue;
+            }
+            let obj = self
+                .objects
+                .get(&id)
+                .ok_or_else(|| GitError::MissingObject(id.clone()))?;
+            match obj {
+                GitObject::Commit { tree, parents, .. } => {
+                    pending.push(tree.clone());
+                    pending.extend(parents.iter().cloned());
                 }
-            ));
+                GitObject::Tree { entries } => {
+                    // Submodule entries (gitlinks) point into other repositories
+                    pending.extend(
+                        entries
+                            .iter()
+                            .filter(|entry| entry.mode != "160000")
+                            .map(|entry| entry.hash.clone()),
+                    );
+                }
+                GitObject::Tag { object, .. } => pending.push(object.clone()),
+                GitObject::Blob { .. } => {}
+            }
+        }
 
-            let hash = obj.compute_hash();
-            log(&format!("Calculated hash: {}", hash));
+        Ok(())
+    }
 
-            // Only add new objects, skip duplicates
-            if !self.objects.contains_key(&hash) {
-                log(&format!("Adding new object with hash: {}", hash));
-                self.add_object(hash.clone(), obj);
-                new_hashes.push(hash);
-            } else {
-                log(&format!(
-                    "Object with hash {} already exists, skipping",
-                    hash
-                ));
+    /// Apply a single ref update, checking it against the ref's current value,
+    /// and log it in the reflog
+    pub fn apply_ref_update(
+        &mut self,
+        ref_name: &str,
+        old_oid: &str,
+        new_oid: &str,
+        context: &RefUpdateContext,
+    ) -> Result<RefChange, GitError> {
+        let is_create = self.object_format.is_null_oid(old_oid);
+        let is_delete = self.object_format.is_null_oid(new_oid);
+        let current = self.refs.get(ref_name).cloned();
+
+        // Checked within its namespace too, so a namespace's HEAD can't be
+        // pushed as an ordinary ref
+        check_ref_name(ref_name)?;
+        let (namespace, name_in_namespace) = Namespace::of(ref_name);
+        check_ref_name(name_in_namespace)?;
+
+        // Symbolic refs are changed through the admin API, never by a push
+        if self.symref_target(ref_name).is_some() {
+            return Err(GitError::RefConflict {
+                ref_name: ref_name.to_string(),
+                reason: "is a symbolic ref".to_string(),
+            });
+        }
+
+        // The client's view of the ref must match ours (compare-and-swap)
+        match (&current, is_create) {
+            (Some(_), true) => {
+                return Err(GitError::RefConflict {
+                    ref_name: ref_name.to_string(),
+                    reason: "already exists".to_string(),
+                })
+            }
+            (Some(current), false) if current != old_oid => {
+                return Err(GitError::RefConflict {
+                    ref_name: ref_name.to_string(),
+                    reason: "stale info".to_string(),
+                })
             }
+            (None, false) => {
+                return Err(GitError::RefConflict {
+                    ref_name: ref_name.to_string(),
+                    reason: "stale info".to_string(),
+                })
+            }
+            _ => {}
         }
 
-        log(&format!(
-            "Added {} new objects to repository",
-            new_hashes.len()
-        ));
+        if !is_delete {
+            // Validate new OID and its history exist
+            self.check_connectivity(new_oid)?;
+        }
 
-        // DEBUG: Log all calculated hashes for comparison
-        log(&format!("All calculated hashes: {:?}", new_hashes));
-        Ok(new_hashes)
+        // Handle different types of ref updates
+        let change = if is_delete {
+            log(&format!("Deleting reference {}", ref_name));
+            self.delete_ref(ref_name)?;
+            self.repoint_head(ref_name)?;
+            RefChange::Delete
+        } else if is_create {
+            log(&format!("Creating new reference {}", ref_name));
+            self.update_ref(ref_name.to_string(), new_oid.to_string())?;
+
+            // The first branch of a repository or namespace becomes its HEAD
+            if name_in_namespace.starts_with("refs/heads/")
+                && self.namespace_branches(&namespace) == [ref_name]
+            {
+                self.set_symref(&namespace.full_name("HEAD"), ref_name)?;
+            }
+            RefChange::Create
+        } else {
+            log(&format!("Updating existing reference {}", ref_name));
+            self.update_ref(ref_name.to_string(), new_oid.to_string())?;
+            RefChange::Update
+        };
+
+        self.record_ref_update(ref_name, old_oid, new_oid, context);
+        self.record_ref_event(ref_name, old_oid, new_oid, context);
+        Ok(change)
     }
 
     /// Update repository refs based on push commands
+    ///
+    /// Each ref succeeds or fails on its own, like git's non-atomic push.
     pub fn update_refs_from_push(
         &mut self,
         ref_updates: Vec<(String, String, String)>,
-    ) -> Result<Vec<String>, String> {
-        let mut updated_refs = Vec::new();
+        context: &RefUpdateContext,
+    ) -> Vec<RefUpdateStatus> {
+        let mut statuses = Vec::new();
 
         for (ref_name, old_oid, new_oid) in ref_updates {
             log(&format!(
@@ -727,115 +1583,278 @@ impl GitRepoState {
                 ref_name, old_oid, new_oid
             ));
 
-            // Validate new OID exists
-            if !self.objects.contains_key(&new_oid) {
-                return Err(format!(
-                    "Cannot update ref {}: new object {} not found in repository",
-                    ref_name, new_oid
-                ));
-            }
-
-            // Handle different types of ref updates
-            if old_oid == "0000000000000000000000000000000000000000" {
-                // Create new reference
-                log(&format!("Creating new reference {}", ref_name));
-                self.update_ref(ref_name.clone(), new_oid);
-                updated_refs.push(format!("create {}", ref_name));
-
-                // For first branch created to empty repo, set HEAD
-                if self.refs.len() == 1 && ref_name.starts_with("refs/heads/") {
-                    self.head = ref_name.clone();
-                    log(&format!("Setting HEAD to {}", ref_name));
-                }
-            } else if new_oid == "0000000000000000000000000000000000000000" {
-                // Delete reference (not in scope for empty repo push)
-                log(&format!("Deleting reference {}", ref_name));
-                self.delete_ref(&ref_name);
-                updated_refs.push(format!("delete {}", ref_name));
+            let (namespace, name_in_namespace) = Namespace::of(&ref_name);
+            let result = if self.is_hidden_ref(&namespace, name_in_namespace) {
+                Err(GitError::PermissionDenied(
+                    "updating a hidden ref".to_string(),
+                ))
             } else {
-                // Update existing reference (not in scope for empty repo push)
-                log(&format!("Updating existing reference {}", ref_name));
-                self.update_ref(ref_name.clone(), new_oid);
-                updated_refs.push(format!("update {}", ref_name));
+                self.apply_ref_update(&ref_name, &old_oid, &new_oid, context)
+            };
+            if let Err(e) = &result {
+                log(&format!(
+                    "❌ Rejected ref update {} ({}): {}",
+                    ref_name,
+                    e.kind(),
+                    e
+                ));
             }
+            statuses.push(RefUpdateStatus { ref_name, result });
         }
 
         log(&format!(
-            "Updated {} refs in repository",
-            updated_refs.len()
+            "Updated {} of {} refs in repository",
+            statuses.iter().filter(|s| s.result.is_ok()).count(),
+            statuses.len()
         ));
-        Ok(updated_refs)
+        statuses
     }
 
     /// Process a complete push operation
+    ///
+    /// An `Err` means the pack itself was rejected and no ref was touched;
+    /// otherwise every ref update carries its own result. Ref names are
+    /// stored names; `namespace` is the one pushed to, for hooks.
     pub fn process_push_operation(
         &mut self,
         pack_data: &[u8],
         ref_updates: Vec<(String, String, String)>,
-    ) -> Result<Vec<String>, String> {
+        context: &RefUpdateContext,
+        namespace: &Namespace,
+    ) -> Result<PushOutcome, GitError> {
         log("Processing complete push operation");
 
-        // Phase 1: Parse and store pack file objects
-        log(&format!(
-            "About to process pack file with {} bytes",
-            pack_data.len()
-        ));
-        let new_hashes = match self.process_pack_file(pack_data) {
-            Ok(hashes) => {
-                log(&format!(
-                    "✅ Successfully processed pack file, got {} new objects",
-                    hashes.len()
-                ));
-                hashes
-            }
-            Err(e) => {
-                log(&format!("❌ Pack file processing failed: {}", e));
-                return Err(format!("pack processing failed: {}", e));
-            }
-        };
-
-        log(&format!(
-            "Processed {} new objects from pack file",
-            new_hashes.len()
-        ));
-
-        // Phase 2: Validate all ref targets exist
-        for (ref_name, old_oid, new_oid) in &ref_updates {
-            log(&format!(
-                "Validating ref update: {} {} -> {}",
-                ref_name, old_oid, new_oid
-            ));
+        // Phase 1: Parse and store pack file objects (delete-only pushes carry no pack)
+        if !pack_data.is_empty() {
             log(&format!(
-                "Available objects: {:?}",
-                self.objects.keys().collect::<Vec<_>>()
+                "About to process pack file with {} bytes",
+                pack_data.len()
             ));
-            if !self.objects.contains_key(new_oid) {
-                return Err(format!(
-                    "Ref update validation failed: object {} not found",
-                    new_oid
-                ));
+            match self.process_pack_file(pack_data) {
+                Ok(hashes) => {
+                    log(&format!(
+                        "✅ Successfully processed pack file, got {} new objects",
+                        hashes.len()
+                    ));
+                }
+                Err(e) => {
+                    log(&format!("❌ Pack file processing failed: {}", e));
+                    return Err(e);
+                }
             }
         }
 
-        log("All ref updates validated successfully");
+        // Phase 2: Let the pre-receive and update hooks refuse ref updates
+        let mut review = self.run_receive_hooks(&ref_updates, context, namespace);
+
+        // Phase 3: Validate and apply each ref update the hooks allowed
+        let allowed = ref_updates
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !review.refused.contains_key(index))
+            .map(|(_, update)| update.clone())
+            .collect();
+        let mut applied = self.update_refs_from_push(allowed, context).into_iter();
+        let statuses: Vec<RefUpdateStatus> = ref_updates
+            .iter()
+            .enumerate()
+            .filter_map(
+                |(index, (ref_name, _, _))| match review.refused.remove(&index) {
+                    Some(error) => Some(RefUpdateStatus {
+                        ref_name: ref_name.clone(),
+                        result: Err(error),
+                    }),
+                    None => applied.next(),
+                },
+            )
+            .collect();
+
+        // Phase 4: Tell the post-receive hooks, webhooks and event stream what changed
+        self.record_push_events(&ref_updates, &statuses, context, namespace);
+        let succeeded: Vec<(String, String, String)> = ref_updates
+            .into_iter()
+            .zip(&statuses)
+            .filter(|(_, status)| status.result.is_ok())
+            .map(|(update, _)| update)
+            .collect();
+        self.notify_post_receive(&succeeded, context, namespace);
+        self.queue_push_webhooks(&succeeded, context, namespace);
+
+        log("Push operation completed");
+        Ok(PushOutcome {
+            statuses,
+            messages: review.messages,
+        })
+    }
+}
 
-        // Phase 3: Update refs
-        let updated_refs = self.update_refs_from_push(ref_updates)?;
+fn push_disabled() -> GitError {
+    GitError::PermissionDenied("pushing to this repository is disabled".to_string())
+}
 
-        log(&format!(
-            "Updated {} references in repository",
-            updated_refs.len()
-        ));
-        // Phase 4: Verify repository consistency
-        let errors = self.validate();
-        if !errors.is_empty() {
-            return Err(format!("Repository validation failed: {:?}", errors));
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::objects::TreeEntry;
+
+    fn repo_with_blob() -> (GitRepoState, String) {
+        let mut repo = GitRepoState::default();
+        let blob = GitObject::Blob {
+            content: b"hello world".to_vec(),
+        };
+        let hash = blob.compute_hash(repo.object_format);
+        repo.add_object(hash.clone(), blob).unwrap();
+        (repo, hash)
+    }
 
-        log("Push operation completed successfully");
-        Ok(updated_refs)
+    fn push() -> RefUpdateContext {
+        RefUpdateContext::push("tester", &[])
     }
-}
 
-#[cfg(test)]
-mod tests {}
+    #[test]
+    fn test_create_and_delete_ref() {
+        let (mut repo, hash) = repo_with_blob();
+        let null = repo.object_format.null_oid();
+
+        let created = repo.update_refs_from_push(
+            vec![("refs/heads/main".to_string(), null.clone(), hash.clone())],
+            &push(),
+        );
+        assert_eq!(created[0].result, Ok(RefChange::Create));
+        assert_eq!(created[0].report_line(), "ok refs/heads/main");
+        assert_eq!(repo.refs.get("refs/heads/main"), Some(&hash));
+
+        let deleted = repo.update_refs_from_push(
+            vec![("refs/heads/main".to_string(), hash.clone(), null)],
+            &push(),
+        );
+        assert_eq!(deleted[0].result, Ok(RefChange::Delete));
+        assert!(repo.refs.is_empty());
+
+        // Both changes are logged, and the deleted ref keeps its log
+        let log = repo.reflog.entries("refs/heads/main");
+        assert_eq!(log.len(), 2);
+        assert_eq!(
+            (log[1].old_id.as_str(), log[1].identity.as_str()),
+            (hash.as_str(), "tester")
+        );
+    }
+
+    #[test]
+    fn test_update_to_missing_object_fails() {
+        let (mut repo, _) = repo_with_blob();
+        let missing = "1".repeat(40);
+
+        let statuses = repo.update_refs_from_push(
+            vec![(
+                "refs/heads/main".to_string(),
+                repo.object_format.null_oid(),
+                missing.clone(),
+            )],
+            &push(),
+        );
+        assert_eq!(statuses[0].result, Err(GitError::MissingObject(missing)));
+        assert_eq!(
+            statuses[0].report_line(),
+            "ng refs/heads/main missing necessary objects"
+        );
+        assert!(repo.refs.is_empty());
+        assert!(repo.reflog.refs.is_empty());
+    }
+
+    #[test]
+    fn test_stale_old_oid_is_a_conflict() {
+        let (mut repo, hash) = repo_with_blob();
+        repo.update_ref("refs/heads/main".to_string(), hash.clone())
+            .unwrap();
+
+        let statuses = repo.update_refs_from_push(
+            vec![
+                ("refs/heads/main".to_string(), "3".repeat(40), hash.clone()),
+                (
+                    "refs/heads/main".to_string(),
+                    repo.object_format.null_oid(),
+                    hash,
+                ),
+            ],
+            &push(),
+        );
+        assert_eq!(statuses[0].report_line(), "ng refs/heads/main stale info");
+        assert_eq!(
+            statuses[1].report_line(),
+            "ng refs/heads/main already exists"
+        );
+    }
+
+    #[test]
+    fn test_funny_ref_names_are_refused_individually() {
+        let (mut repo, hash) = repo_with_blob();
+        let null = repo.object_format.null_oid();
+
+        let statuses = repo.update_refs_from_push(
+            [
+                "refs/heads/a..b",
+                "refs/heads/ok",
+                "refs/heads/x.lock",
+                "main",
+            ]
+            .into_iter()
+            .map(|name| (name.to_string(), null.clone(), hash.clone()))
+            .collect(),
+            &push(),
+        );
+        let lines: Vec<String> = statuses.iter().map(|s| s.report_line()).collect();
+        assert_eq!(
+            lines,
+            [
+                "ng refs/heads/a..b funny refname",
+                "ok refs/heads/ok",
+                "ng refs/heads/x.lock funny refname",
+                "ng main funny refname",
+            ]
+        );
+        assert_eq!(repo.refs.keys().collect::<Vec<_>>(), ["refs/heads/ok"]);
+    }
+
+    #[test]
+    fn test_validate_reports_dangling_tree_entry() {
+        let (mut repo, blob_hash) = repo_with_blob();
+        let tree = GitObject::Tree {
+            entries: vec![
+                TreeEntry::new("100644".to_string(), "a.txt".to_string(), blob_hash),
+                TreeEntry::new("100644".to_string(), "b.txt".to_string(), "2".repeat(40)),
+            ],
+        };
+        let tree_hash = tree.compute_hash(repo.object_format);
+        repo.add_object(tree_hash, tree).unwrap();
+
+        let errors = repo.validate();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("b.txt"));
+    }
+
+    #[test]
+    fn test_dumb_info_refs_peels_annotated_tags() {
+        let (mut repo, blob_hash) = repo_with_blob();
+        let tag = GitObject::Tag {
+            object: blob_hash.clone(),
+            tag_type: "blob".to_string(),
+            tagger: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+            message: "v1\n".to_string(),
+        };
+        let tag_hash = tag.compute_hash(repo.object_format);
+        repo.add_object(tag_hash.clone(), tag).unwrap();
+        repo.update_ref("refs/tags/v1".to_string(), tag_hash.clone())
+            .unwrap();
+
+        let response = repo.dumb_info_refs();
+
+        assert_eq!(
+            String::from_utf8(response.body.unwrap()).unwrap(),
+            format!(
+                "{}\trefs/tags/v1\n{}\trefs/tags/v1^{{}}\n",
+                tag_hash, blob_hash
+            )
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! Author/Committer/Tagger Identities
+//!
+//! Commits and tags store identities as raw header values such as
+//! `colin <colinrozzi@gmail.com> 1754330635 -0400`. `GitObject` keeps those
+//! strings untouched (they feed straight into object hashing), and this
+//! module provides a structured view on top of them.
+//!
+//! Parsing is strict: a `Signature` always formats back to exactly the bytes
+//! it was parsed from, so anything built on it (log views, `deepen-since`,
+//! audit trails) can never disagree with the stored object.
+
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::str::FromStr;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    /// Seconds since the Unix epoch
+    pub timestamp: i64,
+    /// Offset from UTC in minutes (east positive), e.g. `-0400` is `-240`
+    pub tz_offset: i32,
+    /// Git distinguishes `-0000` ("local offset unknown") from `+0000`
+    pub negative_utc: bool,
+}
+
+impl Signature {
+    /// The timezone as written in the object, e.g. `+0530`
+    pub fn tz_string(&self) -> String {
+        let sign = if self.tz_offset < 0 || (self.tz_offset == 0 && self.negative_utc) {
+            '-'
+        } else {
+            '+'
+        };
+        let minutes = self.tz_offset.unsigned_abs();
+        format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60)
+    }
+
+    fn parse_tz(tz: &str) -> Result<(i32, bool), String> {
+        let bytes = tz.as_bytes();
+        if bytes.len() != 5
+            || !(bytes[0] == b'+' || bytes[0] == b'-')
+            || !bytes[1..].iter().all(u8::is_ascii_digit)
+        {
+            return Err(format!("Invalid timezone in signature: {}", tz));
+        }
+
+        let hours: i32 = tz[1..3].parse().map_err(|_| "Invalid timezone hours")?;
+        let minutes: i32 = tz[3..5].parse().map_err(|_| "Invalid timezone minutes")?;
+        if minutes >= 60 {
+            return Err(format!("Invalid timezone in signature: {}", tz));
+        }
+
+        let negative = bytes[0] == b'-';
+        let offset = hours * 60 + minutes;
+        Ok((
+            if negative { -offset } else { offset },
+            negative && offset == 0,
+        ))
+    }
+}
+
+impl FromStr for Signature {
+    type Err = String;
+
+    /// Parse `Name <email> <timestamp> <+/-hhmm>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let open = s
+            .find('<')
+            .ok_or_else(|| format!("Malformed signature (missing '<'): {}", s))?;
+        let close = s[open..]
+            .find('>')
+            .map(|pos| open + pos)
+            .ok_or_else(|| format!("Malformed signature (missing '>'): {}", s))?;
+
+        let name = s[..open]
+            .strip_suffix(' ')
+            .ok_or_else(|| format!("Malformed signature (no space before email): {}", s))?;
+        let email = &s[open + 1..close];
+
+        let when = s[close + 1..]
+            .strip_prefix(' ')
+            .ok_or_else(|| format!("Malformed signature (missing time): {}", s))?;
+        let (timestamp, tz) = when
+            .split_once(' ')
+            .ok_or_else(|| format!("Malformed signature (missing timezone): {}", s))?;
+
+        if timestamp.is_empty() || !timestamp.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(format!("Invalid timestamp in signature: {}", timestamp));
+        }
+        let timestamp: i64 = timestamp
+            .parse()
+            .map_err(|_| format!("Timestamp out of range in signature: {}", timestamp))?;
+        let (tz_offset, negative_utc) = Self::parse_tz(tz)?;
+
+        let signature = Signature {
+            name: name.to_string(),
+            email: email.to_string(),
+            timestamp,
+            tz_offset,
+            negative_utc,
+        };
+
+        // Leading zeros and similar oddities can't be reproduced byte-for-byte
+        if signature.to_string() != s {
+            return Err(format!("Non-canonical signature: {}", s));
+        }
+
+        Ok(signature)
+    }
+}
+
+impl Display for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} <{}> {} {}",
+            self.name,
+            self.email,
+            self.timestamp,
+            self.tz_string()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_signature() {
+        let sig: Signature = "colin <colinrozzi@gmail.com> 1754330635 -0400"
+            .parse()
+            .unwrap();
+
+        assert_eq!(sig.name, "colin");
+        assert_eq!(sig.email, "colinrozzi@gmail.com");
+        assert_eq!(sig.timestamp, 1754330635);
+        assert_eq!(sig.tz_offset, -240);
+        assert!(!sig.negative_utc);
+    }
+
+    #[test]
+    fn test_round_trip_exact() {
+        for raw in [
+            "colin <colinrozzi@gmail.com> 1754330635 -0400",
+            "Ada Lovelace <ada@example.com> 0 +0000",
+            "Someone <someone@example.com> 1700000000 -0000",
+            "India <in@example.com> 1700000000 +0530",
+            " <nameless@example.com> 1700000000 +0100",
+        ] {
+            let sig: Signature = raw.parse().unwrap();
+            assert_eq!(sig.to_string(), raw);
+        }
+    }
+
+    #[test]
+    fn test_rejects_malformed() {
+        for raw in [
+            "no email 1700000000 +0000",
+            "Name <email@example.com>",
+            "Name <email@example.com> 1700000000",
+            "Name <email@example.com> soon +0000",
+            "Name <email@example.com> 1700000000 0000",
+            "Name <email@example.com> 1700000000 +0090",
+            "Name <email@example.com> 01700000000 +0000",
+        ] {
+            assert!(raw.parse::<Signature>().is_err(), "accepted {:?}", raw);
+        }
+    }
+}
@@ -0,0 +1,919 @@
+//! Persisted Repository State
+//!
+//! The actor's state bytes are a versioned, compact binary encoding of
+//! `GitRepoState`:
+//!
+//! ```text
+//! "GSRV" | schema version (u32, big-endian) | bincode(StoredRepository)
+//! ```
+//!
+//! Packed objects stay in their pack files in host storage (see
+//! `object_store`); the state only lists the packs. Loose objects are stored
+//! the way git stores them: zlib-compressed `<type> <size>\0<content>`,
+//! keyed by the raw (binary) object id.
+//!
+//! A repository served from a bare directory on the host (`repo_path`)
+//! persists only its settings and bookkeeping (garbage collection, reflog,
+//! webhooks):
+//! refs, HEAD and objects are read back from the directory on every load.
+//!
+//! State written by an older actor is upgraded on load. State that can't be
+//! decoded, or that comes from a newer actor, is an error: the caller must
+//! refuse to run rather than start over with an empty repository.
+//!
+//! Schema history:
+//!
+//! 1. Unversioned JSON: `GitRepoState` serialized directly, with
+//!    `object_format` and `config` possibly absent
+//! 2. JSON envelope `{"schema_version": 2, "repository": {...}}`; every
+//!    repository field present
+//! 3. Binary encoding above (JSON kept only for the config), every object loose
+//! 4. Adds the host storage area and the list of stored packs
+//! 5. Adds garbage collection bookkeeping (recent writes, last run)
+//! 6. Adds the reflog
+//! 7. Adds symbolic refs other than `HEAD`
+//! 8. Adds queued webhook deliveries and the delivery log
+
+use crate::config::Config;
+use crate::git::gc::GcState;
+use crate::git::object_store::{ObjectStore, PackFile};
+use crate::git::objects::GitObject;
+use crate::git::reflog::Reflog;
+use crate::git::repository::GitRepoState;
+use crate::git::webhooks::WebhookState;
+use crate::utils::compression::{compress_zlib, decompress_zlib};
+use crate::utils::hash::HashAlgorithm;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Display;
+
+/// Schema version written by this build
+pub const CURRENT_SCHEMA_VERSION: u32 = 8;
+
+/// Last schema version that was stored as JSON
+const LAST_JSON_SCHEMA_VERSION: u32 = 2;
+
+/// Prefix of binary state; JSON state always starts with `{`
+const MAGIC: &[u8; 4] = b"GSRV";
+
+/// A migration upgrades a JSON repository document from version `n` to `n + 1`
+type Migration = fn(Value) -> Result<Value, String>;
+
+/// `MIGRATIONS[n - 1]` upgrades JSON schema version `n`. Version 2 to 3 only
+/// changes the encoding, so a version 2 document decodes straight into a
+/// current repository.
+const MIGRATIONS: &[Migration] = &[migrate_v1_to_v2];
+
+thread_local! {
+    // Compressed objects by id. Objects never change once stored, so this saves
+    // recompressing the whole object store every time state is written.
+    static COMPRESSED: RefCell<HashMap<String, Vec<u8>>> = RefCell::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateError {
+    /// The bytes aren't a state document we recognize
+    Malformed(String),
+    /// Written by a newer actor (or an unknown version); upgrading is not possible
+    UnsupportedVersion(u32),
+    /// A migration step failed
+    Migration { from: u32, message: String },
+    /// The (migrated) document doesn't match the schema
+    Invalid { version: u32, message: String },
+}
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateError::Malformed(msg) => write!(f, "malformed repository state: {}", msg),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "repository state schema version {} is not supported (this build reads up to {})",
+                version, CURRENT_SCHEMA_VERSION
+            ),
+            StateError::Migration { from, message } => write!(
+                f,
+                "migrating repository state from schema version {} failed: {}",
+                from, message
+            ),
+            StateError::Invalid { version, message } => write!(
+                f,
+                "invalid repository state (schema version {}): {}",
+                version, message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Schema version 8 layout
+#[derive(Serialize, Deserialize)]
+struct StoredRepository {
+    repo_name: String,
+    head: String,
+    object_format: HashAlgorithm,
+    /// JSON, so settings added later (with defaults) don't need a schema bump
+    config: Vec<u8>,
+    /// Sorted by name
+    refs: Vec<(String, String)>,
+    /// Symbolic ref name and target, sorted by name
+    symrefs: Vec<(String, String)>,
+    /// Raw object id and zlib-compressed loose object, sorted by id
+    objects: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Host storage area holding the packs
+    storage: Option<String>,
+    /// Oldest first
+    packs: Vec<PackFile>,
+    gc: GcState,
+    reflog: Reflog,
+    webhooks: WebhookState,
+}
+
+/// Schema version 7 layout: no webhook state
+#[derive(Serialize, Deserialize)]
+struct StoredRepositoryV7 {
+    repo_name: String,
+    head: String,
+    object_format: HashAlgorithm,
+    config: Vec<u8>,
+    refs: Vec<(String, String)>,
+    symrefs: Vec<(String, String)>,
+    objects: Vec<(Vec<u8>, Vec<u8>)>,
+    storage: Option<String>,
+    packs: Vec<PackFile>,
+    gc: GcState,
+    reflog: Reflog,
+}
+
+impl From<StoredRepositoryV7> for StoredRepository {
+    fn from(v7: StoredRepositoryV7) -> Self {
+        Self {
+            repo_name: v7.repo_name,
+            head: v7.head,
+            object_format: v7.object_format,
+            config: v7.config,
+            refs: v7.refs,
+            symrefs: v7.symrefs,
+            objects: v7.objects,
+            storage: v7.storage,
+            packs: v7.packs,
+            gc: v7.gc,
+            reflog: v7.reflog,
+            webhooks: WebhookState::default(),
+        }
+    }
+}
+
+/// Schema version 6 layout: no symbolic refs
+#[derive(Serialize, Deserialize)]
+struct StoredRepositoryV6 {
+    repo_name: String,
+    head: String,
+    object_format: HashAlgorithm,
+    config: Vec<u8>,
+    refs: Vec<(String, String)>,
+    objects: Vec<(Vec<u8>, Vec<u8>)>,
+    storage: Option<String>,
+    packs: Vec<PackFile>,
+    gc: GcState,
+    reflog: Reflog,
+}
+
+impl From<StoredRepositoryV6> for StoredRepositoryV7 {
+    fn from(v6: StoredRepositoryV6) -> Self {
+        Self {
+            repo_name: v6.repo_name,
+            head: v6.head,
+            object_format: v6.object_format,
+            config: v6.config,
+            refs: v6.refs,
+            symrefs: Vec::new(),
+            objects: v6.objects,
+            storage: v6.storage,
+            packs: v6.packs,
+            gc: v6.gc,
+            reflog: v6.reflog,
+        }
+    }
+}
+
+/// Schema version 5 layout: no reflog
+#[derive(Serialize, Deserialize)]
+struct StoredRepositoryV5 {
+    repo_name: String,
+    head: String,
+    object_format: HashAlgorithm,
+    config: Vec<u8>,
+    refs: Vec<(String, String)>,
+    objects: Vec<(Vec<u8>, Vec<u8>)>,
+    storage: Option<String>,
+    packs: Vec<PackFile>,
+    gc: GcState,
+}
+
+impl From<StoredRepositoryV5> for StoredRepositoryV6 {
+    fn from(v5: StoredRepositoryV5) -> Self {
+        Self {
+            repo_name: v5.repo_name,
+            head: v5.head,
+            object_format: v5.object_format,
+            config: v5.config,
+            refs: v5.refs,
+            objects: v5.objects,
+            storage: v5.storage,
+            packs: v5.packs,
+            gc: v5.gc,
+            reflog: Reflog::default(),
+        }
+    }
+}
+
+/// Schema version 4 layout: no garbage collection state
+#[derive(Serialize, Deserialize)]
+struct StoredRepositoryV4 {
+    repo_name: String,
+    head: String,
+    object_format: HashAlgorithm,
+    config: Vec<u8>,
+    refs: Vec<(String, String)>,
+    objects: Vec<(Vec<u8>, Vec<u8>)>,
+    storage: Option<String>,
+    packs: Vec<PackFile>,
+}
+
+impl From<StoredRepositoryV4> for StoredRepositoryV5 {
+    fn from(v4: StoredRepositoryV4) -> Self {
+        Self {
+            repo_name: v4.repo_name,
+            head: v4.head,
+            object_format: v4.object_format,
+            config: v4.config,
+            refs: v4.refs,
+            objects: v4.objects,
+            storage: v4.storage,
+            packs: v4.packs,
+            gc: GcState::default(),
+        }
+    }
+}
+
+/// Schema version 3 layout: no packs
+#[derive(Serialize, Deserialize)]
+struct StoredRepositoryV3 {
+    repo_name: String,
+    head: String,
+    object_format: HashAlgorithm,
+    config: Vec<u8>,
+    refs: Vec<(String, String)>,
+    objects: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl From<StoredRepositoryV3> for StoredRepositoryV4 {
+    fn from(v3: StoredRepositoryV3) -> Self {
+        Self {
+            repo_name: v3.repo_name,
+            head: v3.head,
+            object_format: v3.object_format,
+            config: v3.config,
+            refs: v3.refs,
+            objects: v3.objects,
+            storage: None,
+            packs: Vec::new(),
+        }
+    }
+}
+
+/// Serialize the repository in the current schema version
+pub fn encode_state(repo: &GitRepoState) -> Result<Vec<u8>, StateError> {
+    // A bare repository's refs and objects are on disk already; the next
+    // decode takes them from memory rather than reading them again
+    if repo.bare.is_some() {
+        repo.remember_bare();
+        return encode_stored(&StoredRepository {
+            repo_name: repo.repo_name.clone(),
+            head: repo.head.clone(),
+            object_format: repo.object_format,
+            config: encode_config(&repo.config)?,
+            refs: Vec::new(),
+            symrefs: Vec::new(),
+            objects: Vec::new(),
+            storage: None,
+            packs: Vec::new(),
+            gc: repo.gc.clone(),
+            reflog: repo.reflog.clone(),
+            webhooks: repo.webhooks.clone(),
+        });
+    }
+
+    let mut refs: Vec<(String, String)> = repo
+        .refs
+        .iter()
+        .map(|(name, id)| (name.clone(), id.clone()))
+        .collect();
+    refs.sort();
+
+    let loose = repo.objects.loose();
+    let mut ids: Vec<&String> = loose.keys().collect();
+    ids.sort();
+    let objects = COMPRESSED.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        // Forget objects that are no longer in the repository
+        cache.retain(|id, _| loose.contains_key(id));
+        ids.into_iter()
+            .map(|id| {
+                let raw_id = hex::decode(id)
+                    .map_err(|e| StateError::Malformed(format!("object id {}: {}", id, e)))?;
+                let compressed = cache
+                    .entry(id.clone())
+                    .or_insert_with(|| compress_zlib(&loose[id].to_loose_format()));
+                Ok((raw_id, compressed.clone()))
+            })
+            .collect::<Result<Vec<_>, StateError>>()
+    })?;
+
+    let stored = StoredRepository {
+        repo_name: repo.repo_name.clone(),
+        head: repo.head.clone(),
+        object_format: repo.object_format,
+        config: encode_config(&repo.config)?,
+        refs,
+        symrefs: repo
+            .symrefs
+            .iter()
+            .map(|(name, target)| (name.clone(), target.clone()))
+            .collect(),
+        objects,
+        storage: repo.objects.storage().map(str::to_string),
+        packs: repo.objects.packs(),
+        gc: repo.gc.clone(),
+        reflog: repo.reflog.clone(),
+        webhooks: repo.webhooks.clone(),
+    };
+    encode_stored(&stored)
+}
+
+fn encode_config(config: &Config) -> Result<Vec<u8>, StateError> {
+    serde_json::to_vec(config).map_err(|e| StateError::Malformed(e.to_string()))
+}
+
+fn encode_stored(stored: &StoredRepository) -> Result<Vec<u8>, StateError> {
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&CURRENT_SCHEMA_VERSION.to_be_bytes());
+    bincode::serialize_into(&mut data, stored).map_err(|e| StateError::Malformed(e.to_string()))?;
+    Ok(data)
+}
+
+/// Decode state bytes of any known schema version, migrating as needed
+pub fn decode_state(data: &[u8]) -> Result<GitRepoState, StateError> {
+    match data.strip_prefix(MAGIC) {
+        Some(rest) => decode_binary(rest),
+        None => decode_json(data),
+    }
+}
+
+/// Whether state bytes look like a repository (of any schema version) rather
+/// than some other document, such as a config passed as init state
+pub fn is_repository_state(data: &[u8]) -> bool {
+    if data.starts_with(MAGIC) {
+        return true;
+    }
+    match serde_json::from_slice::<Value>(data) {
+        Ok(Value::Object(fields)) => {
+            fields.contains_key("schema_version")
+                || (fields.contains_key("refs") && fields.contains_key("objects"))
+        }
+        _ => false,
+    }
+}
+
+fn decode_binary(data: &[u8]) -> Result<GitRepoState, StateError> {
+    let (version, payload) = match data {
+        [a, b, c, d, payload @ ..] => (u32::from_be_bytes([*a, *b, *c, *d]), payload),
+        _ => return Err(StateError::Malformed("truncated header".to_string())),
+    };
+    if version <= LAST_JSON_SCHEMA_VERSION || version > CURRENT_SCHEMA_VERSION {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+    let invalid = |message: String| StateError::Invalid { version, message };
+
+    let stored: StoredRepository = match version {
+        3 => bincode::deserialize::<StoredRepositoryV3>(payload).map(|v3| {
+            let v5 = StoredRepositoryV5::from(StoredRepositoryV4::from(v3));
+            StoredRepositoryV7::from(StoredRepositoryV6::from(v5)).into()
+        }),
+        4 => bincode::deserialize::<StoredRepositoryV4>(payload).map(|v4| {
+            let v6 = StoredRepositoryV6::from(StoredRepositoryV5::from(v4));
+            StoredRepositoryV7::from(v6).into()
+        }),
+        5 => bincode::deserialize::<StoredRepositoryV5>(payload)
+            .map(|v5| StoredRepositoryV7::from(StoredRepositoryV6::from(v5)).into()),
+        6 => bincode::deserialize::<StoredRepositoryV6>(payload)
+            .map(|v6| StoredRepositoryV7::from(v6).into()),
+        7 => bincode::deserialize::<StoredRepositoryV7>(payload).map(StoredRepository::from),
+        _ => bincode::deserialize(payload),
+    }
+    .map_err(|e| invalid(e.to_string()))?;
+    let config: Config =
+        serde_json::from_slice(&stored.config).map_err(|e| invalid(format!("config: {}", e)))?;
+
+    if config.repo_path.is_some() {
+        let mut repo = GitRepoState::open_bare(config).map_err(|e| invalid(e.to_string()))?;
+        repo.repo_name = stored.repo_name;
+        repo.gc = stored.gc;
+        repo.reflog = stored.reflog;
+        repo.webhooks = stored.webhooks;
+        return Ok(repo);
+    }
+
+    let mut objects = HashMap::with_capacity(stored.objects.len());
+    let mut compressed_objects = HashMap::with_capacity(stored.objects.len());
+    for (raw_id, compressed) in stored.objects {
+        let id = hex::encode(&raw_id);
+        let loose =
+            decompress_zlib(&compressed).map_err(|e| invalid(format!("object {}: {}", id, e)))?;
+        let object = GitObject::from_loose_format(&loose, stored.object_format)
+            .map_err(|e| invalid(format!("object {}: {}", id, e)))?;
+        objects.insert(id.clone(), object);
+        compressed_objects.insert(id, compressed);
+    }
+    COMPRESSED.with(|cache| *cache.borrow_mut() = compressed_objects);
+
+    let objects = ObjectStore::restore(stored.storage, objects, stored.packs, stored.object_format)
+        .map_err(|e| invalid(e.to_string()))?;
+
+    Ok(GitRepoState {
+        repo_name: stored.repo_name,
+        refs: stored.refs.into_iter().collect(),
+        objects,
+        head: stored.head,
+        symrefs: stored.symrefs.into_iter().collect(),
+        object_format: stored.object_format,
+        config,
+        bare: None,
+        gc: stored.gc,
+        reflog: stored.reflog,
+        webhooks: stored.webhooks,
+        events: Vec::new(),
+    })
+}
+
+fn decode_json(data: &[u8]) -> Result<GitRepoState, StateError> {
+    let document: Value =
+        serde_json::from_slice(data).map_err(|e| StateError::Malformed(e.to_string()))?;
+    let (version, mut repository) = split_envelope(document)?;
+
+    if version == 0 || version > LAST_JSON_SCHEMA_VERSION {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+
+    for from in version..LAST_JSON_SCHEMA_VERSION {
+        let migrate = MIGRATIONS[(from - 1) as usize];
+        repository =
+            migrate(repository).map_err(|message| StateError::Migration { from, message })?;
+    }
+
+    serde_json::from_value(repository).map_err(|e| StateError::Invalid {
+        version,
+        message: e.to_string(),
+    })
+}
+
+/// Separate the schema version from the repository document
+fn split_envelope(document: Value) -> Result<(u32, Value), StateError> {
+    let mut fields = match document {
+        Value::Object(fields) => fields,
+        _ => return Err(StateError::Malformed("expected a JSON object".to_string())),
+    };
+
+    match fields.remove("schema_version") {
+        Some(version) => {
+            let version = version
+                .as_u64()
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or_else(|| StateError::Malformed(format!("bad schema_version {}", version)))?;
+            let repository = fields
+                .remove("repository")
+                .ok_or_else(|| StateError::Malformed("missing repository".to_string()))?;
+            Ok((version, repository))
+        }
+        // Schema 1 predates the envelope
+        None if fields.contains_key("refs") && fields.contains_key("objects") => {
+            Ok((1, Value::Object(fields)))
+        }
+        None => Err(StateError::Malformed(
+            "no schema_version and not a repository".to_string(),
+        )),
+    }
+}
+
+/// Fill in the fields that were optional before the envelope existed
+fn migrate_v1_to_v2(repository: Value) -> Result<Value, String> {
+    let mut fields: Map<String, Value> = match repository {
+        Value::Object(fields) => fields,
+        _ => return Err("repository is not an object".to_string()),
+    };
+
+    // Repositories from before object-format support are sha1
+    fields
+        .entry("object_format")
+        .or_insert_with(|| Value::String("sha1".to_string()));
+    if !fields.contains_key("config") {
+        let config = serde_json::to_value(Config::default()).map_err(|e| e.to_string())?;
+        fields.insert("config".to_string(), config);
+    }
+
+    Ok(Value::Object(fields))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::objects::TreeEntry;
+    use crate::git::reflog::ReflogEntry;
+    use crate::git::webhooks::PendingDelivery;
+
+    /// How schema version 2 wrote state
+    fn encode_v2_json(repo: &GitRepoState) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({ "schema_version": 2, "repository": repo })).unwrap()
+    }
+
+    /// A history of `commits` commits, each changing one file in a flat tree
+    fn synthetic_repo(commits: usize) -> GitRepoState {
+        let mut repo = GitRepoState::default();
+        let add = |repo: &mut GitRepoState, object: GitObject| {
+            let id = object.compute_hash(repo.object_format);
+            repo.objects.insert(id.clone(), object).unwrap();
+            id
+        };
+
+        let mut parent: Option<String> = None;
+        let mut entries = Vec::new();
+        for i in 0..commits {
+            let content = (0..40)
+                .map(|line| format!("fn item_{}_{}() -> u32 {{ {} }}\n", i, line, i * line))
+                .collect::<String>();
+            let blob = add(
+                &mut repo,
+                GitObject::Blob {
+                    content: content.into_bytes(),
+                },
+            );
+            // Twenty files, each rewritten in turn
+            let name = format!("f{:02}.rs", i % 20);
+            entries.retain(|entry: &TreeEntry| entry.name != name);
+            entries.push(TreeEntry::new("100644".to_string(), name, blob));
+            entries.sort_by(|a, b| a.name.cmp(&b.name));
+            let tree = add(
+                &mut repo,
+                GitObject::Tree {
+                    entries: entries.clone(),
+                },
+            );
+            let commit = add(
+                &mut repo,
+                GitObject::Commit {
+                    tree,
+                    parents: parent.into_iter().collect(),
+                    author: "Dev <dev@example.com> 1700000000 +0000".to_string(),
+                    committer: "Dev <dev@example.com> 1700000000 +0000".to_string(),
+                    message: format!("Add item {}\n", i),
+                },
+            );
+            parent = Some(commit);
+        }
+        repo.refs
+            .insert("refs/heads/main".to_string(), parent.unwrap());
+        repo
+    }
+
+    #[test]
+    fn test_roundtrip_current_version() {
+        let mut repo = synthetic_repo(3);
+        repo.config.agent = "custom/1".to_string();
+        let pack = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/sha1.pack"
+        ))
+        .unwrap();
+        repo.objects.add_pack(&pack, repo.object_format).unwrap();
+        repo.gc.last_run_ms = 1_700_000_000_000;
+        repo.gc
+            .record_write(1_700_000_001_000, repo.objects.ids(), 60);
+        let main = repo.refs["refs/heads/main"].clone();
+        repo.reflog.record(
+            "refs/heads/main",
+            ReflogEntry {
+                old_id: repo.object_format.null_oid(),
+                new_id: main,
+                identity: "alice".to_string(),
+                time_ms: 1_700_000_002_000,
+                message: "push: ci.skip".to_string(),
+            },
+            0,
+        );
+        repo.symrefs.insert(
+            "refs/remotes/origin/HEAD".to_string(),
+            "refs/heads/main".to_string(),
+        );
+        repo.webhooks.next_id = 7;
+        repo.webhooks.pending.push(PendingDelivery {
+            id: 6,
+            event: "push".to_string(),
+            url: "https://ci.example.com/hook".to_string(),
+            signature: Some("sha256=00".to_string()),
+            payload: b"{}".to_vec(),
+            queued_ms: 1_700_000_003_000,
+            attempts: 1,
+            due_ms: 1_700_000_004_000,
+            status: Some(502),
+            error: Some("HTTP 502".to_string()),
+        });
+
+        let encoded = encode_state(&repo).unwrap();
+        assert!(encoded.starts_with(b"GSRV\0\0\0\x08"));
+        assert!(is_repository_state(&encoded));
+        let decoded = decode_state(&encoded).unwrap();
+
+        assert_eq!(decoded.refs, repo.refs);
+        assert_eq!(decoded.objects.loose(), repo.objects.loose());
+        assert_eq!(decoded.objects.packs(), repo.objects.packs());
+        assert_eq!(decoded.objects.ids(), repo.objects.ids());
+        assert_eq!(decoded.config, repo.config);
+        assert_eq!(decoded.head, repo.head);
+        assert_eq!(decoded.gc, repo.gc);
+        assert_eq!(decoded.reflog, repo.reflog);
+        assert_eq!(decoded.symrefs, repo.symrefs);
+        assert_eq!(decoded.webhooks, repo.webhooks);
+
+        // Encoding is deterministic, including from the compressed-object cache
+        assert_eq!(encode_state(&decoded).unwrap(), encoded);
+    }
+
+    #[test]
+    fn test_reads_json_schema_versions() {
+        let legacy = br#"{
+            "repo_name": "legacy",
+            "refs": {"refs/heads/main": "6ebe0ce5a0eebe93bc84cf42453db8da01f18e32"},
+            "objects": {},
+            "head": "refs/heads/main"
+        }"#;
+
+        assert!(is_repository_state(legacy));
+        let repo = decode_state(legacy).unwrap();
+        assert_eq!(repo.repo_name, "legacy");
+        assert_eq!(repo.refs.len(), 1);
+        assert_eq!(repo.object_format, HashAlgorithm::Sha1);
+        assert_eq!(repo.config, Config::default());
+
+        let repo = synthetic_repo(2);
+        let decoded = decode_state(&encode_v2_json(&repo)).unwrap();
+        assert_eq!(decoded.objects.loose(), repo.objects.loose());
+    }
+
+    #[test]
+    fn test_json_schemas_keep_objects_and_refs_through_every_migration() {
+        // "hello\n" committed as `file`, as an unversioned (schema 1) actor
+        // wrote it
+        let blob = "ce013625030ba8dba906f756967f9e9ca394464a";
+        let tree = "fb5a86199f63243160ee5b463d2cd5c36fafeb6d";
+        let commit = "041f1d690df536aa9c7a2728e80251517964495c";
+        let v1 = br#"{
+            "repo_name": "legacy",
+            "refs": {
+                "refs/heads/main": "041f1d690df536aa9c7a2728e80251517964495c",
+                "refs/tags/v1.0": "041f1d690df536aa9c7a2728e80251517964495c"
+            },
+            "objects": {
+                "041f1d690df536aa9c7a2728e80251517964495c": {"Commit": {
+                    "tree": "fb5a86199f63243160ee5b463d2cd5c36fafeb6d",
+                    "parents": [],
+                    "author": "Ada Lovelace <ada@example.com> 1600000000 +0000",
+                    "committer": "Ada Lovelace <ada@example.com> 1600000000 +0000",
+                    "message": "hello\n"
+                }},
+                "ce013625030ba8dba906f756967f9e9ca394464a": {"Blob": {
+                    "content": [104, 101, 108, 108, 111, 10]
+                }},
+                "fb5a86199f63243160ee5b463d2cd5c36fafeb6d": {"Tree": {"entries": [
+                    {"mode": "100644", "name": "file", "hash": "ce013625030ba8dba906f756967f9e9ca394464a"}
+                ]}}
+            },
+            "head": "refs/heads/main"
+        }"#;
+        let mut v2: Value = serde_json::from_slice(v1).unwrap();
+        v2["object_format"] = "sha1".into();
+        v2["config"] = serde_json::to_value(Config::default()).unwrap();
+        let v2 = serde_json::to_vec(&serde_json::json!({ "schema_version": 2, "repository": v2 }))
+            .unwrap();
+
+        for legacy in [&v1[..], &v2] {
+            // Migrated to the current schema, then written and read back in it
+            let migrated = decode_state(legacy).unwrap();
+            let encoded = encode_state(&migrated).unwrap();
+            assert!(encoded.starts_with(b"GSRV\0\0\0\x08"));
+            let repo = decode_state(&encoded).unwrap();
+
+            assert_eq!(repo.repo_name, "legacy");
+            assert_eq!(repo.head, "refs/heads/main");
+            assert_eq!(repo.refs.len(), 2);
+            assert_eq!(repo.refs["refs/heads/main"], commit);
+            assert_eq!(repo.refs["refs/tags/v1.0"], commit);
+            assert_eq!(repo.objects.len(), 3);
+            for id in [blob, tree, commit] {
+                let object = repo.objects.get(id).unwrap();
+                assert_eq!(object.compute_hash(HashAlgorithm::Sha1), id);
+            }
+            assert_eq!(
+                repo.objects.get(blob),
+                Some(GitObject::Blob {
+                    content: b"hello\n".to_vec()
+                })
+            );
+            match repo.objects.get(commit) {
+                Some(GitObject::Commit {
+                    tree: commit_tree,
+                    parents,
+                    message,
+                    ..
+                }) => {
+                    assert_eq!(commit_tree, tree);
+                    assert!(parents.is_empty());
+                    assert_eq!(message, "hello\n");
+                }
+                other => panic!("expected a commit, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_reads_schema_version_3() {
+        let repo = synthetic_repo(2);
+        let v3 = StoredRepositoryV3 {
+            repo_name: repo.repo_name.clone(),
+            head: repo.head.clone(),
+            object_format: repo.object_format,
+            config: serde_json::to_vec(&repo.config).unwrap(),
+            refs: repo.refs.clone().into_iter().collect(),
+            objects: repo
+                .objects
+                .loose()
+                .iter()
+                .map(|(id, object)| {
+                    (
+                        hex::decode(id).unwrap(),
+                        compress_zlib(&object.to_loose_format()),
+                    )
+                })
+                .collect(),
+        };
+        let mut encoded = b"GSRV\0\0\0\x03".to_vec();
+        encoded.extend(bincode::serialize(&v3).unwrap());
+
+        let decoded = decode_state(&encoded).unwrap();
+        assert_eq!(decoded.objects.loose(), repo.objects.loose());
+        assert!(decoded.objects.packs().is_empty());
+    }
+
+    #[test]
+    fn test_reads_schema_version_4() {
+        let repo = synthetic_repo(2);
+        let v4 = StoredRepositoryV4 {
+            repo_name: repo.repo_name.clone(),
+            head: repo.head.clone(),
+            object_format: repo.object_format,
+            config: serde_json::to_vec(&repo.config).unwrap(),
+            refs: repo.refs.clone().into_iter().collect(),
+            objects: Vec::new(),
+            storage: None,
+            packs: Vec::new(),
+        };
+        let mut encoded = b"GSRV\0\0\0\x04".to_vec();
+        encoded.extend(bincode::serialize(&v4).unwrap());
+
+        let decoded = decode_state(&encoded).unwrap();
+        assert_eq!(decoded.refs, repo.refs);
+        assert_eq!(decoded.gc, GcState::default());
+    }
+
+    #[test]
+    fn test_reads_schema_version_5() {
+        let repo = synthetic_repo(2);
+        let gc = GcState {
+            last_run_ms: 1_700_000_000_000,
+            recent_writes: Vec::new(),
+        };
+        let v5 = StoredRepositoryV5 {
+            repo_name: repo.repo_name.clone(),
+            head: repo.head.clone(),
+            object_format: repo.object_format,
+            config: serde_json::to_vec(&repo.config).unwrap(),
+            refs: repo.refs.clone().into_iter().collect(),
+            objects: Vec::new(),
+            storage: None,
+            packs: Vec::new(),
+            gc: gc.clone(),
+        };
+        let mut encoded = b"GSRV\0\0\0\x05".to_vec();
+        encoded.extend(bincode::serialize(&v5).unwrap());
+
+        let decoded = decode_state(&encoded).unwrap();
+        assert_eq!(decoded.refs, repo.refs);
+        assert_eq!(decoded.gc, gc);
+        assert_eq!(decoded.reflog, Reflog::default());
+    }
+
+    #[test]
+    fn test_reads_schema_version_6() {
+        let repo = synthetic_repo(2);
+        let v6 = StoredRepositoryV6 {
+            repo_name: repo.repo_name.clone(),
+            head: repo.head.clone(),
+            object_format: repo.object_format,
+            config: serde_json::to_vec(&repo.config).unwrap(),
+            refs: repo.refs.clone().into_iter().collect(),
+            objects: Vec::new(),
+            storage: None,
+            packs: Vec::new(),
+            gc: GcState::default(),
+            reflog: Reflog::default(),
+        };
+        let mut encoded = b"GSRV\0\0\0\x06".to_vec();
+        encoded.extend(bincode::serialize(&v6).unwrap());
+
+        let decoded = decode_state(&encoded).unwrap();
+        assert_eq!(decoded.refs, repo.refs);
+        assert_eq!(decoded.head, repo.head);
+        assert!(decoded.symrefs.is_empty());
+    }
+
+    #[test]
+    fn test_reads_schema_version_7() {
+        let repo = synthetic_repo(2);
+        let v7 = StoredRepositoryV7 {
+            repo_name: repo.repo_name.clone(),
+            head: repo.head.clone(),
+            object_format: repo.object_format,
+            config: serde_json::to_vec(&repo.config).unwrap(),
+            refs: repo.refs.clone().into_iter().collect(),
+            symrefs: Vec::new(),
+            objects: Vec::new(),
+            storage: None,
+            packs: Vec::new(),
+            gc: GcState::default(),
+            reflog: Reflog::default(),
+        };
+        let mut encoded = b"GSRV\0\0\0\x07".to_vec();
+        encoded.extend(bincode::serialize(&v7).unwrap());
+
+        let decoded = decode_state(&encoded).unwrap();
+        assert_eq!(decoded.refs, repo.refs);
+        assert_eq!(decoded.webhooks, WebhookState::default());
+    }
+
+    #[test]
+    fn test_undecodable_state_is_an_error() {
+        assert!(matches!(
+            decode_state(b"{\"refs\": "),
+            Err(StateError::Malformed(_))
+        ));
+        assert!(matches!(
+            decode_state(br#"{"schema_version": 99, "repository": {}}"#),
+            Err(StateError::UnsupportedVersion(99))
+        ));
+        assert!(matches!(
+            decode_state(br#"{"schema_version": 2, "repository": {"refs": {}}}"#),
+            Err(StateError::Invalid { version: 2, .. })
+        ));
+        assert!(matches!(
+            decode_state(b"GSRV\0\0\0\x63"),
+            Err(StateError::UnsupportedVersion(99))
+        ));
+
+        let mut truncated = encode_state(&synthetic_repo(1)).unwrap();
+        truncated.truncate(truncated.len() - 10);
+        assert!(matches!(
+            decode_state(&truncated),
+            Err(StateError::Invalid { version: 8, .. })
+        ));
+        assert!(!is_repository_state(br#"{"port": 9000}"#));
+    }
+
+    #[test]
+    fn test_binary_state_is_smaller_than_json() {
+        // 1000 commits: 3000 objects
+        let repo = synthetic_repo(1000);
+        assert_eq!(repo.objects.len(), 3000);
+
+        let json = encode_v2_json(&repo).len();
+        let binary = encode_state(&repo).unwrap().len();
+        assert!(
+            binary * 4 < json,
+            "binary state {} bytes, JSON state {} bytes",
+            binary,
+            json
+        );
+    }
+}
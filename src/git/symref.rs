@@ -0,0 +1,331 @@
+//! Symbolic refs.
+//!
+//! `HEAD` names the default branch, and other symbolic refs (such as
+//! `refs/remotes/origin/HEAD`) name another ref under `refs/`. They are
+//! resolved when refs are advertised, but can't be pushed to: a client
+//! updates the ref they point to instead.
+//!
+//! `HEAD` may point to a branch that doesn't exist yet (an unborn branch).
+//! When the branch it points to is deleted, it moves to the configured
+//! default branch or else the first remaining branch, so clones keep getting
+//! a checkout.
+
+use super::namespace::Namespace;
+use super::refname::check_ref_name;
+use super::repository::GitRepoState;
+use crate::bindings::theater::simple::http_types::{HttpRequest, HttpResponse};
+use crate::error::GitError;
+use crate::host::log;
+use crate::protocol::http::{create_response, query_param};
+use std::collections::BTreeMap;
+
+/// Longest chain of symbolic refs followed, like git's `SYMREF_MAXDEPTH`
+const MAX_SYMREF_DEPTH: usize = 5;
+
+impl GitRepoState {
+    /// The ref a symbolic ref points to (`HEAD` included)
+    pub fn symref_target(&self, name: &str) -> Option<&str> {
+        if name == "HEAD" {
+            Some(&self.head)
+        } else {
+            self.symrefs.get(name).map(String::as_str)
+        }
+    }
+
+    /// The object id a ref resolves to, following symbolic refs. `None` for
+    /// missing refs and symbolic refs to them.
+    pub fn resolve_ref(&self, name: &str) -> Option<&String> {
+        let mut name = name;
+        for _ in 0..=MAX_SYMREF_DEPTH {
+            match self.symref_target(name) {
+                Some(target) => name = target,
+                None => return self.refs.get(name),
+            }
+        }
+        None
+    }
+
+    /// Every ref except `HEAD`, sorted by name, with symbolic refs resolved
+    /// (and left out when they don't resolve)
+    pub fn resolved_refs(&self) -> Vec<(&str, &String)> {
+        let mut refs: Vec<(&str, &String)> = self
+            .refs
+            .iter()
+            .map(|(name, id)| (name.as_str(), id))
+            .chain(self.symrefs.keys().filter_map(|name| {
+                let id = self.resolve_ref(name)?;
+                Some((name.as_str(), id))
+            }))
+            .collect();
+        refs.sort();
+        refs
+    }
+
+    /// Create or change a symbolic ref; `HEAD` must point to a branch
+    pub fn set_symref(&mut self, name: &str, target: &str) -> Result<(), GitError> {
+        let invalid = |reason: &str| GitError::RefConflict {
+            ref_name: name.to_string(),
+            reason: reason.to_string(),
+        };
+        if name != "HEAD" && !name.starts_with("refs/") {
+            return Err(invalid("symbolic refs must be HEAD or under refs/"));
+        }
+        if name == "HEAD" && !target.starts_with("refs/heads/") {
+            return Err(invalid("HEAD must point to a branch under refs/heads/"));
+        }
+        if !target.starts_with("refs/") {
+            return Err(invalid("symbolic ref targets must be under refs/"));
+        }
+        if name != "HEAD" {
+            check_ref_name(name)?;
+        }
+        check_ref_name(target)?;
+        if self.refs.contains_key(name) {
+            return Err(invalid("already exists"));
+        }
+
+        // The target mustn't lead back here
+        let mut next = Some(target);
+        for _ in 0..=MAX_SYMREF_DEPTH {
+            match next {
+                Some(current) if current == name => return Err(invalid("symbolic ref loop")),
+                Some(current) => next = self.symref_target(current),
+                None => break,
+            }
+        }
+        if next.is_some() {
+            return Err(invalid("symbolic refs nested too deeply"));
+        }
+
+        log(&format!("Pointing {} to {}", name, target));
+        if name == "HEAD" {
+            if let Some(bare) = &self.bare {
+                bare.write_head(target)?;
+            }
+            self.head = target.to_string();
+        } else {
+            if let Some(bare) = &self.bare {
+                bare.write_symref(name, target)?;
+            }
+            self.symrefs.insert(name.to_string(), target.to_string());
+        }
+        Ok(())
+    }
+
+    /// Delete a symbolic ref other than `HEAD`, returning its target
+    pub fn delete_symref(&mut self, name: &str) -> Result<Option<String>, GitError> {
+        if name == "HEAD" {
+            return Err(GitError::RefConflict {
+                ref_name: name.to_string(),
+                reason: "HEAD can't be deleted".to_string(),
+            });
+        }
+        if !self.symrefs.contains_key(name) {
+            return Ok(None);
+        }
+        log(&format!("Deleting symbolic ref {}", name));
+        if let Some(bare) = &self.bare {
+            bare.delete_ref(name)?;
+        }
+        Ok(self.symrefs.remove(name))
+    }
+
+    /// Move the `HEAD` of a branch's namespace off it when the branch was just
+    /// deleted: to the configured default branch if it exists, else to the
+    /// first remaining branch. With no branches left it stays, unborn.
+    pub fn repoint_head(&mut self, deleted: &str) -> Result<(), GitError> {
+        let (namespace, _) = Namespace::of(deleted);
+        let head = namespace.full_name("HEAD");
+        if self.symref_target(&head) != Some(deleted) {
+            return Ok(());
+        }
+        let default_branch = namespace.full_name(&self.config.default_branch);
+        let replacement = if self.refs.contains_key(&default_branch) {
+            Some(default_branch)
+        } else {
+            self.namespace_branches(&namespace)
+                .first()
+                .map(|branch| branch.to_string())
+        };
+
+        match replacement {
+            Some(branch) => {
+                log(&format!(
+                    "{} branch {} deleted, moving it to {}",
+                    head, deleted, branch
+                ));
+                self.set_symref(&head, &branch)
+            }
+            None => {
+                log(&format!(
+                    "{} branch {} deleted, it is unborn",
+                    head, deleted
+                ));
+                Ok(())
+            }
+        }
+    }
+
+    /// Handle /admin/symref - `GET` lists symbolic refs (`HEAD` included) as
+    /// JSON, `POST ?name=<ref>&target=<ref>` creates or changes one (the
+    /// default branch, for `name=HEAD`), and `DELETE ?name=<ref>` removes one
+    pub fn admin_symref(&mut self, request: &HttpRequest, query: &Option<String>) -> HttpResponse {
+        let param = |name| query_param(query, name);
+        let result = match request.method.as_str() {
+            "GET" => Ok(()),
+            "POST" => match (param("name"), param("target")) {
+                (Some(name), Some(target)) => self.set_symref(name, target),
+                _ => {
+                    return create_response(400, "text/plain", b"missing name or target parameter")
+                }
+            },
+            "DELETE" => match param("name") {
+                Some(name) => self.delete_symref(name).map(|_| ()),
+                None => return create_response(400, "text/plain", b"missing name parameter"),
+            },
+            _ => return create_response(405, "text/plain", b"Method Not Allowed"),
+        };
+        if let Err(e) = result {
+            log(&format!("Symbolic ref change failed: {}", e));
+            return create_response(e.http_status(), "text/plain", e.to_string().as_bytes());
+        }
+
+        let mut symrefs: BTreeMap<&str, &str> = self
+            .symrefs
+            .iter()
+            .map(|(name, target)| (name.as_str(), target.as_str()))
+            .collect();
+        symrefs.insert("HEAD", &self.head);
+        match serde_json::to_vec(&symrefs) {
+            Ok(body) => create_response(200, "application/json", &body),
+            Err(e) => create_response(500, "text/plain", e.to_string().as_bytes()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::objects::GitObject;
+    use crate::git::reflog::RefUpdateContext;
+
+    fn repo_with_branches(branches: &[&str]) -> GitRepoState {
+        let mut repo = GitRepoState::default();
+        let blob = GitObject::Blob {
+            content: b"hello".to_vec(),
+        };
+        let id = blob.compute_hash(repo.object_format);
+        repo.add_object(id.clone(), blob).unwrap();
+        for branch in branches {
+            repo.update_ref(branch.to_string(), id.clone()).unwrap();
+        }
+        repo
+    }
+
+    #[test]
+    fn test_symrefs_resolve_and_are_listed() {
+        let mut repo = repo_with_branches(&["refs/heads/main", "refs/heads/dev"]);
+        let id = repo.refs["refs/heads/main"].clone();
+        repo.set_symref("refs/remotes/origin/HEAD", "refs/heads/dev")
+            .unwrap();
+        repo.set_symref("refs/heads/alias", "refs/remotes/origin/HEAD")
+            .unwrap();
+        repo.set_symref("refs/remotes/origin/gone", "refs/heads/gone")
+            .unwrap();
+
+        assert_eq!(repo.resolve_ref("HEAD"), Some(&id));
+        assert_eq!(repo.resolve_ref("refs/heads/alias"), Some(&id));
+        assert_eq!(repo.resolve_ref("refs/remotes/origin/gone"), None);
+        let names: Vec<&str> = repo
+            .resolved_refs()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "refs/heads/alias",
+                "refs/heads/dev",
+                "refs/heads/main",
+                "refs/remotes/origin/HEAD"
+            ]
+        );
+
+        assert_eq!(
+            repo.delete_symref("refs/remotes/origin/gone"),
+            Ok(Some("refs/heads/gone".to_string()))
+        );
+        assert!(repo.delete_symref("HEAD").is_err());
+    }
+
+    #[test]
+    fn test_invalid_symrefs_are_refused() {
+        let mut repo = repo_with_branches(&["refs/heads/main"]);
+        repo.set_symref("refs/x/a", "refs/x/b").unwrap();
+
+        for (name, target) in [
+            ("HEAD", "refs/tags/v1"),
+            ("ORIG_HEAD", "refs/heads/main"),
+            ("refs/x", "HEAD"),
+            ("refs/heads/main", "refs/heads/dev"),
+            ("refs/x/b", "refs/x/a"),
+            ("refs/x/a", "refs/x/a"),
+        ] {
+            assert!(
+                matches!(
+                    repo.set_symref(name, target),
+                    Err(GitError::RefConflict { .. })
+                ),
+                "{} -> {}",
+                name,
+                target
+            );
+        }
+
+        // Pushes go to the ref a symbolic ref points to
+        let id = repo.refs["refs/heads/main"].clone();
+        let statuses = repo.update_refs_from_push(
+            vec![("refs/x/a".into(), repo.object_format.null_oid(), id)],
+            &RefUpdateContext::push("tester", &[]),
+        );
+        assert_eq!(statuses[0].report_line(), "ng refs/x/a is a symbolic ref");
+
+        for (name, target) in [("refs/x/c", "refs/heads/a..b"), ("refs/x/c d", "refs/x/b")] {
+            assert!(
+                matches!(
+                    repo.set_symref(name, target),
+                    Err(GitError::InvalidRefName { .. })
+                ),
+                "{} -> {}",
+                name,
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn test_deleting_head_branch_moves_head() {
+        let mut repo = repo_with_branches(&["refs/heads/main", "refs/heads/b", "refs/heads/a"]);
+        let push = RefUpdateContext::push("tester", &[]);
+        let null = repo.object_format.null_oid();
+        let id = repo.refs["refs/heads/main"].clone();
+        repo.set_symref("HEAD", "refs/heads/b").unwrap();
+
+        // Back to the default branch first
+        repo.update_refs_from_push(
+            vec![("refs/heads/b".into(), id.clone(), null.clone())],
+            &push,
+        );
+        assert_eq!(repo.head, "refs/heads/main");
+
+        repo.update_refs_from_push(
+            vec![("refs/heads/main".into(), id.clone(), null.clone())],
+            &push,
+        );
+        assert_eq!(repo.head, "refs/heads/a");
+
+        repo.update_refs_from_push(vec![("refs/heads/a".into(), id, null)], &push);
+        assert_eq!(repo.head, "refs/heads/a");
+        assert_eq!(repo.resolve_ref("HEAD"), None);
+    }
+}
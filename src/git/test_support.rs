@@ -0,0 +1,28 @@
+//! Fixtures shared by the unit tests of the `git` modules.
+
+use super::objects::{GitObject, TreeEntry};
+use super::repository::GitRepoState;
+
+/// Add a commit of a single file holding `message` to `repo`, authored and
+/// committed by Ada Lovelace at `time` (seconds), returning its id
+pub fn commit(repo: &mut GitRepoState, message: &str, time: u64, parents: Vec<String>) -> String {
+    let mut add = |object: GitObject| {
+        let id = object.compute_hash(repo.object_format);
+        repo.add_object(id.clone(), object).unwrap();
+        id
+    };
+    let blob = add(GitObject::Blob {
+        content: message.as_bytes().to_vec(),
+    });
+    let tree = add(GitObject::Tree {
+        entries: vec![TreeEntry::new("100644".into(), "file".into(), blob)],
+    });
+    let signature = format!("Ada Lovelace <ada@example.com> {} +0000", time);
+    add(GitObject::Commit {
+        tree,
+        parents,
+        author: signature.clone(),
+        committer: signature,
+        message: message.into(),
+    })
+}
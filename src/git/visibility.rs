@@ -0,0 +1,317 @@
+//! Hidden refs, and which objects fetches may ask for.
+//!
+//! Refs matching `transfer.hide_refs` are left out of every advertisement
+//! and refused to pushes, like git's `transfer.hideRefs`. A fetch may only
+//! want the tips of refs it was shown (or what their annotated tags point
+//! to), so objects left behind by deleted or hidden refs can't be fetched by
+//! id. The `allow_*_sha1_in_want` settings relax that as git's `uploadpack`
+//! options do. Tips are those of the request's namespace. Dumb HTTP clients
+//! ask for objects one at a time, so they get what those tips reach.
+
+use super::namespace::Namespace;
+use super::repository::GitRepoState;
+use crate::error::GitError;
+use crate::host::log;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+/// Sorted ref tips and the objects they reach
+type Reachable = (Vec<String>, Rc<HashSet<String>>);
+
+thread_local! {
+    // Objects reachable from the last set of tips asked about (sorted), which
+    // dumb HTTP needs for every object it serves. Objects never change, so
+    // the same tips always reach the same ones.
+    static REACHABLE: RefCell<Option<Reachable>> = const { RefCell::new(None) };
+}
+
+/// Whether `patterns` hide a ref, given its name within the request's
+/// namespace and its stored name (which `^` patterns match). Each pattern is
+/// a ref prefix ending at a `/`, or is unhidden with `!`; as in git, the last
+/// matching pattern wins.
+pub fn ref_is_hidden(patterns: &[String], name: &str, full_name: &str) -> bool {
+    for pattern in patterns.iter().rev() {
+        let (unhide, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern.as_str()),
+        };
+        let (subject, pattern) = match pattern.strip_prefix('^') {
+            Some(pattern) => (full_name, pattern),
+            None => (name, pattern),
+        };
+        let matches = subject
+            .strip_prefix(pattern.trim_end_matches('/'))
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+        if matches {
+            return !unhide;
+        }
+    }
+    false
+}
+
+impl GitRepoState {
+    /// Whether `namespace`'s ref `name` is hidden from its clients
+    pub fn is_hidden_ref(&self, namespace: &Namespace, name: &str) -> bool {
+        let patterns = &self.config.transfer.hide_refs;
+        !patterns.is_empty() && ref_is_hidden(patterns, name, &namespace.full_name(name))
+    }
+
+    /// What `HEAD` resolves to in `namespace`, unless it's hidden
+    pub fn advertised_head(&self, namespace: &Namespace) -> Option<&String> {
+        if self.is_hidden_ref(namespace, "HEAD") {
+            return None;
+        }
+        self.namespace_resolve(namespace, "HEAD")
+    }
+
+    /// The refs in `namespace` that aren't hidden, as `namespace_refs`
+    /// lists them
+    pub fn advertised_refs(&self, namespace: &Namespace) -> Vec<(&str, &String)> {
+        self.namespace_refs(namespace)
+            .into_iter()
+            .filter(|(name, _)| !self.is_hidden_ref(namespace, name))
+            .collect()
+    }
+
+    /// Refs some namespace advertises, by stored name
+    pub fn visible_refs(&self) -> Vec<(&String, &String)> {
+        self.refs
+            .iter()
+            .filter(|(full_name, _)| {
+                let (namespace, name) = Namespace::of(full_name);
+                !self.is_hidden_ref(&namespace, name)
+            })
+            .collect()
+    }
+
+    /// Objects clients may learn about: what visible refs reach, or
+    /// everything (`None`) with `allow_any_sha1_in_want`
+    pub fn visible_objects(&self) -> Option<HashSet<String>> {
+        if self.config.transfer.allow_any_sha1_in_want {
+            return None;
+        }
+        Some(self.reachable_objects(self.visible_refs().into_iter().map(|(_, id)| id)))
+    }
+
+    /// Objects a dumb HTTP client of `namespace` may download: what the tips
+    /// a fetch may want reach, or everything (`None`) with
+    /// `allow_any_sha1_in_want`
+    pub fn fetchable_objects(&self, namespace: &Namespace) -> Option<Rc<HashSet<String>>> {
+        if self.config.transfer.allow_any_sha1_in_want {
+            return None;
+        }
+        let mut tips: Vec<String> = self.want_tips(namespace).into_iter().collect();
+        tips.sort();
+        let cached = REACHABLE.with(|cache| match &*cache.borrow() {
+            Some((cached, reachable)) if *cached == tips => Some(reachable.clone()),
+            _ => None,
+        });
+        if cached.is_some() {
+            return cached;
+        }
+        let reachable = Rc::new(self.reachable_objects(tips.iter()));
+        REACHABLE.with(|cache| *cache.borrow_mut() = Some((tips, reachable.clone())));
+        Some(reachable)
+    }
+
+    /// Refuse a fetch that wants anything but the tips of `namespace`'s
+    /// advertised refs, unless the `allow_*_sha1_in_want` settings allow it
+    pub fn check_wants(&self, wants: &[String], namespace: &Namespace) -> Result<(), GitError> {
+        let transfer = &self.config.transfer;
+        if transfer.allow_any_sha1_in_want {
+            return Ok(());
+        }
+
+        let tips = self.want_tips(namespace);
+        let mut refused: Vec<&String> = wants.iter().filter(|id| !tips.contains(*id)).collect();
+        if !refused.is_empty() && transfer.allow_reachable_sha1_in_want {
+            let reachable = self.reachable_objects(tips.iter());
+            refused.retain(|id| !reachable.contains(*id));
+        }
+        match refused.first() {
+            Some(id) => {
+                log(&format!("Refusing want {}: not a ref tip", id));
+                Err(GitError::PermissionDenied(format!("not our ref {}", id)))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// What a fetch from `namespace` may want by id: its advertised tips and
+    /// what their annotated tags point to, hidden tips included with
+    /// `allow_tip_sha1_in_want` or `allow_reachable_sha1_in_want`
+    fn want_tips(&self, namespace: &Namespace) -> HashSet<String> {
+        let transfer = &self.config.transfer;
+        let (head, refs) =
+            if transfer.allow_tip_sha1_in_want || transfer.allow_reachable_sha1_in_want {
+                (
+                    self.namespace_resolve(namespace, "HEAD"),
+                    self.namespace_refs(namespace),
+                )
+            } else {
+                (
+                    self.advertised_head(namespace),
+                    self.advertised_refs(namespace),
+                )
+            };
+        let mut tips: HashSet<String> = head
+            .into_iter()
+            .chain(refs.into_iter().map(|(_, id)| id))
+            .cloned()
+            .collect();
+        let peeled: Vec<String> = tips.iter().filter_map(|id| self.peel_tag(id)).collect();
+        tips.extend(peeled);
+        tips
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::test_support::commit;
+    use crate::HttpRequest;
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn test_hide_ref_patterns() {
+        let hide = patterns(&["refs/pull", "!refs/pull/open/", "^refs/namespaces/ci"]);
+        let hidden = |name: &str, full_name: &str| ref_is_hidden(&hide, name, full_name);
+
+        assert!(hidden("refs/pull", "refs/pull"));
+        assert!(hidden("refs/pull/1/head", "refs/pull/1/head"));
+        assert!(!hidden("refs/pulls", "refs/pulls"));
+        assert!(!hidden("refs/pull/open/2", "refs/pull/open/2"));
+        assert!(!hidden("refs/heads/main", "refs/heads/main"));
+        assert!(hidden(
+            "refs/heads/main",
+            "refs/namespaces/ci/refs/heads/main"
+        ));
+        assert!(!ref_is_hidden(&[], "refs/pull/1", "refs/pull/1"));
+    }
+
+    #[test]
+    fn test_wants_must_be_tips_unless_allowed() {
+        let mut repo = GitRepoState::default();
+        repo.config.transfer.hide_refs = patterns(&["refs/hidden"]);
+        let base = commit(&mut repo, "base", 0, vec![]);
+        let main = commit(&mut repo, "main", 0, vec![base.clone()]);
+        let secret = commit(&mut repo, "secret", 0, vec![]);
+        let orphan = commit(&mut repo, "deleted branch", 0, vec![]);
+        repo.update_ref("refs/heads/main".into(), main.clone())
+            .unwrap();
+        repo.update_ref("refs/hidden/secret".into(), secret.clone())
+            .unwrap();
+        let root = Namespace::default();
+        let names: Vec<&str> = repo
+            .advertised_refs(&root)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["refs/heads/main"]);
+
+        let check = |repo: &GitRepoState, want: &String| {
+            repo.check_wants(std::slice::from_ref(want), &root)
+        };
+        assert_eq!(check(&repo, &main), Ok(()));
+        for want in [&base, &secret, &orphan] {
+            assert!(matches!(
+                check(&repo, want),
+                Err(GitError::PermissionDenied(_))
+            ));
+        }
+
+        repo.config.transfer.allow_tip_sha1_in_want = true;
+        assert_eq!(check(&repo, &secret), Ok(()));
+        assert!(check(&repo, &base).is_err());
+
+        repo.config.transfer.allow_reachable_sha1_in_want = true;
+        assert_eq!(check(&repo, &base), Ok(()));
+        assert!(check(&repo, &orphan).is_err());
+
+        repo.config.transfer.allow_any_sha1_in_want = true;
+        assert_eq!(check(&repo, &orphan), Ok(()));
+
+        // Another namespace's tips aren't this one's
+        repo.config.transfer = Default::default();
+        let fork = Namespace::new("fork").unwrap();
+        assert!(repo.check_wants(&[main], &fork).is_err());
+    }
+
+    #[test]
+    fn test_debug_pages_leave_out_hidden_refs_and_their_objects() {
+        let mut repo = GitRepoState::default();
+        assert!(!repo.config.features.debug_routes);
+        repo.config.features.debug_routes = true;
+        repo.config.transfer.hide_refs = patterns(&["refs/hidden"]);
+        let main = commit(&mut repo, "main", 0, vec![]);
+        let secret = commit(&mut repo, "secret", 0, vec![]);
+        let orphan = commit(&mut repo, "deleted branch", 0, vec![]);
+        repo.update_ref("refs/heads/main".into(), main.clone())
+            .unwrap();
+        repo.update_ref("refs/hidden/secret".into(), secret.clone())
+            .unwrap();
+        repo.update_ref("refs/namespaces/fork/refs/hidden/x".into(), secret.clone())
+            .unwrap();
+
+        let page = |repo: &mut GitRepoState, path: &str| {
+            let response = repo.dispatch(&HttpRequest {
+                method: "GET".to_string(),
+                uri: path.to_string(),
+                headers: vec![],
+                body: None,
+            });
+            String::from_utf8(response.body.unwrap_or_default()).unwrap()
+        };
+        assert_eq!(
+            page(&mut repo, "/refs"),
+            format!("refs/heads/main : {}\n", main)
+        );
+        let objects = page(&mut repo, "/objects");
+        assert_eq!(objects.lines().count(), 3);
+        assert!(objects.contains(&main));
+        assert!(!objects.contains(&secret) && !objects.contains(&orphan));
+
+        repo.config.transfer.allow_any_sha1_in_want = true;
+        assert!(page(&mut repo, "/objects").contains(&orphan));
+    }
+
+    #[test]
+    fn test_dumb_http_serves_only_fetchable_objects() {
+        let mut repo = GitRepoState::default();
+        assert!(repo.config.features.dumb_http);
+        repo.config.transfer.hide_refs = patterns(&["refs/hidden"]);
+        let base = commit(&mut repo, "base", 0, vec![]);
+        let main = commit(&mut repo, "main", 0, vec![base.clone()]);
+        let secret = commit(&mut repo, "secret", 0, vec![]);
+        let orphan = commit(&mut repo, "deleted branch", 0, vec![]);
+        repo.update_ref("refs/heads/main".into(), main.clone())
+            .unwrap();
+        repo.update_ref("refs/hidden/secret".into(), secret.clone())
+            .unwrap();
+
+        let status = |repo: &mut GitRepoState, id: &str| {
+            repo.dispatch(&HttpRequest {
+                method: "GET".to_string(),
+                uri: format!("/objects/{}/{}", &id[..2], &id[2..]),
+                headers: vec![],
+                body: None,
+            })
+            .status
+        };
+        assert_eq!(status(&mut repo, &main), 200);
+        assert_eq!(status(&mut repo, &base), 200);
+        assert_eq!(status(&mut repo, &secret), 404);
+        assert_eq!(status(&mut repo, &orphan), 404);
+
+        repo.config.transfer.allow_tip_sha1_in_want = true;
+        assert_eq!(status(&mut repo, &secret), 200);
+        assert_eq!(status(&mut repo, &orphan), 404);
+
+        repo.config.transfer.allow_any_sha1_in_want = true;
+        assert_eq!(status(&mut repo, &orphan), 200);
+    }
+}
@@ -0,0 +1,629 @@
+//! Outgoing webhooks.
+//!
+//! Each endpoint in `webhooks.endpoints` is POSTed a JSON payload for the
+//! events it subscribes to, through the Theater `http-client`:
+//!
+//! - `push`: one per ref a push changed, with the commits it added
+//! - `create` / `delete`: a branch or tag was created or deleted by a push
+//! - `repository`: the repository was created
+//!
+//! Payloads are signed with the endpoint's secret like GitHub's
+//! (`X-Hub-Signature-256: sha256=<HMAC-SHA256 hex>`). Events are queued with
+//! the repository and sent after the request that caused them. Each delivery
+//! remembers when its next attempt is due, so a failed one is retried with
+//! doubling waits without holding up the actor, and each one ends up in the
+//! delivery log served at `GET /admin/webhooks/deliveries`.
+
+use super::namespace::Namespace;
+use super::objects::GitObject;
+use super::reflog::RefUpdateContext;
+use super::repository::GitRepoState;
+use super::signature::Signature;
+use crate::bindings::theater::simple::http_types::{HttpRequest, HttpResponse};
+use crate::host::{self, log};
+use crate::protocol::http::{create_response, query_param};
+use crate::utils::hash::hmac_sha256;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+/// Most commits listed in a push payload (the newest ones)
+pub const MAX_PAYLOAD_COMMITS: usize = 20;
+
+/// Deliveries waiting to be sent, and the log of finished ones
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct WebhookState {
+    /// Oldest first
+    pub pending: Vec<PendingDelivery>,
+    /// Oldest first, at most `webhooks.delivery_log_size`
+    pub log: Vec<Delivery>,
+    /// Id of the next queued delivery
+    pub next_id: u64,
+}
+
+/// A payload queued for one endpoint
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PendingDelivery {
+    pub id: u64,
+    pub event: String,
+    pub url: String,
+    /// `X-Hub-Signature-256` value, when the endpoint has a secret
+    pub signature: Option<String>,
+    /// JSON
+    pub payload: Vec<u8>,
+    pub queued_ms: u64,
+    /// Attempts made so far
+    pub attempts: u32,
+    /// When the next attempt may be made
+    pub due_ms: u64,
+    /// Status of the last response, if there was one
+    pub status: Option<u16>,
+    /// Why the last attempt failed
+    pub error: Option<String>,
+}
+
+/// How a delivery went
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Delivery {
+    pub id: u64,
+    pub event: String,
+    pub url: String,
+    pub queued_ms: u64,
+    /// When the last attempt finished
+    pub finished_ms: u64,
+    pub attempts: u32,
+    /// Whether the endpoint answered with a 2xx status
+    pub delivered: bool,
+    /// Status of the last response, if there was one
+    pub status: Option<u16>,
+    /// Why the last attempt failed
+    pub error: Option<String>,
+}
+
+impl GitRepoState {
+    /// Whether deliveries are waiting to be sent
+    pub fn webhooks_pending(&self) -> bool {
+        !self.webhooks.pending.is_empty()
+    }
+
+    /// Queue `payload` for every endpoint subscribed to `event`
+    pub fn queue_webhook(&mut self, event: &str, payload: &Value) {
+        let payload = match serde_json::to_vec(payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                log(&format!("❌ Failed to encode {} webhook: {}", event, e));
+                return;
+            }
+        };
+        let now = host::now();
+        for endpoint in &self.config.webhooks.endpoints {
+            if !endpoint.events.iter().any(|e| e == event || e == "*") {
+                continue;
+            }
+            let signature = endpoint.secret.as_ref().map(|secret| {
+                format!(
+                    "sha256={}",
+                    hex::encode(hmac_sha256(secret.as_bytes(), &payload))
+                )
+            });
+            self.webhooks.pending.push(PendingDelivery {
+                id: self.webhooks.next_id,
+                event: event.to_string(),
+                url: endpoint.url.clone(),
+                signature,
+                payload: payload.clone(),
+                queued_ms: now,
+                attempts: 0,
+                due_ms: now,
+                status: None,
+                error: None,
+            });
+            self.webhooks.next_id += 1;
+        }
+    }
+
+    /// Queue the `repository` event for a newly created repository
+    pub fn queue_repository_created(&mut self) {
+        let payload = json!({
+            "action": "created",
+            "repository": self.webhook_repository(&Namespace::default()),
+        });
+        self.queue_webhook("repository", &payload);
+    }
+
+    /// Queue the `push`, `create` and `delete` events for the ref updates a
+    /// push made (stored names, in `namespace`)
+    pub fn queue_push_webhooks(
+        &mut self,
+        applied: &[(String, String, String)],
+        context: &RefUpdateContext,
+        namespace: &Namespace,
+    ) {
+        if self.config.webhooks.endpoints.is_empty() {
+            return;
+        }
+        let repository = self.webhook_repository(namespace);
+        let pusher = json!({ "name": context.identity });
+
+        for (full_name, before, after) in applied {
+            let ref_name = namespace.strip(full_name).unwrap_or(full_name);
+            let created = self.object_format.is_null_oid(before);
+            let deleted = self.object_format.is_null_oid(after);
+            let commits = if deleted {
+                Vec::new()
+            } else {
+                self.pushed_commits(full_name, before, after)
+            };
+            let forced =
+                !created && !deleted && !self.commit_ancestry([after.as_str()]).contains(before);
+            let head_commit = if deleted {
+                Value::Null
+            } else {
+                self.commit_json(after).unwrap_or(Value::Null)
+            };
+
+            let payload = json!({
+                "ref": ref_name,
+                "before": before,
+                "after": after,
+                "created": created,
+                "deleted": deleted,
+                "forced": forced,
+                "repository": repository,
+                "pusher": pusher,
+                "push_options": context.push_options,
+                "commits": commits,
+                "head_commit": head_commit,
+            });
+            self.queue_webhook("push", &payload);
+
+            let short_name = ref_name
+                .strip_prefix("refs/heads/")
+                .map(|name| (name, "branch"))
+                .or_else(|| {
+                    ref_name
+                        .strip_prefix("refs/tags/")
+                        .map(|name| (name, "tag"))
+                });
+            if let (Some((name, ref_type)), true) = (short_name, created || deleted) {
+                let payload = json!({
+                    "ref": name,
+                    "ref_type": ref_type,
+                    "repository": repository,
+                    "pusher": pusher,
+                });
+                self.queue_webhook(if created { "create" } else { "delete" }, &payload);
+            }
+        }
+    }
+
+    fn webhook_repository(&self, namespace: &Namespace) -> Value {
+        json!({
+            "name": self.repo_name,
+            "namespace": namespace.name(),
+            "default_branch": self.namespace_head(namespace),
+            "object_format": self.object_format.name(),
+        })
+    }
+
+    /// The commits an update of `ref_name` to `after` added, oldest first and
+    /// at most `MAX_PAYLOAD_COMMITS`: those not reachable from `before`, or
+    /// for a new ref from any other ref
+    pub fn pushed_commits(&self, ref_name: &str, before: &str, after: &str) -> Vec<Value> {
+        let known = if self.object_format.is_null_oid(before) {
+            self.commit_ancestry(
+                self.refs
+                    .iter()
+                    .filter(|(name, _)| *name != ref_name)
+                    .map(|(_, id)| id.as_str()),
+            )
+        } else {
+            self.commit_ancestry([before])
+        };
+
+        let mut added = Vec::new();
+        let mut seen = HashSet::new();
+        let mut pending = vec![after.to_string()];
+        while let Some(id) = pending.pop() {
+            if known.contains(&id) || !seen.insert(id.clone()) {
+                continue;
+            }
+            let Some(object) = self.objects.get(&id) else {
+                continue;
+            };
+            let time = match object.committer() {
+                Some(Ok(committer)) => committer.timestamp,
+                _ => 0,
+            };
+            match object {
+                GitObject::Commit { parents, .. } => {
+                    added.push((time, id));
+                    pending.extend(parents);
+                }
+                GitObject::Tag { object, .. } => pending.push(object),
+                _ => {}
+            }
+        }
+
+        added.sort();
+        let skip = added.len().saturating_sub(MAX_PAYLOAD_COMMITS);
+        added
+            .iter()
+            .skip(skip)
+            .filter_map(|(_, id)| self.commit_json(id))
+            .collect()
+    }
+
+    /// Every commit reachable from `tips`, peeling tags
+    pub fn commit_ancestry<'a>(&self, tips: impl IntoIterator<Item = &'a str>) -> HashSet<String> {
+        let mut seen = HashSet::new();
+        let mut pending: Vec<String> = tips.into_iter().map(str::to_string).collect();
+        while let Some(id) = pending.pop() {
+            if seen.contains(&id) {
+                continue;
+            }
+            match self.objects.get(&id) {
+                Some(GitObject::Commit { parents, .. }) => {
+                    pending.extend(parents);
+                    seen.insert(id);
+                }
+                Some(GitObject::Tag { object, .. }) => pending.push(object),
+                _ => {}
+            }
+        }
+        seen
+    }
+
+    fn commit_json(&self, id: &str) -> Option<Value> {
+        let object = self.objects.get(id)?;
+        let GitObject::Commit { message, .. } = &object else {
+            return None;
+        };
+        let committer = object.committer().and_then(Result::ok);
+        Some(json!({
+            "id": id,
+            "message": message,
+            "timestamp": committer.as_ref().map(|signature| signature.timestamp),
+            "author": person(object.author().and_then(Result::ok).as_ref()),
+            "committer": person(committer.as_ref()),
+        }))
+    }
+
+    /// Make one attempt at each delivery that is due, and log the ones that
+    /// succeeded or ran out of attempts. The others stay queued for a retry
+    /// `retry_backoff_ms` later, doubled for each retry after the first.
+    /// Returns whether any delivery was attempted.
+    pub fn deliver_webhooks(&mut self) -> bool {
+        let settings = self.config.webhooks.clone();
+        let now = host::now();
+        let (due, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.webhooks.pending)
+            .into_iter()
+            .partition(|pending| pending.due_ms <= now);
+        self.webhooks.pending = waiting;
+        if due.is_empty() {
+            return false;
+        }
+
+        for mut pending in due {
+            let mut headers = vec![
+                ("Content-Type".to_string(), "application/json".to_string()),
+                ("User-Agent".to_string(), self.config.agent.clone()),
+                ("X-Git-Server-Event".to_string(), pending.event.clone()),
+                ("X-Git-Server-Delivery".to_string(), pending.id.to_string()),
+            ];
+            if let Some(signature) = &pending.signature {
+                headers.push(("X-Hub-Signature-256".to_string(), signature.clone()));
+            }
+            let request = HttpRequest {
+                method: "POST".to_string(),
+                uri: pending.url.clone(),
+                headers,
+                body: Some(pending.payload.clone()),
+            };
+
+            pending.attempts += 1;
+            let delivered = match host::send_http(&request) {
+                Ok(response) if (200..300).contains(&response.status) => {
+                    pending.status = Some(response.status);
+                    pending.error = None;
+                    true
+                }
+                Ok(response) => {
+                    pending.status = Some(response.status);
+                    pending.error = Some(format!("HTTP {}", response.status));
+                    false
+                }
+                Err(e) => {
+                    pending.status = None;
+                    pending.error = Some(e);
+                    false
+                }
+            };
+            let finished_ms = host::now();
+
+            if !delivered && pending.attempts < settings.max_attempts {
+                let wait = settings
+                    .retry_backoff_ms
+                    .saturating_mul(1 << (pending.attempts - 1).min(16));
+                pending.due_ms = finished_ms.saturating_add(wait);
+                self.webhooks.pending.push(pending);
+                continue;
+            }
+
+            match &pending.error {
+                None => log(&format!(
+                    "✅ Delivered {} webhook {} to {}",
+                    pending.event, pending.id, pending.url
+                )),
+                Some(e) => log(&format!(
+                    "❌ {} webhook {} to {} failed after {} attempts: {}",
+                    pending.event, pending.id, pending.url, pending.attempts, e
+                )),
+            }
+            self.webhooks.log.push(Delivery {
+                id: pending.id,
+                event: pending.event,
+                url: pending.url,
+                queued_ms: pending.queued_ms,
+                finished_ms,
+                attempts: pending.attempts,
+                delivered,
+                status: pending.status,
+                error: pending.error,
+            });
+        }
+        // Keep the queue oldest first
+        self.webhooks.pending.sort_by_key(|pending| pending.id);
+
+        let excess = self
+            .webhooks
+            .log
+            .len()
+            .saturating_sub(settings.delivery_log_size);
+        self.webhooks.log.drain(..excess);
+        true
+    }
+
+    /// Handle GET /admin/webhooks/deliveries[?event=<name>] - The delivery
+    /// log as JSON, newest first, with how many deliveries are still queued
+    pub fn admin_webhook_deliveries(
+        &self,
+        request: &HttpRequest,
+        query: &Option<String>,
+    ) -> HttpResponse {
+        if request.method != "GET" {
+            return create_response(405, "text/plain", b"Method Not Allowed");
+        }
+        let event = query_param(query, "event");
+        let deliveries: Vec<&Delivery> = self
+            .webhooks
+            .log
+            .iter()
+            .rev()
+            .filter(|delivery| event.is_none_or(|event| delivery.event == event))
+            .collect();
+        let body = json!({
+            "pending": self.webhooks.pending.len(),
+            "deliveries": deliveries,
+        });
+        match serde_json::to_vec(&body) {
+            Ok(body) => create_response(200, "application/json", &body),
+            Err(e) => create_response(500, "text/plain", e.to_string().as_bytes()),
+        }
+    }
+}
+
+/// Name and email of a signature, `null` when it doesn't parse
+fn person(signature: Option<&Signature>) -> Value {
+    signature.map_or(
+        Value::Null,
+        |signature| json!({ "name": signature.name, "email": signature.email }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WebhookEndpoint;
+    use crate::git::test_support::commit;
+    use crate::host::register_native_http_client;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn endpoint(url: &str, events: &[&str]) -> WebhookEndpoint {
+        WebhookEndpoint {
+            url: url.to_string(),
+            secret: Some("s3cret".to_string()),
+            events: events.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_push_payloads_list_new_commits() {
+        let mut repo = GitRepoState::default();
+        repo.config.webhooks.endpoints = vec![
+            endpoint("http://ci.example.com/hook", &["push"]),
+            endpoint("http://chat.example.com/hook", &["create", "delete"]),
+        ];
+        let base = commit(&mut repo, "base", 100, vec![]);
+        let first = commit(&mut repo, "first", 200, vec![base.clone()]);
+        let second = commit(&mut repo, "second", 300, vec![first.clone()]);
+        let null = repo.object_format.null_oid();
+        repo.update_ref("refs/heads/main".into(), base.clone())
+            .unwrap();
+        repo.update_ref("refs/heads/topic".into(), second.clone())
+            .unwrap();
+        let context = RefUpdateContext::push("alice", &[]);
+
+        repo.queue_push_webhooks(
+            &[
+                ("refs/heads/main".into(), base.clone(), second.clone()),
+                ("refs/heads/topic".into(), null.clone(), second.clone()),
+            ],
+            &context,
+            &Namespace::default(),
+        );
+        let events: Vec<(&str, &str)> = repo
+            .webhooks
+            .pending
+            .iter()
+            .map(|d| (d.event.as_str(), d.url.as_str()))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                ("push", "http://ci.example.com/hook"),
+                ("push", "http://ci.example.com/hook"),
+                ("create", "http://chat.example.com/hook"),
+            ]
+        );
+
+        let main: Value = serde_json::from_slice(&repo.webhooks.pending[0].payload).unwrap();
+        let messages: Vec<&str> = main["commits"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["message"].as_str().unwrap())
+            .collect();
+        assert_eq!(messages, ["first", "second"]);
+        assert_eq!(main["forced"], false);
+        assert_eq!(main["head_commit"]["id"], second.as_str());
+        assert_eq!(main["commits"][0]["author"]["name"], "Ada Lovelace");
+        assert_eq!(main["commits"][0]["author"]["email"], "ada@example.com");
+        assert_eq!(main["commits"][0]["timestamp"], 200);
+        assert_eq!(main["pusher"]["name"], "alice");
+
+        // A new branch only lists commits no other branch has
+        let topic: Value = serde_json::from_slice(&repo.webhooks.pending[1].payload).unwrap();
+        assert_eq!(topic["created"], true);
+        assert_eq!(topic["commits"].as_array().unwrap().len(), 2);
+        let create: Value = serde_json::from_slice(&repo.webhooks.pending[2].payload).unwrap();
+        assert_eq!(
+            create,
+            json!({
+                "ref": "topic",
+                "ref_type": "branch",
+                "repository": main["repository"],
+                "pusher": {"name": "alice"},
+            })
+        );
+
+        // Signed with the endpoint's secret
+        let pending = &repo.webhooks.pending[0];
+        assert_eq!(
+            pending.signature,
+            Some(format!(
+                "sha256={}",
+                hex::encode(hmac_sha256(b"s3cret", &pending.payload))
+            ))
+        );
+
+        // Rewinding is a forced push
+        repo.webhooks = WebhookState::default();
+        let other = commit(&mut repo, "other", 400, vec![base.clone()]);
+        repo.queue_push_webhooks(
+            &[("refs/heads/main".into(), second, other)],
+            &context,
+            &Namespace::default(),
+        );
+        let forced: Value = serde_json::from_slice(&repo.webhooks.pending[0].payload).unwrap();
+        assert_eq!(forced["forced"], true);
+        assert_eq!(forced["commits"].as_array().unwrap().len(), 1);
+
+        // Identities git couldn't parse either are left out, not guessed at
+        let odd = GitObject::Commit {
+            tree: repo.object_format.null_oid(),
+            parents: vec![],
+            author: "Ada <Lovelace> <ada@example.com> 100 +0000".into(),
+            committer: "Ada Lovelace <ada@example.com> 100 +0000".into(),
+            message: "odd".into(),
+        };
+        let odd_id = odd.compute_hash(repo.object_format);
+        repo.add_object(odd_id.clone(), odd).unwrap();
+        let odd = repo.commit_json(&odd_id).unwrap();
+        assert_eq!(odd["author"], Value::Null);
+        assert_eq!(odd["committer"]["name"], "Ada Lovelace");
+        assert_eq!(odd["timestamp"], 100);
+    }
+
+    #[test]
+    fn test_failed_deliveries_are_retried_and_logged() {
+        let mut repo = GitRepoState::default();
+        repo.config.webhooks.endpoints = vec![endpoint("http://ci.example.com/hook", &["*"])];
+        repo.config.webhooks.retry_backoff_ms = 60_000;
+        repo.config.webhooks.delivery_log_size = 2;
+
+        // Fails twice, then succeeds
+        let requests = Rc::new(RefCell::new(Vec::new()));
+        let seen = requests.clone();
+        register_native_http_client(Rc::new(move |request| {
+            seen.borrow_mut().push(request.clone());
+            match seen.borrow().len() {
+                1 => Err("connection refused".to_string()),
+                2 => Ok(HttpResponse {
+                    status: 502,
+                    headers: vec![],
+                    body: None,
+                }),
+                _ => Ok(HttpResponse {
+                    status: 204,
+                    headers: vec![],
+                    body: None,
+                }),
+            }
+        }));
+
+        // One attempt per call; a failure waits for its due time, doubling
+        repo.queue_repository_created();
+        assert!(repo.webhooks_pending());
+        assert!(repo.deliver_webhooks());
+        let first = repo.webhooks.pending[0].clone();
+        assert_eq!(first.attempts, 1);
+        assert_eq!(first.error.as_deref(), Some("connection refused"));
+        assert!(first.due_ms >= first.queued_ms + 60_000);
+        assert!(!repo.deliver_webhooks());
+        assert_eq!(requests.borrow().len(), 1);
+
+        repo.webhooks.pending[0].due_ms = 0;
+        assert!(repo.deliver_webhooks());
+        let second = repo.webhooks.pending[0].clone();
+        assert_eq!(second.attempts, 2);
+        assert_eq!(second.status, Some(502));
+        assert!(second.due_ms >= first.due_ms + 60_000);
+        assert!(repo.webhooks.log.is_empty());
+
+        repo.webhooks.pending[0].due_ms = 0;
+        assert!(repo.deliver_webhooks());
+        assert!(!repo.webhooks_pending());
+
+        let delivery = &repo.webhooks.log[0];
+        assert!(delivery.delivered);
+        assert_eq!(delivery.attempts, 3);
+        assert_eq!(delivery.status, Some(204));
+        let request = &requests.borrow()[2];
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.uri, "http://ci.example.com/hook");
+        assert!(request
+            .headers
+            .contains(&("X-Git-Server-Event".to_string(), "repository".to_string())));
+        assert!(request
+            .headers
+            .iter()
+            .any(|(name, value)| name == "X-Hub-Signature-256" && value.starts_with("sha256=")));
+
+        // Gives up after max_attempts, and the log keeps the newest entries
+        repo.config.webhooks.max_attempts = 1;
+        register_native_http_client(Rc::new(|_| Err("connection refused".to_string())));
+        repo.queue_repository_created();
+        repo.queue_repository_created();
+        assert!(repo.deliver_webhooks());
+        assert!(!repo.webhooks_pending());
+        let ids: Vec<u64> = repo.webhooks.log.iter().map(|d| d.id).collect();
+        assert_eq!(ids, [1, 2]);
+        assert!(!repo.webhooks.log[1].delivered);
+        assert_eq!(
+            repo.webhooks.log[1].error.as_deref(),
+            Some("connection refused")
+        );
+    }
+}
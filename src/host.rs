@@ -0,0 +1,560 @@
+//! Host Services
+//!
+//! The git core (`git::*`, `protocol::*`, `utils::*`) never calls the Theater
+//! bindings directly. Everything it needs from its environment goes through
+//! the `Host` trait, so the same code runs inside the actor and in native
+//! `cargo test` builds.
+//!
+//! - On `wasm32` the default host forwards to the Theater runtime imports;
+//!   repository files live in a Theater content store, addressed by label,
+//!   and paths go through the Theater `filesystem` interface.
+//! - Natively the default host writes log lines to stderr (captured by the
+//!   test harness unless a test fails), keeps storage areas in memory, uses
+//!   the real filesystem for paths, delivers messages to actors stood in
+//!   for by `register_native_actor` and HTTP requests to the client set by
+//!   `register_native_http_client`.
+//!
+//! Embedders and tests can swap the host with `set_host`.
+
+use crate::bindings::theater::simple::http_types::{HttpRequest, HttpResponse};
+use std::cell::RefCell;
+#[cfg(not(target_arch = "wasm32"))]
+use std::collections::HashMap;
+
+/// Environment services required by the git core
+pub trait Host {
+    /// Emit a diagnostic log line
+    fn log(&self, msg: &str);
+
+    /// Look up an environment variable (`None` if unset or not accessible)
+    fn get_var(&self, name: &str) -> Option<String>;
+
+    /// Current time in milliseconds since the Unix epoch
+    fn now(&self) -> u64;
+
+    /// Create a storage area for repository files, returning its id
+    fn create_storage(&self) -> Result<String, String>;
+
+    /// Read a file from a storage area (`None` if it doesn't exist)
+    fn read_file(&self, storage: &str, path: &str) -> Result<Option<Vec<u8>>, String>;
+
+    /// Create or replace a file in a storage area
+    fn write_file(&self, storage: &str, path: &str, data: &[u8]) -> Result<(), String>;
+
+    /// Remove a file from a storage area; removing a missing file is not an error
+    fn remove_file(&self, storage: &str, path: &str) -> Result<(), String>;
+
+    /// Read a file on the filesystem (`None` if it doesn't exist)
+    fn read_path(&self, path: &str) -> Result<Option<Vec<u8>>, String>;
+
+    /// Create or replace a file on the filesystem; its directory must exist
+    fn write_path(&self, path: &str, data: &[u8]) -> Result<(), String>;
+
+    /// Get ready for `write_path` to write binary (non-UTF-8) files. A host
+    /// that can only write text may write them through a shell when
+    /// `allow_shell` is set, and fails otherwise.
+    fn prepare_binary_writes(&self, allow_shell: bool) -> Result<(), String>;
+
+    /// Names of the entries in a directory (`None` if `path` isn't a directory)
+    fn list_dir(&self, path: &str) -> Result<Option<Vec<String>>, String>;
+
+    /// Create a directory and any missing parents
+    fn create_dir(&self, path: &str) -> Result<(), String>;
+
+    /// Delete a file; deleting a file that doesn't exist is not an error
+    fn remove_path(&self, path: &str) -> Result<(), String>;
+
+    /// Rename a file, replacing `to` if it exists. A host that can't rename
+    /// files itself may do it through a shell once `prepare_binary_writes`
+    /// has allowed that, and fails otherwise.
+    fn rename_path(&self, from: &str, to: &str) -> Result<(), String>;
+
+    /// Send a one-way message to another actor
+    fn send_message(&self, actor: &str, msg: &[u8]) -> Result<(), String>;
+
+    /// Send a request to another actor and wait for its response
+    fn request_message(&self, actor: &str, msg: &[u8]) -> Result<Vec<u8>, String>;
+
+    /// Make an outgoing HTTP request
+    fn send_http(&self, request: &HttpRequest) -> Result<HttpResponse, String>;
+}
+
+/// Host backed by the Theater runtime imports
+#[cfg(target_arch = "wasm32")]
+pub struct TheaterHost;
+
+#[cfg(target_arch = "wasm32")]
+impl Host for TheaterHost {
+    fn log(&self, msg: &str) {
+        crate::bindings::theater::simple::runtime::log(msg);
+    }
+
+    fn get_var(&self, name: &str) -> Option<String> {
+        crate::bindings::theater::simple::environment::get_var(name)
+    }
+
+    fn now(&self) -> u64 {
+        crate::bindings::theater::simple::timing::now()
+    }
+
+    fn create_storage(&self) -> Result<String, String> {
+        crate::bindings::theater::simple::store::new()
+    }
+
+    fn read_file(&self, storage: &str, path: &str) -> Result<Option<Vec<u8>>, String> {
+        use crate::bindings::theater::simple::store;
+        match store::get_by_label(storage, path)? {
+            Some(content) => store::get(storage, &content).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn write_file(&self, storage: &str, path: &str, data: &[u8]) -> Result<(), String> {
+        crate::bindings::theater::simple::store::replace_content_at_label(storage, path, data)
+            .map(|_| ())
+    }
+
+    fn remove_file(&self, storage: &str, path: &str) -> Result<(), String> {
+        // The content itself stays in the content-addressed store
+        use crate::bindings::theater::simple::store;
+        match store::get_by_label(storage, path)? {
+            Some(_) => store::remove_label(storage, path),
+            None => Ok(()),
+        }
+    }
+
+    fn read_path(&self, path: &str) -> Result<Option<Vec<u8>>, String> {
+        use crate::bindings::theater::simple::filesystem;
+        if !filesystem::path_exists(path)? {
+            return Ok(None);
+        }
+        filesystem::read_file(path).map(Some)
+    }
+
+    fn write_path(&self, path: &str, data: &[u8]) -> Result<(), String> {
+        use crate::bindings::theater::simple::filesystem;
+
+        // `write-file` only takes strings. Anything else (zlib data, packs) is
+        // written as base64 next to the target, decoded by `sh` into a
+        // temporary file and renamed into place, so a failed write leaves the
+        // old file and no leftovers behind.
+        if let Ok(text) = std::str::from_utf8(data) {
+            return filesystem::write_file(path, text);
+        }
+        if !SHELL_WRITES.with(|enabled| enabled.get()) {
+            return Err(format!(
+                "can't write binary file {} without `shell_writes`",
+                path
+            ));
+        }
+        let encoded = format!("{}.b64", path);
+        filesystem::write_file(&encoded, &base64_encode(data))?;
+        run_sh(
+            r#"base64 -d "$1" > "$2.tmp" && mv -f "$2.tmp" "$2"; status=$?; rm -f "$1" "$2.tmp"; exit $status"#,
+            &[&encoded, path],
+        )
+        .map_err(|e| format!("writing {} failed: {}", path, e))
+    }
+
+    fn prepare_binary_writes(&self, allow_shell: bool) -> Result<(), String> {
+        if SHELL_WRITES.with(|enabled| enabled.get()) {
+            return Ok(());
+        }
+        if !allow_shell {
+            return Err(
+                "the filesystem interface only writes text; set `shell_writes` \
+                        to write objects and packs through `sh` and `base64`"
+                    .to_string(),
+            );
+        }
+        // Find out now, rather than on the first push, whether the handler
+        // lets us run them
+        run_sh(
+            "command -v base64 >/dev/null && command -v mv >/dev/null",
+            &[],
+        )
+        .map_err(|e| format!("`shell_writes` needs `sh` and `base64`: {}", e))?;
+        SHELL_WRITES.with(|enabled| enabled.set(true));
+        Ok(())
+    }
+
+    fn list_dir(&self, path: &str) -> Result<Option<Vec<String>>, String> {
+        use crate::bindings::theater::simple::filesystem;
+        if !filesystem::path_exists(path)? {
+            return Ok(None);
+        }
+        // Listing a file fails; there is no other way to tell the two apart
+        Ok(filesystem::list_files(path).ok())
+    }
+
+    fn create_dir(&self, path: &str) -> Result<(), String> {
+        use crate::bindings::theater::simple::filesystem;
+        let mut current = String::new();
+        for part in path.split('/').filter(|part| !part.is_empty()) {
+            if !current.is_empty() || path.starts_with('/') {
+                current.push('/');
+            }
+            current.push_str(part);
+            if !filesystem::path_exists(&current)? {
+                filesystem::create_dir(&current)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_path(&self, path: &str) -> Result<(), String> {
+        use crate::bindings::theater::simple::filesystem;
+        if filesystem::path_exists(path)? {
+            filesystem::delete_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn rename_path(&self, from: &str, to: &str) -> Result<(), String> {
+        // The filesystem interface has no rename
+        if !SHELL_WRITES.with(|enabled| enabled.get()) {
+            return Err(format!("can't rename {} without `shell_writes`", from));
+        }
+        run_sh(r#"mv -f "$1" "$2""#, &[from, to])
+            .map_err(|e| format!("renaming {} failed: {}", from, e))
+    }
+
+    fn send_message(&self, actor: &str, msg: &[u8]) -> Result<(), String> {
+        crate::bindings::theater::simple::message_server_host::send(actor, msg)
+    }
+
+    fn request_message(&self, actor: &str, msg: &[u8]) -> Result<Vec<u8>, String> {
+        crate::bindings::theater::simple::message_server_host::request(actor, msg)
+    }
+
+    fn send_http(&self, request: &HttpRequest) -> Result<HttpResponse, String> {
+        crate::bindings::theater::simple::http_client::send_http(request)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+thread_local! {
+    // Whether binary files may be written through `sh` (see `prepare_binary_writes`)
+    static SHELL_WRITES: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Run a `sh -c` script with positional arguments through the filesystem
+/// handler, which must allow it
+#[cfg(target_arch = "wasm32")]
+fn run_sh(script: &str, args: &[&str]) -> Result<(), String> {
+    use crate::bindings::theater::simple::filesystem::{self, CommandResult};
+    let args: Vec<String> = ["-c", script, "sh"]
+        .iter()
+        .chain(args)
+        .map(|arg| arg.to_string())
+        .collect();
+    match filesystem::execute_command(".", "sh", &args)? {
+        CommandResult::Success(output) if output.exit_code == 0 => Ok(()),
+        CommandResult::Success(output) => {
+            Err(format!("exit code {}: {}", output.exit_code, output.stderr))
+        }
+        CommandResult::Error(error) => Err(error.message),
+    }
+}
+
+/// Standard base64 with padding, for passing binary file content as a string
+#[cfg(any(target_arch = "wasm32", test))]
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (i, &byte)| {
+            bits | (byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= group.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Host used for native builds and tests
+#[cfg(not(target_arch = "wasm32"))]
+pub struct NativeHost;
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Host for NativeHost {
+    fn log(&self, msg: &str) {
+        eprintln!("{}", msg);
+    }
+
+    fn get_var(&self, name: &str) -> Option<String> {
+        std::env::var(name).ok()
+    }
+
+    fn now(&self) -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default()
+    }
+
+    fn create_storage(&self) -> Result<String, String> {
+        NATIVE_FILES.with(|files| {
+            let mut files = files.borrow_mut();
+            files.push(HashMap::new());
+            Ok(format!("native-{}", files.len() - 1))
+        })
+    }
+
+    fn read_file(&self, storage: &str, path: &str) -> Result<Option<Vec<u8>>, String> {
+        with_native_storage(storage, |files| Ok(files.get(path).cloned()))
+    }
+
+    fn write_file(&self, storage: &str, path: &str, data: &[u8]) -> Result<(), String> {
+        with_native_storage(storage, |files| {
+            files.insert(path.to_string(), data.to_vec());
+            Ok(())
+        })
+    }
+
+    fn remove_file(&self, storage: &str, path: &str) -> Result<(), String> {
+        with_native_storage(storage, |files| {
+            files.remove(path);
+            Ok(())
+        })
+    }
+
+    fn read_path(&self, path: &str) -> Result<Option<Vec<u8>>, String> {
+        match std::fs::read(path) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("{}: {}", path, e)),
+        }
+    }
+
+    fn write_path(&self, path: &str, data: &[u8]) -> Result<(), String> {
+        std::fs::write(path, data).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn prepare_binary_writes(&self, _allow_shell: bool) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn list_dir(&self, path: &str) -> Result<Option<Vec<String>>, String> {
+        if !std::path::Path::new(path).is_dir() {
+            return Ok(None);
+        }
+        let entries = std::fs::read_dir(path).map_err(|e| format!("{}: {}", path, e))?;
+        entries
+            .map(|entry| {
+                entry
+                    .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                    .map_err(|e| format!("{}: {}", path, e))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Some)
+    }
+
+    fn create_dir(&self, path: &str) -> Result<(), String> {
+        std::fs::create_dir_all(path).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn remove_path(&self, path: &str) -> Result<(), String> {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(format!("{}: {}", path, e)),
+            _ => Ok(()),
+        }
+    }
+
+    fn rename_path(&self, from: &str, to: &str) -> Result<(), String> {
+        std::fs::rename(from, to).map_err(|e| format!("{}: {}", from, e))
+    }
+
+    fn send_message(&self, actor: &str, msg: &[u8]) -> Result<(), String> {
+        self.request_message(actor, msg).map(|_| ())
+    }
+
+    fn request_message(&self, actor: &str, msg: &[u8]) -> Result<Vec<u8>, String> {
+        let handler = NATIVE_ACTORS.with(|actors| actors.borrow().get(actor).cloned());
+        match handler {
+            Some(handler) => handler(msg),
+            None => Err(format!("no actor {}", actor)),
+        }
+    }
+
+    fn send_http(&self, request: &HttpRequest) -> Result<HttpResponse, String> {
+        let client = NATIVE_HTTP_CLIENT.with(|client| client.borrow().clone());
+        match client {
+            Some(client) => client(request),
+            None => Err(format!("no HTTP client for {}", request.uri)),
+        }
+    }
+}
+
+/// What a native stand-in for an actor does with each message it gets,
+/// returning the response to requests
+#[cfg(not(target_arch = "wasm32"))]
+pub type NativeActor = std::rc::Rc<dyn Fn(&[u8]) -> Result<Vec<u8>, String>>;
+
+/// Have the native host deliver messages for `actor` to `handler`
+#[cfg(not(target_arch = "wasm32"))]
+pub fn register_native_actor(actor: &str, handler: NativeActor) {
+    NATIVE_ACTORS.with(|actors| actors.borrow_mut().insert(actor.to_string(), handler));
+}
+
+/// What the native host answers outgoing HTTP requests with
+#[cfg(not(target_arch = "wasm32"))]
+pub type NativeHttpClient = std::rc::Rc<dyn Fn(&HttpRequest) -> Result<HttpResponse, String>>;
+
+/// Have the native host send outgoing HTTP requests to `client`
+#[cfg(not(target_arch = "wasm32"))]
+pub fn register_native_http_client(client: NativeHttpClient) {
+    NATIVE_HTTP_CLIENT.with(|current| *current.borrow_mut() = Some(client));
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+thread_local! {
+    // Storage areas of the native host, indexed by the number in their id
+    static NATIVE_FILES: RefCell<Vec<HashMap<String, Vec<u8>>>> = const { RefCell::new(Vec::new()) };
+
+    // Stand-ins for other actors, by actor id
+    static NATIVE_ACTORS: RefCell<HashMap<String, NativeActor>> = RefCell::new(HashMap::new());
+
+    // Where outgoing HTTP requests go
+    static NATIVE_HTTP_CLIENT: RefCell<Option<NativeHttpClient>> = const { RefCell::new(None) };
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn with_native_storage<T>(
+    storage: &str,
+    f: impl FnOnce(&mut HashMap<String, Vec<u8>>) -> Result<T, String>,
+) -> Result<T, String> {
+    NATIVE_FILES.with(|files| {
+        let mut files = files.borrow_mut();
+        let area = storage
+            .strip_prefix("native-")
+            .and_then(|n| n.parse::<usize>().ok())
+            .and_then(|n| files.get_mut(n))
+            .ok_or_else(|| format!("unknown storage {}", storage))?;
+        f(area)
+    })
+}
+
+fn default_host() -> Box<dyn Host> {
+    #[cfg(target_arch = "wasm32")]
+    {
+        Box::new(TheaterHost)
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        Box::new(NativeHost)
+    }
+}
+
+thread_local! {
+    static HOST: RefCell<Box<dyn Host>> = RefCell::new(default_host());
+}
+
+/// Replace the host used by the current thread
+pub fn set_host(host: Box<dyn Host>) {
+    HOST.with(|current| *current.borrow_mut() = host);
+}
+
+/// Log through the current host
+pub fn log(msg: &str) {
+    HOST.with(|host| host.borrow().log(msg));
+}
+
+/// Environment variable lookup through the current host
+pub fn get_var(name: &str) -> Option<String> {
+    HOST.with(|host| host.borrow().get_var(name))
+}
+
+/// Current time through the current host
+pub fn now() -> u64 {
+    HOST.with(|host| host.borrow().now())
+}
+
+/// Create a storage area through the current host
+pub fn create_storage() -> Result<String, String> {
+    HOST.with(|host| host.borrow().create_storage())
+}
+
+/// Read a stored file through the current host
+pub fn read_file(storage: &str, path: &str) -> Result<Option<Vec<u8>>, String> {
+    HOST.with(|host| host.borrow().read_file(storage, path))
+}
+
+/// Write a stored file through the current host
+pub fn write_file(storage: &str, path: &str, data: &[u8]) -> Result<(), String> {
+    HOST.with(|host| host.borrow().write_file(storage, path, data))
+}
+
+/// Remove a stored file through the current host
+pub fn remove_file(storage: &str, path: &str) -> Result<(), String> {
+    HOST.with(|host| host.borrow().remove_file(storage, path))
+}
+
+/// Read a file on the filesystem through the current host
+pub fn read_path(path: &str) -> Result<Option<Vec<u8>>, String> {
+    HOST.with(|host| host.borrow().read_path(path))
+}
+
+/// Write a file on the filesystem through the current host
+pub fn write_path(path: &str, data: &[u8]) -> Result<(), String> {
+    HOST.with(|host| host.borrow().write_path(path, data))
+}
+
+/// Get the current host ready to write binary files with `write_path`
+pub fn prepare_binary_writes(allow_shell: bool) -> Result<(), String> {
+    HOST.with(|host| host.borrow().prepare_binary_writes(allow_shell))
+}
+
+/// List a directory through the current host
+pub fn list_dir(path: &str) -> Result<Option<Vec<String>>, String> {
+    HOST.with(|host| host.borrow().list_dir(path))
+}
+
+/// Create a directory (and its parents) through the current host
+pub fn create_dir(path: &str) -> Result<(), String> {
+    HOST.with(|host| host.borrow().create_dir(path))
+}
+
+/// Delete a file through the current host
+pub fn remove_path(path: &str) -> Result<(), String> {
+    HOST.with(|host| host.borrow().remove_path(path))
+}
+
+/// Rename a file over another through the current host
+pub fn rename_path(from: &str, to: &str) -> Result<(), String> {
+    HOST.with(|host| host.borrow().rename_path(from, to))
+}
+
+/// Send a one-way message to an actor through the current host
+pub fn send_message(actor: &str, msg: &[u8]) -> Result<(), String> {
+    HOST.with(|host| host.borrow().send_message(actor, msg))
+}
+
+/// Send a request to an actor through the current host
+pub fn request_message(actor: &str, msg: &[u8]) -> Result<Vec<u8>, String> {
+    HOST.with(|host| host.borrow().request_message(actor, msg))
+}
+
+/// Make an outgoing HTTP request through the current host
+pub fn send_http(request: &HttpRequest) -> Result<HttpResponse, String> {
+    HOST.with(|host| host.borrow().send_http(request))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64_encode() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(&[0x78, 0x01, 0xff, 0xfe]), "eAH//g==");
+    }
+}
@@ -1,38 +1,169 @@
-mod git;
-mod protocol;
-mod utils;
+pub mod config;
+pub mod error;
+pub mod git;
+pub mod host;
+pub mod protocol;
+pub mod utils;
 
 #[allow(warnings)]
 mod bindings;
 
+// HTTP request/response types are plain data, shared by the core and the actor glue
+pub use bindings::theater::simple::http_types::{HttpRequest, HttpResponse};
+
 use bindings::exports::theater::simple::actor::Guest;
 use bindings::exports::theater::simple::http_handlers::Guest as HttpHandlers;
 use bindings::exports::theater::simple::http_handlers::{HandlerId, WebsocketMessage};
+use bindings::exports::theater::simple::message_server_client::Guest as MessageServerClient;
 use bindings::theater::simple::http_framework::{self};
-use bindings::theater::simple::http_types::ServerConfig;
-use bindings::theater::simple::http_types::{HttpRequest, HttpResponse, MiddlewareResult};
+use bindings::theater::simple::http_types::MiddlewareResult;
+use bindings::theater::simple::http_types::{ServerConfig, TlsConfig};
+use bindings::theater::simple::message_server_host;
 use bindings::theater::simple::runtime::log;
-use git::objects::GitObject;
+use bindings::theater::simple::types::{ChannelAccept, ChannelId};
+use bindings::theater::simple::websocket_types::MessageType;
+use config::Config;
 use git::repository::GitRepoState;
-use protocol::http::create_response;
+use git::state::{decode_state, encode_state, is_repository_state};
+use protocol::daemon::{error_packet, DaemonSession, DAEMON_PORT};
+use protocol::events::EventStream;
+use std::cell::RefCell;
+use std::collections::HashMap;
 
 struct Component;
 
+thread_local! {
+    // Open git:// connections by channel. They only live as long as the bridged
+    // TCP connection, so they're kept out of the persisted repository state.
+    static DAEMON_SESSIONS: RefCell<HashMap<ChannelId, DaemonSession>> = RefCell::new(HashMap::new());
+
+    // This actor's id, for messages it sends itself
+    static SELF_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+
+    // The HTTP server, and the /events WebSocket connections open on it
+    static SERVER_ID: RefCell<Option<u64>> = const { RefCell::new(None) };
+    static EVENT_STREAM: RefCell<EventStream> = RefCell::new(EventStream::default());
+}
+
+/// Message this actor sends itself to deliver queued webhooks once the
+/// request that queued them has been answered, and again while retries wait
+const DELIVER_WEBHOOKS: &[u8] = b"deliver-webhooks";
+
+/// Have queued webhooks delivered after this request, or right away if the
+/// actor can't message itself
+fn schedule_webhook_delivery(repo: &mut GitRepoState) {
+    if let Err(e) = send_deliver_webhooks() {
+        log(&format!("Delivering webhooks now ({})", e));
+        repo.deliver_webhooks();
+    }
+}
+
+/// Post `DELIVER_WEBHOOKS` to this actor
+fn send_deliver_webhooks() -> Result<(), String> {
+    match SELF_ID.with(|id| id.borrow().clone()) {
+        Some(id) => message_server_host::send(&id, DELIVER_WEBHOOKS),
+        None => Err("actor id unknown".to_string()),
+    }
+}
+
+/// Send the events recorded while handling a request to the `/events`
+/// connections subscribed to them, dropping connections that can't be reached
+fn publish_events(repo: &mut GitRepoState) {
+    let events = repo.take_events();
+    let Some(server_id) = SERVER_ID.with(|id| *id.borrow()) else {
+        return;
+    };
+    EVENT_STREAM.with(|stream| {
+        let mut stream = stream.borrow_mut();
+        for event in events {
+            let recipients = stream.recipients(&event);
+            if recipients.is_empty() {
+                continue;
+            }
+            let message = WebsocketMessage {
+                ty: MessageType::Text,
+                data: None,
+                text: Some(serde_json::to_string(&event).unwrap_or_default()),
+            };
+            for connection in recipients {
+                if let Err(e) =
+                    http_framework::send_websocket_message(server_id, connection, &message)
+                {
+                    log(&format!(
+                        "❌ Dropping event connection {}: {}",
+                        connection, e
+                    ));
+                    stream.disconnect(connection);
+                }
+            }
+        }
+    });
+}
+
+/// Decode the persisted repository. Undecodable state is an error rather than
+/// a fresh repository, so a bad upgrade can never silently discard data.
+fn load_repo(state: Option<&[u8]>) -> Result<GitRepoState, String> {
+    match state {
+        Some(bytes) => decode_state(bytes).map_err(|e| {
+            log(&format!("❌ Refusing to load repository state: {}", e));
+            e.to_string()
+        }),
+        None => Ok(GitRepoState::default()),
+    }
+}
+
+fn save_repo(repo: &GitRepoState) -> Result<Vec<u8>, String> {
+    encode_state(repo).map_err(|e| format!("Failed to serialize state: {}", e))
+}
+
 impl Guest for Component {
     fn init(state: Option<Vec<u8>>, params: (String,)) -> Result<(Option<Vec<u8>>,), String> {
         log("🚀 Initializing git-server actor with Protocol v2!");
         let (self_id,) = params;
         log(&format!("Git server actor ID: {}", &self_id));
+        SELF_ID.with(|id| *id.borrow_mut() = Some(self_id.clone()));
+
+        // Existing state is a repository; anything else is a config document
+        let (existing, base_config) = match state {
+            Some(bytes) if is_repository_state(&bytes) => {
+                let repo = load_repo(Some(&bytes))?;
+                let config = repo.config.clone();
+                (Some(repo), config)
+            }
+            Some(bytes) => {
+                log("Init state is not a repository, reading it as configuration");
+                (None, Config::from_json(&bytes).map_err(|e| e.to_string())?)
+            }
+            None => (None, Config::default()),
+        };
 
-        // Parse existing state or create new
-        let repo_state = match state {
-            Some(bytes) => serde_json::from_slice::<GitRepoState>(&bytes).unwrap_or_else(|_| {
-                log("Failed to parse existing state, creating new");
-                GitRepoState::default()
-            }),
+        // Environment variables override the document, then everything is validated
+        let config = base_config.load().map_err(|e| e.to_string())?;
+
+        let mut repo_state = match existing {
+            existing if config.repo_path.is_some() => {
+                let mut repo = GitRepoState::open_bare(config.clone()).map_err(|e| {
+                    log(&format!("❌ Failed to open bare repository: {}", e));
+                    e.to_string()
+                })?;
+                log(&format!(
+                    "📂 Serving bare repository at {}",
+                    config.repo_path.as_deref().unwrap_or_default()
+                ));
+                if let Some(existing) = existing {
+                    repo.repo_name = existing.repo_name;
+                }
+                repo
+            }
+            Some(mut repo) => {
+                repo.config = config.clone();
+                repo
+            }
             None => {
                 log("No existing state, creating new git repository");
-                GitRepoState::default()
+                let mut repo = GitRepoState::from_config(config.clone());
+                repo.queue_repository_created();
+                repo
             }
         };
 
@@ -47,15 +178,19 @@ impl Guest for Component {
         ));
 
         // Set up HTTP server for Git Protocol v2
-        let config = ServerConfig {
-            port: Some(8080),
-            host: Some("0.0.0.0".to_string()),
-            tls_config: None,
+        let server_config = ServerConfig {
+            port: Some(config.port),
+            host: Some(config.host.clone()),
+            tls_config: config.tls.as_ref().map(|tls| TlsConfig {
+                cert_path: tls.cert_path.clone(),
+                key_path: tls.key_path.clone(),
+            }),
         };
 
         // Create the server
-        let server_id = http_framework::create_server(&config)
+        let server_id = http_framework::create_server(&server_config)
             .map_err(|e| format!("Failed to create HTTP server: {}", e))?;
+        SERVER_ID.with(|id| *id.borrow_mut() = Some(server_id));
 
         // Register git handler
         let git_handler = match http_framework::register_handler("git") {
@@ -97,45 +232,197 @@ impl Guest for Component {
             }
         }
 
+        // The same routes for each ref namespace
+        if config.features.namespaces {
+            for (path, method) in [
+                ("/ns/{namespace}/info/refs", "GET"),
+                ("/ns/{namespace}/git-upload-pack", "POST"),
+                ("/ns/{namespace}/git-receive-pack", "POST"),
+            ] {
+                match http_framework::add_route(server_id, path, method, git_handler) {
+                    Ok(_) => log(&format!("✅ Added {} {} route (namespaced)", method, path)),
+                    Err(e) => {
+                        log(&format!("❌ Failed to add {} route: {}", path, e));
+                        return Err(format!("Failed to add {} route: {}", path, e));
+                    }
+                }
+            }
+        }
+
         // Add modern debug routes
-        match http_framework::add_route(server_id, "/", "GET", git_handler) {
-            Ok(_) => log("✅ Added GET / debug route"),
-            Err(e) => {
-                log(&format!("❌ Failed to add / route: {}", e));
-                return Err(format!("Failed to add / route: {}", e));
+        if config.features.debug_routes {
+            match http_framework::add_route(server_id, "/", "GET", git_handler) {
+                Ok(_) => log("✅ Added GET / debug route"),
+                Err(e) => {
+                    log(&format!("❌ Failed to add / route: {}", e));
+                    return Err(format!("Failed to add / route: {}", e));
+                }
+            }
+
+            match http_framework::add_route(server_id, "/refs", "GET", git_handler) {
+                Ok(_) => log("✅ Added GET /refs debug route"),
+                Err(e) => {
+                    log(&format!("❌ Failed to add /refs route: {}", e));
+                    return Err(format!("Failed to add /refs route: {}", e));
+                }
+            }
+
+            match http_framework::add_route(server_id, "/objects", "GET", git_handler) {
+                Ok(_) => log("✅ Added GET /objects debug route"),
+                Err(e) => {
+                    log(&format!("❌ Failed to add /objects route: {}", e));
+                    return Err(format!("Failed to add /objects route: {}", e));
+                }
             }
         }
 
-        match http_framework::add_route(server_id, "/refs", "GET", git_handler) {
-            Ok(_) => log("✅ Added GET /refs debug route"),
-            Err(e) => {
-                log(&format!("❌ Failed to add /refs route: {}", e));
-                return Err(format!("Failed to add /refs route: {}", e));
+        // Dumb HTTP protocol routes (read-only static files)
+        if config.features.dumb_http {
+            match http_framework::add_route(server_id, "/HEAD", "GET", git_handler) {
+                Ok(_) => log("✅ Added GET /HEAD route (dumb protocol)"),
+                Err(e) => {
+                    log(&format!("❌ Failed to add /HEAD route: {}", e));
+                    return Err(format!("Failed to add /HEAD route: {}", e));
+                }
+            }
+
+            match http_framework::add_route(server_id, "/objects/info/packs", "GET", git_handler) {
+                Ok(_) => log("✅ Added GET /objects/info/packs route (dumb protocol)"),
+                Err(e) => {
+                    log(&format!(
+                        "❌ Failed to add /objects/info/packs route: {}",
+                        e
+                    ));
+                    return Err(format!("Failed to add /objects/info/packs route: {}", e));
+                }
+            }
+
+            match http_framework::add_route(server_id, "/objects/{dir}/{file}", "GET", git_handler)
+            {
+                Ok(_) => log("✅ Added GET /objects/{dir}/{file} route (dumb protocol)"),
+                Err(e) => {
+                    log(&format!("❌ Failed to add loose object route: {}", e));
+                    return Err(format!("Failed to add loose object route: {}", e));
+                }
             }
         }
 
-        match http_framework::add_route(server_id, "/objects", "GET", git_handler) {
-            Ok(_) => log("✅ Added GET /objects debug route"),
-            Err(e) => {
-                log(&format!("❌ Failed to add /objects route: {}", e));
-                return Err(format!("Failed to add /objects route: {}", e));
+        // Maintenance routes
+        if config.features.admin_routes {
+            match http_framework::add_route(server_id, "/admin/gc", "POST", git_handler) {
+                Ok(_) => log("✅ Added POST /admin/gc route"),
+                Err(e) => {
+                    log(&format!("❌ Failed to add /admin/gc route: {}", e));
+                    return Err(format!("Failed to add /admin/gc route: {}", e));
+                }
+            }
+
+            match http_framework::add_route(server_id, "/admin/repack", "POST", git_handler) {
+                Ok(_) => log("✅ Added POST /admin/repack route"),
+                Err(e) => {
+                    log(&format!("❌ Failed to add /admin/repack route: {}", e));
+                    return Err(format!("Failed to add /admin/repack route: {}", e));
+                }
+            }
+
+            match http_framework::add_route(server_id, "/admin/reflog", "GET", git_handler) {
+                Ok(_) => log("✅ Added GET /admin/reflog route"),
+                Err(e) => {
+                    log(&format!("❌ Failed to add /admin/reflog route: {}", e));
+                    return Err(format!("Failed to add /admin/reflog route: {}", e));
+                }
+            }
+
+            match http_framework::add_route(server_id, "/admin/reflog/restore", "POST", git_handler)
+            {
+                Ok(_) => log("✅ Added POST /admin/reflog/restore route"),
+                Err(e) => {
+                    log(&format!(
+                        "❌ Failed to add /admin/reflog/restore route: {}",
+                        e
+                    ));
+                    return Err(format!("Failed to add /admin/reflog/restore route: {}", e));
+                }
+            }
+
+            for method in ["GET", "POST", "DELETE"] {
+                match http_framework::add_route(server_id, "/admin/symref", method, git_handler) {
+                    Ok(_) => log(&format!("✅ Added {} /admin/symref route", method)),
+                    Err(e) => {
+                        log(&format!("❌ Failed to add /admin/symref route: {}", e));
+                        return Err(format!("Failed to add /admin/symref route: {}", e));
+                    }
+                }
+            }
+
+            match http_framework::add_route(
+                server_id,
+                "/admin/webhooks/deliveries",
+                "GET",
+                git_handler,
+            ) {
+                Ok(_) => log("✅ Added GET /admin/webhooks/deliveries route"),
+                Err(e) => {
+                    log(&format!(
+                        "❌ Failed to add /admin/webhooks/deliveries route: {}",
+                        e
+                    ));
+                    return Err(format!(
+                        "Failed to add /admin/webhooks/deliveries route: {}",
+                        e
+                    ));
+                }
+            }
+        }
+
+        // Live repository events
+        if config.features.event_stream {
+            match http_framework::enable_websocket(
+                server_id,
+                "/events",
+                Some(git_handler),
+                git_handler,
+                Some(git_handler),
+            ) {
+                Ok(_) => log("✅ Enabled /events WebSocket"),
+                Err(e) => {
+                    log(&format!("❌ Failed to enable /events WebSocket: {}", e));
+                    return Err(format!("Failed to enable /events WebSocket: {}", e));
+                }
             }
         }
 
         // Start the server
         match http_framework::start_server(server_id) {
-            Ok(_) => log("🌐 HTTP server started successfully on port 8080"),
+            Ok(_) => log(&format!(
+                "🌐 HTTP server started successfully on {}:{}",
+                config.host, config.port
+            )),
             Err(e) => {
                 log(&format!("❌ Failed to start HTTP server: {}", e));
                 return Err(format!("Failed to start HTTP server: {}", e));
             }
         }
 
+        if config.features.git_daemon {
+            log(&format!(
+                "📡 git:// connections accepted over message-server channels (bridge port {})",
+                DAEMON_PORT
+            ));
+        }
+
         log("🎉 Git Protocol v2 server initialization completed!");
 
+        // Init answers nobody, so the repository event can go out right away
+        if repo_state.webhooks_pending() {
+            repo_state.deliver_webhooks();
+            if repo_state.webhooks_pending() {
+                schedule_webhook_delivery(&mut repo_state);
+            }
+        }
+
         // Serialize and return the repository state
-        let serialized_state = serde_json::to_vec(&repo_state)
-            .map_err(|e| format!("Failed to serialize state: {}", e))?;
+        let serialized_state = save_repo(&repo_state)?;
 
         Ok((Some(serialized_state),))
     }
@@ -153,23 +440,32 @@ impl HttpHandlers for Component {
         ));
 
         // Parse current state
-        let mut repo_state = match state {
-            Some(bytes) => serde_json::from_slice::<GitRepoState>(&bytes).unwrap_or_default(),
-            None => GitRepoState::default(),
-        };
+        let mut repo_state = load_repo(state.as_deref())?;
 
         let response = repo_state.dispatch(&request);
 
         log(&format!("Response: {}", response.status));
 
+        // Scheduled garbage collection runs after the request it follows
+        let now = host::now();
+        if repo_state.gc_due(now) {
+            if let Err(e) = repo_state.collect_garbage(now, false) {
+                log(&format!("Scheduled garbage collection failed: {}", e));
+            }
+        }
+
+        if repo_state.webhooks_pending() {
+            schedule_webhook_delivery(&mut repo_state);
+        }
+        publish_events(&mut repo_state);
+
         match response.body {
             Some(ref body) => log(&format!("Response body: {}", String::from_utf8_lossy(body))),
             None => log("No response body"),
         }
 
         // Serialize updated state
-        let serialized_state = serde_json::to_vec(&repo_state)
-            .map_err(|e| format!("Failed to serialize state: {}", e))?;
+        let serialized_state = save_repo(&repo_state)?;
 
         Ok((Some(serialized_state), (response,)))
     }
@@ -189,25 +485,173 @@ impl HttpHandlers for Component {
 
     fn handle_websocket_connect(
         state: Option<Vec<u8>>,
-        _params: (HandlerId, u64, String, Option<String>),
+        params: (HandlerId, u64, String, Option<String>),
     ) -> Result<(Option<Vec<u8>>,), String> {
+        let (_, connection, path, query) = params;
+        log(&format!(
+            "Event stream connection {} opened: {}",
+            connection, path
+        ));
+        EVENT_STREAM
+            .with(|stream| stream.borrow_mut().connect(connection, &path, query))
+            .map_err(|e| e.to_string())?;
         Ok((state,))
     }
 
     fn handle_websocket_disconnect(
         state: Option<Vec<u8>>,
-        _params: (HandlerId, u64),
+        params: (HandlerId, u64),
     ) -> Result<(Option<Vec<u8>>,), String> {
+        let (_, connection) = params;
+        log(&format!("Event stream connection {} closed", connection));
+        EVENT_STREAM.with(|stream| stream.borrow_mut().disconnect(connection));
         Ok((state,))
     }
 
+    /// Clients only send subscription changes; each is answered with the
+    /// connection's subscriptions
     fn handle_websocket_message(
-        _state: Option<Vec<u8>>,
-        _params: (HandlerId, u64, WebsocketMessage),
+        state: Option<Vec<u8>>,
+        params: (HandlerId, u64, WebsocketMessage),
     ) -> Result<(Option<Vec<u8>>, (Vec<WebsocketMessage>,)), String> {
-        // WebSocket not used for Git protocol
-        Err("WebSocket not supported for Git Protocol v2".to_string())
+        let (_, connection, message) = params;
+        let replies = match (message.ty, message.text) {
+            (MessageType::Text, Some(text)) => {
+                let reply = EVENT_STREAM
+                    .with(|stream| stream.borrow_mut().handle_message(connection, &text));
+                vec![WebsocketMessage {
+                    ty: MessageType::Text,
+                    data: None,
+                    text: Some(reply),
+                }]
+            }
+            (MessageType::Close, _) => {
+                EVENT_STREAM.with(|stream| stream.borrow_mut().disconnect(connection));
+                Vec::new()
+            }
+            _ => Vec::new(),
+        };
+        Ok((state, (replies,)))
+    }
+}
+
+impl MessageServerClient for Component {
+    fn handle_send(
+        state: Option<Vec<u8>>,
+        params: (Vec<u8>,),
+    ) -> Result<(Option<Vec<u8>>,), String> {
+        let (msg,) = params;
+        if msg != DELIVER_WEBHOOKS {
+            return Ok((state,));
+        }
+
+        let mut repo_state = load_repo(state.as_deref())?;
+        let attempted = repo_state.deliver_webhooks();
+        // Retries wait for their due time by posting the message again, behind
+        // whatever else the actor has to handle in the meantime
+        if repo_state.webhooks_pending() {
+            if let Err(e) = send_deliver_webhooks() {
+                log(&format!(
+                    "Webhook retries wait for the next request ({})",
+                    e
+                ));
+            }
+        }
+        if !attempted {
+            return Ok((state,));
+        }
+        Ok((Some(save_repo(&repo_state)?),))
+    }
+
+    fn handle_request(
+        state: Option<Vec<u8>>,
+        _params: (String, Vec<u8>),
+    ) -> Result<(Option<Vec<u8>>, (Option<Vec<u8>>,)), String> {
+        Ok((state, (None,)))
+    }
+
+    /// A git:// connection bridged from TCP: the initial message is the first
+    /// data read from the socket, and the accept message carries the advertisement
+    fn handle_channel_open(
+        state: Option<Vec<u8>>,
+        params: (String, Vec<u8>),
+    ) -> Result<(Option<Vec<u8>>, (ChannelAccept,)), String> {
+        let (channel_id, initial_msg) = params;
+        log(&format!("git:// channel opened: {}", channel_id));
+
+        let repo_state = load_repo(state.as_deref())?;
+
+        let accept = match DaemonSession::open(&repo_state, &initial_msg) {
+            Ok((session, advertisement)) => {
+                DAEMON_SESSIONS.with(|sessions| sessions.borrow_mut().insert(channel_id, session));
+                ChannelAccept {
+                    accepted: true,
+                    message: Some(advertisement),
+                }
+            }
+            Err(e) => {
+                log(&format!("❌ Rejecting git:// connection: {}", e));
+                ChannelAccept {
+                    accepted: false,
+                    message: Some(error_packet(&e)),
+                }
+            }
+        };
+
+        Ok((state, (accept,)))
+    }
+
+    fn handle_channel_message(
+        state: Option<Vec<u8>>,
+        params: (ChannelId, Vec<u8>),
+    ) -> Result<(Option<Vec<u8>>,), String> {
+        let (channel_id, data) = params;
+
+        let repo_state = load_repo(state.as_deref())?;
+
+        let result =
+            DAEMON_SESSIONS.with(
+                |sessions| match sessions.borrow_mut().get_mut(&channel_id) {
+                    Some(session) => session
+                        .receive(&repo_state, &data)
+                        .map(|output| (output, session.is_finished())),
+                    None => Err(error::GitError::Protocol(format!(
+                        "Unknown git:// channel: {}",
+                        channel_id
+                    ))),
+                },
+            );
+
+        let (output, finished) = match result {
+            Ok(response) => response,
+            Err(e) => {
+                log(&format!("❌ git:// session failed: {}", e));
+                (error_packet(&e), true)
+            }
+        };
+
+        if !output.is_empty() {
+            message_server_host::send_on_channel(&channel_id, &output)?;
+        }
+        if finished {
+            DAEMON_SESSIONS.with(|sessions| sessions.borrow_mut().remove(&channel_id));
+            message_server_host::close_channel(&channel_id)?;
+        }
+
+        Ok((state,))
+    }
+
+    fn handle_channel_close(
+        state: Option<Vec<u8>>,
+        params: (ChannelId,),
+    ) -> Result<(Option<Vec<u8>>,), String> {
+        let (channel_id,) = params;
+        log(&format!("git:// channel closed: {}", channel_id));
+        DAEMON_SESSIONS.with(|sessions| sessions.borrow_mut().remove(&channel_id));
+        Ok((state,))
     }
 }
 
+// Component exports only exist in the wasm build; native builds are for testing the core
+#[cfg(target_arch = "wasm32")]
 bindings::export!(Component with_types_in bindings);
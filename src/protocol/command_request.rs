@@ -1,86 +1,118 @@
-use crate::bindings::theater::simple::runtime::log;
+use crate::error::GitError;
+use crate::host::log;
+use crate::protocol::pktline::{PktLine, PktLineReader};
 
 #[derive(Debug)]
 pub struct CommandRequest {
     pub command: String,
-    #[allow(dead_code)]
     pub capabilities: Vec<String>,
     pub args: Vec<String>,
+    pub data: Vec<u8>, // Bytes after the closing flush-pkt (the pack for `push`)
 }
 
-pub fn parse_command_request(data: &[u8]) -> Result<CommandRequest, String> {
+/// Parse a Protocol v2 command request:
+/// `command=<cmd>`, capability lines, delim-pkt, argument lines, flush-pkt,
+/// then any trailing data
+pub fn parse_command_request(data: &[u8]) -> Result<CommandRequest, GitError> {
     log(&format!(
         "Parsing Protocol v2 command request, data length: {} bytes",
         data.len()
     ));
 
-    let mut lines = Vec::new();
-    let mut pos = 0;
+    let mut reader = PktLineReader::from_bytes(data);
+    let mut capabilities = Vec::new();
+    let mut args = Vec::new();
+    let mut in_args = false;
 
-    while pos < data.len() {
-        if pos + 4 > data.len() {
-            break;
+    while let Some(packet) = reader.read_packet()? {
+        match packet {
+            // Flush/response-end packet - end of request
+            PktLine::Flush | PktLine::ResponseEnd => break,
+            // Delimiter packet - capabilities are followed by arguments
+            PktLine::Delim => in_args = true,
+            PktLine::Data(_) => {
+                let line = packet.as_text()?.unwrap_or_default();
+                if line.is_empty() {
+                    continue;
+                }
+                if in_args {
+                    args.push(line.to_string());
+                } else {
+                    capabilities.push(line.to_string());
+                }
+            }
         }
-
-        let len_bytes = &data[pos..pos + 4];
-        let len_str = std::str::from_utf8(len_bytes).map_err(|_| "Invalid packet")?;
-        let len = u16::from_str_radix(len_str, 16).map_err(|_| "Invalid packet length")?;
-
-        if len == 0 {
-            // Flush packet - end of request
-            // pos += 4; // Unused assignment removed
-            break;
-        }
-
-        if len == 1 {
-            // Delimiter packet - continue
-            pos += 4;
-            continue;
-        }
-
-        if len < 4 {
-            return Err(format!("Invalid packet length: {} (must be >= 4)", len));
-        }
-
-        if pos + len as usize > data.len() {
-            return Err(format!(
-                "Packet extends beyond data: need {} bytes, have {}",
-                len,
-                data.len() - pos
-            ));
-        }
-
-        let content = &data[pos + 4..pos + len as usize];
-        let line = std::str::from_utf8(content)
-            .map_err(|e| format!("Invalid UTF-8 in packet content: {}", e))?
-            .trim_end_matches('\n');
-
-        if !line.is_empty() {
-            lines.push(line.to_string());
-        }
-        pos += len as usize;
     }
 
-    if lines.is_empty() {
-        return Err("No command found in request".to_string());
+    if capabilities.is_empty() {
+        return Err(GitError::Protocol(
+            "No command found in request".to_string(),
+        ));
     }
 
-    let first_line = &lines[0];
+    let first_line = capabilities.remove(0);
     let command = if let Some(cmd) = first_line.strip_prefix("command=") {
         cmd.to_string()
     } else {
-        return Err(format!("Invalid command format: {}", first_line));
+        return Err(GitError::Protocol(format!(
+            "Invalid command format: {}",
+            first_line
+        )));
     };
 
     log(&format!(
-        "Parsed Protocol v2 command: '{}' with {} args",
+        "Parsed Protocol v2 command: '{}' with {} capabilities and {} args",
         command,
-        lines.len() - 1
+        capabilities.len(),
+        args.len()
     ));
 
+    let data = reader.remaining().to_vec();
+
     Ok(CommandRequest {
         command,
-        capabilities: vec![],
-        args: lines[1..].to_vec(),
+        capabilities,
+        args,
+        data,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::pktline::PktLineWriter;
+
+    #[test]
+    fn test_capabilities_and_args_are_separated() {
+        let mut writer = PktLineWriter::new();
+        writer.write_line("command=fetch").unwrap();
+        writer.write_line("agent=git/2.39.5").unwrap();
+        writer.write_line("object-format=sha1").unwrap();
+        writer.delim();
+        writer.write_line("thin-pack").unwrap();
+        writer.write_line("done").unwrap();
+        writer.flush();
+
+        let request = parse_command_request(&writer.into_bytes()).unwrap();
+
+        assert_eq!(request.command, "fetch");
+        assert_eq!(
+            request.capabilities,
+            vec!["agent=git/2.39.5", "object-format=sha1"]
+        );
+        assert_eq!(request.args, vec!["thin-pack", "done"]);
+        assert!(request.data.is_empty());
+    }
+
+    #[test]
+    fn test_missing_command_is_protocol_error() {
+        assert!(matches!(
+            parse_command_request(b"0000"),
+            Err(GitError::Protocol(_))
+        ));
+        assert!(matches!(
+            parse_command_request(b"000cls-refs\n0000"),
+            Err(GitError::Protocol(_))
+        ));
+    }
+}
@@ -0,0 +1,356 @@
+//! git:// Daemon Protocol
+//!
+//! A git:// connection opens with a single pkt-line naming the service and
+//! repository, e.g. `git-upload-pack /repo.git\0host=example.com\0\0version=2\0`.
+//! After that the connection carries the same upload-pack conversation as
+//! smart HTTP, except that it is stateful: v0/v1 negotiation rounds share one
+//! stream, and v2 clients send any number of commands before a final flush.
+//!
+//! A `/ns/<name>` path serves that ref namespace, as `/ns/<name>/` URLs do
+//! over HTTP.
+//!
+//! `DaemonSession` is transport-agnostic: feed it the bytes read from the
+//! connection and write back whatever it returns.
+
+use crate::error::GitError;
+use crate::git::namespace::Namespace;
+use crate::git::repository::GitRepoState;
+use crate::host::log;
+use crate::protocol::command_request::parse_command_request;
+use crate::protocol::pktline::{PktLine, PktLineReader, PktLineWriter};
+use crate::protocol::upload_request::UploadRequest;
+
+/// Default git:// port
+pub const DAEMON_PORT: u16 = 9418;
+
+/// The initial request line of a git:// connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct DaemonRequest {
+    pub service: String,
+    pub path: String,
+    pub host: Option<String>,
+    pub extra_params: Vec<String>, // e.g. "version=2"
+}
+
+impl DaemonRequest {
+    /// Protocol version requested through the extra parameters (0 when absent)
+    pub fn version(&self) -> u32 {
+        self.extra_params
+            .iter()
+            .filter_map(|param| param.strip_prefix("version="))
+            .filter_map(|version| version.parse().ok())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Parse `<service> <path>\0[host=<host>\0][\0<extra>\0...]`
+pub fn parse_daemon_request(line: &[u8]) -> Result<DaemonRequest, GitError> {
+    let line = std::str::from_utf8(line)
+        .map_err(|e| GitError::Protocol(format!("Invalid UTF-8 in daemon request: {}", e)))?;
+    let mut fields = line.trim_end_matches('\n').split('\0');
+
+    let command = fields.next().unwrap_or_default();
+    let (service, path) = command
+        .split_once(' ')
+        .ok_or_else(|| GitError::Protocol(format!("Invalid daemon request: {}", command)))?;
+
+    let mut host = None;
+    let mut extra_params = Vec::new();
+    let mut in_extra = false;
+    for field in fields {
+        if in_extra {
+            if !field.is_empty() {
+                extra_params.push(field.to_string());
+            }
+        } else if field.is_empty() {
+            // An empty field separates the host parameter from extra parameters
+            in_extra = true;
+        } else if let Some(value) = field.strip_prefix("host=") {
+            host = Some(value.to_string());
+        }
+    }
+
+    Ok(DaemonRequest {
+        service: service.to_string(),
+        path: path.to_string(),
+        host,
+        extra_params,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    /// v0/v1: collecting want lines up to the first flush
+    Wants,
+    /// v0/v1: negotiation rounds of have lines, each ending in flush, until done
+    Haves,
+    /// v2: command requests, each ending in flush
+    Commands,
+    Finished,
+}
+
+/// One git:// upload-pack connection
+#[derive(Debug)]
+pub struct DaemonSession {
+    pub request: DaemonRequest,
+    namespace: Namespace,
+    reader: PktLineReader,
+    phase: Phase,
+    upload: UploadRequest,
+    command: PktLineWriter,
+}
+
+impl DaemonSession {
+    /// Start a session from the first bytes of a connection, returning the
+    /// session and the server's opening message (the advertisement)
+    pub fn open(repo: &GitRepoState, data: &[u8]) -> Result<(Self, Vec<u8>), GitError> {
+        let mut reader = PktLineReader::from_bytes(data);
+        let line = match reader.next_packet()? {
+            Some(PktLine::Data(line)) => line,
+            _ => {
+                return Err(GitError::Protocol(
+                    "Expected git:// request line".to_string(),
+                ))
+            }
+        };
+        let request = parse_daemon_request(&line)?;
+        log(&format!(
+            "git:// request: {} {} (host: {:?}, version {})",
+            request.service,
+            request.path,
+            request.host,
+            request.version()
+        ));
+
+        if !repo.config.features.git_daemon {
+            return Err(GitError::PermissionDenied(
+                "git:// access is disabled".to_string(),
+            ));
+        }
+        if request.service != "git-upload-pack" {
+            // Pushing is only available over smart HTTP
+            return Err(GitError::PermissionDenied(format!(
+                "service not enabled: {}",
+                request.service
+            )));
+        }
+
+        let namespace = if repo.config.features.namespaces {
+            Namespace::from_path(&request.path)?.0
+        } else {
+            Namespace::default()
+        };
+
+        let (phase, mut output) = if request.version() >= 2 {
+            (Phase::Commands, repo.upload_pack_v2_capabilities()?)
+        } else {
+            (
+                Phase::Wants,
+                repo.upload_pack_ref_advertisement(request.version(), &namespace)?,
+            )
+        };
+
+        let mut session = Self {
+            request,
+            namespace,
+            reader: PktLineReader::new(),
+            phase,
+            upload: UploadRequest::default(),
+            command: PktLineWriter::new(),
+        };
+
+        // Anything the client sent after the request line is processed right away
+        let rest = reader.remaining().to_vec();
+        output.extend(session.receive(repo, &rest)?);
+        Ok((session, output))
+    }
+
+    /// Whether the conversation is over and the connection can be closed
+    pub fn is_finished(&self) -> bool {
+        self.phase == Phase::Finished
+    }
+
+    /// Process bytes received from the client, returning bytes to send back
+    pub fn receive(&mut self, repo: &GitRepoState, data: &[u8]) -> Result<Vec<u8>, GitError> {
+        self.reader.feed(data);
+        let mut output = Vec::new();
+
+        while self.phase != Phase::Finished {
+            let packet = match self.reader.next_packet()? {
+                Some(packet) => packet,
+                None => break,
+            };
+            match self.phase {
+                Phase::Wants | Phase::Haves => output.extend(self.negotiate(repo, packet)?),
+                Phase::Commands => output.extend(self.command(repo, packet)?),
+                Phase::Finished => {}
+            }
+        }
+
+        Ok(output)
+    }
+
+    /// v0/v1 want/have negotiation over a stateful connection
+    fn negotiate(&mut self, repo: &GitRepoState, packet: PktLine) -> Result<Vec<u8>, GitError> {
+        let line = match packet {
+            PktLine::Data(_) => packet.as_text()?.unwrap_or_default().to_string(),
+            PktLine::Flush if self.phase == Phase::Wants => {
+                // An empty want list means the client is already up to date
+                self.phase = if self.upload.wants.is_empty() {
+                    Phase::Finished
+                } else {
+                    Phase::Haves
+                };
+                return Ok(Vec::new());
+            }
+            // End of a negotiation round; haves accumulate across rounds
+            PktLine::Flush => return repo.upload_pack_v1_response(&self.upload, &self.namespace),
+            PktLine::Delim | PktLine::ResponseEnd => {
+                return Err(GitError::Protocol(
+                    "Unexpected special packet in upload-pack request".to_string(),
+                ))
+            }
+        };
+
+        match (self.phase, line.as_str()) {
+            (Phase::Wants, _) if line.starts_with("want ") => {
+                // Only the first want may carry capabilities after a space
+                let mut parts = line["want ".len()..].split(' ');
+                let oid = parts.next().unwrap_or_default();
+                if self.upload.wants.is_empty() {
+                    self.upload.capabilities = parts.map(|s| s.to_string()).collect();
+                }
+                self.upload.wants.push(oid.to_string());
+                Ok(Vec::new())
+            }
+            (Phase::Haves, _) if line.starts_with("have ") => {
+                self.upload.haves.push(line["have ".len()..].to_string());
+                Ok(Vec::new())
+            }
+            (Phase::Haves, "done") => {
+                self.upload.done = true;
+                self.phase = Phase::Finished;
+                repo.upload_pack_v1_response(&self.upload, &self.namespace)
+            }
+            _ => Err(GitError::Protocol(format!(
+                "Unexpected line in upload-pack request: {}",
+                line
+            ))),
+        }
+    }
+
+    /// v2 command requests; a flush on its own ends the session
+    fn command(&mut self, repo: &GitRepoState, packet: PktLine) -> Result<Vec<u8>, GitError> {
+        match packet {
+            PktLine::Data(payload) => self.command.write_data(&payload)?,
+            PktLine::Delim => self.command.delim(),
+            PktLine::ResponseEnd => {
+                return Err(GitError::Protocol(
+                    "Unexpected response-end packet in command request".to_string(),
+                ))
+            }
+            PktLine::Flush => {
+                let mut request = std::mem::take(&mut self.command).into_bytes();
+                if request.is_empty() {
+                    self.phase = Phase::Finished;
+                    return Ok(Vec::new());
+                }
+                request.extend(b"0000");
+                let parsed = parse_command_request(&request)?;
+                return repo.upload_pack_command(&parsed, &self.namespace);
+            }
+        }
+        Ok(Vec::new())
+    }
+}
+
+/// The `ERR` packet sent before closing a connection that failed
+pub fn error_packet(error: &GitError) -> Vec<u8> {
+    let mut writer = PktLineWriter::new();
+    writer.write_message(&format!("ERR {}", error.pkt_line_message()));
+    writer.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request_with_host_and_version() {
+        let request = parse_daemon_request(
+            b"git-upload-pack /repo.git\0host=example.com:9418\0\0version=2\0",
+        )
+        .unwrap();
+
+        assert_eq!(request.service, "git-upload-pack");
+        assert_eq!(request.path, "/repo.git");
+        assert_eq!(request.host.as_deref(), Some("example.com:9418"));
+        assert_eq!(request.extra_params, vec!["version=2"]);
+        assert_eq!(request.version(), 2);
+    }
+
+    #[test]
+    fn test_parse_request_without_host() {
+        let request = parse_daemon_request(b"git-upload-pack /repo.git\0").unwrap();
+
+        assert_eq!(request.host, None);
+        assert_eq!(request.version(), 0);
+        assert!(parse_daemon_request(b"git-upload-pack").is_err());
+    }
+
+    #[test]
+    fn test_receive_pack_is_refused() {
+        let mut writer = PktLineWriter::new();
+        writer
+            .write_data(b"git-receive-pack /repo.git\0host=localhost\0")
+            .unwrap();
+
+        assert!(matches!(
+            DaemonSession::open(&GitRepoState::default(), &writer.into_bytes()),
+            Err(GitError::PermissionDenied(_))
+        ));
+    }
+
+    #[test]
+    fn test_disabled_daemon_refuses_upload_pack() {
+        let mut repo = GitRepoState::default();
+        repo.config.features.git_daemon = false;
+        let mut writer = PktLineWriter::new();
+        writer
+            .write_data(b"git-upload-pack /repo.git\0host=localhost\0")
+            .unwrap();
+
+        assert!(matches!(
+            DaemonSession::open(&repo, &writer.into_bytes()),
+            Err(GitError::PermissionDenied(_))
+        ));
+    }
+
+    #[test]
+    fn test_v2_session_handles_commands_until_flush() {
+        let repo = GitRepoState::default();
+        let mut writer = PktLineWriter::new();
+        writer
+            .write_data(b"git-upload-pack /repo.git\0host=localhost\0\0version=2\0")
+            .unwrap();
+        let (mut session, advertisement) =
+            DaemonSession::open(&repo, &writer.into_bytes()).unwrap();
+        assert!(advertisement.starts_with(b"000eversion 2\n"));
+
+        // Commands may arrive split across reads
+        let mut command = PktLineWriter::new();
+        command.write_line("command=ls-refs").unwrap();
+        command.delim();
+        command.write_line("unborn").unwrap();
+        command.flush();
+        let command = command.into_bytes();
+        assert!(session.receive(&repo, &command[..5]).unwrap().is_empty());
+        let listed = session.receive(&repo, &command[5..]).unwrap();
+        assert!(String::from_utf8_lossy(&listed).contains("unborn HEAD"));
+        assert!(!session.is_finished());
+
+        assert!(session.receive(&repo, b"0000").unwrap().is_empty());
+        assert!(session.is_finished());
+    }
+}
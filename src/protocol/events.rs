@@ -0,0 +1,230 @@
+//! The `/events` WebSocket stream.
+//!
+//! Each connection subscribes to the events of one or more ref namespaces
+//! ("repositories" sharing the object store), optionally only some event
+//! types. The connecting URL sets the first subscription:
+//! `/events?namespace=fork&events=push,ref_update`; without a `namespace`
+//! the connection sees every namespace. Clients change their subscriptions
+//! with text messages:
+//!
+//! ```text
+//! {"action":"subscribe","namespace":"fork","events":["push"]}
+//! {"action":"unsubscribe","namespace":"fork"}
+//! ```
+//!
+//! and are answered with their subscriptions, or an error. Events are sent
+//! as the JSON of `RepoEvent`; garbage collection concerns every namespace,
+//! so it goes to every connection that asked for `gc`.
+//!
+//! Connections only live as long as the socket, so `EventStream` is kept
+//! out of the persisted repository state.
+
+use crate::error::GitError;
+use crate::git::events::RepoEvent;
+use crate::git::namespace::Namespace;
+use crate::protocol::http::query_param;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+
+/// The `type`s events are streamed with
+pub const EVENT_TYPES: &[&str] = &["ref_update", "commit", "push", "gc"];
+
+/// Events a connection wants
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Subscription {
+    /// The namespace to follow (`None`: every namespace)
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// Event types to send (empty: all)
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+impl Subscription {
+    fn validate(&self) -> Result<(), GitError> {
+        if let Some(name) = &self.namespace {
+            Namespace::new(name)?;
+        }
+        match self
+            .events
+            .iter()
+            .find(|e| !EVENT_TYPES.contains(&e.as_str()))
+        {
+            Some(unknown) => Err(GitError::Protocol(format!(
+                "unknown event type '{}' (expected one of {})",
+                unknown,
+                EVENT_TYPES.join(", ")
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether `event` should be sent for this subscription
+    pub fn matches(&self, event: &RepoEvent) -> bool {
+        let namespace = match (&self.namespace, event) {
+            (None, _) | (_, RepoEvent::Gc { .. }) => true,
+            (Some(name), event) => event.namespace() == Some(name.as_str()),
+        };
+        namespace && (self.events.is_empty() || self.events.iter().any(|e| e == event.kind()))
+    }
+}
+
+/// A message from a client
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe(Subscription),
+    Unsubscribe {
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+}
+
+/// Open `/events` connections and what each subscribed to
+#[derive(Debug, Default)]
+pub struct EventStream {
+    connections: BTreeMap<u64, Vec<Subscription>>,
+}
+
+impl EventStream {
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// Open a connection to `path`, subscribed as its query asks
+    pub fn connect(
+        &mut self,
+        connection: u64,
+        path: &str,
+        query: Option<String>,
+    ) -> Result<(), GitError> {
+        let query = match path.split_once('?') {
+            Some((_, query)) => Some(query.to_string()),
+            None => query,
+        };
+        let subscription = Subscription {
+            namespace: query_param(&query, "namespace")
+                .filter(|name| !name.is_empty())
+                .map(str::to_string),
+            events: query_param(&query, "events")
+                .map(|events| {
+                    events
+                        .split(',')
+                        .filter(|e| !e.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+        subscription.validate()?;
+        self.connections.insert(connection, vec![subscription]);
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self, connection: u64) {
+        self.connections.remove(&connection);
+    }
+
+    /// Handle a client's text message, returning the reply
+    pub fn handle_message(&mut self, connection: u64, text: &str) -> String {
+        match self.apply(connection, text) {
+            Ok(subscriptions) => json!({
+                "type": "subscriptions",
+                "subscriptions": subscriptions,
+            }),
+            Err(e) => json!({ "type": "error", "message": e.to_string() }),
+        }
+        .to_string()
+    }
+
+    fn apply(&mut self, connection: u64, text: &str) -> Result<&[Subscription], GitError> {
+        let message: ClientMessage = serde_json::from_str(text)
+            .map_err(|e| GitError::Protocol(format!("unreadable message: {}", e)))?;
+        let subscriptions = self.connections.entry(connection).or_default();
+        match message {
+            ClientMessage::Subscribe(subscription) => {
+                subscription.validate()?;
+                subscriptions.retain(|s| s.namespace != subscription.namespace);
+                subscriptions.push(subscription);
+            }
+            ClientMessage::Unsubscribe { namespace } => {
+                subscriptions.retain(|s| s.namespace != namespace);
+            }
+        }
+        Ok(subscriptions)
+    }
+
+    /// Connections `event` should be sent to
+    pub fn recipients(&self, event: &RepoEvent) -> Vec<u64> {
+        self.connections
+            .iter()
+            .filter(|(_, subscriptions)| subscriptions.iter().any(|s| s.matches(event)))
+            .map(|(connection, _)| *connection)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::gc::GcReport;
+
+    fn ref_update(namespace: Option<&str>) -> RepoEvent {
+        RepoEvent::RefUpdate {
+            namespace: namespace.map(str::to_string),
+            ref_name: "refs/heads/main".to_string(),
+            old_id: "0".repeat(40),
+            new_id: "1".repeat(40),
+            identity: "alice".to_string(),
+            message: "push".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_subscriptions_filter_events() {
+        let mut stream = EventStream::default();
+        stream.connect(1, "/events", None).unwrap();
+        stream
+            .connect(2, "/events?namespace=fork&events=ref_update", None)
+            .unwrap();
+        stream
+            .connect(3, "/events", Some("events=gc".to_string()))
+            .unwrap();
+
+        assert_eq!(stream.recipients(&ref_update(None)), [1]);
+        assert_eq!(stream.recipients(&ref_update(Some("fork"))), [1, 2]);
+        let gc = RepoEvent::Gc {
+            report: GcReport::default(),
+        };
+        assert_eq!(stream.recipients(&gc), [1, 3]);
+
+        // Follow another namespace too, then stop following fork
+        let reply = stream.handle_message(2, r#"{"action":"subscribe","namespace":"other"}"#);
+        let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+        assert_eq!(reply["type"], "subscriptions");
+        assert_eq!(reply["subscriptions"].as_array().unwrap().len(), 2);
+        assert_eq!(stream.recipients(&ref_update(Some("other"))), [1, 2]);
+        stream.handle_message(2, r#"{"action":"unsubscribe","namespace":"fork"}"#);
+        assert_eq!(stream.recipients(&ref_update(Some("fork"))), [1]);
+
+        stream.disconnect(1);
+        assert_eq!(stream.recipients(&gc), [2, 3]);
+    }
+
+    #[test]
+    fn test_bad_subscriptions_are_refused() {
+        let mut stream = EventStream::default();
+        assert!(stream.connect(1, "/events?events=tag", None).is_err());
+        assert!(stream.connect(1, "/events?namespace=a/b", None).is_err());
+        assert!(stream.is_empty());
+
+        stream.connect(1, "/events", None).unwrap();
+        for message in ["not json", r#"{"action":"subscribe","events":["nope"]}"#] {
+            let reply: serde_json::Value =
+                serde_json::from_str(&stream.handle_message(1, message)).unwrap();
+            assert_eq!(reply["type"], "error");
+        }
+        assert_eq!(stream.recipients(&ref_update(None)), [1]);
+    }
+}
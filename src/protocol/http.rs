@@ -1,12 +1,166 @@
 // Git Protocol Handler - Supporting both v1 and v2
 // FIXED: Handle Protocol v1 fallback for push operations
 
-use crate::bindings::theater::simple::http_types::HttpResponse;
-use crate::bindings::theater::simple::runtime::log;
+use crate::bindings::theater::simple::http_types::{HttpRequest, HttpResponse};
+use crate::error::GitError;
+use crate::host::log;
+use crate::protocol::pktline::{PktLine, PktLineReader, PktLineWriter, BAND_DATA, BAND_PROGRESS};
+use crate::utils::compression::{decompress_deflate, decompress_gzip};
 
-pub const CAPABILITIES: &str = "report-status delete-refs ofs-delta agent=git-server/0.1.0";
+pub const CAPABILITIES: &str = "report-status delete-refs ofs-delta push-options";
+pub const UPLOAD_PACK_CAPABILITIES: &str =
+    "multi_ack multi_ack_detailed side-band side-band-64k ofs-delta no-progress";
 pub const MAX_PKT_PAYLOAD: usize = 0xFFF0 - 4; // pkt-line payload limit = 65 516
 pub const MAX_SIDEBAND_DATA: usize = MAX_PKT_PAYLOAD - 1; // minus 1-byte channel
+pub const MAX_SMALL_SIDEBAND_DATA: usize = 1000 - 5; // side-band (not -64k) packet limit
+
+/// Protocol version requested through the `Git-Protocol` header (`version=2`);
+/// 0 when the header is absent
+pub fn requested_protocol_version(request: &HttpRequest) -> u32 {
+    request
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Git-Protocol"))
+        .flat_map(|(_, value)| value.split(':'))
+        .filter_map(|param| param.trim().strip_prefix("version="))
+        .filter_map(|version| version.parse().ok())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Value of `name=value` in a query string
+pub fn query_param<'a>(query: &'a Option<String>, name: &str) -> Option<&'a str> {
+    query
+        .as_deref()?
+        .split('&')
+        .find_map(|param| param.strip_prefix(name)?.strip_prefix('='))
+}
+
+/// Who is making a request: the user name of `Authorization: Basic`
+/// credentials (checked by whatever sits in front of the server), or
+/// `anonymous`
+pub fn request_identity(request: &HttpRequest) -> String {
+    request
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Authorization"))
+        .and_then(|(_, value)| {
+            let (scheme, credentials) = value.trim().split_once(' ')?;
+            if !scheme.eq_ignore_ascii_case("Basic") {
+                return None;
+            }
+            let decoded = decode_base64(credentials.trim())?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let user = decoded
+                .split_once(':')
+                .map_or(decoded.as_str(), |(user, _)| user);
+            (!user.is_empty()).then(|| user.to_string())
+        })
+        .unwrap_or_else(|| "anonymous".to_string())
+}
+
+/// Standard (padded or unpadded) base64
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(text.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for byte in text.trim_end_matches('=').bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+/// Inflate a gzip/deflate-encoded request body (git compresses large
+/// upload-pack negotiations). Returns `None` when the body isn't encoded.
+/// The inflated body may be at most `max_len` bytes.
+pub fn decode_request_body(
+    request: &HttpRequest,
+    max_len: u64,
+) -> Result<Option<HttpRequest>, GitError> {
+    let encoding = request
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Content-Encoding"))
+        .map(|(_, value)| value.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    let decode: fn(&[u8], u64) -> std::io::Result<Vec<u8>> = match encoding.as_str() {
+        "" | "identity" => return Ok(None),
+        "gzip" | "x-gzip" => decompress_gzip,
+        "deflate" => decompress_deflate,
+        other => {
+            return Err(GitError::Protocol(format!(
+                "Unsupported Content-Encoding: {}",
+                other
+            )))
+        }
+    };
+
+    let encoded = match &request.body {
+        Some(body) => body,
+        None => return Ok(None),
+    };
+    let body = decode(encoded, max_len)
+        .map_err(|e| GitError::Protocol(format!("Invalid {} request body: {}", encoding, e)))?;
+    if body.len() as u64 > max_len {
+        return Err(GitError::LimitExceeded(format!(
+            "inflated request body exceeds maximum of {} bytes",
+            max_len
+        )));
+    }
+    log(&format!(
+        "Inflated {} request body: {} -> {} bytes",
+        encoding,
+        encoded.len(),
+        body.len()
+    ));
+
+    Ok(Some(HttpRequest {
+        method: request.method.clone(),
+        uri: request.uri.clone(),
+        headers: request
+            .headers
+            .iter()
+            .filter(|(name, _)| !name.eq_ignore_ascii_case("Content-Encoding"))
+            .cloned()
+            .collect(),
+        body: Some(body),
+    }))
+}
+
+/// Whether a POST body is a Protocol v2 command request: either the client asked
+/// for v2, or the body starts with `command=` (v0/v1 bodies start with want/ref updates)
+pub fn is_v2_request(request: &HttpRequest, body: &[u8]) -> bool {
+    requested_protocol_version(request) >= 2
+        || matches!(
+            PktLineReader::from_bytes(body).read_packet(),
+            Ok(Some(PktLine::Data(ref line))) if line.starts_with(b"command=")
+        )
+}
+
+/// Side-band frame size negotiated by the client, if it asked for side-band at all
+pub fn sideband_frame_size(capabilities: &[String]) -> Option<usize> {
+    if capabilities.iter().any(|cap| cap == "side-band-64k") {
+        Some(MAX_SIDEBAND_DATA)
+    } else if capabilities.iter().any(|cap| cap == "side-band") {
+        Some(MAX_SMALL_SIDEBAND_DATA)
+    } else {
+        None
+    }
+}
 
 pub fn create_response(status: u16, content_type: &str, body: &[u8]) -> HttpResponse {
     let headers = vec![
@@ -26,81 +180,112 @@ pub fn not_found() -> HttpResponse {
     create_response(404, "text/plain", b"Not Found")
 }
 
-pub fn create_error_response(message: &str) -> HttpResponse {
-    let mut data = Vec::new();
-    let error_line = format!("ERR {}\n", message);
-    data.extend(encode_pkt_line(error_line.as_bytes()));
-    data.extend(encode_flush_pkt());
-    create_response(400, "application/x-git-upload-pack-result", &data)
+/// Content type a smart HTTP route answers with (`info/refs?service=`,
+/// `git-upload-pack` or `git-receive-pack`, under a namespace or not), or
+/// `None` for routes that don't speak pkt-lines
+pub fn smart_content_type(path: &str, query: &Option<String>) -> Option<&'static str> {
+    if path.ends_with("/info/refs") {
+        return match query_param(query, "service")? {
+            "git-upload-pack" => Some("application/x-git-upload-pack-advertisement"),
+            "git-receive-pack" => Some("application/x-git-receive-pack-advertisement"),
+            _ => None,
+        };
+    }
+    match path.rsplit('/').next()? {
+        "git-upload-pack" => Some("application/x-git-upload-pack-result"),
+        "git-receive-pack" => Some("application/x-git-receive-pack-result"),
+        _ => None,
+    }
+}
+
+/// Answer a failed request. A smart HTTP route (`content_type` is what it
+/// answers with) gets an `ERR` pkt-line and status 200, since git only shows
+/// the message (as `remote error: ...`) in a response it reads as a success.
+/// Other routes get the message as text, with the error's HTTP status.
+pub fn create_error_response(error: &GitError, content_type: Option<&str>) -> HttpResponse {
+    log(&format!("Request failed ({}): {}", error.kind(), error));
+    match content_type {
+        Some(content_type) => {
+            let mut writer = PktLineWriter::new();
+            writer.write_message(&format!("ERR {}", error.pkt_line_message()));
+            writer.flush();
+            create_response(200, content_type, &writer.into_bytes())
+        }
+        None => create_response(
+            error.http_status(),
+            "text/plain",
+            format!("{}\n", error).as_bytes(),
+        ),
+    }
 }
 
-pub fn create_status_response(success: bool, ref_statuses: Vec<String>) -> HttpResponse {
-    create_status_response_with_capabilities(success, ref_statuses, &[])
+/// Report-status for a push whose request or pack couldn't be processed
+pub fn create_unpack_error_response(
+    error: &GitError,
+    ref_names: &[String],
+    capabilities: &[String],
+) -> HttpResponse {
+    log(&format!("Push failed ({}): {}", error.kind(), error));
+    let ref_statuses = ref_names
+        .iter()
+        .map(|name| format!("ng {} {}", name, error.ng_reason()))
+        .collect();
+    create_status_response_with_capabilities(Err(error), ref_statuses, capabilities)
 }
 
 pub fn create_status_response_with_capabilities(
-    success: bool,
+    unpack: Result<(), &GitError>,
+    ref_statuses: Vec<String>,
+    capabilities: &[String],
+) -> HttpResponse {
+    create_status_response_with_messages(unpack, ref_statuses, capabilities, &[])
+}
+
+/// Report-status preceded by `messages` for the pusher, which git prints as
+/// `remote:` lines. They need the progress channel, so are dropped without
+/// side-band.
+pub fn create_status_response_with_messages(
+    unpack: Result<(), &GitError>,
     ref_statuses: Vec<String>,
     capabilities: &[String],
+    messages: &[String],
 ) -> HttpResponse {
-    let mut data = Vec::new();
-    let use_sideband = capabilities.contains(&"side-band-64k".to_string());
+    let sideband = sideband_frame_size(capabilities);
 
     log(&format!(
         "Creating status response with sideband: {}",
-        use_sideband
+        sideband.is_some()
     ));
 
+    // The report itself is a pkt-line stream ending in flush
+    let mut report = PktLineWriter::new();
+
     // Unpack status
-    if success {
-        if use_sideband {
-            data.extend(encode_status_message(b"unpack ok\n"));
-        } else {
-            data.extend(encode_pkt_line(b"unpack ok\n"));
-        }
-    } else if use_sideband {
-        data.extend(encode_status_message(b"unpack failed\n"));
-    } else {
-        data.extend(encode_pkt_line(b"unpack failed\n"));
-    }
+    let unpack_line = match unpack {
+        Ok(()) => "unpack ok".to_string(),
+        Err(error) => format!("unpack {}", error),
+    };
+    report.write_message(&unpack_line);
 
     // Reference statuses
     for status in ref_statuses {
-        let line = format!("{}\n", status);
-        if use_sideband {
-            data.extend(encode_status_message(line.as_bytes()));
-        } else {
-            data.extend(encode_pkt_line(line.as_bytes()));
-        }
+        report.write_message(&status);
     }
+    report.flush();
 
-    data.extend(encode_flush_pkt());
-    create_response(200, "application/x-git-receive-pack-result", &data)
-}
-
-// ============================================================================
-// Packet utilities
-pub fn encode_pkt_line(data: &[u8]) -> Vec<u8> {
-    let total_len = data.len() + 4;
-    let mut result = format!("{:04x}", total_len).into_bytes();
-    result.extend_from_slice(data);
-    result
-}
-
-pub fn encode_flush_pkt() -> Vec<u8> {
-    b"0000".to_vec()
-}
-
-// Sideband encoding functions
-pub fn encode_sideband_data(band: u8, payload: &[u8]) -> Vec<u8> {
-    let mut out = Vec::with_capacity(4 + 1 + payload.len());
-    let len_total = 4 /*header*/ + 1 /*band*/ + payload.len();
-    out.extend(format!("{len_total:04x}").as_bytes()); // <-- include the 4 bytes!
-    out.push(band);
-    out.extend(payload);
-    out
-}
+    // With side-band the whole report travels on the data channel
+    let data = if let Some(frame_size) = sideband {
+        let mut muxed = PktLineWriter::new();
+        for message in messages {
+            let line = format!("{}\n", message);
+            muxed.write_sideband_frames(BAND_PROGRESS, line.as_bytes(), frame_size);
+        }
+        muxed.write_sideband_frames(BAND_DATA, &report.into_bytes(), frame_size);
+        muxed.flush();
+        muxed.into_bytes()
+    } else {
+        report.into_bytes()
+    };
 
-pub fn encode_status_message(message: &[u8]) -> Vec<u8> {
-    encode_sideband_data(1, message) // Band 1 = status messages
+    create_response(200, "application/x-git-receive-pack-result", &data)
 }
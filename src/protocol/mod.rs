@@ -1,3 +1,7 @@
 pub mod command_request;
+pub mod daemon;
+pub mod events;
 pub mod http;
+pub mod pktline;
 pub mod push_request;
+pub mod upload_request;
@@ -0,0 +1,294 @@
+//! pkt-line Codec
+//!
+//! Every git protocol exchange is framed as pkt-lines: a 4-digit hex length
+//! (including the 4 length bytes) followed by the payload. Lengths below 4
+//! are special packets:
+//!
+//! - `0000` flush-pkt: end of a message section
+//! - `0001` delim-pkt: separates sections in protocol v2
+//! - `0002` response-end-pkt: end of a stateless-connect response (v2)
+//!
+//! `PktLineReader` decodes incrementally (feed bytes as they arrive, take
+//! packets once complete), and `PktLineWriter` encodes while enforcing the
+//! maximum packet size and side-band multiplexing.
+
+use crate::error::GitError;
+use crate::protocol::http::{MAX_PKT_PAYLOAD, MAX_SIDEBAND_DATA};
+
+/// Side-band channel carrying pack data
+pub const BAND_DATA: u8 = 1;
+/// Side-band channel carrying progress messages (`remote:` output)
+pub const BAND_PROGRESS: u8 = 2;
+/// Side-band channel carrying a fatal error message
+pub const BAND_ERROR: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PktLine {
+    Data(Vec<u8>),
+    Flush,
+    Delim,
+    ResponseEnd,
+}
+
+impl PktLine {
+    /// Payload as text with the trailing newline removed, for data packets
+    pub fn as_text(&self) -> Result<Option<&str>, GitError> {
+        match self {
+            PktLine::Data(payload) => std::str::from_utf8(payload)
+                .map(|line| Some(line.trim_end_matches('\n')))
+                .map_err(|e| GitError::Protocol(format!("Invalid UTF-8 in packet content: {}", e))),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Incremental pkt-line decoder
+#[derive(Debug, Default)]
+pub struct PktLineReader {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl PktLineReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reader over a complete message
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let mut reader = Self::new();
+        reader.feed(data);
+        reader
+    }
+
+    /// Append newly received bytes
+    pub fn feed(&mut self, data: &[u8]) {
+        // Drop consumed bytes before growing the buffer
+        if self.pos > 0 {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Bytes received but not yet consumed as packets (e.g. a trailing pack)
+    pub fn remaining(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    /// Decode the next packet, or `Ok(None)` if more input is needed
+    pub fn next_packet(&mut self) -> Result<Option<PktLine>, GitError> {
+        let available = &self.buf[self.pos..];
+        if available.len() < 4 {
+            return Ok(None);
+        }
+
+        let len_str = std::str::from_utf8(&available[..4])
+            .map_err(|_| GitError::Protocol("Invalid packet".to_string()))?;
+        let len = usize::from_str_radix(len_str, 16)
+            .map_err(|_| GitError::Protocol(format!("Invalid packet length: {:?}", len_str)))?;
+
+        let packet = match len {
+            0 => PktLine::Flush,
+            1 => PktLine::Delim,
+            2 => PktLine::ResponseEnd,
+            3 => {
+                return Err(GitError::Protocol(
+                    "Invalid packet length: 3 (must be >= 4)".to_string(),
+                ))
+            }
+            _ if len > MAX_PKT_PAYLOAD + 4 => {
+                return Err(GitError::Protocol(format!(
+                    "Packet length {} exceeds maximum of {}",
+                    len,
+                    MAX_PKT_PAYLOAD + 4
+                )))
+            }
+            _ if available.len() < len => return Ok(None),
+            _ => PktLine::Data(available[4..len].to_vec()),
+        };
+
+        self.pos += if let PktLine::Data(_) = packet {
+            len
+        } else {
+            4
+        };
+        Ok(Some(packet))
+    }
+
+    /// Decode the next packet of a complete message; running out of input is an error
+    pub fn read_packet(&mut self) -> Result<Option<PktLine>, GitError> {
+        match self.next_packet()? {
+            Some(packet) => Ok(Some(packet)),
+            None if self.remaining().is_empty() => Ok(None),
+            None => Err(GitError::Protocol(format!(
+                "Truncated packet: {} trailing bytes",
+                self.remaining().len()
+            ))),
+        }
+    }
+}
+
+/// pkt-line encoder
+#[derive(Debug, Default)]
+pub struct PktLineWriter {
+    buf: Vec<u8>,
+}
+
+impl PktLineWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write one data packet, rejecting payloads over the protocol limit
+    pub fn write_data(&mut self, payload: &[u8]) -> Result<(), GitError> {
+        if payload.len() > MAX_PKT_PAYLOAD {
+            return Err(GitError::LimitExceeded(format!(
+                "pkt-line payload of {} bytes exceeds maximum of {}",
+                payload.len(),
+                MAX_PKT_PAYLOAD
+            )));
+        }
+        self.buf
+            .extend(format!("{:04x}", payload.len() + 4).as_bytes());
+        self.buf.extend_from_slice(payload);
+        Ok(())
+    }
+
+    /// Write a text line, adding the terminating newline
+    pub fn write_line(&mut self, line: &str) -> Result<(), GitError> {
+        let mut payload = Vec::with_capacity(line.len() + 1);
+        payload.extend_from_slice(line.as_bytes());
+        payload.push(b'\n');
+        self.write_data(&payload)
+    }
+
+    /// Write a human-readable line, truncating it to fit in a single pkt-line
+    pub fn write_message(&mut self, message: &str) {
+        let mut end = message.len().min(MAX_PKT_PAYLOAD - 1);
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.write_line(&message[..end])
+            .expect("truncated line always fits in a pkt-line");
+    }
+
+    pub fn flush(&mut self) {
+        self.buf.extend(b"0000");
+    }
+
+    pub fn delim(&mut self) {
+        self.buf.extend(b"0001");
+    }
+
+    pub fn response_end(&mut self) {
+        self.buf.extend(b"0002");
+    }
+
+    /// Multiplex `data` onto a side-band channel, split into maximum-size frames
+    pub fn write_sideband(&mut self, band: u8, data: &[u8]) {
+        self.write_sideband_frames(band, data, MAX_SIDEBAND_DATA);
+    }
+
+    /// Multiplex `data` in frames of at most `max_data` bytes
+    /// (`side-band` clients only accept 1000-byte packets)
+    pub fn write_sideband_frames(&mut self, band: u8, data: &[u8], max_data: usize) {
+        for chunk in data.chunks(max_data) {
+            self.buf
+                .extend(format!("{:04x}", chunk.len() + 5).as_bytes());
+            self.buf.push(band);
+            self.buf.extend_from_slice(chunk);
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Split a side-band packet payload into its channel and data
+pub fn demux_sideband(payload: &[u8]) -> Result<(u8, &[u8]), GitError> {
+    match payload.split_first() {
+        Some((&band, data)) if (BAND_DATA..=BAND_ERROR).contains(&band) => Ok((band, data)),
+        Some((&band, _)) => Err(GitError::Protocol(format!(
+            "Invalid side-band channel: {}",
+            band
+        ))),
+        None => Err(GitError::Protocol("Empty side-band packet".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_special_packets() {
+        let mut reader = PktLineReader::from_bytes(b"0008abcd000000010002");
+
+        assert_eq!(
+            reader.read_packet().unwrap(),
+            Some(PktLine::Data(b"abcd".to_vec()))
+        );
+        assert_eq!(reader.read_packet().unwrap(), Some(PktLine::Flush));
+        assert_eq!(reader.read_packet().unwrap(), Some(PktLine::Delim));
+        assert_eq!(reader.read_packet().unwrap(), Some(PktLine::ResponseEnd));
+        assert_eq!(reader.read_packet().unwrap(), None);
+    }
+
+    #[test]
+    fn test_incremental_input() {
+        let mut reader = PktLineReader::new();
+
+        reader.feed(b"000");
+        assert_eq!(reader.next_packet().unwrap(), None);
+        reader.feed(b"9hel");
+        assert_eq!(reader.next_packet().unwrap(), None);
+        reader.feed(b"lo0000");
+
+        assert_eq!(
+            reader.next_packet().unwrap(),
+            Some(PktLine::Data(b"hello".to_vec()))
+        );
+        assert_eq!(reader.next_packet().unwrap(), Some(PktLine::Flush));
+    }
+
+    #[test]
+    fn test_truncated_and_invalid_lengths() {
+        assert!(PktLineReader::from_bytes(b"0010abc").read_packet().is_err());
+        assert!(PktLineReader::from_bytes(b"0003").read_packet().is_err());
+        assert!(PktLineReader::from_bytes(b"zzzz").read_packet().is_err());
+        assert!(PktLineReader::from_bytes(b"fff5").read_packet().is_err());
+    }
+
+    #[test]
+    fn test_writer_enforces_max_payload() {
+        let mut writer = PktLineWriter::new();
+        assert!(writer.write_data(&vec![b'x'; MAX_PKT_PAYLOAD]).is_ok());
+        assert!(matches!(
+            writer.write_data(&vec![b'x'; MAX_PKT_PAYLOAD + 1]),
+            Err(GitError::LimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn test_sideband_round_trip() {
+        let data = vec![7u8; MAX_SIDEBAND_DATA * 2 + 10];
+        let mut writer = PktLineWriter::new();
+        writer.write_sideband(BAND_DATA, &data);
+        writer.flush();
+
+        let mut reader = PktLineReader::from_bytes(&writer.into_bytes());
+        let mut received = Vec::new();
+        let mut frames = 0;
+        while let Some(PktLine::Data(payload)) = reader.read_packet().unwrap() {
+            let (band, chunk) = demux_sideband(&payload).unwrap();
+            assert_eq!(band, BAND_DATA);
+            received.extend_from_slice(chunk);
+            frames += 1;
+        }
+
+        assert_eq!(frames, 3);
+        assert_eq!(received, data);
+    }
+}
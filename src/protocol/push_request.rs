@@ -1,117 +1,83 @@
-use crate::bindings::theater::simple::runtime::log;
+use crate::error::GitError;
+use crate::host::log;
+use crate::protocol::command_request::CommandRequest;
+use crate::protocol::pktline::{PktLine, PktLineReader};
 
 #[derive(Debug)]
 pub struct PushRequest {
     pub ref_updates: Vec<(String, String, String)>, // (ref_name, old_oid, new_oid)
     pub pack_data: Vec<u8>,
     pub capabilities: Vec<String>, // Client-requested capabilities
+    pub push_options: Vec<String>, // `git push -o` values, when push-options was negotiated
 }
 
-pub fn parse_receive_pack_request(data: &[u8]) -> Result<PushRequest, String> {
+pub fn parse_receive_pack_request(data: &[u8]) -> Result<PushRequest, GitError> {
     log("Parsing Protocol v1 receive-pack request");
 
-    let mut cursor = 0;
+    let mut reader = PktLineReader::from_bytes(data);
     let mut ref_updates = Vec::new();
     let mut capabilities = Vec::new();
-    let mut pack_start = 0;
     let mut first_ref = true;
 
-    // Phase 1: Parse ref update commands
-    while cursor < data.len() {
-        if cursor + 4 > data.len() {
-            break;
-        }
-
+    // Phase 1: Parse ref update commands up to the flush-pkt
+    loop {
         // Check for PACK signature
-        if data[cursor..].starts_with(b"PACK") {
-            pack_start = cursor;
+        if reader.remaining().starts_with(b"PACK") {
             break;
         }
 
-        let len_str =
-            std::str::from_utf8(&data[cursor..cursor + 4]).map_err(|_| "Invalid packet")?;
-        let len = u16::from_str_radix(len_str, 16).map_err(|_| "Invalid packet length")?;
-
-        if len == 0 {
-            cursor += 4;
-            continue; // Flush packet
-        }
-
-        if len < 4 || cursor + len as usize > data.len() {
-            return Err("Invalid packet".to_string());
-        }
-
-        let content = &data[cursor + 4..cursor + len as usize];
-        let line = std::str::from_utf8(content)
-            .map_err(|_| "Invalid UTF-8")?
-            .trim_end_matches('\n');
+        let packet = match reader.read_packet()? {
+            Some(packet) => packet,
+            None => break,
+        };
+        let line = match packet {
+            PktLine::Data(_) => packet.as_text()?.unwrap_or_default(),
+            // Flush ends the command list; the pack (if any) follows
+            PktLine::Flush => break,
+            PktLine::Delim | PktLine::ResponseEnd => {
+                return Err(GitError::Protocol(
+                    "Unexpected special packet in push commands".to_string(),
+                ))
+            }
+        };
 
         // Parse ref update line: "old-oid new-oid ref-name [\0capabilities]"
-        if first_ref {
-            // First ref may contain capabilities after null byte
-            if let Some(null_pos) = line.find('\0') {
-                let ref_part = &line[..null_pos];
-                let cap_part = &line[null_pos + 1..];
-
-                // Parse capabilities
+        // Only the first ref may carry capabilities after a null byte
+        let ref_part = match line.split_once('\0') {
+            Some((ref_part, cap_part)) if first_ref => {
                 capabilities = cap_part.split_whitespace().map(|s| s.to_string()).collect();
-
                 log(&format!("Parsed capabilities: {:?}", capabilities));
+                ref_part
+            }
+            _ => line,
+        };
+        first_ref = false;
+
+        if let Some(update) = parse_ref_update(ref_part) {
+            ref_updates.push(update);
+        }
+    }
 
-                // Parse ref update from the part before null
-                let parts: Vec<&str> = ref_part.split_whitespace().collect();
-                if parts.len() >= 3 {
-                    ref_updates.push((
-                        parts[2].to_string(), // ref name
-                        parts[0].to_string(), // old oid
-                        parts[1].to_string(), // new oid
-                    ));
-                    log(&format!(
-                        "Parsed ref update: {} {} -> {}",
-                        parts[2], parts[0], parts[1]
-                    ));
+    // Phase 2: Push options, one per line up to their own flush-pkt
+    let mut push_options = Vec::new();
+    if capabilities.iter().any(|cap| cap == "push-options") {
+        while let Some(packet) = reader.read_packet()? {
+            match packet {
+                PktLine::Data(_) => {
+                    push_options.push(packet.as_text()?.unwrap_or_default().to_string())
                 }
-            } else {
-                // No capabilities, parse as normal
-                let parts: Vec<&str> = line.split_whitespace().collect();
-                if parts.len() >= 3 {
-                    ref_updates.push((
-                        parts[2].to_string(), // ref name
-                        parts[0].to_string(), // old oid
-                        parts[1].to_string(), // new oid
-                    ));
-                    log(&format!(
-                        "Parsed ref update: {} {} -> {}",
-                        parts[2], parts[0], parts[1]
-                    ));
+                PktLine::Flush => break,
+                PktLine::Delim | PktLine::ResponseEnd => {
+                    return Err(GitError::Protocol(
+                        "Unexpected special packet in push options".to_string(),
+                    ))
                 }
             }
-            first_ref = false;
-        } else {
-            // Subsequent refs don't have capabilities
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 3 {
-                ref_updates.push((
-                    parts[2].to_string(), // ref name
-                    parts[0].to_string(), // old oid
-                    parts[1].to_string(), // new oid
-                ));
-                log(&format!(
-                    "Parsed ref update: {} {} -> {}",
-                    parts[2], parts[0], parts[1]
-                ));
-            }
         }
-
-        cursor += len as usize;
     }
 
-    // Phase 2: Extract pack data
-    let pack_data = if pack_start > 0 {
-        data[pack_start..].to_vec()
-    } else {
-        Vec::new()
-    };
+    // Phase 3: Extract pack data
+    let pack_data = reader.remaining().to_vec();
 
     log(&format!(
         "Parsed {} ref updates, {} bytes pack data",
@@ -123,5 +89,152 @@ pub fn parse_receive_pack_request(data: &[u8]) -> Result<PushRequest, String> {
         ref_updates,
         pack_data,
         capabilities,
+        push_options,
     })
 }
+
+/// Convert a Protocol v2 `push` command: ref update lines and `push-option
+/// <value>` lines are the arguments, and the pack follows the closing
+/// flush-pkt.
+///
+/// Protocol v2 defines no `push` command, and git itself pushes over v0/v1
+/// even when asked for v2. This one is our own extension, for clients that
+/// want to discover refs with `ls-refs` and push over the same protocol.
+pub fn parse_push_command(request: &CommandRequest) -> Result<PushRequest, GitError> {
+    log("Parsing Protocol v2 push command");
+
+    let mut ref_updates = Vec::new();
+    let mut push_options = Vec::new();
+    for line in &request.args {
+        if let Some(option) = line.strip_prefix("push-option ") {
+            push_options.push(option.to_string());
+            continue;
+        }
+        let update = parse_ref_update(line)
+            .ok_or_else(|| GitError::Protocol(format!("Invalid ref update: {}", line)))?;
+        ref_updates.push(update);
+    }
+
+    Ok(PushRequest {
+        ref_updates,
+        pack_data: request.data.clone(),
+        capabilities: request.capabilities.clone(),
+        push_options,
+    })
+}
+
+/// Parse "old-oid new-oid ref-name" into (ref_name, old_oid, new_oid). The
+/// name is everything after the second space, so a name with spaces in it
+/// reaches ref validation whole rather than cut short.
+fn parse_ref_update(line: &str) -> Option<(String, String, String)> {
+    let parts: Vec<&str> = line.trim_end_matches('\n').splitn(3, ' ').collect();
+    if parts.len() < 3 || parts[2].is_empty() {
+        return None;
+    }
+    log(&format!(
+        "Parsed ref update: {} {} -> {}",
+        parts[2], parts[0], parts[1]
+    ));
+    Some((
+        parts[2].to_string(), // ref name
+        parts[0].to_string(), // old oid
+        parts[1].to_string(), // new oid
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::pktline::PktLineWriter;
+
+    #[test]
+    fn test_commands_capabilities_and_pack() {
+        let old = "0".repeat(40);
+        let new = "a".repeat(40);
+        let mut writer = PktLineWriter::new();
+        writer
+            .write_line(&format!(
+                "{} {} refs/heads/main\0report-status side-band-64k",
+                old, new
+            ))
+            .unwrap();
+        writer
+            .write_line(&format!("{} {} refs/heads/dev", old, new))
+            .unwrap();
+        writer
+            .write_line(&format!("{} {} refs/heads/a b", old, new))
+            .unwrap();
+        writer.flush();
+        let mut body = writer.into_bytes();
+        body.extend_from_slice(b"PACK\0\0\0\x02");
+
+        let push = parse_receive_pack_request(&body).unwrap();
+
+        assert_eq!(push.capabilities, vec!["report-status", "side-band-64k"]);
+        assert_eq!(push.ref_updates.len(), 3);
+        assert_eq!(
+            push.ref_updates[1],
+            ("refs/heads/dev".to_string(), old.clone(), new.clone())
+        );
+        // Kept whole, to be refused as a funny refname
+        assert_eq!(
+            push.ref_updates[2],
+            ("refs/heads/a b".to_string(), old, new)
+        );
+        assert_eq!(push.pack_data, b"PACK\0\0\0\x02");
+    }
+
+    #[test]
+    fn test_push_options_follow_the_commands() {
+        let old = "0".repeat(40);
+        let new = "a".repeat(40);
+        let mut writer = PktLineWriter::new();
+        writer
+            .write_line(&format!(
+                "{} {} refs/heads/main\0report-status push-options",
+                old, new
+            ))
+            .unwrap();
+        writer.flush();
+        writer.write_line("ci.skip").unwrap();
+        writer.write_line("reason=hotfix").unwrap();
+        writer.flush();
+        let mut body = writer.into_bytes();
+        body.extend_from_slice(b"PACK\0\0\0\x02");
+
+        let push = parse_receive_pack_request(&body).unwrap();
+
+        assert_eq!(push.push_options, vec!["ci.skip", "reason=hotfix"]);
+        assert_eq!(push.ref_updates.len(), 1);
+        assert_eq!(push.pack_data, b"PACK\0\0\0\x02");
+    }
+
+    #[test]
+    fn test_v2_push_command() {
+        let old = "0".repeat(40);
+        let new = "a".repeat(40);
+        let mut writer = PktLineWriter::new();
+        writer.write_line("command=push").unwrap();
+        writer.write_line("report-status").unwrap();
+        writer.delim();
+        writer
+            .write_line(&format!("{} {} refs/heads/main", old, new))
+            .unwrap();
+        writer.write_line("push-option ci.skip").unwrap();
+        writer.write_line("push-option reason=a b").unwrap();
+        writer.flush();
+        let mut body = writer.into_bytes();
+        body.extend_from_slice(b"PACK\0\0\0\x02");
+
+        let command = crate::protocol::command_request::parse_command_request(&body).unwrap();
+        let push = parse_push_command(&command).unwrap();
+
+        assert_eq!(push.capabilities, vec!["report-status"]);
+        assert_eq!(
+            push.ref_updates,
+            vec![("refs/heads/main".to_string(), old, new)]
+        );
+        assert_eq!(push.push_options, vec!["ci.skip", "reason=a b"]);
+        assert_eq!(push.pack_data, b"PACK\0\0\0\x02");
+    }
+}
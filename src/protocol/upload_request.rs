@@ -0,0 +1,106 @@
+use crate::error::GitError;
+use crate::host::log;
+use crate::protocol::pktline::{PktLine, PktLineReader};
+
+/// A Protocol v0/v1 upload-pack negotiation round (one stateless HTTP POST)
+#[derive(Debug, Default)]
+pub struct UploadRequest {
+    pub wants: Vec<String>,
+    pub haves: Vec<String>,
+    pub capabilities: Vec<String>, // Client-requested capabilities (from the first want)
+    pub done: bool,
+}
+
+/// Parse `want` lines, a flush-pkt, then `have` lines ending in flush-pkt or `done`
+pub fn parse_upload_pack_request(data: &[u8]) -> Result<UploadRequest, GitError> {
+    log("Parsing Protocol v1 upload-pack request");
+
+    let mut reader = PktLineReader::from_bytes(data);
+    let mut request = UploadRequest::default();
+
+    while let Some(packet) = reader.read_packet()? {
+        let line = match packet {
+            PktLine::Data(_) => packet.as_text()?.unwrap_or_default(),
+            // Flushes separate the want list and each batch of haves
+            PktLine::Flush => continue,
+            PktLine::Delim | PktLine::ResponseEnd => {
+                return Err(GitError::Protocol(
+                    "Unexpected special packet in upload-pack request".to_string(),
+                ))
+            }
+        };
+
+        if let Some(want) = line.strip_prefix("want ") {
+            // Only the first want may carry capabilities after a space
+            let mut parts = want.split(' ');
+            let oid = parts.next().unwrap_or_default();
+            if request.wants.is_empty() {
+                request.capabilities = parts.map(|s| s.to_string()).collect();
+                log(&format!("Parsed capabilities: {:?}", request.capabilities));
+            }
+            request.wants.push(oid.to_string());
+        } else if let Some(have) = line.strip_prefix("have ") {
+            request.haves.push(have.to_string());
+        } else if line == "done" {
+            request.done = true;
+            break;
+        } else {
+            return Err(GitError::Protocol(format!(
+                "Unexpected line in upload-pack request: {}",
+                line
+            )));
+        }
+    }
+
+    log(&format!(
+        "Parsed {} wants, {} haves, done={}",
+        request.wants.len(),
+        request.haves.len(),
+        request.done
+    ));
+
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::pktline::PktLineWriter;
+
+    #[test]
+    fn test_wants_haves_and_done() {
+        let want = "a".repeat(40);
+        let have = "b".repeat(40);
+        let mut writer = PktLineWriter::new();
+        writer
+            .write_line(&format!("want {} multi_ack_detailed side-band-64k", want))
+            .unwrap();
+        writer.write_line(&format!("want {}", have)).unwrap();
+        writer.flush();
+        writer.write_line(&format!("have {}", have)).unwrap();
+        writer.flush();
+        writer.write_line("done").unwrap();
+
+        let request = parse_upload_pack_request(&writer.into_bytes()).unwrap();
+
+        assert_eq!(request.wants, vec![want, have.clone()]);
+        assert_eq!(
+            request.capabilities,
+            vec!["multi_ack_detailed", "side-band-64k"]
+        );
+        assert_eq!(request.haves, vec![have]);
+        assert!(request.done);
+    }
+
+    #[test]
+    fn test_unknown_line_is_protocol_error() {
+        let mut writer = PktLineWriter::new();
+        writer.write_line("deepen 1").unwrap();
+        writer.flush();
+
+        assert!(matches!(
+            parse_upload_pack_request(&writer.into_bytes()),
+            Err(GitError::Protocol(_))
+        ));
+    }
+}
@@ -1,4 +1,4 @@
-use flate2::read::ZlibDecoder;
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
 use std::io::{Read, Write};
@@ -28,7 +28,6 @@ pub fn compress_zlib(data: &[u8]) -> Vec<u8> {
 ///
 /// Decompresses zlib-compressed data (RFC 1950) as used in Git loose objects.
 /// Uses the same high-performance flate2 library with zlib-rs backend.
-#[allow(dead_code)]
 pub fn decompress_zlib(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
     let mut decoder = ZlibDecoder::new(data);
     let mut result = Vec::new();
@@ -36,6 +35,40 @@ pub fn decompress_zlib(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
     Ok(result)
 }
 
+/// Decompress a gzip stream (RFC 1952), as sent with `Content-Encoding: gzip`
+///
+/// Stops after `max_len + 1` bytes, so callers can reject oversized output
+/// without inflating all of it.
+pub fn decompress_gzip(data: &[u8], max_len: u64) -> Result<Vec<u8>, std::io::Error> {
+    let mut result = Vec::new();
+    GzDecoder::new(data)
+        .take(max_len.saturating_add(1))
+        .read_to_end(&mut result)?;
+    Ok(result)
+}
+
+/// Decompress an HTTP `deflate` body
+///
+/// The spec says zlib-wrapped (RFC 1950), but some clients send raw deflate
+/// (RFC 1951), so fall back to that when the zlib header doesn't parse.
+/// Output is capped at `max_len + 1` bytes like `decompress_gzip`.
+pub fn decompress_deflate(data: &[u8], max_len: u64) -> Result<Vec<u8>, std::io::Error> {
+    let limit = max_len.saturating_add(1);
+    let mut result = Vec::new();
+    if ZlibDecoder::new(data)
+        .take(limit)
+        .read_to_end(&mut result)
+        .is_ok()
+    {
+        return Ok(result);
+    }
+    result.clear();
+    DeflateDecoder::new(data)
+        .take(limit)
+        .read_to_end(&mut result)?;
+    Ok(result)
+}
+
 /// Calculate Adler-32 checksum (now mainly for compatibility/testing)
 ///
 /// Note: flate2 handles checksums internally, but we keep this function
@@ -151,6 +184,34 @@ mod tests {
         assert_eq!(decompressed, original);
     }
 
+    #[test]
+    fn test_decompress_gzip_and_deflate() {
+        use flate2::write::{DeflateEncoder, GzEncoder};
+
+        let original = b"0032want 6ebe0ce5a0eebe93bc84cf42453db8da01f18e32\n00000009done\n";
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(original).unwrap();
+        let gzip = gzip.finish().unwrap();
+        assert_eq!(decompress_gzip(&gzip, 1024).unwrap(), original);
+        // Output stops one byte past the limit
+        assert_eq!(decompress_gzip(&gzip, 10).unwrap().len(), 11);
+
+        // deflate bodies may be zlib-wrapped or raw
+        assert_eq!(
+            decompress_deflate(&compress_zlib(original), 1024).unwrap(),
+            original
+        );
+        let mut raw = DeflateEncoder::new(Vec::new(), Compression::default());
+        raw.write_all(original).unwrap();
+        assert_eq!(
+            decompress_deflate(&raw.finish().unwrap(), 1024).unwrap(),
+            original
+        );
+
+        assert!(decompress_gzip(original, 1024).is_err());
+    }
+
     #[test]
     fn test_compress_decompress_roundtrip() {
         let test_data = b"This is test data for compression roundtrip testing. It should compress and decompress perfectly.";
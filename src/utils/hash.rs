@@ -1,4 +1,75 @@
+use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+/// Object id hash algorithm used by a repository (`extensions.objectFormat`)
+///
+/// Git repositories are either SHA-1 (the historical default) or SHA-256
+/// (`git init --object-format=sha256`). The algorithm decides the object id
+/// width everywhere: object hashing, binary tree entries, pack trailers and
+/// the all-zero "null" oid used for ref creation and deletion.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// Name used in the `object-format=<name>` capability
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    /// Parse an `object-format` capability value
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha1" => Some(HashAlgorithm::Sha1),
+            "sha256" => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    /// Length of a binary object id in bytes
+    pub fn raw_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+        }
+    }
+
+    /// Length of a hex object id in characters
+    pub fn hex_len(&self) -> usize {
+        self.raw_len() * 2
+    }
+
+    /// The all-zero object id for this algorithm
+    pub fn null_oid(&self) -> String {
+        "0".repeat(self.hex_len())
+    }
+
+    /// Check whether `oid` is this algorithm's null object id
+    pub fn is_null_oid(&self, oid: &str) -> bool {
+        oid.len() == self.hex_len() && oid.bytes().all(|b| b == b'0')
+    }
+
+    /// Hash `data` and return the raw digest
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Sha1 => sha1_hash(data).to_vec(),
+            HashAlgorithm::Sha256 => sha256_hash(data).to_vec(),
+        }
+    }
+
+    /// Hash `data` and return the lowercase hex digest
+    pub fn hex_digest(&self, data: &[u8]) -> String {
+        hex::encode(self.digest(data))
+    }
+}
 
 /// Calculate SHA-1 hash using the sha1 crate
 pub fn sha1_hash(data: &[u8]) -> [u8; 20] {
@@ -7,5 +78,77 @@ pub fn sha1_hash(data: &[u8]) -> [u8; 20] {
     hasher.finalize().into()
 }
 
+/// Calculate SHA-256 hash using the sha2 crate
+pub fn sha256_hash(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+/// HMAC-SHA256 (RFC 2104) of `data` keyed with `key`, as webhook signatures use
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&sha256_hash(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(data);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_digests() {
+        assert_eq!(
+            HashAlgorithm::Sha1.hex_digest(b"abc"),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            HashAlgorithm::Sha256.hex_digest(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231 test cases 2 and 6 (a key longer than the block)
+        assert_eq!(
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex::encode(hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn test_null_oid_widths() {
+        assert_eq!(HashAlgorithm::Sha1.null_oid().len(), 40);
+        assert_eq!(HashAlgorithm::Sha256.null_oid().len(), 64);
+        assert!(HashAlgorithm::Sha256.is_null_oid(&"0".repeat(64)));
+        assert!(!HashAlgorithm::Sha256.is_null_oid(&"0".repeat(40)));
+    }
+
+    #[test]
+    fn test_object_format_names() {
+        for algo in [HashAlgorithm::Sha1, HashAlgorithm::Sha256] {
+            assert_eq!(HashAlgorithm::from_name(algo.name()), Some(algo));
+        }
+        assert_eq!(HashAlgorithm::from_name("md5"), None);
+    }
+}
@@ -0,0 +1,91 @@
+#!/bin/bash
+# Regenerates the pack fixtures used by the native integration tests.
+#
+# Each fixture is a two-commit history packed by real git without deltas:
+#   <format>.pack  - pack file as a client would send it on push
+#   <format>.head  - id of the tip commit
+#   <format>.idx   - the pack's index as written by `git index-pack`
+#
+# Author/committer identities and dates are fixed so object ids are stable.
+
+set -euo pipefail
+
+cd "$(dirname "$0")"
+
+export GIT_AUTHOR_NAME="colin"
+export GIT_AUTHOR_EMAIL="colinrozzi@gmail.com"
+export GIT_AUTHOR_DATE="1754330635 -0400"
+export GIT_COMMITTER_NAME="colin"
+export GIT_COMMITTER_EMAIL="colinrozzi@gmail.com"
+export GIT_COMMITTER_DATE="1754330635 -0400"
+
+for format in sha1 sha256; do
+    repo=$(mktemp -d)
+
+    git -C "$repo" init -q --object-format="$format" -b main
+    mkdir "$repo/src"
+    echo "# fixture" > "$repo/README.md"
+    echo "fn main() {}" > "$repo/src/main.rs"
+    git -C "$repo" add .
+    git -C "$repo" -c commit.gpgsign=false commit -q -m "Initial commit"
+
+    echo "more text" >> "$repo/README.md"
+    git -C "$repo" add .
+    git -C "$repo" -c commit.gpgsign=false commit -q -m "Second commit"
+
+    git -C "$repo" rev-parse HEAD > "$format.head"
+    git -C "$repo" rev-list --objects HEAD \
+        | git -C "$repo" pack-objects -q --window=0 --stdout > "$format.pack"
+    cp "$format.pack" "$repo/fixture.pack"
+    git -C "$repo" index-pack -o "$repo/fixture.idx" "$repo/fixture.pack" > /dev/null
+    cp "$repo/fixture.idx" "$format.idx"
+
+    rm -rf "$repo"
+done
+
+# Objects whose headers the object model doesn't keep, with their git ids:
+#   signed.pack  - an annotated tag, a gpg-signed commit, a commit with an
+#                  `encoding` header and a merge of a signed tag (`mergetag`)
+#   signed.idx   - the pack's index as written by `git index-pack`
+#   signed.ids   - `<name> <id>` of each of those objects
+#
+# The signing key is made up on the spot, so the ids change on every run.
+repo=$(mktemp -d)
+export GNUPGHOME="$repo/.gnupg"
+mkdir -m 700 "$GNUPGHOME"
+gpg -q --batch --passphrase '' --quick-gen-key "colin <colinrozzi@gmail.com>" ed25519 sign never 2> /dev/null
+
+git -C "$repo" init -q -b main
+git -C "$repo" config user.signingkey colinrozzi@gmail.com
+echo "# fixture" > "$repo/README.md"
+git -C "$repo" add .
+git -C "$repo" -c commit.gpgsign=false commit -q -m "Initial commit"
+git -C "$repo" checkout -q -b side
+
+echo "signed" >> "$repo/README.md"
+git -C "$repo" commit -q -a -S -m "Signed commit"
+signed=$(git -C "$repo" rev-parse HEAD)
+git -C "$repo" tag -a v1.0 -m "Release 1.0"
+git -C "$repo" tag -s v1.1 -m "Release 1.1"
+
+git -C "$repo" checkout -q main
+echo "encoded" > "$repo/NOTES"
+git -C "$repo" add NOTES
+git -C "$repo" -c commit.gpgsign=false -c i18n.commitEncoding=ISO-8859-1 commit -q -m "Encoded commit"
+encoded=$(git -C "$repo" rev-parse HEAD)
+git -C "$repo" -c commit.gpgsign=false merge -q --no-ff --no-edit v1.1
+
+{
+    echo "tag $(git -C "$repo" rev-parse v1.0)"
+    echo "signed $signed"
+    echo "encoded $encoded"
+    echo "merge $(git -C "$repo" rev-parse HEAD)"
+} > signed.ids
+git -C "$repo" rev-list --objects HEAD v1.0 v1.1 \
+    | git -C "$repo" pack-objects -q --window=0 --stdout > signed.pack
+cp signed.pack "$repo/fixture.pack"
+git -C "$repo" index-pack -o "$repo/fixture.idx" "$repo/fixture.pack" > /dev/null
+cp "$repo/fixture.idx" signed.idx
+
+gpgconf --kill gpg-agent 2> /dev/null || true
+rm -rf "$repo"
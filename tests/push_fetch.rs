@@ -0,0 +1,1608 @@
+//! Native end-to-end tests: push a real git pack, then fetch it back.
+//!
+//! Fixtures come from `tests/fixtures/generate.sh`.
+
+use git_server::config::{Config, WebhookEndpoint};
+use git_server::git::delta::create_delta;
+use git_server::git::objects::{
+    DeltaBase, GitObject, LooseObjectSerializer, PackSerializer, TreeEntry, OBJ_REF_DELTA,
+};
+use git_server::git::repository::GitRepoState;
+use git_server::git::state::{decode_state, encode_state};
+use git_server::host::{register_native_actor, register_native_http_client};
+use git_server::protocol::daemon::DaemonSession;
+use git_server::protocol::events::EventStream;
+use git_server::protocol::pktline::{
+    demux_sideband, PktLine, PktLineReader, PktLineWriter, BAND_DATA, BAND_PROGRESS,
+};
+use git_server::utils::compression::{compress_zlib, decompress_zlib};
+use git_server::utils::hash::{hmac_sha256, HashAlgorithm};
+use git_server::{HttpRequest, HttpResponse};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+fn fixture(format: HashAlgorithm) -> (Vec<u8>, String) {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+    let pack = std::fs::read(format!("{}/{}.pack", dir, format.name())).unwrap();
+    let head = std::fs::read_to_string(format!("{}/{}.head", dir, format.name())).unwrap();
+    (pack, head.trim().to_string())
+}
+
+/// The pack of tags and commits with headers the object model doesn't keep,
+/// and the git id of each of them by name (`tag`, `signed`, `encoded`, `merge`)
+fn signed_fixture() -> (Vec<u8>, Vec<(String, String)>) {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+    let pack = std::fs::read(format!("{}/signed.pack", dir)).unwrap();
+    let ids = std::fs::read_to_string(format!("{}/signed.ids", dir)).unwrap();
+    let ids = ids
+        .lines()
+        .map(|line| {
+            let (name, id) = line.split_once(' ').unwrap();
+            (name.to_string(), id.to_string())
+        })
+        .collect();
+    (pack, ids)
+}
+
+fn fixture_id<'a>(ids: &'a [(String, String)], name: &str) -> &'a str {
+    &ids.iter().find(|(n, _)| n == name).unwrap().1
+}
+
+fn request(method: &str, uri: &str, body: Option<Vec<u8>>) -> HttpRequest {
+    HttpRequest {
+        method: method.to_string(),
+        uri: uri.to_string(),
+        headers: vec![],
+        body,
+    }
+}
+
+fn body_text(response: &HttpResponse) -> String {
+    String::from_utf8_lossy(response.body.as_deref().unwrap_or_default()).into_owned()
+}
+
+fn push_main(repo: &mut GitRepoState, pack: &[u8], head: &str) -> HttpResponse {
+    let command = format!(
+        "{} {} refs/heads/main\0report-status object-format={} agent=git/2.39.5",
+        repo.object_format.null_oid(),
+        head,
+        repo.object_format.name()
+    );
+    let mut writer = PktLineWriter::new();
+    writer.write_line(&command).unwrap();
+    writer.flush();
+    let mut body = writer.into_bytes();
+    body.extend_from_slice(pack);
+
+    repo.dispatch(&request("POST", "/git-receive-pack", Some(body)))
+}
+
+/// Extract the pack from a v2 fetch response (side-band channel 1 frames)
+fn extract_pack(response: &[u8]) -> Vec<u8> {
+    let mut reader = PktLineReader::from_bytes(response);
+    let mut pack = Vec::new();
+    let mut in_packfile = false;
+
+    while let Some(packet) = reader.read_packet().unwrap() {
+        if let PktLine::Data(payload) = packet {
+            if in_packfile {
+                let (band, data) = demux_sideband(&payload).unwrap();
+                if band == BAND_DATA {
+                    pack.extend_from_slice(data);
+                }
+            } else if payload == b"packfile\n" {
+                in_packfile = true;
+            }
+        }
+    }
+
+    pack
+}
+
+fn push_then_fetch(format: HashAlgorithm) {
+    let (pack, head) = fixture(format);
+    let expected: Vec<String> = PackSerializer::parse(&pack, format)
+        .unwrap()
+        .iter()
+        .map(|obj| obj.compute_hash(format))
+        .collect();
+
+    let mut repo = GitRepoState::with_object_format(format);
+
+    let pushed = push_main(&mut repo, &pack, &head);
+    assert_eq!(pushed.status, 200);
+    assert!(body_text(&pushed).contains("ok refs/heads/main"));
+    assert_eq!(repo.refs.get("refs/heads/main"), Some(&head));
+    assert_eq!(repo.objects.len(), expected.len());
+    assert!(repo.validate().is_empty());
+    // The pack is stored as received rather than unpacked
+    assert_eq!(repo.objects.packs().len(), 1);
+    assert!(repo.objects.loose().is_empty());
+
+    let mut ls_refs = PktLineWriter::new();
+    ls_refs.write_line("command=ls-refs").unwrap();
+    ls_refs.flush();
+    let listed = repo.dispatch(&request(
+        "POST",
+        "/git-upload-pack",
+        Some(ls_refs.into_bytes()),
+    ));
+    assert!(body_text(&listed).contains(&format!("{} refs/heads/main", head)));
+
+    let mut fetch = PktLineWriter::new();
+    fetch.write_line("command=fetch").unwrap();
+    fetch.delim();
+    fetch.write_line(&format!("want {}", head)).unwrap();
+    fetch.write_line("done").unwrap();
+    fetch.flush();
+    let fetched = repo.dispatch(&request(
+        "POST",
+        "/git-upload-pack",
+        Some(fetch.into_bytes()),
+    ));
+    assert_eq!(fetched.status, 200);
+
+    let served = extract_pack(fetched.body.as_deref().unwrap());
+    let mut served_ids: Vec<String> = PackSerializer::parse(&served, format)
+        .unwrap()
+        .iter()
+        .map(|obj| obj.compute_hash(format))
+        .collect();
+    let mut expected_ids = expected;
+    served_ids.sort();
+    expected_ids.sort();
+    assert_eq!(served_ids, expected_ids);
+
+    // Stored entries are copied into the fetch pack byte for byte
+    let crcs = |pack: &[u8]| {
+        let mut crcs: Vec<u32> = PackSerializer::parse_entries(pack, format)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.crc32)
+            .collect();
+        crcs.sort();
+        crcs
+    };
+    assert_eq!(crcs(&served), crcs(&pack));
+}
+
+#[test]
+fn sha1_push_then_fetch() {
+    push_then_fetch(HashAlgorithm::Sha1);
+}
+
+#[test]
+fn sha256_push_then_fetch() {
+    push_then_fetch(HashAlgorithm::Sha256);
+}
+
+#[test]
+fn advertisement_reports_object_format() {
+    let mut repo = GitRepoState::with_object_format(HashAlgorithm::Sha256);
+
+    let upload = repo.dispatch(&request("GET", "/info/refs?service=git-upload-pack", None));
+    assert!(body_text(&upload).contains("object-format=sha256"));
+
+    let receive = repo.dispatch(&request("GET", "/info/refs?service=git-receive-pack", None));
+    let text = body_text(&receive);
+    assert!(text.contains(&format!("{} capabilities^{{}}", "0".repeat(64))));
+    assert!(text.contains("object-format=sha256"));
+}
+
+#[test]
+fn push_with_mismatched_object_format_is_rejected() {
+    let (pack, head) = fixture(HashAlgorithm::Sha256);
+    let mut repo = GitRepoState::default();
+
+    let command = format!(
+        "{} {} refs/heads/main\0report-status object-format=sha256",
+        HashAlgorithm::Sha256.null_oid(),
+        head
+    );
+    let mut writer = PktLineWriter::new();
+    writer.write_line(&command).unwrap();
+    writer.flush();
+    let mut body = writer.into_bytes();
+    body.extend_from_slice(&pack);
+
+    let response = repo.dispatch(&request("POST", "/git-receive-pack", Some(body)));
+    let text = body_text(&response);
+    assert!(text.contains("unpack protocol error: object-format mismatch"));
+    assert!(text.contains("ng refs/heads/main protocol error: object-format mismatch"));
+    assert!(repo.refs.is_empty());
+}
+
+#[test]
+fn corrupt_pack_reports_unpack_error_and_ng() {
+    let (mut pack, head) = fixture(HashAlgorithm::Sha1);
+    let last = pack.len() - 1;
+    pack[last] ^= 0xff;
+
+    let mut repo = GitRepoState::default();
+    let response = push_main(&mut repo, &pack, &head);
+    let text = body_text(&response);
+
+    assert!(text.contains("unpack corrupt pack: Pack checksum mismatch"));
+    assert!(text.contains("ng refs/heads/main unpacker error"));
+    assert!(repo.objects.is_empty());
+}
+
+fn pkt_lines(response: &[u8]) -> Vec<String> {
+    let mut reader = PktLineReader::from_bytes(response);
+    let mut lines = Vec::new();
+    while let Ok(Some(packet)) = reader.read_packet() {
+        if let Ok(Some(line)) = packet.as_text() {
+            lines.push(line.to_string());
+        }
+    }
+    lines
+}
+
+/// The `ERR` message a smart HTTP route answered with. It comes with status
+/// 200 and the route's own content type, or git wouldn't show it.
+fn remote_error(response: &HttpResponse, content_type: &str) -> String {
+    assert_eq!(response.status, 200);
+    assert!(response
+        .headers
+        .contains(&("Content-Type".to_string(), content_type.to_string())));
+    let lines = pkt_lines(response.body.as_deref().unwrap());
+    lines[0].strip_prefix("ERR ").unwrap().to_string()
+}
+
+fn v1_fetch_body(wants: &[&str], capabilities: &str, haves: &[&str], done: bool) -> Vec<u8> {
+    let mut writer = PktLineWriter::new();
+    for (i, want) in wants.iter().enumerate() {
+        if i == 0 {
+            writer
+                .write_line(&format!("want {} {}", want, capabilities))
+                .unwrap();
+        } else {
+            writer.write_line(&format!("want {}", want)).unwrap();
+        }
+    }
+    writer.flush();
+    for have in haves {
+        writer.write_line(&format!("have {}", have)).unwrap();
+    }
+    if done {
+        writer.write_line("done").unwrap();
+    } else {
+        writer.flush();
+    }
+    writer.into_bytes()
+}
+
+#[test]
+fn v1_advertisement_without_git_protocol_header() {
+    let (pack, head) = fixture(HashAlgorithm::Sha1);
+    let mut repo = GitRepoState::default();
+    push_main(&mut repo, &pack, &head);
+
+    let v0 = repo.dispatch(&request("GET", "/info/refs?service=git-upload-pack", None));
+    let lines = pkt_lines(v0.body.as_deref().unwrap());
+    assert_eq!(lines[0], "# service=git-upload-pack");
+    assert!(lines[1].starts_with(&format!("{} HEAD\0", head)));
+    assert!(lines[1].contains("multi_ack_detailed"));
+    assert!(lines[1].contains("symref=HEAD:refs/heads/main"));
+    assert_eq!(lines[2], format!("{} refs/heads/main", head));
+
+    let mut v1_request = request("GET", "/info/refs?service=git-upload-pack", None);
+    v1_request.headers = vec![("git-protocol".to_string(), "version=1".to_string())];
+    let v1 = repo.dispatch(&v1_request);
+    assert_eq!(pkt_lines(v1.body.as_deref().unwrap())[1], "version 1");
+
+    let mut v2_request = request("GET", "/info/refs?service=git-upload-pack", None);
+    v2_request.headers = vec![("Git-Protocol".to_string(), "version=2".to_string())];
+    let v2 = repo.dispatch(&v2_request);
+    assert_eq!(pkt_lines(v2.body.as_deref().unwrap())[0], "version 2");
+}
+
+#[test]
+fn v1_fetch_sends_full_pack_on_done() {
+    let (pack, head) = fixture(HashAlgorithm::Sha1);
+    let mut repo = GitRepoState::default();
+    push_main(&mut repo, &pack, &head);
+
+    let body = v1_fetch_body(&[&head], "multi_ack_detailed side-band-64k", &[], true);
+    let response = repo.dispatch(&request("POST", "/git-upload-pack", Some(body)));
+    assert_eq!(response.status, 200);
+
+    let mut reader = PktLineReader::from_bytes(response.body.as_deref().unwrap());
+    assert_eq!(
+        reader.read_packet().unwrap(),
+        Some(PktLine::Data(b"NAK\n".to_vec()))
+    );
+    let mut served = Vec::new();
+    while let Some(PktLine::Data(payload)) = reader.read_packet().unwrap() {
+        let (band, data) = demux_sideband(&payload).unwrap();
+        assert_eq!(band, BAND_DATA);
+        served.extend_from_slice(data);
+    }
+    let objects = PackSerializer::parse(&served, HashAlgorithm::Sha1).unwrap();
+    assert_eq!(objects.len(), repo.objects.len());
+}
+
+#[test]
+fn v1_negotiation_acks_common_and_sends_only_missing_objects() {
+    let (pack, head) = fixture(HashAlgorithm::Sha1);
+    let mut repo = GitRepoState::default();
+    push_main(&mut repo, &pack, &head);
+    let parent = match repo.objects.get(&head) {
+        Some(GitObject::Commit { parents, .. }) => parents[0].clone(),
+        other => panic!("head is not a commit: {:?}", other),
+    };
+    let unknown = "1".repeat(40);
+
+    // Negotiation round: the parent is common, and it's enough to give up
+    let body = v1_fetch_body(&[&head], "multi_ack_detailed", &[&parent], false);
+    let round = repo.dispatch(&request("POST", "/git-upload-pack", Some(body)));
+    assert_eq!(
+        pkt_lines(round.body.as_deref().unwrap()),
+        vec![
+            format!("ACK {} common", parent),
+            format!("ACK {} ready", parent),
+            "NAK".to_string(),
+        ]
+    );
+
+    // With a second want the parent doesn't reach, it's not enough
+    let GitObject::Commit {
+        tree,
+        author,
+        committer,
+        ..
+    } = repo.objects.get(&head).unwrap()
+    else {
+        unreachable!()
+    };
+    let orphan = GitObject::Commit {
+        tree,
+        parents: vec![],
+        author,
+        committer,
+        message: "Orphan\n".to_string(),
+    };
+    let orphan_id = orphan.compute_hash(HashAlgorithm::Sha1);
+    repo.add_object(orphan_id.clone(), orphan).unwrap();
+    repo.update_ref("refs/heads/orphan".to_string(), orphan_id.clone())
+        .unwrap();
+    let body = v1_fetch_body(
+        &[&head, &orphan_id],
+        "multi_ack_detailed",
+        &[&parent],
+        false,
+    );
+    let round = repo.dispatch(&request("POST", "/git-upload-pack", Some(body)));
+    assert_eq!(
+        pkt_lines(round.body.as_deref().unwrap()),
+        vec![format!("ACK {} common", parent), "NAK".to_string()]
+    );
+
+    // Final round without side-band: the raw pack follows the final ACK
+    let body = v1_fetch_body(&[&head], "multi_ack_detailed", &[&unknown, &parent], true);
+    let response = repo.dispatch(&request("POST", "/git-upload-pack", Some(body)));
+    let response = response.body.unwrap();
+    let mut reader = PktLineReader::from_bytes(&response);
+    assert_eq!(
+        reader.read_packet().unwrap(),
+        Some(PktLine::Data(
+            format!("ACK {} common\n", parent).into_bytes()
+        ))
+    );
+    assert_eq!(
+        reader.read_packet().unwrap(),
+        Some(PktLine::Data(format!("ACK {}\n", parent).into_bytes()))
+    );
+    let served = PackSerializer::parse(reader.remaining(), HashAlgorithm::Sha1).unwrap();
+    let served_ids: Vec<String> = served
+        .iter()
+        .map(|obj| obj.compute_hash(HashAlgorithm::Sha1))
+        .collect();
+    assert!(served_ids.contains(&head));
+    assert!(!served_ids.contains(&parent));
+    assert!(served_ids.len() < repo.objects.len());
+}
+
+fn v2_request(method: &str, uri: &str, body: Option<Vec<u8>>) -> HttpRequest {
+    let mut request = request(method, uri, body);
+    request.headers = vec![("Git-Protocol".to_string(), "version=2".to_string())];
+    request
+}
+
+#[test]
+fn receive_pack_advertisement_respects_git_protocol_header() {
+    let mut repo = GitRepoState::default();
+
+    let v1 = repo.dispatch(&request("GET", "/info/refs?service=git-receive-pack", None));
+    assert_eq!(
+        pkt_lines(v1.body.as_deref().unwrap())[0],
+        "# service=git-receive-pack"
+    );
+
+    let v2 = repo.dispatch(&v2_request(
+        "GET",
+        "/info/refs?service=git-receive-pack",
+        None,
+    ));
+    let lines = pkt_lines(v2.body.as_deref().unwrap());
+    assert_eq!(lines[0], "version 2");
+    assert!(lines.iter().any(|line| line.starts_with("ls-refs")));
+    assert!(lines.iter().any(|line| line.starts_with("push=")));
+}
+
+#[test]
+fn v2_ls_refs_then_push() {
+    let (pack, head) = fixture(HashAlgorithm::Sha1);
+    let mut repo = GitRepoState::default();
+
+    // Ref discovery on the empty repository
+    let mut ls_refs = PktLineWriter::new();
+    ls_refs.write_line("command=ls-refs").unwrap();
+    ls_refs.delim();
+    ls_refs.write_line("symrefs").unwrap();
+    ls_refs.write_line("unborn").unwrap();
+    ls_refs.flush();
+    let listed = repo.dispatch(&v2_request(
+        "POST",
+        "/git-receive-pack",
+        Some(ls_refs.into_bytes()),
+    ));
+    assert_eq!(
+        pkt_lines(listed.body.as_deref().unwrap()),
+        vec!["unborn HEAD symref-target:refs/heads/main"]
+    );
+
+    let mut push = PktLineWriter::new();
+    push.write_line("command=push").unwrap();
+    push.write_line("report-status").unwrap();
+    push.write_line("object-format=sha1").unwrap();
+    push.delim();
+    push.write_line(&format!(
+        "{} {} refs/heads/main",
+        HashAlgorithm::Sha1.null_oid(),
+        head
+    ))
+    .unwrap();
+    push.write_line("push-option reason=first").unwrap();
+    push.flush();
+    let mut body = push.into_bytes();
+    body.extend_from_slice(&pack);
+
+    let pushed = repo.dispatch(&v2_request("POST", "/git-receive-pack", Some(body)));
+    assert_eq!(
+        pkt_lines(pushed.body.as_deref().unwrap()),
+        vec!["unpack ok", "ok refs/heads/main"]
+    );
+    assert_eq!(repo.refs.get("refs/heads/main"), Some(&head));
+    assert_eq!(
+        repo.reflog.entries("refs/heads/main")[0].message,
+        "push: reason=first"
+    );
+}
+
+#[test]
+fn dumb_protocol_serves_refs_head_and_loose_objects() {
+    let (pack, head) = fixture(HashAlgorithm::Sha1);
+    let mut repo = GitRepoState::default();
+    push_main(&mut repo, &pack, &head);
+
+    let refs = repo.dispatch(&request("GET", "/info/refs", None));
+    assert_eq!(body_text(&refs), format!("{}\trefs/heads/main\n", head));
+
+    let head_file = repo.dispatch(&request("GET", "/HEAD", None));
+    assert_eq!(body_text(&head_file), "ref: refs/heads/main\n");
+
+    let packs = repo.dispatch(&request("GET", "/objects/info/packs", None));
+    assert_eq!(packs.status, 200);
+
+    let loose = repo.dispatch(&request(
+        "GET",
+        &format!("/objects/{}/{}", &head[..2], &head[2..]),
+        None,
+    ));
+    assert_eq!(loose.status, 200);
+    let object = GitObject::from_loose_format(
+        &decompress_zlib(loose.body.as_deref().unwrap()).unwrap(),
+        HashAlgorithm::Sha1,
+    )
+    .unwrap();
+    assert_eq!(object.compute_hash(HashAlgorithm::Sha1), head);
+
+    let missing = repo.dispatch(&request(
+        "GET",
+        &format!("/objects/00/{}", "0".repeat(38)),
+        None,
+    ));
+    assert_eq!(missing.status, 404);
+    let malformed = repo.dispatch(&request("GET", "/objects/info/alternates", None));
+    assert_eq!(malformed.status, 404);
+}
+
+#[test]
+fn git_daemon_v0_session_sends_pack_after_done() {
+    let (pack, head) = fixture(HashAlgorithm::Sha1);
+    let mut repo = GitRepoState::default();
+    push_main(&mut repo, &pack, &head);
+
+    let mut hello = PktLineWriter::new();
+    hello
+        .write_data(b"git-upload-pack /repo.git\0host=localhost\0")
+        .unwrap();
+    let (mut session, advertisement) = DaemonSession::open(&repo, &hello.into_bytes()).unwrap();
+    let lines = pkt_lines(&advertisement);
+    assert!(lines[0].starts_with(&format!("{} HEAD\0", head)));
+
+    let body = v1_fetch_body(&[&head], "multi_ack_detailed side-band-64k", &[], true);
+    let response = session.receive(&repo, &body).unwrap();
+    assert!(session.is_finished());
+
+    let mut reader = PktLineReader::from_bytes(&response);
+    assert_eq!(
+        reader.read_packet().unwrap(),
+        Some(PktLine::Data(b"NAK\n".to_vec()))
+    );
+    let mut served = Vec::new();
+    while let Some(PktLine::Data(payload)) = reader.read_packet().unwrap() {
+        served.extend_from_slice(demux_sideband(&payload).unwrap().1);
+    }
+    let objects = PackSerializer::parse(&served, HashAlgorithm::Sha1).unwrap();
+    assert_eq!(objects.len(), repo.objects.len());
+}
+
+#[test]
+fn gzip_encoded_upload_pack_body_is_inflated() {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    let (pack, head) = fixture(HashAlgorithm::Sha1);
+    let mut repo = GitRepoState::default();
+    push_main(&mut repo, &pack, &head);
+
+    // Large negotiations are gzipped by git; the haves here are all unknown
+    let haves: Vec<String> = (0..200).map(|i| format!("{:040x}", i + 1)).collect();
+    let have_refs: Vec<&str> = haves.iter().map(String::as_str).collect();
+    let body = v1_fetch_body(&[&head], "multi_ack_detailed", &have_refs, false);
+    let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+    gzip.write_all(&body).unwrap();
+
+    let mut gzipped = request("POST", "/git-upload-pack", Some(gzip.finish().unwrap()));
+    gzipped.headers = vec![("Content-Encoding".to_string(), "gzip".to_string())];
+    let response = repo.dispatch(&gzipped);
+    assert_eq!(response.status, 200);
+    assert_eq!(pkt_lines(response.body.as_deref().unwrap()), vec!["NAK"]);
+
+    let mut corrupt = request("POST", "/git-upload-pack", Some(body));
+    corrupt.headers = vec![("Content-Encoding".to_string(), "gzip".to_string())];
+    let response = repo.dispatch(&corrupt);
+    assert!(
+        remote_error(&response, "application/x-git-upload-pack-result")
+            .contains("Invalid gzip request body")
+    );
+}
+
+#[test]
+fn config_sets_agent_limits_and_disabled_features() {
+    let mut config = Config {
+        agent: "acme-git/7".to_string(),
+        ..Config::default()
+    };
+    config.limits.max_body_bytes = 64;
+    config.features.receive_pack = false;
+    config.features.dumb_http = false;
+    let mut repo = GitRepoState::from_config(config);
+
+    let response = repo.dispatch(&request("GET", "/info/refs?service=git-upload-pack", None));
+    assert!(body_text(&response).contains("agent=acme-git/7"));
+    assert!(!body_text(&response).contains("git-server/"));
+
+    let response = repo.dispatch(&request("GET", "/info/refs?service=git-receive-pack", None));
+    assert_eq!(
+        remote_error(&response, "application/x-git-receive-pack-advertisement"),
+        "permission denied: pushing to this repository is disabled"
+    );
+    let (pack, head) = fixture(HashAlgorithm::Sha1);
+    repo.config.limits.max_body_bytes = u64::MAX;
+    let pushed = push_main(&mut repo, &pack, &head);
+    assert!(
+        remote_error(&pushed, "application/x-git-receive-pack-result")
+            .contains("pushing to this repository is disabled")
+    );
+    assert!(repo.refs.is_empty());
+
+    // Routes that don't speak pkt-lines keep their HTTP status
+    assert_eq!(repo.dispatch(&request("GET", "/HEAD", None)).status, 404);
+    assert_eq!(
+        repo.dispatch(&request("GET", "/info/refs", None)).status,
+        400
+    );
+    let unknown = repo.dispatch(&request("GET", "/info/refs?service=git-frob", None));
+    assert_eq!(unknown.status, 400);
+    assert!(body_text(&unknown).contains("Unknown service: git-frob"));
+
+    repo.config.limits.max_body_bytes = 64;
+    let body = v1_fetch_body(&[&head], "multi_ack_detailed", &[], true);
+    let response = repo.dispatch(&request("POST", "/git-upload-pack", Some(body)));
+    assert!(
+        remote_error(&response, "application/x-git-upload-pack-result")
+            .contains("exceeds maximum of 64")
+    );
+}
+
+#[test]
+fn pack_with_too_many_objects_is_rejected() {
+    let mut repo = GitRepoState::default();
+    repo.config.limits.max_pack_objects = 1;
+    let (pack, head) = fixture(HashAlgorithm::Sha1);
+
+    let response = push_main(&mut repo, &pack, &head);
+    assert!(body_text(&response).contains("limit exceeded"));
+    assert!(repo.refs.is_empty());
+    assert!(repo.objects.is_empty());
+}
+
+fn push_ref(
+    repo: &mut GitRepoState,
+    ref_name: &str,
+    old: &str,
+    new: &str,
+    pack: &[u8],
+) -> HttpResponse {
+    let mut writer = PktLineWriter::new();
+    writer
+        .write_line(&format!("{} {} {}\0report-status", old, new, ref_name))
+        .unwrap();
+    writer.flush();
+    let mut body = writer.into_bytes();
+    body.extend_from_slice(pack);
+
+    repo.dispatch(&request("POST", "/git-receive-pack", Some(body)))
+}
+
+/// A pack with a root commit whose tree holds `files` small blobs
+fn wide_commit_pack(files: usize, format: HashAlgorithm) -> (Vec<u8>, String) {
+    let mut objects: Vec<GitObject> = (0..files)
+        .map(|i| GitObject::Blob {
+            content: format!("file {}\n", i).into_bytes(),
+        })
+        .collect();
+    let entries = objects
+        .iter()
+        .enumerate()
+        .map(|(i, blob)| {
+            TreeEntry::new(
+                "100644".to_string(),
+                format!("f{:04}", i),
+                blob.compute_hash(format),
+            )
+        })
+        .collect();
+    let tree = GitObject::Tree { entries };
+    let commit = GitObject::Commit {
+        tree: tree.compute_hash(format),
+        parents: vec![],
+        author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+        committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+        message: "Wide tree\n".to_string(),
+    };
+    let head = commit.compute_hash(format);
+    objects.push(tree);
+    objects.push(commit);
+
+    let mut pack = b"PACK".to_vec();
+    pack.extend_from_slice(&2u32.to_be_bytes());
+    pack.extend_from_slice(&(objects.len() as u32).to_be_bytes());
+    for object in &objects {
+        pack.extend(PackSerializer::serialize_object(object).unwrap());
+    }
+    let checksum = format.digest(&pack);
+    pack.extend(checksum);
+    (pack, head)
+}
+
+#[test]
+fn thin_pack_push_is_completed_with_its_bases() {
+    let format = HashAlgorithm::Sha1;
+    let (pack, head) = fixture(format);
+    let mut repo = GitRepoState::default();
+    assert_eq!(push_main(&mut repo, &pack, &head).status, 200);
+
+    // What `git push` sends by default: the new commit as a delta against
+    // its parent, which only the server has
+    let GitObject::Commit {
+        tree,
+        author,
+        committer,
+        ..
+    } = repo.objects.get(&head).unwrap()
+    else {
+        panic!("head is not a commit");
+    };
+    let second = GitObject::Commit {
+        tree,
+        parents: vec![head.clone()],
+        author,
+        committer,
+        message: "Second commit\n".to_string(),
+    };
+    let second_id = second.compute_hash(format);
+    let base = LooseObjectSerializer::serialize_content(&repo.objects.get(&head).unwrap());
+    let target = LooseObjectSerializer::serialize_content(&second);
+    let delta = create_delta(&base, &target, target.len()).unwrap();
+    let mut thin = b"PACK\0\0\0\x02\0\0\0\x01".to_vec();
+    thin.extend(PackSerializer::encode_entry(
+        OBJ_REF_DELTA,
+        delta.len(),
+        Some(&DeltaBase::Id(hex::decode(&head).unwrap())),
+        &compress_zlib(&delta),
+    ));
+    thin.extend(format.digest(&thin));
+
+    let pushed = push_ref(&mut repo, "refs/heads/main", &head, &second_id, &thin);
+    assert_eq!(
+        pkt_lines(pushed.body.as_deref().unwrap()),
+        vec!["unpack ok", "ok refs/heads/main"]
+    );
+    assert_eq!(repo.objects.get(&second_id), Some(second));
+
+    // The stored pack holds the base too, so it can be read on its own
+    let reloaded = decode_state(&encode_state(&repo).unwrap()).unwrap();
+    assert!(reloaded.objects.get(&second_id).is_some());
+    assert_eq!(repo.objects.packs().len(), 2);
+}
+
+#[test]
+fn tags_and_signed_commits_keep_their_git_ids() {
+    let (pack, ids) = signed_fixture();
+    let format = HashAlgorithm::Sha1;
+    let null = format.null_oid();
+    let merge = fixture_id(&ids, "merge");
+    let tag = fixture_id(&ids, "tag");
+    let mut repo = GitRepoState::default();
+
+    let mut writer = PktLineWriter::new();
+    writer
+        .write_line(&format!(
+            "{} {} refs/heads/main\0report-status",
+            null, merge
+        ))
+        .unwrap();
+    writer
+        .write_line(&format!("{} {} refs/tags/v1.0", null, tag))
+        .unwrap();
+    writer.flush();
+    let mut body = writer.into_bytes();
+    body.extend_from_slice(&pack);
+    let pushed = repo.dispatch(&request("POST", "/git-receive-pack", Some(body)));
+    let report = body_text(&pushed);
+    assert!(report.contains("unpack ok"), "{}", report);
+    assert!(report.contains("ok refs/heads/main"), "{}", report);
+    assert!(report.contains("ok refs/tags/v1.0"), "{}", report);
+
+    // Stored under their git ids, with the bytes those ids hash
+    for (name, id) in &ids {
+        let raw = repo
+            .objects
+            .get_raw(id)
+            .unwrap_or_else(|| panic!("{} missing", name));
+        assert_eq!(&raw.id(format), id);
+    }
+
+    // And fetched back the same
+    let mut fetch = PktLineWriter::new();
+    fetch.write_line("command=fetch").unwrap();
+    fetch.delim();
+    fetch.write_line(&format!("want {}", merge)).unwrap();
+    fetch.write_line(&format!("want {}", tag)).unwrap();
+    fetch.write_line("done").unwrap();
+    fetch.flush();
+    let fetched = repo.dispatch(&request(
+        "POST",
+        "/git-upload-pack",
+        Some(fetch.into_bytes()),
+    ));
+    let served = extract_pack(fetched.body.as_deref().unwrap());
+    let served: Vec<String> = PackSerializer::parse_entries(&served, format)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.id)
+        .collect();
+    for (name, id) in &ids {
+        assert!(served.contains(id), "{} {} wasn't served", name, id);
+    }
+
+    // Dumb HTTP serves each as a loose object whose content hashes to its id
+    for (name, id) in &ids {
+        let uri = format!("/objects/{}/{}", &id[..2], &id[2..]);
+        let response = repo.dispatch(&request("GET", &uri, None));
+        assert_eq!(response.status, 200, "{}", name);
+        let loose = decompress_zlib(response.body.as_deref().unwrap()).unwrap();
+        assert_eq!(&hex::encode(format.digest(&loose)), id, "{}", name);
+    }
+}
+
+#[test]
+fn bare_repository_is_served_and_updated_in_place() {
+    let format = HashAlgorithm::Sha1;
+    let dir = std::env::temp_dir().join(format!("git-server-{}-bare-push", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let path = dir.to_str().unwrap().to_string();
+    let config = Config {
+        repo_path: Some(path.clone()),
+        ..Config::default()
+    };
+    let mut repo = GitRepoState::open_bare(config).unwrap();
+    let read = |file: &str| std::fs::read(dir.join(file)).unwrap();
+
+    // A small push is written as loose objects and plain ref files
+    let (pack, head) = fixture(format);
+    let pushed = push_main(&mut repo, &pack, &head);
+    assert!(body_text(&pushed).contains("ok refs/heads/main"));
+    assert_eq!(read("HEAD"), b"ref: refs/heads/main\n");
+    assert_eq!(read("refs/heads/main"), format!("{}\n", head).as_bytes());
+    let fixture_objects = PackSerializer::parse(&pack, format).unwrap();
+    for object in &fixture_objects {
+        let id = object.compute_hash(format);
+        let loose = decompress_zlib(&read(&format!("objects/{}/{}", &id[..2], &id[2..]))).unwrap();
+        assert_eq!(loose, object.to_loose_format());
+    }
+
+    // A large one is kept as a pack with an index
+    let (wide, wide_head) = wide_commit_pack(150, format);
+    let pushed = push_ref(
+        &mut repo,
+        "refs/heads/wide",
+        &format.null_oid(),
+        &wide_head,
+        &wide,
+    );
+    assert!(body_text(&pushed).contains("ok refs/heads/wide"));
+    let name = hex::encode(&wide[wide.len() - 20..]);
+    assert_eq!(read(&format!("objects/pack/pack-{}.pack", name)), wide);
+    assert!(dir.join(format!("objects/pack/pack-{}.idx", name)).exists());
+
+    // Only settings are persisted. A new actor reads everything else back
+    // from disk...
+    let saved = encode_state(&repo).unwrap();
+    let from_disk = {
+        let saved = saved.clone();
+        std::thread::spawn(move || {
+            let repo = decode_state(&saved).unwrap();
+            (repo.refs, repo.objects.ids())
+        })
+        .join()
+        .unwrap()
+    };
+    assert_eq!(from_disk, (repo.refs.clone(), repo.objects.ids()));
+
+    // ...and this one keeps what it saved instead of reading it all again
+    std::fs::write(dir.join("refs/heads/outside"), format!("{}\n", head)).unwrap();
+    let mut reloaded = decode_state(&saved).unwrap();
+    assert_eq!(reloaded.refs, repo.refs);
+    assert_eq!(reloaded.objects.ids(), repo.objects.ids());
+    assert_eq!(reloaded.objects.len(), fixture_objects.len() + 152);
+    assert!(reloaded.validate().is_empty());
+    std::fs::remove_file(dir.join("refs/heads/outside")).unwrap();
+
+    let mut fetch = PktLineWriter::new();
+    fetch.write_line("command=fetch").unwrap();
+    fetch.delim();
+    fetch.write_line(&format!("want {}", head)).unwrap();
+    fetch.write_line(&format!("want {}", wide_head)).unwrap();
+    fetch.write_line("done").unwrap();
+    fetch.flush();
+    let fetched = reloaded.dispatch(&request(
+        "POST",
+        "/git-upload-pack",
+        Some(fetch.into_bytes()),
+    ));
+    let served = extract_pack(fetched.body.as_deref().unwrap());
+    assert_eq!(
+        PackSerializer::parse(&served, format).unwrap().len(),
+        reloaded.objects.len()
+    );
+
+    let deleted = push_ref(
+        &mut reloaded,
+        "refs/heads/wide",
+        &wide_head,
+        &format.null_oid(),
+        &[],
+    );
+    assert!(body_text(&deleted).contains("ok refs/heads/wide"));
+    assert!(!dir.join("refs/heads/wide").exists());
+
+    // Collecting garbage deletes the unreachable pack from disk
+    reloaded.config.gc.grace_period_secs = 0;
+    let report = reloaded.collect_garbage(u64::MAX, false).unwrap();
+    assert_eq!((report.pruned, report.packs_removed), (152, 1));
+    assert!(!dir
+        .join(format!("objects/pack/pack-{}.pack", name))
+        .exists());
+    assert!(!dir.join(format!("objects/pack/pack-{}.idx", name)).exists());
+    assert!(reloaded.validate().is_empty());
+
+    // Symbolic refs are ref files git reads too
+    reloaded
+        .set_symref("refs/remotes/origin/HEAD", "refs/heads/main")
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(dir.join("refs/remotes/origin/HEAD")).unwrap(),
+        "ref: refs/heads/main\n"
+    );
+    let reopened = decode_state(&encode_state(&reloaded).unwrap()).unwrap();
+    assert_eq!(reopened.symrefs, reloaded.symrefs);
+    assert_eq!(reopened.resolved_refs().len(), 2);
+}
+
+#[test]
+fn bare_repository_writes_loose_objects_as_received() {
+    let (pack, ids) = signed_fixture();
+    let format = HashAlgorithm::Sha1;
+    let dir = std::env::temp_dir().join(format!("git-server-{}-bare-signed", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let config = Config {
+        repo_path: Some(dir.to_str().unwrap().to_string()),
+        ..Config::default()
+    };
+    let mut repo = GitRepoState::open_bare(config).unwrap();
+
+    let tag = fixture_id(&ids, "tag");
+    let pushed = push_ref(&mut repo, "refs/tags/v1.0", &format.null_oid(), tag, &pack);
+    assert!(body_text(&pushed).contains("ok refs/tags/v1.0"));
+    assert!(repo.objects.packs().is_empty());
+
+    // Each file holds the bytes its id hashes, headers and all
+    for (name, id) in &ids {
+        let file = dir.join(format!("objects/{}/{}", &id[..2], &id[2..]));
+        let loose = decompress_zlib(&std::fs::read(&file).unwrap()).unwrap();
+        assert_eq!(&hex::encode(format.digest(&loose)), id, "{}", name);
+    }
+    assert!(!dir.join("refs/tags/v1.0.lock").exists());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("refs/tags/v1.0")).unwrap(),
+        format!("{}\n", tag)
+    );
+}
+
+#[test]
+fn admin_gc_prunes_objects_of_deleted_branch() {
+    let format = HashAlgorithm::Sha1;
+    let mut repo = GitRepoState::default();
+    repo.config.gc.grace_period_secs = 0;
+    let (pack, head) = fixture(format);
+    assert!(body_text(&push_main(&mut repo, &pack, &head)).contains("ok refs/heads/main"));
+    let (wide, wide_head) = wide_commit_pack(150, format);
+    push_ref(
+        &mut repo,
+        "refs/heads/wide",
+        &format.null_oid(),
+        &wide_head,
+        &wide,
+    );
+    push_ref(
+        &mut repo,
+        "refs/heads/wide",
+        &wide_head,
+        &format.null_oid(),
+        &[],
+    );
+
+    // Off by default
+    assert_eq!(
+        repo.dispatch(&request("POST", "/admin/gc", None)).status,
+        404
+    );
+    repo.config.features.admin_routes = true;
+
+    // The deleted branch's reflog still points at its commit
+    let kept = repo.dispatch(&request("POST", "/admin/gc?dry_run=true", None));
+    let report: serde_json::Value = serde_json::from_slice(kept.body.as_deref().unwrap()).unwrap();
+    assert_eq!(report["pruned"], 0);
+    // ... until the reflog entries expire
+    repo.reflog.refs.remove("refs/heads/wide");
+
+    let dry_run = repo.dispatch(&request("POST", "/admin/gc?dry_run=true", None));
+    assert_eq!(dry_run.status, 200);
+    let report: serde_json::Value =
+        serde_json::from_slice(dry_run.body.as_deref().unwrap()).unwrap();
+    assert_eq!(report["dry_run"], true);
+    assert_eq!(report["pruned"], 152);
+    assert_eq!(report["packs_removed"], 1);
+    assert!(repo.objects.contains_key(&wide_head));
+
+    let collected = repo.dispatch(&request("POST", "/admin/gc", None));
+    let report: serde_json::Value =
+        serde_json::from_slice(collected.body.as_deref().unwrap()).unwrap();
+    assert_eq!(report["dry_run"], false);
+    assert_eq!(report["pruned"], 152);
+    assert!(!repo.objects.contains_key(&wide_head));
+    assert_eq!(repo.objects.packs().len(), 1);
+    assert!(repo.validate().is_empty());
+}
+
+#[test]
+fn repack_stores_deltas_that_fetches_reuse() {
+    let format = HashAlgorithm::Sha1;
+    let mut repo = GitRepoState::default();
+    repo.config.features.admin_routes = true;
+    let mut content: String = (0..300).map(|i| format!("line {}\n", i)).collect();
+    let mut parents = vec![];
+    for n in 0..5 {
+        content.push_str(&format!("revision {}\n", n));
+        let blob = GitObject::Blob {
+            content: content.clone().into_bytes(),
+        };
+        let blob_id = blob.compute_hash(format);
+        let tree = GitObject::Tree {
+            entries: vec![TreeEntry::new(
+                "100644".into(),
+                "notes.txt".into(),
+                blob_id.clone(),
+            )],
+        };
+        let tree_id = tree.compute_hash(format);
+        let commit = GitObject::Commit {
+            tree: tree_id.clone(),
+            parents,
+            author: "A <a@example.com> 0 +0000".into(),
+            committer: "A <a@example.com> 0 +0000".into(),
+            message: format!("revision {}\n", n),
+        };
+        let commit_id = commit.compute_hash(format);
+        for (id, object) in [
+            (blob_id, blob),
+            (tree_id, tree),
+            (commit_id.clone(), commit),
+        ] {
+            repo.add_object(id, object).unwrap();
+        }
+        parents = vec![commit_id];
+    }
+    let head = parents[0].clone();
+    repo.update_ref("refs/heads/main".into(), head.clone())
+        .unwrap();
+
+    let repacked = repo.dispatch(&request("POST", "/admin/repack?depth=2", None));
+    assert_eq!(repacked.status, 200);
+    let report: serde_json::Value =
+        serde_json::from_slice(repacked.body.as_deref().unwrap()).unwrap();
+    assert_eq!(
+        (report["objects"].as_u64(), report["depth"].as_u64()),
+        (Some(15), Some(2))
+    );
+    assert!(report["deltas"].as_u64().unwrap() >= 3);
+    assert_eq!(
+        repo.dispatch(&request("POST", "/admin/repack?window=many", None))
+            .status,
+        400
+    );
+
+    // The served pack carries the stored deltas, and resolves to every object
+    let mut fetch = PktLineWriter::new();
+    fetch.write_line("command=fetch").unwrap();
+    fetch.delim();
+    fetch.write_line(&format!("want {}", head)).unwrap();
+    fetch.write_line("done").unwrap();
+    fetch.flush();
+    let fetched = repo.dispatch(&v2_request(
+        "POST",
+        "/git-upload-pack",
+        Some(fetch.into_bytes()),
+    ));
+    let served = extract_pack(fetched.body.as_deref().unwrap());
+    let entries = PackSerializer::parse_entries(&served, format).unwrap();
+    assert_eq!(entries.len(), 15);
+    let ref_deltas = entries
+        .iter()
+        .filter(|entry| served[entry.offset as usize] >> 4 & 0x7 == 7)
+        .count();
+    assert!(ref_deltas >= 3, "{} deltas served", ref_deltas);
+    for entry in entries {
+        let id = entry.object.compute_hash(format);
+        assert_eq!(repo.objects.get(&id), Some(entry.object));
+    }
+}
+
+#[test]
+fn reflog_records_force_push_and_restores_it() {
+    let format = HashAlgorithm::Sha1;
+    let mut repo = GitRepoState::default();
+    repo.config.gc.grace_period_secs = 0;
+    repo.config.features.admin_routes = true;
+    let (pack, head) = fixture(format);
+    assert!(body_text(&push_main(&mut repo, &pack, &head)).contains("ok refs/heads/main"));
+
+    // Force-push an unrelated history over main, as alice, with push options
+    let (wide, wide_head) = wide_commit_pack(3, format);
+    let mut writer = PktLineWriter::new();
+    writer
+        .write_line(&format!(
+            "{} {} refs/heads/main\0report-status push-options",
+            head, wide_head
+        ))
+        .unwrap();
+    writer.flush();
+    writer.write_line("reason=oops").unwrap();
+    writer.flush();
+    let mut body = writer.into_bytes();
+    body.extend_from_slice(&wide);
+    let mut push = request("POST", "/git-receive-pack", Some(body));
+    push.headers.push((
+        "Authorization".to_string(),
+        "Basic YWxpY2U6c2VjcmV0".to_string(),
+    ));
+    assert!(body_text(&repo.dispatch(&push)).contains("ok refs/heads/main"));
+
+    // The overwritten history is still a GC root
+    repo.dispatch(&request("POST", "/admin/gc", None));
+    assert!(repo.objects.contains_key(&head));
+
+    let response = repo.dispatch(&request("GET", "/admin/reflog?ref=refs/heads/main", None));
+    assert_eq!(response.status, 200);
+    let log: serde_json::Value = serde_json::from_slice(response.body.as_deref().unwrap()).unwrap();
+    assert_eq!(log[0]["old_id"], head);
+    assert_eq!(log[0]["new_id"], wide_head);
+    assert_eq!(log[0]["identity"], "alice");
+    assert_eq!(log[0]["message"], "push: reason=oops");
+    assert_eq!(log[1]["identity"], "anonymous");
+    assert_eq!(log[1]["message"], "push");
+
+    let restored = repo.dispatch(&request(
+        "POST",
+        "/admin/reflog/restore?ref=refs/heads/main&at=1",
+        None,
+    ));
+    assert_eq!(restored.status, 200);
+    let entry: serde_json::Value =
+        serde_json::from_slice(restored.body.as_deref().unwrap()).unwrap();
+    assert_eq!(entry["new_id"], head);
+    assert_eq!(entry["message"], "restore: refs/heads/main@{1}");
+    assert_eq!(repo.refs["refs/heads/main"], head);
+
+    let all: serde_json::Value = serde_json::from_slice(
+        repo.dispatch(&request("GET", "/admin/reflog", None))
+            .body
+            .as_deref()
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(all["refs/heads/main"].as_array().unwrap().len(), 3);
+
+    // Out of range
+    let response = repo.dispatch(&request(
+        "POST",
+        "/admin/reflog/restore?ref=refs/heads/main&at=7",
+        None,
+    ));
+    assert_eq!(response.status, 400);
+}
+
+#[test]
+fn symrefs_are_advertised_and_head_is_configurable() {
+    let format = HashAlgorithm::Sha1;
+    let (pack, head) = fixture(format);
+    let mut repo = GitRepoState::default();
+    repo.config.features.admin_routes = true;
+    push_main(&mut repo, &pack, &head);
+    push_ref(&mut repo, "refs/heads/dev", &format.null_oid(), &head, &[]);
+
+    // Change the default branch and add a remote-tracking style symref
+    let set_head = repo.dispatch(&request(
+        "POST",
+        "/admin/symref?name=HEAD&target=refs/heads/dev",
+        None,
+    ));
+    assert_eq!(set_head.status, 200);
+    let symref = repo.dispatch(&request(
+        "POST",
+        "/admin/symref?name=refs/remotes/origin/HEAD&target=refs/heads/main",
+        None,
+    ));
+    let listed: serde_json::Value =
+        serde_json::from_slice(symref.body.as_deref().unwrap()).unwrap();
+    assert_eq!(listed["HEAD"], "refs/heads/dev");
+    assert_eq!(listed["refs/remotes/origin/HEAD"], "refs/heads/main");
+    let loop_back = repo.dispatch(&request(
+        "POST",
+        "/admin/symref?name=HEAD&target=refs/remotes/origin/HEAD",
+        None,
+    ));
+    assert_eq!(loop_back.status, 409);
+
+    let v0 = repo.dispatch(&request("GET", "/info/refs?service=git-upload-pack", None));
+    let lines = pkt_lines(v0.body.as_deref().unwrap());
+    assert!(lines[1].contains("symref=HEAD:refs/heads/dev"));
+    assert_eq!(lines[4], format!("{} refs/remotes/origin/HEAD", head));
+
+    let mut ls_refs = PktLineWriter::new();
+    ls_refs.write_line("command=ls-refs").unwrap();
+    ls_refs.delim();
+    for arg in [
+        "symrefs",
+        "peel",
+        "unborn",
+        "ref-prefix HEAD",
+        "ref-prefix refs/remotes/",
+    ] {
+        ls_refs.write_line(arg).unwrap();
+    }
+    ls_refs.flush();
+    let listed = repo.dispatch(&v2_request(
+        "POST",
+        "/git-upload-pack",
+        Some(ls_refs.into_bytes()),
+    ));
+    assert_eq!(
+        pkt_lines(listed.body.as_deref().unwrap()),
+        vec![
+            format!("{} HEAD symref-target:refs/heads/dev", head),
+            format!(
+                "{} refs/remotes/origin/HEAD symref-target:refs/heads/main",
+                head
+            ),
+        ]
+    );
+
+    // Symbolic refs can't be pushed to, and deleting HEAD's branch moves HEAD
+    let pushed = push_ref(&mut repo, "refs/remotes/origin/HEAD", &head, &head, &[]);
+    assert!(body_text(&pushed).contains("ng refs/remotes/origin/HEAD is a symbolic ref"));
+    push_ref(&mut repo, "refs/heads/dev", &head, &format.null_oid(), &[]);
+    assert_eq!(repo.head, "refs/heads/main");
+    assert_eq!(
+        body_text(&repo.dispatch(&request("GET", "/HEAD", None))),
+        "ref: refs/heads/main\n"
+    );
+
+    let deleted = repo.dispatch(&request(
+        "DELETE",
+        "/admin/symref?name=refs/remotes/origin/HEAD",
+        None,
+    ));
+    assert_eq!(deleted.status, 200);
+    assert!(repo.symrefs.is_empty());
+}
+
+#[test]
+fn namespaces_share_objects_and_keep_refs_apart() {
+    let format = HashAlgorithm::Sha1;
+    let (pack, head) = fixture(format);
+    let mut repo = GitRepoState::default();
+    push_main(&mut repo, &pack, &head);
+    let objects = repo.objects.len();
+
+    // A fork pushes the same history to its own namespace
+    let mut writer = PktLineWriter::new();
+    writer
+        .write_line(&format!(
+            "{} {} refs/heads/topic\0report-status",
+            format.null_oid(),
+            head
+        ))
+        .unwrap();
+    writer.flush();
+    let mut body = writer.into_bytes();
+    body.extend_from_slice(&pack);
+    let pushed = repo.dispatch(&request("POST", "/ns/fork/git-receive-pack", Some(body)));
+    assert!(body_text(&pushed).contains("ok refs/heads/topic"));
+    assert_eq!(repo.objects.len(), objects);
+    assert_eq!(repo.refs["refs/namespaces/fork/refs/heads/topic"], head);
+
+    // Each side only sees its own refs, and the fork has its own HEAD
+    let fork = repo.dispatch(&request(
+        "GET",
+        "/ns/fork/info/refs?service=git-upload-pack",
+        None,
+    ));
+    let lines = pkt_lines(fork.body.as_deref().unwrap());
+    assert!(lines[1].starts_with(&format!("{} HEAD\0", head)));
+    assert!(lines[1].contains("symref=HEAD:refs/heads/topic"));
+    assert_eq!(lines[2], format!("{} refs/heads/topic", head));
+    assert_eq!(lines.len(), 3);
+
+    let receive = repo.dispatch(&request(
+        "GET",
+        "/ns/fork/info/refs?service=git-receive-pack",
+        None,
+    ));
+    let lines = pkt_lines(receive.body.as_deref().unwrap());
+    assert!(lines[1].starts_with(&format!("{} refs/heads/topic\0", head)));
+
+    let mut ls_refs = PktLineWriter::new();
+    ls_refs.write_line("command=ls-refs").unwrap();
+    ls_refs.delim();
+    ls_refs.write_line("symrefs").unwrap();
+    ls_refs.write_line("ref-prefix refs/heads/").unwrap();
+    ls_refs.flush();
+    let listed = repo.dispatch(&v2_request(
+        "POST",
+        "/git-upload-pack",
+        Some(ls_refs.into_bytes()),
+    ));
+    assert_eq!(
+        pkt_lines(listed.body.as_deref().unwrap()),
+        vec![format!("{} refs/heads/main", head)]
+    );
+
+    // A fetch through the namespace gets the shared objects
+    let mut fetch = PktLineWriter::new();
+    fetch.write_line("command=fetch").unwrap();
+    fetch.delim();
+    fetch.write_line(&format!("want {}", head)).unwrap();
+    fetch.write_line("done").unwrap();
+    fetch.flush();
+    let fetched = repo.dispatch(&v2_request(
+        "POST",
+        "/ns/fork/git-upload-pack",
+        Some(fetch.into_bytes()),
+    ));
+    assert!(extract_pack(fetched.body.as_deref().unwrap()).starts_with(b"PACK"));
+
+    let invalid = repo.dispatch(&request(
+        "GET",
+        "/ns/a..b/info/refs?service=git-upload-pack",
+        None,
+    ));
+    assert!(remote_error(&invalid, "application/x-git-upload-pack-advertisement").contains("a..b"));
+    repo.config.features.namespaces = false;
+    assert_eq!(
+        repo.dispatch(&request(
+            "GET",
+            "/ns/fork/info/refs?service=git-upload-pack",
+            None
+        ))
+        .status,
+        404
+    );
+}
+
+#[test]
+fn hidden_refs_are_not_advertised_pushed_or_fetched() {
+    let format = HashAlgorithm::Sha1;
+    let (pack, head) = fixture(format);
+    let mut repo = GitRepoState::default();
+    push_main(&mut repo, &pack, &head);
+    push_ref(
+        &mut repo,
+        "refs/pull/1/head",
+        &format.null_oid(),
+        &head,
+        &[],
+    );
+    repo.config.transfer.hide_refs = vec!["refs/pull".to_string()];
+
+    let v0 = repo.dispatch(&request("GET", "/info/refs?service=git-upload-pack", None));
+    let lines = pkt_lines(v0.body.as_deref().unwrap());
+    assert_eq!(lines.len(), 3);
+    assert!(!lines.iter().any(|line| line.contains("refs/pull")));
+    let receive = repo.dispatch(&request("GET", "/info/refs?service=git-receive-pack", None));
+    assert!(!body_text(&receive).contains("refs/pull"));
+
+    let pushed = push_ref(
+        &mut repo,
+        "refs/pull/2/head",
+        &format.null_oid(),
+        &head,
+        &[],
+    );
+    assert!(body_text(&pushed).contains("ng refs/pull/2/head permission denied"));
+    assert!(!repo.refs.contains_key("refs/pull/2/head"));
+
+    // Once main is gone its commit is only a hidden ref's tip
+    push_ref(&mut repo, "refs/heads/main", &head, &format.null_oid(), &[]);
+    let fetch = v1_fetch_body(&[&head], "multi_ack_detailed side-band-64k", &[], true);
+    let refused = repo.dispatch(&request("POST", "/git-upload-pack", Some(fetch.clone())));
+    assert!(
+        remote_error(&refused, "application/x-git-upload-pack-result")
+            .contains(&format!("not our ref {}", head))
+    );
+
+    repo.config.transfer.allow_tip_sha1_in_want = true;
+    let allowed = repo.dispatch(&request("POST", "/git-upload-pack", Some(fetch)));
+    assert_eq!(allowed.status, 200);
+}
+
+/// Progress (`remote:`) text and the report from a side-band push response
+fn demux_push_response(response: &HttpResponse) -> (String, String) {
+    let mut reader = PktLineReader::from_bytes(response.body.as_deref().unwrap());
+    let (mut progress, mut report) = (Vec::new(), Vec::new());
+    while let Some(packet) = reader.read_packet().unwrap() {
+        if let PktLine::Data(payload) = packet {
+            match demux_sideband(&payload).unwrap() {
+                (BAND_PROGRESS, data) => progress.extend_from_slice(data),
+                (_, data) => report.extend_from_slice(data),
+            }
+        }
+    }
+    (
+        String::from_utf8(progress).unwrap(),
+        String::from_utf8(report).unwrap(),
+    )
+}
+
+#[test]
+fn hook_actors_review_pushes_and_hear_about_them() {
+    let format = HashAlgorithm::Sha1;
+    let (pack, head) = fixture(format);
+    let mut repo = GitRepoState::default();
+    register_native_actor(
+        "policy",
+        Rc::new(|_| {
+            Ok(
+                br#"{"message":"wip branches stay local","rejected":{"refs/heads/wip":"no wip"}}"#
+                    .to_vec(),
+            )
+        }),
+    );
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let log = received.clone();
+    register_native_actor(
+        "ci",
+        Rc::new(move |msg| {
+            log.borrow_mut()
+                .push(serde_json::from_slice::<serde_json::Value>(msg).unwrap());
+            Ok(vec![])
+        }),
+    );
+    repo.config.hooks.pre_receive = vec!["policy".to_string()];
+    repo.config.hooks.post_receive = vec!["ci".to_string()];
+
+    let null = format.null_oid();
+    let mut writer = PktLineWriter::new();
+    writer
+        .write_line(&format!(
+            "{} {} refs/heads/main\0report-status side-band-64k",
+            null, head
+        ))
+        .unwrap();
+    writer
+        .write_line(&format!("{} {} refs/heads/wip", null, head))
+        .unwrap();
+    writer.flush();
+    let mut body = writer.into_bytes();
+    body.extend_from_slice(&pack);
+    let response = repo.dispatch(&request("POST", "/git-receive-pack", Some(body)));
+
+    let (progress, report) = demux_push_response(&response);
+    assert_eq!(progress, "wip branches stay local\n");
+    assert!(report.contains("ok refs/heads/main"));
+    assert!(report.contains("ng refs/heads/wip no wip"));
+    assert_eq!(repo.refs.get("refs/heads/main"), Some(&head));
+    assert!(!repo.refs.contains_key("refs/heads/wip"));
+
+    // post-receive only hears about what changed
+    let events = received.borrow();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["hook"], "post-receive");
+    assert_eq!(events[0]["updates"].as_array().unwrap().len(), 1);
+    assert_eq!(events[0]["updates"][0]["ref"], "refs/heads/main");
+    assert_eq!(events[0]["updates"][0]["new_id"], head.as_str());
+    drop(events);
+
+    // A declined push changes nothing
+    register_native_actor(
+        "freeze",
+        Rc::new(|_| Ok(br#"{"accept":false,"message":"frozen"}"#.to_vec())),
+    );
+    repo.config.hooks.pre_receive = vec!["freeze".to_string()];
+    let response = push_ref(&mut repo, "refs/heads/main", &head, &null, &[]);
+    assert!(body_text(&response).contains("ng refs/heads/main pre-receive hook declined"));
+    assert_eq!(repo.refs.get("refs/heads/main"), Some(&head));
+    assert_eq!(received.borrow().len(), 1);
+}
+
+#[test]
+fn pushes_send_signed_webhooks_and_log_deliveries() {
+    let format = HashAlgorithm::Sha1;
+    let (pack, head) = fixture(format);
+    let mut repo = GitRepoState::default();
+    repo.config.features.admin_routes = true;
+    repo.config.webhooks.endpoints = vec![WebhookEndpoint {
+        url: "https://ci.example.com/hook".to_string(),
+        secret: Some("s3cret".to_string()),
+        events: vec!["push".to_string(), "delete".to_string()],
+    }];
+    let sent = Rc::new(RefCell::new(Vec::new()));
+    let log = sent.clone();
+    register_native_http_client(Rc::new(move |request: &HttpRequest| {
+        log.borrow_mut().push(request.clone());
+        Ok(HttpResponse {
+            status: 200,
+            headers: vec![],
+            body: None,
+        })
+    }));
+
+    push_main(&mut repo, &pack, &head);
+    push_ref(&mut repo, "refs/heads/main", &head, &format.null_oid(), &[]);
+    assert!(repo.webhooks_pending());
+    assert!(sent.borrow().is_empty());
+    assert!(repo.deliver_webhooks());
+
+    // push, push, delete; nothing for the create the endpoint didn't ask for
+    let sent = sent.borrow();
+    let events: Vec<&str> = sent
+        .iter()
+        .map(|request| {
+            let (_, event) = request
+                .headers
+                .iter()
+                .find(|(name, _)| name == "X-Git-Server-Event")
+                .unwrap();
+            event.as_str()
+        })
+        .collect();
+    assert_eq!(events, ["push", "push", "delete"]);
+    let body = sent[0].body.as_deref().unwrap();
+    let payload: serde_json::Value = serde_json::from_slice(body).unwrap();
+    assert_eq!(payload["ref"], "refs/heads/main");
+    assert_eq!(payload["after"], head.as_str());
+    assert_eq!(payload["created"], true);
+    assert!(!payload["commits"].as_array().unwrap().is_empty());
+    let signature = format!("sha256={}", hex::encode(hmac_sha256(b"s3cret", body)));
+    assert!(sent[0]
+        .headers
+        .contains(&("X-Hub-Signature-256".to_string(), signature)));
+
+    let response = repo.dispatch(&request("GET", "/admin/webhooks/deliveries", None));
+    let log: serde_json::Value = serde_json::from_slice(response.body.as_deref().unwrap()).unwrap();
+    assert_eq!(log["pending"], 0);
+    let deliveries = log["deliveries"].as_array().unwrap();
+    assert_eq!(deliveries.len(), 3);
+    assert_eq!(deliveries[0]["event"], "delete");
+    assert_eq!(deliveries[0]["delivered"], true);
+    assert_eq!(deliveries[0]["attempts"], 1);
+}
+
+#[test]
+fn pushes_and_gc_are_streamed_to_event_subscribers() {
+    let format = HashAlgorithm::Sha1;
+    let (pack, head) = fixture(format);
+    let mut repo = GitRepoState::default();
+    repo.config.features.admin_routes = true;
+    let mut stream = EventStream::default();
+    stream.connect(1, "/events", None).unwrap();
+    stream
+        .connect(2, "/events?namespace=fork&events=push", None)
+        .unwrap();
+
+    push_main(&mut repo, &pack, &head);
+    let events = repo.take_events();
+    let kinds: Vec<&str> = events.iter().map(|e| e.kind()).collect();
+    assert_eq!(kinds.first(), Some(&"ref_update"));
+    assert_eq!(kinds.last(), Some(&"push"));
+    assert!(kinds[1..kinds.len() - 1]
+        .iter()
+        .all(|kind| *kind == "commit"));
+    assert!(kinds.len() > 2);
+    assert!(events.iter().all(|e| stream.recipients(e) == [1]));
+    let json = serde_json::to_value(events.last().unwrap()).unwrap();
+    assert_eq!(json["pusher"], "anonymous");
+    assert_eq!(json["updates"][0]["ref"], "refs/heads/main");
+    assert_eq!(json["updates"][0]["new_id"], head.as_str());
+
+    // The same commit pushed to the fork namespace reaches its subscriber too
+    let mut writer = PktLineWriter::new();
+    writer
+        .write_line(&format!(
+            "{} {} refs/heads/main\0report-status",
+            format.null_oid(),
+            head
+        ))
+        .unwrap();
+    writer.flush();
+    let body = writer.into_bytes();
+    let response = repo.dispatch(&request("POST", "/ns/fork/git-receive-pack", Some(body)));
+    assert_eq!(response.status, 200);
+    let events = repo.take_events();
+    let push = events.iter().find(|e| e.kind() == "push").unwrap();
+    assert_eq!(push.namespace(), Some("fork"));
+    assert_eq!(stream.recipients(push), [1, 2]);
+
+    let response = repo.dispatch(&request("POST", "/admin/gc", None));
+    assert_eq!(response.status, 200);
+    let events = repo.take_events();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].kind(), "gc");
+    assert_eq!(stream.recipients(&events[0]), [1]);
+}